@@ -0,0 +1,36 @@
+//! `UdonNative.bench_parse`: drive the parser over an input without building
+//! any Ruby objects per event, for measuring parser throughput on its own.
+//!
+//! The result is `[count, checksum]`: the number of events and an FNV-1a
+//! hash of their kinds in order. Neither depends on spans or content, so
+//! the checksum changes only when the event structure does, which makes a
+//! cheap sanity check that a faster parser still does the same work.
+
+use magnus::{Error, Ruby, Value};
+use udon_core::{Event, Parser};
+
+use crate::{coerce, event_type};
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// A fixed number for each kind of event.
+fn kind(event: &Event) -> u64 {
+    u64::from(event_type::tag(event)) + 1
+}
+
+/// `UdonNative.bench_parse(input)`
+///
+/// The input is parsed as given: no BOM stripping, newline normalization or
+/// NUL check, and no options.
+pub fn bench_parse(ruby: &Ruby, input: Value) -> Result<(usize, u64), Error> {
+    let input = coerce::string(ruby, input)?;
+    let input_bytes = unsafe { input.as_slice() };
+    let mut count = 0;
+    let mut checksum = FNV_OFFSET;
+    Parser::new(input_bytes).parse(|event| {
+        count += 1;
+        checksum = (checksum ^ kind(&event)).wrapping_mul(FNV_PRIME);
+    });
+    Ok((count, checksum))
+}
@@ -0,0 +1,216 @@
+//! Driving a user-provided builder object from parse events.
+//!
+//! `UdonNative.build(input, builder)` calls builder methods as the document is
+//! parsed instead of materializing event hashes. Each method is only called if
+//! the builder responds to it, so partial builders are fine:
+//!
+//! - `start_element(name)` / `end_element` (name is nil for anonymous elements;
+//!   embedded elements use the same pair)
+//! - `start_directive(name)` / `end_directive`
+//! - `attribute(key, value)` (flags arrive as `true`)
+//! - `value(value)` for values outside an attribute, e.g. directive arguments
+//! - `text(string)`, `comment(string)`
+//! - `interpolation(expression)`, `reference(id)`
+//! - `error(code, span)`
+//!
+//! Values are converted to Ruby types: Integer, Float, true/false/nil, Array,
+//! and `UdonNative::Interpolation` for `!{{...}}`; anything else is a String.
+
+use std::collections::HashMap;
+
+use magnus::{
+    encoding::RbEncoding, prelude::*, Error, IntoValue, RArray, RString, Ruby, Symbol, Value,
+};
+use udon_core::{Event, Parser};
+
+use crate::{coerce, error_code_name, span_to_hash, writer::Interpolation};
+
+struct Build<'a> {
+    ruby: &'a Ruby,
+    builder: Value,
+    responds: HashMap<&'static str, bool>,
+    /// Start method waiting for its name event.
+    awaiting_name: Option<&'static str>,
+    /// Attribute key waiting for its value.
+    pending_attr: Option<Value>,
+    /// Arrays being filled, innermost last.
+    arrays: Vec<RArray>,
+    in_comment: bool,
+    failure: Option<Error>,
+}
+
+impl<'a> Build<'a> {
+    fn call(&mut self, method: &'static str, args: &[Value]) -> Result<(), Error> {
+        let responds = match self.responds.get(method) {
+            Some(&responds) => responds,
+            None => {
+                let responds = self.builder.respond_to(method, false)?;
+                self.responds.insert(method, responds);
+                responds
+            }
+        };
+        if responds {
+            let _: Value = self.builder.funcall(method, args)?;
+        }
+        Ok(())
+    }
+
+    fn string(&self, content: &[u8]) -> Value {
+        RString::enc_new(content, RbEncoding::utf8()).as_value()
+    }
+
+    /// Deliver anything left waiting before a structural event.
+    fn settle(&mut self) -> Result<(), Error> {
+        if let Some(method) = self.awaiting_name.take() {
+            self.call(method, &[self.ruby.qnil().as_value()])?;
+        }
+        if let Some(key) = self.pending_attr.take() {
+            self.call("attribute", &[key, self.ruby.qtrue().as_value()])?;
+        }
+        Ok(())
+    }
+
+    /// Place a converted value in the open array, the pending attribute, or
+    /// the enclosing block.
+    fn value(&mut self, value: Value) -> Result<(), Error> {
+        if let Some(array) = self.arrays.last() {
+            return array.push(value);
+        }
+        if let Some(method) = self.awaiting_name.take() {
+            self.call(method, &[self.ruby.qnil().as_value()])?;
+        }
+        match self.pending_attr.take() {
+            Some(key) => self.call("attribute", &[key, value]),
+            None => self.call("value", &[value]),
+        }
+    }
+
+    fn numeric(&self, conversion: &str, content: &[u8]) -> Result<Value, Error> {
+        let text = self.string(content);
+        self.ruby.module_kernel().funcall(conversion, (text,))
+    }
+
+    fn event(&mut self, event: &Event) -> Result<(), Error> {
+        match event {
+            Event::ElementStart { .. } | Event::EmbeddedStart { .. } => {
+                self.settle()?;
+                self.awaiting_name = Some("start_element");
+            }
+            Event::DirectiveStart { .. } => {
+                self.settle()?;
+                self.awaiting_name = Some("start_directive");
+            }
+            Event::Name { content, .. } if self.awaiting_name.is_some() => {
+                let method = self.awaiting_name.take().expect("awaiting name");
+                self.call(method, &[self.string(content)])?;
+            }
+            Event::ElementEnd { .. } | Event::EmbeddedEnd { .. } => {
+                self.settle()?;
+                self.call("end_element", &[])?;
+            }
+            Event::DirectiveEnd { .. } => {
+                self.settle()?;
+                self.call("end_directive", &[])?;
+            }
+            Event::Attr { content, .. } => {
+                self.settle()?;
+                self.pending_attr = Some(self.string(content));
+            }
+            Event::ArrayStart { .. } => self.arrays.push(RArray::new()),
+            Event::ArrayEnd { .. } => {
+                if let Some(array) = self.arrays.pop() {
+                    self.value(array.as_value())?;
+                }
+            }
+            Event::CommentStart { .. } => {
+                self.settle()?;
+                self.in_comment = true;
+            }
+            Event::CommentEnd { .. } => self.in_comment = false,
+            Event::Text { content, .. }
+            | Event::RawContent { content, .. }
+            | Event::Raw { content, .. } => {
+                self.settle()?;
+                let method = if self.in_comment { "comment" } else { "text" };
+                self.call(method, &[self.string(content)])?;
+            }
+            Event::BoolTrue { .. } => self.value(self.ruby.qtrue().as_value())?,
+            Event::BoolFalse { .. } => self.value(self.ruby.qfalse().as_value())?,
+            Event::Nil { .. } => self.value(self.ruby.qnil().as_value())?,
+            Event::Integer { content, .. } => {
+                let value = self.numeric("Integer", content)?;
+                self.value(value)?;
+            }
+            Event::Float { content, .. } => {
+                let value = self.numeric("Float", content)?;
+                self.value(value)?;
+            }
+            Event::StringValue { content, .. }
+            | Event::BareValue { content, .. }
+            | Event::Rational { content, .. }
+            | Event::Complex { content, .. } => self.value(self.string(content))?,
+            Event::Interpolation { content, .. } => {
+                let expression = String::from_utf8_lossy(content).into_owned();
+                if self.arrays.is_empty() && self.pending_attr.is_none() {
+                    self.settle()?;
+                    self.call("interpolation", &[expression.into_value_with(self.ruby)])?;
+                } else {
+                    let marker = Interpolation::new(expression).into_value_with(self.ruby);
+                    self.value(marker)?;
+                }
+            }
+            Event::Reference { content, .. } => {
+                if self.arrays.is_empty() && self.pending_attr.is_none() {
+                    self.settle()?;
+                    self.call("reference", &[self.string(content)])?;
+                } else {
+                    self.value(self.string(content))?;
+                }
+            }
+            Event::Error { code, span } => {
+                self.settle()?;
+                let code = Symbol::new(error_code_name(code)).as_value();
+                self.call("error", &[code, span_to_hash(span).as_value()])?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// `UdonNative.build(input, builder)`
+///
+/// Returns `builder.result` if the builder defines it, otherwise the builder.
+/// An exception raised by a builder method stops the build and propagates.
+pub fn build(ruby: &Ruby, input: Value, builder: Value) -> Result<Value, Error> {
+    let input = coerce::bytes(ruby, input)?;
+    let input_bytes = &input[..];
+    let mut state = Build {
+        ruby,
+        builder,
+        responds: HashMap::new(),
+        awaiting_name: None,
+        pending_attr: None,
+        arrays: Vec::new(),
+        in_comment: false,
+        failure: None,
+    };
+
+    Parser::new(input_bytes).parse(|event| {
+        if state.failure.is_none() {
+            if let Err(err) = state.event(&event) {
+                state.failure = Some(err);
+            }
+        }
+    });
+    if let Some(err) = state.failure.take() {
+        return Err(err);
+    }
+    state.settle()?;
+
+    if builder.respond_to("result", false)? {
+        builder.funcall("result", ())
+    } else {
+        Ok(builder)
+    }
+}
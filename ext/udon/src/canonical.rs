@@ -0,0 +1,67 @@
+//! Canonicalization of attribute values (`canonicalize: true`).
+//!
+//! Scalar string values directly following an attribute key are trimmed of
+//! surrounding whitespace, and values matching one of the configured boolean
+//! tokens (ASCII case-insensitively) become `bool_true`/`bool_false` events.
+
+use std::borrow::Cow;
+
+use udon_core::Event;
+
+/// Tokens treated as booleans when canonicalizing.
+pub struct BooleanTokens {
+    pub truthy: Vec<Vec<u8>>,
+    pub falsy: Vec<Vec<u8>>,
+}
+
+impl Default for BooleanTokens {
+    fn default() -> Self {
+        BooleanTokens {
+            truthy: vec![b"true".to_vec(), b"yes".to_vec(), b"on".to_vec()],
+            falsy: vec![b"false".to_vec(), b"no".to_vec(), b"off".to_vec()],
+        }
+    }
+}
+
+impl BooleanTokens {
+    fn lookup(&self, value: &[u8]) -> Option<bool> {
+        let matches = |tokens: &[Vec<u8>]| tokens.iter().any(|t| t.eq_ignore_ascii_case(value));
+        if matches(&self.truthy) {
+            Some(true)
+        } else if matches(&self.falsy) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// Canonical replacement for an attribute value event, if it changes.
+pub fn canonicalize<'e>(event: &'e Event<'_>, tokens: &BooleanTokens) -> Option<Event<'e>> {
+    let (content, span, quoted) = match event {
+        Event::StringValue { content, span } => (content, span, true),
+        Event::BareValue { content, span } => (content, span, false),
+        _ => return None,
+    };
+
+    let trimmed = content.as_ref().trim_ascii();
+    match tokens.lookup(trimmed) {
+        Some(true) => Some(Event::BoolTrue {
+            content: Cow::Borrowed(b"true"),
+            span: span.clone(),
+        }),
+        Some(false) => Some(Event::BoolFalse {
+            content: Cow::Borrowed(b"false"),
+            span: span.clone(),
+        }),
+        None if trimmed.len() == content.len() => None,
+        None if quoted => Some(Event::StringValue {
+            content: Cow::Borrowed(trimmed),
+            span: span.clone(),
+        }),
+        None => Some(Event::BareValue {
+            content: Cow::Borrowed(trimmed),
+            span: span.clone(),
+        }),
+    }
+}
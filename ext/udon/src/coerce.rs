@@ -0,0 +1,63 @@
+//! Argument coercion shared by the entry points that take a document, a
+//! reader or a path, so they accept the same duck types and fail the same
+//! way.
+
+use magnus::{prelude::*, Error, RString, Ruby, Value};
+
+fn type_error(ruby: &Ruby, expected: &str, value: Value) -> Error {
+    Error::new(
+        ruby.exception_type_error(),
+        format!("expected {}, got {}", expected, value.class().inspect()),
+    )
+}
+
+/// A String, or an object responding to `#to_str`.
+pub fn string(ruby: &Ruby, value: Value) -> Result<RString, Error> {
+    if let Some(string) = RString::from_value(value) {
+        return Ok(string);
+    }
+    if value.respond_to("to_str", false)? {
+        let string: Value = value.funcall("to_str", ())?;
+        if let Some(string) = RString::from_value(string) {
+            return Ok(string);
+        }
+    }
+    Err(type_error(
+        ruby,
+        "a String or an object responding to #to_str",
+        value,
+    ))
+}
+
+/// The bytes of a document taken as `string` takes it, copied out of the
+/// String. Entry points whose parse runs Ruby code (blocks, handlers,
+/// resolvers, builders) parse the copy: the code may append to the
+/// caller's String, which moves its bytes out from under a borrowed slice.
+pub fn bytes(ruby: &Ruby, value: Value) -> Result<Vec<u8>, Error> {
+    let string = string(ruby, value)?;
+    Ok(unsafe { string.as_slice() }.to_vec())
+}
+
+/// An IO-like object responding to `#read`.
+pub fn reader(ruby: &Ruby, value: Value) -> Result<Value, Error> {
+    if value.respond_to("read", false)? {
+        return Ok(value);
+    }
+    Err(type_error(
+        ruby,
+        "an IO-like object responding to #read",
+        value,
+    ))
+}
+
+/// A path: a String, or an object responding to `#to_path` (Pathname) or
+/// `#to_str`.
+pub fn path(ruby: &Ruby, value: Value) -> Result<RString, Error> {
+    if RString::from_value(value).is_none() && value.respond_to("to_path", false)? {
+        let path: Value = value.funcall("to_path", ())?;
+        if let Some(path) = RString::from_value(path) {
+            return Ok(path);
+        }
+    }
+    string(ruby, value).map_err(|_| type_error(ruby, "a path String or Pathname", value))
+}
@@ -0,0 +1,154 @@
+//! `format: :columnar` output for `UdonNative.parse`.
+//!
+//! Instead of one hash per event, the result is one array per field, all the
+//! same length, with index `i` describing event `i`:
+//! `{type: [...], start: [...], end: [...], name: [...], value: [...]}`.
+//! Events are never materialized as hashes.
+
+use magnus::{prelude::*, Error, RArray, RHash, RString, Ruby, Symbol};
+use udon_core::Event;
+
+use crate::{
+    error_code_name, event_parts, event_type, event_type_name, intern, literal,
+    normalize::{check_input, normalize, rejected_nul},
+    options::{ParseOptions, SpanMode, UnknownDirectives, ValuePlugins},
+    Converter,
+};
+
+/// Remove `format:` from `keywords`; true for `:columnar`, false for
+/// `:events` (the default).
+pub fn take_format(ruby: &Ruby, keywords: RHash) -> Result<bool, Error> {
+    let Some(format) = keywords.delete::<_, Option<Symbol>>(Symbol::new("format"))? else {
+        return Ok(false);
+    };
+    match format.name()?.as_ref() {
+        "events" => Ok(false),
+        "columnar" => Ok(true),
+        other => Err(Error::new(
+            ruby.exception_arg_error(),
+            format!("invalid value for format: :{}", other),
+        )),
+    }
+}
+
+/// Parse into columns.
+///
+/// `:name` holds the content of `:name` and `:attr` events (element names
+/// and attribute keys), `:value` the content of every other event that has
+/// content, and the code of `:error` events. `start`/`end` are byte offsets
+/// into the original input. With `type_tag: true` a `:tag` column holds
+/// each event's integer type tag.
+pub fn parse_columnar(
+    ruby: &Ruby,
+    input_bytes: &[u8],
+    options: &ParseOptions,
+) -> Result<RHash, Error> {
+    for (enabled, name) in [
+        (options.unify_directives, "unify_directives"),
+        (options.shape_hash, "shape_hash"),
+        (options.header_spans, "header_spans"),
+        (options.mark_container, "mark_container"),
+        (options.classify, "classify"),
+        (options.precompute_extents, "precompute_extents"),
+        (options.split_interpolations, "split_interpolations"),
+        (options.severity, "severity"),
+        (
+            options.interpolation_resolver.is_some(),
+            "interpolation_resolver",
+        ),
+        (options.env.is_some(), "interpolations: :env"),
+        (
+            options.structured_interpolations,
+            "interpolations: :structured",
+        ),
+        (options.conditions.is_some(), "conditions"),
+        (options.sort_by_span, "sort_by_span"),
+        (options.emit_document_bounds, "emit_document_bounds"),
+        (options.value_plugins != ValuePlugins::Off, "value_plugins"),
+        (options.parse_times, "parse_times"),
+        (
+            options.unknown_directives != UnknownDirectives::Allow,
+            "unknown_directives",
+        ),
+        (options.capture_indent, "capture_indent"),
+        (options.capture_column, "capture_column"),
+        (options.parent_name, "parent_name"),
+        (options.content_lengths, "content_lengths"),
+        (options.structured_directives, "directive_args: :structured"),
+        (
+            options.directive_namespaces.is_some(),
+            "directive_namespaces",
+        ),
+        (
+            options.spans == SpanMode::LineColPacked,
+            "spans: :line_col_packed",
+        ),
+        (options.spans == SpanMode::Object, "spans: :object"),
+        (options.spans == SpanMode::Utf16, "spans: :utf16"),
+    ] {
+        if enabled {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                format!("format: :columnar does not support {}", name),
+            ));
+        }
+    }
+
+    check_input(ruby, input_bytes, options)?;
+    let (normalized, offsets) = normalize(input_bytes, options);
+    let mut converter = Converter::new(ruby, input_bytes, options, offsets);
+    let types = RArray::new();
+    let tags = options.type_tag.then(RArray::new);
+    let starts = RArray::new();
+    let ends = RArray::new();
+    let names = RArray::new();
+    let values = RArray::new();
+    let nil = ruby.qnil().as_value();
+
+    if let Some(at) = rejected_nul(input_bytes, options) {
+        let _ = types.push(Symbol::new("error"));
+        if let Some(tags) = tags {
+            let _ = tags.push(event_type::tag_of("error"));
+        }
+        let _ = starts.push(at as i64);
+        let _ = ends.push(at as i64 + 1);
+        let _ = names.push(nil);
+        let _ = values.push(Symbol::new("nul_byte"));
+    } else {
+        literal::parse(&normalized, options, |event| {
+            let rewritten = converter.rewrite(&event);
+            let event = rewritten.as_ref().unwrap_or(&event);
+            let (span, content) = event_parts(event);
+            let span = converter.spans.reported(span);
+            let content = content.map(|bytes| match event {
+                Event::Attr { .. } if options.intern_keys => intern::key(ruby, bytes).as_value(),
+                _ => RString::from_slice(bytes).as_value(),
+            });
+
+            let (name, value) = match event {
+                Event::Name { .. } | Event::Attr { .. } => (content.unwrap_or(nil), nil),
+                Event::Error { code, .. } => (nil, Symbol::new(error_code_name(code)).as_value()),
+                _ => (nil, content.unwrap_or(nil)),
+            };
+            let _ = types.push(Symbol::new(event_type_name(event)));
+            if let Some(tags) = tags {
+                let _ = tags.push(event_type::tag(event));
+            }
+            let _ = starts.push(span.start as i64);
+            let _ = ends.push(span.end as i64);
+            let _ = names.push(name);
+            let _ = values.push(value);
+        });
+    }
+
+    let columns = RHash::new();
+    columns.aset(Symbol::new("type"), types)?;
+    if let Some(tags) = tags {
+        columns.aset(Symbol::new("tag"), tags)?;
+    }
+    columns.aset(Symbol::new("start"), starts)?;
+    columns.aset(Symbol::new("end"), ends)?;
+    columns.aset(Symbol::new("name"), names)?;
+    columns.aset(Symbol::new("value"), values)?;
+    Ok(columns)
+}
@@ -0,0 +1,418 @@
+//! Conditional directives evaluated while parsing (`conditions:`).
+//!
+//! `!if <expr>` keeps its indented block when the expression is true and
+//! `!unless <expr>` when it is false; the directive's own events go either
+//! way, so the result reads as if the condition had been resolved by hand.
+//! The expression is the rest of the directive's line:
+//!
+//! ```text
+//! expr    = and { ("or" | "||") and }
+//! and     = unary { ("and" | "&&") unary }
+//! unary   = ("not" | "!") unary | primary
+//! primary = "(" expr ")"
+//!         | name ("==" | "=" | "!=") value    comparison
+//!         | ":" name value                    same as name == value
+//!         | name                              the variable is set, not false or nil
+//! value   = word | "quoted string"
+//! ```
+//!
+//! Names and words are runs of letters, digits and `_ - . /`; `and`, `or`
+//! and `not` are keywords. Values compare as strings (`to_s` of the
+//! context's values). A variable missing from the context is an error
+//! unless `unknown_conditions: :false`, which makes any test of it false.
+//!
+//! Events of a skipped block are never converted, and conditionals nest: a
+//! skipped block's own conditionals are not evaluated at all.
+
+use std::{collections::HashMap, ops::Range};
+
+use magnus::{prelude::*, r_hash::ForEach, Error, RHash, Ruby, Value};
+use udon_core::Event;
+
+use crate::{errors, event_parts, Converter};
+
+/// A context value: its string form, and whether Ruby finds it truthy.
+pub struct Variable {
+    text: String,
+    truthy: bool,
+}
+
+/// The `conditions:` context.
+pub struct Conditions {
+    variables: HashMap<String, Variable>,
+    /// `unknown_conditions: :false`: tests of unknown variables are false.
+    pub unknown_false: bool,
+}
+
+impl Conditions {
+    /// Read the `conditions:` Hash; keys and values are taken by `to_s`.
+    pub fn from_hash(hash: RHash) -> Result<Self, Error> {
+        let mut variables = HashMap::new();
+        hash.foreach(|key: Value, value: Value| {
+            let key: String = key.funcall("to_s", ())?;
+            let text: String = value.funcall("to_s", ())?;
+            let truthy = value.to_bool();
+            variables.insert(key, Variable { text, truthy });
+            Ok(ForEach::Continue)
+        })?;
+        Ok(Conditions {
+            variables,
+            unknown_false: false,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Equal,
+    NotEqual,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Colon,
+}
+
+fn is_word(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.' | b'/') || b >= 0x80
+}
+
+fn tokenize(expression: &[u8]) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut at = 0;
+    while at < expression.len() {
+        let rest = &expression[at..];
+        let (token, len) = match rest[0] {
+            b' ' | b'\t' | b'\r' => {
+                at += 1;
+                continue;
+            }
+            b'(' => (Token::Open, 1),
+            b')' => (Token::Close, 1),
+            b':' => (Token::Colon, 1),
+            b'=' if rest.starts_with(b"==") => (Token::Equal, 2),
+            b'=' => (Token::Equal, 1),
+            b'!' if rest.starts_with(b"!=") => (Token::NotEqual, 2),
+            b'!' => (Token::Not, 1),
+            b'&' if rest.starts_with(b"&&") => (Token::And, 2),
+            b'|' if rest.starts_with(b"||") => (Token::Or, 2),
+            b'"' => {
+                let mut text = Vec::new();
+                let mut end = None;
+                let mut i = 1;
+                while i < rest.len() {
+                    match rest[i] {
+                        b'\\' if i + 1 < rest.len() => {
+                            text.push(rest[i + 1]);
+                            i += 2;
+                        }
+                        b'"' => {
+                            end = Some(i + 1);
+                            break;
+                        }
+                        b => {
+                            text.push(b);
+                            i += 1;
+                        }
+                    }
+                }
+                let end = end.ok_or("unclosed string")?;
+                (
+                    Token::Quoted(String::from_utf8_lossy(&text).into_owned()),
+                    end,
+                )
+            }
+            b if is_word(b) => {
+                let len = rest.iter().take_while(|&&b| is_word(b)).count();
+                let word = String::from_utf8_lossy(&rest[..len]).into_owned();
+                let token = match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Word(word),
+                };
+                (token, len)
+            }
+            b => return Err(format!("unexpected {:?}", b as char)),
+        };
+        tokens.push(token);
+        at += len;
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over the tokens of one expression.
+struct Evaluator<'a> {
+    conditions: &'a Conditions,
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Evaluator<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.peek() == Some(token);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<bool, String> {
+        let mut value = self.and()?;
+        while self.eat(&Token::Or) {
+            value |= self.and()?;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let mut value = self.unary()?;
+        while self.eat(&Token::And) {
+            value &= self.unary()?;
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<bool, String> {
+        if self.eat(&Token::Not) {
+            return Ok(!self.unary()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool, String> {
+        match self.next() {
+            Some(Token::Open) => {
+                let value = self.or()?;
+                if !self.eat(&Token::Close) {
+                    return Err("missing )".to_string());
+                }
+                Ok(value)
+            }
+            Some(Token::Colon) => match self.next() {
+                Some(Token::Word(name)) => self.compare(&name, true),
+                _ => Err("expected a name after :".to_string()),
+            },
+            Some(Token::Word(name)) => {
+                if self.eat(&Token::Equal) {
+                    self.compare(&name, true)
+                } else if self.eat(&Token::NotEqual) {
+                    self.compare(&name, false)
+                } else {
+                    Ok(self
+                        .variable(&name)?
+                        .is_some_and(|variable| variable.truthy))
+                }
+            }
+            Some(token) => Err(format!("unexpected {}", describe(&token))),
+            None => Err("expression ends early".to_string()),
+        }
+    }
+
+    /// `name == value` (or `!=` when `equal` is false).
+    fn compare(&mut self, name: &str, equal: bool) -> Result<bool, String> {
+        let value = match self.next() {
+            Some(Token::Word(value) | Token::Quoted(value)) => value,
+            _ => return Err(format!("expected a value to compare {} with", name)),
+        };
+        Ok(match self.variable(name)? {
+            Some(variable) => (variable.text == value) == equal,
+            None => false,
+        })
+    }
+
+    /// The variable `name`; `None` when unknown and `unknown_false` is set.
+    fn variable(&self, name: &str) -> Result<Option<&Variable>, String> {
+        match self.conditions.variables.get(name) {
+            Some(variable) => Ok(Some(variable)),
+            None if self.conditions.unknown_false => Ok(None),
+            None => Err(format!("unknown variable {:?}", name)),
+        }
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(word) => format!("{:?}", word),
+        Token::Quoted(text) => format!("\"{}\"", text),
+        Token::Equal => "==".to_string(),
+        Token::NotEqual => "!=".to_string(),
+        Token::And => "and".to_string(),
+        Token::Or => "or".to_string(),
+        Token::Not => "not".to_string(),
+        Token::Open => "(".to_string(),
+        Token::Close => ")".to_string(),
+        Token::Colon => ":".to_string(),
+    }
+}
+
+/// Evaluate `expression` against `conditions`.
+fn evaluate(conditions: &Conditions, expression: &[u8]) -> Result<bool, String> {
+    let tokens = tokenize(expression)?;
+    if tokens.is_empty() {
+        return Err("missing condition".to_string());
+    }
+    let mut evaluator = Evaluator {
+        conditions,
+        tokens,
+        at: 0,
+    };
+    let value = evaluator.or()?;
+    match evaluator.peek() {
+        Some(token) => Err(format!("unexpected {}", describe(token))),
+        None => Ok(value),
+    }
+}
+
+/// What the parse loop does with an event.
+pub enum Admit {
+    /// Convert it.
+    Keep,
+    /// Convert a held `:directive_start` with this span first, then it.
+    AfterHeld(Range<usize>),
+    /// Leave it out.
+    Skip,
+}
+
+/// An open directive.
+struct Frame {
+    /// An `!if`/`!unless`, whose own end event is dropped.
+    conditional: bool,
+    /// Its events are left out: a false conditional, or any directive
+    /// inside one.
+    skip: bool,
+}
+
+/// Per-parse conditional state, fed every event in order.
+pub struct Filter<'a> {
+    conditions: &'a Conditions,
+    /// The parser's input, which the expressions are read from.
+    source: &'a [u8],
+    frames: Vec<Frame>,
+    /// Open frames with `skip` set.
+    skipped: usize,
+    /// A `:directive_start` waiting for its name.
+    held: Option<Range<usize>>,
+    /// End of the innermost conditional's line; its arguments end there.
+    header_end: Option<usize>,
+    /// The first bad expression: its message and its directive's span.
+    failure: Option<(String, Range<usize>)>,
+}
+
+impl<'a> Filter<'a> {
+    pub fn new(conditions: &'a Conditions, source: &'a [u8]) -> Self {
+        Filter {
+            conditions,
+            source,
+            frames: Vec::new(),
+            skipped: 0,
+            held: None,
+            header_end: None,
+            failure: None,
+        }
+    }
+
+    pub fn admit(&mut self, event: &Event) -> Admit {
+        if self.failure.is_some() {
+            return Admit::Skip;
+        }
+        let held = self.held.take();
+        if let Some(start) = &held {
+            if let Event::Name { content, span } = event {
+                let negate = match &content[..] {
+                    b"if" => Some(false),
+                    b"unless" => Some(true),
+                    _ => None,
+                };
+                if let Some(negate) = negate {
+                    self.evaluate(start.start, span.end, negate);
+                    return Admit::Skip;
+                }
+            }
+            self.open(false, false);
+        }
+        match (held, self.next(event)) {
+            (Some(start), Admit::Keep) => Admit::AfterHeld(start),
+            (_, admit) => admit,
+        }
+    }
+
+    /// Open the conditional starting at `start` whose name ends at
+    /// `name_end`, evaluating the rest of its line.
+    fn evaluate(&mut self, start: usize, name_end: usize, negate: bool) {
+        let line_end = memchr::memchr(b'\n', &self.source[name_end..])
+            .map_or(self.source.len(), |at| name_end + at);
+        match evaluate(self.conditions, &self.source[name_end..line_end]) {
+            Ok(value) => self.open(true, value == negate),
+            Err(message) => self.failure = Some((message, start..line_end)),
+        }
+        self.header_end = Some(line_end);
+    }
+
+    /// `admit` for an event that is not a held directive's name.
+    fn next(&mut self, event: &Event) -> Admit {
+        match event {
+            Event::DirectiveStart { span } => {
+                self.header_end = None;
+                if self.skipped > 0 {
+                    self.open(false, true);
+                } else {
+                    self.held = Some(span.clone());
+                }
+                Admit::Skip
+            }
+            Event::DirectiveEnd { .. } => {
+                self.header_end = None;
+                match self.frames.pop() {
+                    Some(frame) if frame.skip => {
+                        self.skipped -= 1;
+                        Admit::Skip
+                    }
+                    Some(frame) if frame.conditional => Admit::Skip,
+                    _ => Admit::Keep,
+                }
+            }
+            _ if self.skipped > 0 => Admit::Skip,
+            _ => match self.header_end {
+                Some(end) if event_parts(event).0.start < end => Admit::Skip,
+                _ => {
+                    self.header_end = None;
+                    Admit::Keep
+                }
+            },
+        }
+    }
+
+    fn open(&mut self, conditional: bool, skip: bool) {
+        if skip {
+            self.skipped += 1;
+        }
+        self.frames.push(Frame { conditional, skip });
+    }
+
+    /// Raise the first bad expression as `UdonNative::ConditionError`.
+    pub fn finish(&self, ruby: &Ruby, converter: &Converter) -> Result<(), Error> {
+        let Some((message, span)) = &self.failure else {
+            return Ok(());
+        };
+        let span = converter.spans.original(span);
+        let written = String::from_utf8_lossy(&converter.spans.source[span.clone()]);
+        Err(errors::condition_error(
+            ruby,
+            format!("{} in `{}` at byte {}", message, written.trim(), span.start),
+        ))
+    }
+}
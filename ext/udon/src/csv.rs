@@ -0,0 +1,323 @@
+//! CSV export of repeated elements.
+//!
+//! Streams the parse, collects the attributes of every element with the
+//! requested name, and writes one CSV row per element without building any
+//! intermediate Ruby objects.
+
+use magnus::{
+    encoding::RbEncoding,
+    prelude::*,
+    scan_args::{get_kwargs, scan_args},
+    Error, RHash, RString, Ruby, Symbol, TryConvert, Value,
+};
+use udon_core::{Event, Parser};
+
+use crate::{coerce, write_to_io, OUTPUT_FLUSH_BYTES};
+
+/// Number of records sampled for `columns: :auto` when `sample:` is not given.
+const DEFAULT_AUTO_SAMPLE: usize = 100;
+
+/// Attributes of one matching element, in source order.
+#[derive(Default)]
+struct Record {
+    attrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Record {
+    /// Value for `key`; repeated keys (e.g. classes) are joined with spaces.
+    fn value(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut value: Option<Vec<u8>> = None;
+        for (k, v) in &self.attrs {
+            if k.as_slice() == key {
+                match value.as_mut() {
+                    Some(joined) => {
+                        joined.push(b' ');
+                        joined.extend_from_slice(v);
+                    }
+                    None => value = Some(v.clone()),
+                }
+            }
+        }
+        value
+    }
+}
+
+enum Columns {
+    Fixed(Vec<Vec<u8>>),
+    /// Union of attribute keys over the first `n` records.
+    Auto(usize),
+}
+
+/// Open bracket on the collector's stack.
+struct Frame {
+    /// Index into `open_records` when this element is a matching record.
+    record: Option<usize>,
+}
+
+/// Collects records from the event stream and writes CSV rows.
+struct CsvExport<'a> {
+    element: &'a [u8],
+    columns: Columns,
+    stack: Vec<Frame>,
+    open_records: Vec<Record>,
+    /// The innermost frame is an element whose name has not arrived yet.
+    awaiting_name: bool,
+    /// Attribute key waiting for its value, when it belongs to a record.
+    pending_key: Option<Vec<u8>>,
+    /// Array items collected for the pending key.
+    array_depth: usize,
+    array_items: Vec<Vec<u8>>,
+    /// Records held back while sampling for `columns: :auto`.
+    sampled: Vec<Record>,
+    header_written: bool,
+    out: Vec<u8>,
+}
+
+impl<'a> CsvExport<'a> {
+    fn new(element: &'a [u8], columns: Columns) -> Self {
+        CsvExport {
+            element,
+            columns,
+            stack: Vec::new(),
+            open_records: Vec::new(),
+            awaiting_name: false,
+            pending_key: None,
+            array_depth: 0,
+            array_items: Vec::new(),
+            sampled: Vec::new(),
+            header_written: false,
+            out: Vec::new(),
+        }
+    }
+
+    fn event(&mut self, event: &Event) {
+        match event {
+            Event::ElementStart { .. } => {
+                self.stack.push(Frame { record: None });
+                self.awaiting_name = true;
+                self.pending_key = None;
+            }
+            Event::EmbeddedStart { .. } | Event::DirectiveStart { .. } => {
+                self.stack.push(Frame { record: None });
+                self.awaiting_name = false;
+                self.pending_key = None;
+            }
+            Event::Name { content, .. } if self.awaiting_name => {
+                self.awaiting_name = false;
+                if content.as_ref() == self.element {
+                    self.open_records.push(Record::default());
+                    if let Some(frame) = self.stack.last_mut() {
+                        frame.record = Some(self.open_records.len() - 1);
+                    }
+                }
+            }
+            Event::ElementEnd { .. } | Event::EmbeddedEnd { .. } | Event::DirectiveEnd { .. } => {
+                self.awaiting_name = false;
+                self.pending_key = None;
+                if let Some(Frame { record: Some(_) }) = self.stack.pop() {
+                    if let Some(record) = self.open_records.pop() {
+                        self.finish_record(record);
+                    }
+                }
+            }
+            Event::Attr { content, .. } => {
+                self.awaiting_name = false;
+                let owned = matches!(self.stack.last(), Some(Frame { record: Some(_) }));
+                self.pending_key = owned.then(|| content.to_vec());
+            }
+            Event::ArrayStart { .. } if self.pending_key.is_some() => {
+                self.array_depth += 1;
+            }
+            Event::ArrayEnd { .. } if self.array_depth > 0 => {
+                self.array_depth -= 1;
+                if self.array_depth == 0 {
+                    let value = self.array_items.join(&b' ');
+                    self.array_items.clear();
+                    self.set_value(value);
+                }
+            }
+            _ => {
+                if let Some(value) = scalar_text(event) {
+                    if self.array_depth > 0 {
+                        self.array_items.push(value);
+                    } else if self.pending_key.is_some() {
+                        self.set_value(value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_value(&mut self, value: Vec<u8>) {
+        if let (Some(key), Some(record)) = (self.pending_key.take(), self.open_records.last_mut()) {
+            record.attrs.push((key, value));
+        }
+    }
+
+    fn finish_record(&mut self, record: Record) {
+        if let Columns::Auto(sample) = self.columns {
+            self.sampled.push(record);
+            if self.sampled.len() >= sample {
+                self.resolve_auto_columns();
+            }
+            return;
+        }
+        self.write_header();
+        self.write_record(&record);
+    }
+
+    /// Fix the column set from the sampled records and write them out.
+    fn resolve_auto_columns(&mut self) {
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        for record in &self.sampled {
+            for (key, _) in &record.attrs {
+                if !keys.contains(key) {
+                    keys.push(key.clone());
+                }
+            }
+        }
+        self.columns = Columns::Fixed(keys);
+        self.write_header();
+        for record in std::mem::take(&mut self.sampled) {
+            self.write_record(&record);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Columns::Auto(_) = self.columns {
+            self.resolve_auto_columns();
+        }
+        self.write_header();
+    }
+
+    fn write_header(&mut self) {
+        if self.header_written {
+            return;
+        }
+        self.header_written = true;
+        if let Columns::Fixed(columns) = &self.columns {
+            let row: Vec<&[u8]> = columns.iter().map(|c| c.as_slice()).collect();
+            write_row(&mut self.out, &row);
+        }
+    }
+
+    fn write_record(&mut self, record: &Record) {
+        if let Columns::Fixed(columns) = &self.columns {
+            let values: Vec<Vec<u8>> = columns
+                .iter()
+                .map(|column| record.value(column).unwrap_or_default())
+                .collect();
+            let row: Vec<&[u8]> = values.iter().map(|v| v.as_slice()).collect();
+            write_row(&mut self.out, &row);
+        }
+    }
+}
+
+/// Stringify a scalar value event per CSV conventions (nil is empty).
+fn scalar_text(event: &Event) -> Option<Vec<u8>> {
+    match event {
+        Event::StringValue { content, .. }
+        | Event::BareValue { content, .. }
+        | Event::Integer { content, .. }
+        | Event::Float { content, .. }
+        | Event::Rational { content, .. }
+        | Event::Complex { content, .. } => Some(content.to_vec()),
+        Event::BoolTrue { .. } => Some(b"true".to_vec()),
+        Event::BoolFalse { .. } => Some(b"false".to_vec()),
+        Event::Nil { .. } => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+/// Append one RFC 4180 row, quoting fields that need it.
+fn write_row(out: &mut Vec<u8>, fields: &[&[u8]]) {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        let needs_quotes = field
+            .iter()
+            .any(|&b| matches!(b, b',' | b'"' | b'\n' | b'\r'));
+        if needs_quotes {
+            out.push(b'"');
+            for &byte in field.iter() {
+                if byte == b'"' {
+                    out.push(b'"');
+                }
+                out.push(byte);
+            }
+            out.push(b'"');
+        } else {
+            out.extend_from_slice(field);
+        }
+    }
+    out.push(b'\n');
+}
+
+/// `UdonNative.to_csv(input, element:, columns:, sample: 100, io: nil)`
+///
+/// `columns` is an Array of attribute names or `:auto` (the union of keys over
+/// the first `sample` matching elements). Returns the CSV text, or writes it
+/// to `io` and returns the IO.
+pub fn to_csv(ruby: &Ruby, args: &[Value]) -> Result<Value, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::string(ruby, input)?;
+    let kwargs = get_kwargs::<_, (String, Value), (Option<usize>, Option<Value>), ()>(
+        args.keywords,
+        &["element", "columns"],
+        &["sample", "io"],
+    )?;
+    let (element, columns) = kwargs.required;
+    let (sample, io) = kwargs.optional;
+    let io = io.filter(|io| !io.is_nil());
+
+    let columns = match Symbol::from_value(columns) {
+        Some(symbol) if symbol.name()? == "auto" => {
+            Columns::Auto(sample.unwrap_or(DEFAULT_AUTO_SAMPLE).max(1))
+        }
+        Some(symbol) => {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                format!("invalid value for columns: :{}", symbol.name()?),
+            ))
+        }
+        None => Columns::Fixed(
+            Vec::<String>::try_convert(columns)?
+                .into_iter()
+                .map(String::into_bytes)
+                .collect(),
+        ),
+    };
+
+    let input_bytes = unsafe { input.as_slice() };
+    let mut export = CsvExport::new(element.as_bytes(), columns);
+    let mut failure: Option<Error> = None;
+
+    Parser::new(input_bytes).parse(|event| {
+        if failure.is_some() {
+            return;
+        }
+        export.event(&event);
+        if let Some(io) = io {
+            if export.out.len() >= OUTPUT_FLUSH_BYTES {
+                if let Err(err) = write_to_io(io, std::mem::take(&mut export.out)) {
+                    failure = Some(err);
+                }
+            }
+        }
+    });
+
+    if let Some(err) = failure {
+        return Err(err);
+    }
+    export.finish();
+
+    match io {
+        Some(io) => {
+            write_to_io(io, std::mem::take(&mut export.out))?;
+            Ok(io)
+        }
+        None => Ok(RString::enc_new(export.out, RbEncoding::utf8()).as_value()),
+    }
+}
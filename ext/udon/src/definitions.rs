@@ -0,0 +1,175 @@
+//! Document-local definitions for `parse_tree(expand_definitions: true)`.
+//!
+//! `!define name` captures its indented block under `name`, and each
+//! `!use name` is replaced by a copy of that block. Attributes written on
+//! the `!use` override those of the copied top-level elements the way
+//! `merge` layers attributes: a key an element already has takes the new
+//! value in place of its first one, other keys are appended. Definitions
+//! apply to the whole document wherever they appear, may use each other,
+//! and are dropped from the tree; expansion nests at most
+//! `max_expansion_depth` uses deep, which also stops a definition that
+//! uses itself.
+//!
+//! Copied nodes keep the definition's spans and record the use that placed
+//! them (`NodeData::expanded_from`). Problems are recorded as tree errors
+//! at the directive instead of being raised: `undefined_definition`,
+//! `expansion_too_deep`, `missing_definition_name`, and
+//! `duplicate_definition` for a later `!define` of a name (the first one
+//! is kept).
+
+use std::collections::HashMap;
+
+use crate::tree::{self, Expansion, NodeData, NodeKind, Tree, TreeError, ROOT};
+
+pub const DEFAULT_MAX_DEPTH: usize = 16;
+
+fn is_directive(node: &NodeData, name: &[u8]) -> bool {
+    node.kind == NodeKind::Directive && node.name.as_deref() == Some(name)
+}
+
+/// The definition name a `!define` or `!use` gives as its first argument.
+fn name_of(node: &NodeData) -> Option<&[u8]> {
+    match node.values.first() {
+        Some(tree::Value::Scalar { content, .. }) if !content.is_empty() => Some(content),
+        _ => None,
+    }
+}
+
+struct Expander<'a> {
+    tree: &'a Tree,
+    /// `!define` nodes by name.
+    definitions: HashMap<&'a [u8], usize>,
+    max_depth: usize,
+    out: Tree,
+}
+
+impl<'a> Expander<'a> {
+    /// Record `code` at `node`, once however often the node is copied.
+    fn error(&mut self, code: &'static str, node: &NodeData) {
+        let error = TreeError {
+            code,
+            span: node.span.clone(),
+            file: node.file,
+        };
+        let seen = self.out.errors.iter().any(|other| {
+            other.code == error.code && other.span == error.span && other.file == error.file
+        });
+        if !seen {
+            self.out.errors.push(error);
+        }
+    }
+
+    /// Find the definitions in the subtree at `index`.
+    fn collect(&mut self, index: usize) {
+        let tree = self.tree;
+        for &child in &tree.node(index).children {
+            let node = tree.node(child);
+            if is_directive(node, b"define") {
+                match name_of(node) {
+                    None => self.error("missing_definition_name", node),
+                    Some(name) if self.definitions.contains_key(name) => {
+                        self.error("duplicate_definition", node)
+                    }
+                    Some(name) => {
+                        self.definitions.insert(name, child);
+                    }
+                }
+            }
+            self.collect(child);
+        }
+    }
+
+    /// Copy the children of the input's node `index` under `parent`,
+    /// expanding uses, as part of `expansion` nested `depth` uses deep.
+    /// Returns the copies made directly under `parent`.
+    fn copy(
+        &mut self,
+        index: usize,
+        parent: usize,
+        expansion: Option<usize>,
+        depth: usize,
+    ) -> Vec<usize> {
+        let tree = self.tree;
+        let mut copied = Vec::new();
+        for &child in &tree.node(index).children {
+            let node = tree.node(child);
+            if is_directive(node, b"define") {
+                continue;
+            }
+            if is_directive(node, b"use") {
+                copied.extend(self.expand(node, parent, expansion, depth));
+                continue;
+            }
+            let mut copy = node.clone();
+            copy.parent = Some(parent);
+            copy.children = Vec::new();
+            copy.expanded_from = expansion;
+            let at = self.out.nodes.len();
+            self.out.nodes.push(copy);
+            self.out.nodes[parent].children.push(at);
+            copied.push(at);
+            self.copy(child, at, expansion, depth);
+        }
+        copied
+    }
+
+    /// Splice the definition named by the `!use` directive `node` under
+    /// `parent`; `enclosing` is the expansion the use itself was copied by.
+    fn expand(
+        &mut self,
+        node: &'a NodeData,
+        parent: usize,
+        enclosing: Option<usize>,
+        depth: usize,
+    ) -> Vec<usize> {
+        let Some(name) = name_of(node) else {
+            self.error("missing_definition_name", node);
+            return Vec::new();
+        };
+        let Some(&definition) = self.definitions.get(name) else {
+            self.error("undefined_definition", node);
+            return Vec::new();
+        };
+        if depth >= self.max_depth {
+            self.error("expansion_too_deep", node);
+            return Vec::new();
+        }
+
+        let site = match (enclosing, node.file) {
+            (Some(enclosing), _) => self.out.expansions[enclosing].site.clone(),
+            (None, Some(file)) => self.tree.files[file].included_at.clone(),
+            (None, None) => node.span.clone(),
+        };
+        let expansion = self.out.expansions.len();
+        self.out.expansions.push(Expansion {
+            name: name.to_vec(),
+            span: node.span.clone(),
+            file: node.file,
+            site,
+        });
+        let copied = self.copy(definition, parent, Some(expansion), depth + 1);
+        for &at in &copied {
+            let copy = &mut self.out.nodes[at];
+            if matches!(copy.kind, NodeKind::Element | NodeKind::Embedded) {
+                copy.override_attrs(node);
+            }
+        }
+        copied
+    }
+}
+
+/// `tree` with its definitions expanded; see the module docs.
+pub fn expand(tree: &Tree, max_depth: usize) -> Tree {
+    let mut out = tree.clone();
+    out.nodes.truncate(1);
+    out.nodes[ROOT].children.clear();
+    let mut expander = Expander {
+        tree,
+        definitions: HashMap::new(),
+        max_depth,
+        out,
+    };
+    expander.collect(ROOT);
+    expander.copy(ROOT, ROOT, None, 0);
+    expander.out
+}
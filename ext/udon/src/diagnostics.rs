@@ -0,0 +1,129 @@
+//! `UdonNative.diagnostics`: a document's errors and warnings in the shape
+//! an editor's diagnostics endpoint wants.
+//!
+//! Each diagnostic is `{message:, code:, severity:, range: {start: {line:,
+//! col:}, end: {line:, col:}}}`, with 0-based lines and columns counted in
+//! characters (code points, not LSP's UTF-16 units; tabs expanded under
+//! `tab_width:`).
+//! `severity` folds the `severity: true` levels into LSP's: `:fatal` and
+//! `:recoverable` errors are `:error`, `:stylistic` ones and the parser's
+//! warnings `:warning`. Warnings have no code.
+
+use magnus::{scan_args::scan_args, Error, RArray, RHash, RString, Ruby, Symbol, Value};
+use udon_core::{Event, ParseErrorCode, Parser};
+
+use crate::{
+    coerce, content_to_rstring, error_code_name, error_severity,
+    line_index::LineIndex,
+    normalize::{check_input, normalize, rejected_nul},
+    options::{ParseOptions, SpanMode},
+    SpanFormatter,
+};
+
+/// What went wrong, for a parse error's `:message`.
+fn error_message(code: &ParseErrorCode) -> &'static str {
+    match code {
+        ParseErrorCode::UnexpectedEof => "unexpected end of input",
+        ParseErrorCode::UnexpectedChar => "unexpected character",
+        ParseErrorCode::Unclosed => "element is never closed",
+        ParseErrorCode::UnclosedStringValue => "string is never closed",
+        ParseErrorCode::UnclosedArray => "array is never closed",
+        ParseErrorCode::UnclosedFreeform => "freeform block is never closed",
+        ParseErrorCode::UnclosedText => "text is never closed",
+        ParseErrorCode::UnclosedInterpolation => "interpolation is never closed",
+        ParseErrorCode::NoTabs => "tabs are not allowed in indentation",
+    }
+}
+
+/// Builds diagnostics over the original input.
+struct Builder<'a> {
+    source: &'a [u8],
+    lines: LineIndex,
+    diagnostics: RArray,
+}
+
+impl Builder<'_> {
+    fn position(&self, offset: usize) -> RHash {
+        let (line, col) = self.lines.line_col(self.source, offset);
+        let hash = RHash::new();
+        let _ = hash.aset(Symbol::new("line"), line);
+        let _ = hash.aset(Symbol::new("col"), col);
+        hash
+    }
+
+    /// Add a diagnostic over `span`, which indexes the original input.
+    fn push(
+        &self,
+        message: RString,
+        code: Option<&str>,
+        severity: &str,
+        span: std::ops::Range<usize>,
+    ) {
+        let range = RHash::new();
+        let _ = range.aset(Symbol::new("start"), self.position(span.start));
+        let _ = range.aset(Symbol::new("end"), self.position(span.end));
+        let hash = RHash::new();
+        let _ = hash.aset(Symbol::new("message"), message);
+        let _ = hash.aset(Symbol::new("code"), code.map(Symbol::new));
+        let _ = hash.aset(Symbol::new("severity"), Symbol::new(severity));
+        let _ = hash.aset(Symbol::new("range"), range);
+        let _ = self.diagnostics.push(hash);
+    }
+}
+
+/// `UdonNative.diagnostics(input, **options)`
+///
+/// Takes the input options of `parse` (`tab_width:`, `strip_bom:`,
+/// `normalize_newlines:`, `nul_bytes:`, ...); options that shape events do
+/// not change what is reported. Diagnostics come in document order.
+pub fn diagnostics(ruby: &Ruby, args: &[Value]) -> Result<RArray, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::string(ruby, input)?;
+    let options = ParseOptions::from_hash(ruby, args.keywords)?;
+    let input_bytes = unsafe { input.as_slice() };
+
+    check_input(ruby, input_bytes, &options)?;
+    let builder = Builder {
+        source: input_bytes,
+        lines: LineIndex::new(input_bytes).with_tab_width(options.tab_width),
+        diagnostics: RArray::new(),
+    };
+    if let Some(at) = rejected_nul(input_bytes, &options) {
+        builder.push(
+            RString::new("NUL byte in input"),
+            Some("nul_byte"),
+            "error",
+            at..at + 1,
+        );
+        return Ok(builder.diagnostics);
+    }
+    let (normalized, offsets) = normalize(input_bytes, &options);
+    let spans = SpanFormatter::new(SpanMode::Hash, None, input_bytes, offsets);
+
+    Parser::new(&normalized).parse(|event| match event {
+        Event::Error { code, span } => {
+            let severity = match error_severity(&code) {
+                "stylistic" => "warning",
+                _ => "error",
+            };
+            builder.push(
+                RString::new(error_message(&code)),
+                Some(error_code_name(&code)),
+                severity,
+                spans.original(&span),
+            );
+        }
+        Event::Warning { content, span } => {
+            builder.push(
+                content_to_rstring(&content),
+                None,
+                "warning",
+                spans.original(&span),
+            );
+        }
+        _ => {}
+    });
+
+    Ok(builder.diagnostics)
+}
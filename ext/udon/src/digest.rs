@@ -0,0 +1,304 @@
+//! Structural digests of documents and subtrees.
+//!
+//! The digest covers the canonical structure of the tree, not its source
+//! text: spans and indentation never count, values are canonicalized as with
+//! `canonicalize: true` (trimmed, boolean tokens mapped, quoted and bare
+//! strings alike, digit separators dropped), and the `Ignore` set drops
+//! comments, normalizes text whitespace, or sorts attributes by key.
+//!
+//! `Shapes` computes the content-free skeleton digests behind
+//! `shape_hash: true`.
+
+use std::borrow::Cow;
+
+use magnus::{Error, Ruby, Symbol};
+use sha2::{Digest as _, Sha256, Sha384, Sha512};
+use udon_core::Event;
+
+use crate::{
+    canonical::{canonicalize, BooleanTokens},
+    tree::{NodeKind, Tree, Value},
+};
+
+/// Aspects of a document that do not affect its digest.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ignore {
+    pub comments: bool,
+    /// Collapse whitespace runs in text to one space and trim; adjacent text
+    /// nodes are joined first, so re-wrapped text digests the same.
+    pub whitespace: bool,
+    pub attribute_order: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Algorithm {
+    #[default]
+    Sha256,
+    Sha384,
+    Sha512,
+    /// 64-bit XXH3: fast, for change detection rather than security.
+    Xxh3,
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(Algorithm::Sha256),
+            "sha384" => Some(Algorithm::Sha384),
+            "sha512" => Some(Algorithm::Sha512),
+            "xxh3" => Some(Algorithm::Xxh3),
+            _ => None,
+        }
+    }
+
+    /// The algorithm named by an optional `algorithm` argument.
+    pub fn from_symbol(ruby: &Ruby, name: Option<Symbol>) -> Result<Self, Error> {
+        let Some(name) = name else {
+            return Ok(Algorithm::default());
+        };
+        let name = name.name()?;
+        Algorithm::from_name(&name).ok_or_else(|| {
+            Error::new(
+                ruby.exception_arg_error(),
+                format!("unsupported digest algorithm: :{}", name),
+            )
+        })
+    }
+}
+
+/// Hex digest of the subtree at `index`.
+pub fn digest(tree: &Tree, index: usize, ignore: Ignore, algorithm: Algorithm) -> String {
+    let mut out = Vec::new();
+    Canonical {
+        tree,
+        ignore,
+        tokens: BooleanTokens::default(),
+        out: &mut out,
+    }
+    .node(index);
+    hash_hex(&out, algorithm)
+}
+
+/// Hex digest of `bytes`.
+pub fn hash_hex(bytes: &[u8], algorithm: Algorithm) -> String {
+    let hash = match algorithm {
+        Algorithm::Sha256 => Sha256::digest(bytes).to_vec(),
+        Algorithm::Sha384 => Sha384::digest(bytes).to_vec(),
+        Algorithm::Sha512 => Sha512::digest(bytes).to_vec(),
+        Algorithm::Xxh3 => xxhash_rust::xxh3::xxh3_64(bytes).to_be_bytes().to_vec(),
+    };
+    hex(&hash)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Incremental structural "shape" digests for `shape_hash: true`.
+///
+/// A shape covers a node's kind and name, the set of its attribute keys, and
+/// the shapes of its child elements, embedded elements and directives in
+/// order. Values, text and comments never count, so elements that differ
+/// only in content share a shape.
+#[derive(Default)]
+pub struct Shapes {
+    stack: Vec<ShapeFrame>,
+}
+
+struct ShapeFrame {
+    kind: u8,
+    name: Option<Vec<u8>>,
+    keys: Vec<Vec<u8>>,
+    children: Vec<[u8; 32]>,
+}
+
+impl Shapes {
+    /// Start a node; `kind` distinguishes elements, embedded and directives.
+    pub fn open(&mut self, kind: u8) {
+        self.stack.push(ShapeFrame {
+            kind,
+            name: None,
+            keys: Vec::new(),
+            children: Vec::new(),
+        });
+    }
+
+    /// Name the innermost node, if it has no name or attributes yet.
+    pub fn name(&mut self, name: &[u8]) {
+        if let Some(frame) = self.stack.last_mut() {
+            if frame.name.is_none() && frame.keys.is_empty() {
+                frame.name = Some(name.to_vec());
+            }
+        }
+    }
+
+    pub fn key(&mut self, key: &[u8]) {
+        if let Some(frame) = self.stack.last_mut() {
+            frame.keys.push(key.to_vec());
+        }
+    }
+
+    /// Finish the innermost node and return its hex shape digest.
+    pub fn close(&mut self) -> Option<String> {
+        let mut frame = self.stack.pop()?;
+        frame.keys.sort();
+        frame.keys.dedup();
+
+        let mut hasher = Sha256::new();
+        hasher.update([frame.kind]);
+        let name = frame.name.unwrap_or_default();
+        hasher.update((name.len() as u64).to_be_bytes());
+        hasher.update(&name);
+        for key in &frame.keys {
+            hasher.update(b"a");
+            hasher.update((key.len() as u64).to_be_bytes());
+            hasher.update(key);
+        }
+        for child in &frame.children {
+            hasher.update(b"c");
+            hasher.update(child);
+        }
+        let digest: [u8; 32] = hasher.finalize().into();
+
+        if let Some(parent) = self.stack.last_mut() {
+            parent.children.push(digest);
+        }
+        Some(hex(&digest))
+    }
+}
+
+/// Writes an unambiguous byte encoding of the canonical tree: every field is
+/// tagged and length-prefixed.
+struct Canonical<'a> {
+    tree: &'a Tree,
+    ignore: Ignore,
+    tokens: BooleanTokens,
+    out: &'a mut Vec<u8>,
+}
+
+impl Canonical<'_> {
+    fn field(&mut self, tag: u8, bytes: &[u8]) {
+        self.out.push(tag);
+        self.out
+            .extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+        self.out.extend_from_slice(bytes);
+    }
+
+    fn node(&mut self, index: usize) {
+        let node = self.tree.node(index);
+        match node.kind {
+            NodeKind::Text => return self.text(&node.content),
+            NodeKind::Comment => return self.field(b'c', &node.content),
+            NodeKind::Interpolation => return self.field(b'i', &node.content),
+            NodeKind::Reference => return self.field(b'r', &node.content),
+            NodeKind::Freeform => return self.field(b'f', &node.content),
+            _ => {}
+        }
+
+        self.field(b'<', node.kind.name().as_bytes());
+        if let Some(name) = &node.name {
+            self.field(b'n', name);
+        }
+        let mut attrs: Vec<_> = node.attrs.iter().collect();
+        if self.ignore.attribute_order {
+            attrs.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        for (key, value) in attrs {
+            self.field(b'a', key);
+            self.value(value);
+        }
+        for value in &node.values {
+            self.out.push(b'v');
+            self.value(value);
+        }
+        self.children(&node.children);
+        self.out.push(b'>');
+    }
+
+    fn children(&mut self, children: &[usize]) {
+        let mut text: Option<Vec<u8>> = None;
+        for &child in children {
+            let node = self.tree.node(child);
+            if node.kind == NodeKind::Comment && self.ignore.comments {
+                continue;
+            }
+            if node.kind == NodeKind::Text && self.ignore.whitespace {
+                let joined = text.get_or_insert_with(Vec::new);
+                joined.push(b' ');
+                joined.extend_from_slice(&node.content);
+                continue;
+            }
+            if let Some(joined) = text.take() {
+                self.text(&joined);
+            }
+            self.node(child);
+        }
+        if let Some(joined) = text.take() {
+            self.text(&joined);
+        }
+    }
+
+    fn text(&mut self, content: &[u8]) {
+        if !self.ignore.whitespace {
+            return self.field(b't', content);
+        }
+        let normalized = content
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>()
+            .join(&b' ');
+        if !normalized.is_empty() {
+            self.field(b't', &normalized);
+        }
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Array(items) => {
+                self.out.push(b'[');
+                for item in items {
+                    self.value(item);
+                }
+                self.out.push(b']');
+            }
+            Value::Scalar { kind, content } => {
+                let (tag, bytes) = self.scalar(kind, content);
+                self.field(tag, &bytes);
+            }
+        }
+    }
+
+    /// Canonical (tag, bytes) for a scalar.
+    fn scalar<'c>(&self, kind: &str, content: &'c [u8]) -> (u8, Cow<'c, [u8]>) {
+        let event = match kind {
+            "string_value" => Event::StringValue {
+                content: Cow::Borrowed(content),
+                span: 0..0,
+            },
+            "bare_value" => Event::BareValue {
+                content: Cow::Borrowed(content),
+                span: 0..0,
+            },
+            "bool_true" => return (b'T', Cow::Borrowed(b"")),
+            "bool_false" => return (b'F', Cow::Borrowed(b"")),
+            "nil" => return (b'N', Cow::Borrowed(b"")),
+            "integer" | "float" | "rational" | "complex" => {
+                let digits: Vec<u8> = content.iter().copied().filter(|&b| b != b'_').collect();
+                return (b'#', Cow::Owned(digits));
+            }
+            "interpolation" => return (b'i', Cow::Borrowed(content)),
+            _ => return (b's', Cow::Borrowed(content)),
+        };
+        match canonicalize(&event, &self.tokens) {
+            Some(Event::BoolTrue { .. }) => (b'T', Cow::Borrowed(b"")),
+            Some(Event::BoolFalse { .. }) => (b'F', Cow::Borrowed(b"")),
+            Some(Event::StringValue {
+                content: trimmed, ..
+            })
+            | Some(Event::BareValue {
+                content: trimmed, ..
+            }) => (b's', Cow::Owned(trimmed.into_owned())),
+            _ => (b's', Cow::Borrowed(content)),
+        }
+    }
+}
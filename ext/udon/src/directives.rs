@@ -0,0 +1,636 @@
+//! `UdonNative::Directives`: handlers for directives by namespace and name,
+//! run by `parse(input, directives: :dispatch)`.
+//!
+//! Handlers live in a Hash on the module, keyed `"name"` or
+//! `"namespace:name"`, so Ruby keeps them alive. While parsing, the events
+//! of a directive with a handler are held back from its `:directive_start`
+//! to its `:directive_end`; the handler then runs with the directive's
+//! arguments, span, a context Hash and a `HandlerContext` (see
+//! `handler_context`), and decides what takes the held events' place.
+//! Nested directives are dispatched innermost first, so an outer handler
+//! sees what its inner ones made of its body. Directives without a handler
+//! pass through as they are, except the `deprecation_directive:`
+//! (`!deprecated`), which has one built in (`deprecated`). libudon reports
+//! only block directives, so there are no inline directives to dispatch
+//! yet.
+//!
+//! A directive's arguments are the values and `:key value` attributes right
+//! after its name (`Arguments`): handlers get the values as `args` and the
+//! attributes as `ctx[:options]`. `Structured` folds the same arguments,
+//! and the rest of the directive's events, into a single event for
+//! `directive_args: :structured`.
+
+use std::ops::Range;
+
+use magnus::{
+    block::Proc, function, prelude::*, value::Lazy, Error, RArray, RHash, RModule, RString, Ruby,
+    Symbol, Value,
+};
+use udon_core::Event;
+
+use crate::{
+    document::value_to_ruby,
+    handler_context::{self, HandlerContext, State},
+    literal,
+    normalize::{check_input, normalize, rejected_nul},
+    nul_error,
+    options::ParseOptions,
+    tree::{self, scalar_kind},
+    Converter,
+};
+
+static DIRECTIVES: Lazy<RModule> = Lazy::new(|ruby| {
+    let module: RModule = ruby
+        .class_object()
+        .const_get("UdonNative")
+        .expect("UdonNative is defined at init");
+    module
+        .const_get("Directives")
+        .expect("Directives is defined at init")
+});
+
+fn handlers(ruby: &Ruby) -> Result<RHash, Error> {
+    ruby.get_inner(&DIRECTIVES).ivar_get("@handlers")
+}
+
+/// The registry key for a directive: `"name"` or `"namespace:name"`.
+fn key(namespace: Option<String>, name: String) -> String {
+    match namespace {
+        Some(namespace) => format!("{}:{}", namespace, name),
+        None => name,
+    }
+}
+
+/// `namespace, name` or just `name` from `register`/`unregister` arguments.
+fn key_args(ruby: &Ruby, args: &[Value]) -> Result<String, Error> {
+    match args {
+        [name] => Ok(key(None, String::try_convert(*name)?)),
+        [namespace, name] => Ok(key(
+            Option::<String>::try_convert(*namespace)?,
+            String::try_convert(*name)?,
+        )),
+        _ => Err(Error::new(
+            ruby.exception_arg_error(),
+            format!(
+                "wrong number of arguments (given {}, expected 1..2)",
+                args.len()
+            ),
+        )),
+    }
+}
+
+/// `UdonNative::Directives.register(namespace = nil, name) { |args, span, ctx, context| ... }`
+///
+/// Replaces any handler already registered for the directive.
+fn register(ruby: &Ruby, args: &[Value]) -> Result<(), Error> {
+    let key = key_args(ruby, args)?;
+    if !ruby.block_given() {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "Directives.register requires a block",
+        ));
+    }
+    handlers(ruby)?.aset(key, ruby.block_proc()?)
+}
+
+/// `UdonNative::Directives.unregister(namespace = nil, name)`: whether a
+/// handler was registered.
+fn unregister(ruby: &Ruby, args: &[Value]) -> Result<bool, Error> {
+    let key = key_args(ruby, args)?;
+    Ok(!handlers(ruby)?.delete::<_, Value>(key)?.is_nil())
+}
+
+/// `UdonNative::Directives.clear`
+fn clear(ruby: &Ruby) -> Result<(), Error> {
+    let _: Value = handlers(ruby)?.funcall("clear", ())?;
+    Ok(())
+}
+
+/// `UdonNative::Directives.registered`: the registered keys.
+fn registered(ruby: &Ruby) -> Result<RArray, Error> {
+    handlers(ruby)?.funcall("keys", ())
+}
+
+/// The registered keys, for `unknown_directives:`.
+pub fn registered_keys(ruby: &Ruby) -> Result<Vec<String>, Error> {
+    Vec::<String>::try_convert(registered(ruby)?.as_value())
+}
+
+/// Whether `parse`'s `directives:` keyword asks for dispatch, removing it
+/// from `keywords`.
+pub fn take_mode(ruby: &Ruby, keywords: RHash) -> Result<bool, Error> {
+    let Some(mode) = keywords.delete::<_, Option<Symbol>>(Symbol::new("directives"))? else {
+        return Ok(false);
+    };
+    match mode.name()?.as_ref() {
+        "events" => Ok(false),
+        "dispatch" => Ok(true),
+        other => Err(Error::new(
+            ruby.exception_arg_error(),
+            format!("invalid value for directives: :{}", other),
+        )),
+    }
+}
+
+/// A directive's arguments as they are read: positional values and
+/// `:key value` options, up to the first event that is neither.
+#[derive(Default)]
+pub struct Arguments {
+    positional: Vec<tree::Value>,
+    options: Vec<(Vec<u8>, tree::Value)>,
+    /// Arrays being filled, innermost last.
+    arrays: Vec<Vec<tree::Value>>,
+    /// An option key still waiting for its value.
+    key: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl Arguments {
+    /// Take `event` if the arguments are still being read; whether it was
+    /// one of them.
+    pub fn take(&mut self, event: &Event) -> bool {
+        if self.done {
+            return false;
+        }
+        let value = match (event, scalar_kind(event)) {
+            (Event::Attr { content, .. }, _) if self.arrays.is_empty() => {
+                self.flag_pending();
+                self.key = Some(content.to_vec());
+                return true;
+            }
+            (Event::ArrayStart { .. }, _) => {
+                self.arrays.push(Vec::new());
+                return true;
+            }
+            (Event::ArrayEnd { .. }, _) if !self.arrays.is_empty() => {
+                tree::Value::Array(self.arrays.pop().unwrap_or_default())
+            }
+            (_, Some((kind, content))) => tree::Value::Scalar {
+                kind,
+                content: content.to_vec(),
+            },
+            _ => {
+                self.finish();
+                return false;
+            }
+        };
+        match (self.arrays.last_mut(), self.key.take()) {
+            (Some(array), key) => {
+                array.push(value);
+                self.key = key;
+            }
+            (None, Some(key)) => self.options.push((key, value)),
+            (None, None) => self.positional.push(value),
+        }
+        true
+    }
+
+    /// An option key without a value is a flag.
+    fn flag_pending(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.options.push((key, tree::Value::flag()));
+        }
+    }
+
+    /// Stop reading arguments.
+    pub fn finish(&mut self) {
+        if !self.done {
+            self.flag_pending();
+            self.done = true;
+        }
+    }
+
+    /// The positional values as an Array and the options as a Hash keyed
+    /// by String.
+    pub fn to_ruby(&self, ruby: &Ruby) -> Result<(RArray, RHash), Error> {
+        let positional = RArray::with_capacity(self.positional.len());
+        for value in &self.positional {
+            positional.push(value_to_ruby(ruby, value)?)?;
+        }
+        let options = RHash::new();
+        for (key, value) in &self.options {
+            options.aset(RString::from_slice(key), value_to_ruby(ruby, value)?)?;
+        }
+        Ok((positional, options))
+    }
+}
+
+/// `namespace` and `name` of a directive written `namespace:name` or
+/// `name`.
+fn split_name(name: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    match name.iter().position(|&b| b == b':') {
+        Some(colon) => (Some(&name[..colon]), &name[colon + 1..]),
+        None => (None, name),
+    }
+}
+
+/// What runs for a directive: a registered handler, or the built-in one
+/// for `deprecation_directive:` when nothing is registered under its name.
+enum Handler {
+    Registered(Proc),
+    Deprecated,
+}
+
+/// An open directive.
+struct Frame {
+    /// Index in the output of its `:directive_start` hash.
+    at: usize,
+    /// Parser span of its `directive_start`.
+    start: Range<usize>,
+    /// Its name is the next event.
+    awaiting_name: bool,
+    name: Vec<u8>,
+    /// Its handler, if it has one; only then are arguments kept.
+    handler: Option<Handler>,
+    arguments: Arguments,
+    /// Output entries that are its name and arguments.
+    header: usize,
+    /// The first element in its body and how many elements are open in it,
+    /// for the built-in deprecation handler.
+    element: Option<(Range<usize>, usize)>,
+}
+
+impl Frame {
+    fn new(at: usize, start: Range<usize>) -> Self {
+        Frame {
+            at,
+            start,
+            awaiting_name: true,
+            name: Vec::new(),
+            handler: None,
+            arguments: Arguments::default(),
+            header: 0,
+            element: None,
+        }
+    }
+
+    /// Follow an element event in the directive's body.
+    fn track_element(&mut self, event: &Event) {
+        match (event, &mut self.element) {
+            (Event::ElementStart { span }, None) => self.element = Some((span.clone(), 1)),
+            (Event::ElementStart { .. }, Some((_, open))) if *open > 0 => *open += 1,
+            (Event::ElementEnd { span }, Some((extent, open))) if *open > 0 => {
+                *open -= 1;
+                extent.end = extent.end.max(span.end);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parse `input_bytes`, running registered directive handlers.
+pub fn parse_dispatching(
+    ruby: &Ruby,
+    input_bytes: &[u8],
+    options: &ParseOptions,
+) -> Result<RArray, Error> {
+    if options.unify_directives {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "directives: :dispatch cannot be combined with unify_directives",
+        ));
+    }
+    check_input(ruby, input_bytes, options)?;
+    let result = RArray::new();
+    if let Some(at) = rejected_nul(input_bytes, options) {
+        result.push(nul_error(input_bytes, at..at + 1, options))?;
+        return Ok(result);
+    }
+    let handlers = handlers(ruby)?;
+    let (normalized, offsets) = normalize(input_bytes, options);
+    let mut converter = Converter::new(ruby, input_bytes, options, offsets);
+    if !handlers.is_empty() {
+        converter.track_path();
+    }
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut failure: Option<Error> = None;
+
+    literal::parse(&normalized, options, |event| {
+        if failure.is_some() {
+            return;
+        }
+        let Some(hash) = converter.convert(&event) else {
+            return;
+        };
+        let _ = result.push(hash);
+        for warning in converter.take_warnings() {
+            let _ = result.push(warning);
+        }
+        let awaiting_name = frames.last().is_some_and(|frame| frame.awaiting_name);
+        if let Some(frame) = frames.last_mut() {
+            frame.awaiting_name = false;
+        }
+        match &event {
+            Event::Name { content, .. } if awaiting_name => {
+                let frame = frames.last_mut().expect("awaiting a directive name");
+                frame.name = content.to_vec();
+                frame.header = 1;
+                let key = String::from_utf8_lossy(content).into_owned();
+                let deprecation = options.deprecation_directive.as_deref() == Some(key.as_str());
+                frame.handler = match handlers.get(key).and_then(Proc::from_value) {
+                    Some(handler) => Some(Handler::Registered(handler)),
+                    None if deprecation => Some(Handler::Deprecated),
+                    None => None,
+                };
+            }
+            Event::DirectiveStart { span } => {
+                if let Some(frame) = frames.last_mut() {
+                    frame.arguments.finish();
+                }
+                frames.push(Frame::new(result.len() - 1, span.clone()));
+            }
+            Event::DirectiveEnd { span } => {
+                let Some(mut frame) = frames.pop() else {
+                    return;
+                };
+                frame.arguments.finish();
+                let span = frame.start.start..span.end.max(frame.start.end);
+                let outcome = match &frame.handler {
+                    Some(Handler::Registered(handler)) => {
+                        dispatch(ruby, &converter, result, &frame, *handler, span)
+                    }
+                    Some(Handler::Deprecated) => deprecated(ruby, &converter, result, &frame, span),
+                    None => Ok(()),
+                };
+                if let Err(error) = outcome {
+                    failure = Some(error);
+                }
+            }
+            _ => {
+                if let Some(frame) = frames.last_mut().filter(|frame| frame.handler.is_some()) {
+                    if frame.arguments.take(&event) {
+                        frame.header += 1;
+                    } else {
+                        frame.track_element(&event);
+                    }
+                }
+            }
+        }
+    });
+    converter.take_failure()?;
+    match failure {
+        Some(error) => Err(error),
+        None => Ok(result),
+    }
+}
+
+/// A directive being collected under `directive_args: :structured`.
+struct Open {
+    /// Index in the output of its `:directive_start` hash.
+    at: usize,
+    start: Range<usize>,
+    awaiting_name: bool,
+    name: Vec<u8>,
+    arguments: Arguments,
+    /// Indices in the output of the hashes folded into the directive
+    /// event: its name and arguments.
+    folded: Vec<usize>,
+}
+
+/// Folds each directive's events into one `:directive` event
+/// (`directive_args: :structured`):
+/// `{ type: :directive, name:, namespace:, args: [...], options: {...},
+/// body: [...], span: }`, `body` holding the events after the arguments,
+/// nested directives already folded. Only the directives still open are
+/// tracked; their events stay where they are in the output until the
+/// directive ends.
+#[derive(Default)]
+pub struct Structured {
+    open: Vec<Open>,
+}
+
+impl Structured {
+    /// Follow `event`, whose hash is the output's entry `index` (if it has
+    /// one); at a directive's end, replace its events with the directive
+    /// event.
+    pub fn event(
+        &mut self,
+        ruby: &Ruby,
+        converter: &Converter,
+        result: RArray,
+        event: &Event,
+        index: Option<usize>,
+    ) -> Result<(), Error> {
+        let Some(index) = index else {
+            return Ok(());
+        };
+        let awaiting_name = self.open.last().is_some_and(|open| open.awaiting_name);
+        if let Some(open) = self.open.last_mut() {
+            open.awaiting_name = false;
+        }
+        match event {
+            Event::DirectiveStart { span } => {
+                if let Some(open) = self.open.last_mut() {
+                    open.arguments.finish();
+                }
+                self.open.push(Open {
+                    at: index,
+                    start: span.clone(),
+                    awaiting_name: true,
+                    name: Vec::new(),
+                    arguments: Arguments::default(),
+                    folded: Vec::new(),
+                });
+            }
+            Event::Name { content, .. } if awaiting_name => {
+                let open = self.open.last_mut().expect("awaiting a directive name");
+                open.name = content.to_vec();
+                open.folded.push(index);
+            }
+            Event::DirectiveEnd { span } => {
+                let Some(mut open) = self.open.pop() else {
+                    return Ok(());
+                };
+                open.arguments.finish();
+                let span = open.start.start..span.end.max(open.start.end);
+                let held: RArray = result.funcall("slice!", (open.at, result.len() - open.at))?;
+                let body = RArray::new();
+                // Everything between the start and end events that is not
+                // the name or an argument.
+                for (offset, hash) in held.each().enumerate().skip(1) {
+                    let hash = hash?;
+                    if offset + 1 < held.len() && !open.folded.contains(&(open.at + offset)) {
+                        body.push(hash)?;
+                    }
+                }
+                let (args, options) = open.arguments.to_ruby(ruby)?;
+                let (namespace, name) = split_name(&open.name);
+                let hash = RHash::new();
+                hash.aset(Symbol::new("type"), Symbol::new("directive"))?;
+                hash.aset(Symbol::new("name"), RString::from_slice(name))?;
+                hash.aset(Symbol::new("namespace"), namespace.map(RString::from_slice))?;
+                hash.aset(Symbol::new("args"), args)?;
+                hash.aset(Symbol::new("options"), options)?;
+                hash.aset(Symbol::new("body"), body)?;
+                hash.aset(Symbol::new("span"), converter.spans.convert(&span))?;
+                result.push(hash)?;
+            }
+            _ => {
+                if let Some(open) = self.open.last_mut() {
+                    if open.arguments.take(event) {
+                        open.folded.push(index);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `:error` event for a directive whose handler raised
+/// (`directive_failed`) or recorded an error (`directive_error`).
+fn directive_error(
+    converter: &Converter,
+    code: &str,
+    message: Value,
+    directive: &[u8],
+    span: &Range<usize>,
+) -> RHash {
+    let hash = RHash::new();
+    let _ = hash.aset(Symbol::new("type"), Symbol::new("error"));
+    let _ = hash.aset(Symbol::new("code"), Symbol::new(code));
+    let _ = hash.aset(Symbol::new("message"), message);
+    let _ = hash.aset(Symbol::new("directive"), RString::from_slice(directive));
+    let _ = hash.aset(Symbol::new("span"), converter.spans.convert(span));
+    if converter.options.severity {
+        let _ = hash.aset(Symbol::new("severity"), Symbol::new("recoverable"));
+    }
+    hash
+}
+
+/// Run `handler` for `frame`, whose events are the tail of `result`, and
+/// put what it returns in their place: the events unchanged for nil, none
+/// for false or `:suppress`, or the events of a returned Array. A handler
+/// that raises, or returns anything else, leaves the events unchanged and
+/// adds a `directive_failed` error; errors pushed onto `ctx[:errors]`
+/// follow as `directive_error` events, then the warnings emitted through
+/// its `HandlerContext`. A `break` or `throw` out of the handler is raised
+/// from the parse.
+fn dispatch(
+    ruby: &Ruby,
+    converter: &Converter,
+    result: RArray,
+    frame: &Frame,
+    handler: Proc,
+    span: Range<usize>,
+) -> Result<(), Error> {
+    let held: RArray = result.funcall("slice!", (frame.at, result.len() - frame.at))?;
+    let (args, options) = frame.arguments.to_ruby(ruby)?;
+    let (namespace, name) = split_name(&frame.name);
+    let errors = RArray::new();
+    let ctx = RHash::new();
+    ctx.aset(Symbol::new("name"), RString::from_slice(name))?;
+    ctx.aset(Symbol::new("namespace"), namespace.map(RString::from_slice))?;
+    ctx.aset(Symbol::new("options"), options)?;
+    ctx.aset(
+        Symbol::new("events"),
+        held.funcall::<_, _, RArray>("dup", ())?,
+    )?;
+    ctx.aset(Symbol::new("errors"), errors)?;
+
+    let reported = converter.spans.convert(&span);
+    let state = State::new(converter, &span);
+    let (outcome, warnings) =
+        HandlerContext::scope(state, |context| {
+            match handler_context::accepts(handler.as_value(), 4) {
+                true => handler.call::<_, Value>((args, reported, ctx, context)),
+                false => handler.call((args, reported, ctx)),
+            }
+        });
+    let replacement = match outcome {
+        Ok(value) if value.is_nil() => Ok(held),
+        Ok(value) if !value.to_bool() => Ok(RArray::new()),
+        Ok(value) => match Symbol::from_value(value) {
+            Some(symbol) if symbol.name()? == "suppress" => Ok(RArray::new()),
+            _ => RArray::from_value(value).ok_or_else(|| {
+                let class = unsafe { value.classname() }.into_owned();
+                format!(
+                    "handler returned {}; expected an Array of events, nil, false or :suppress",
+                    class
+                )
+            }),
+        },
+        Err(error) => match error.value() {
+            Some(exception) => Err(exception.funcall("message", ())?),
+            None => return Err(error),
+        },
+    };
+    let replacement = match replacement {
+        Ok(events) => events,
+        Err(message) => {
+            let _ = held.push(directive_error(
+                converter,
+                "directive_failed",
+                RString::new(&message).as_value(),
+                &frame.name,
+                &span,
+            ));
+            held
+        }
+    };
+    for event in replacement.each() {
+        result.push(event?)?;
+    }
+    for message in errors.each() {
+        result.push(directive_error(
+            converter,
+            "directive_error",
+            message?,
+            &frame.name,
+            &span,
+        ))?;
+    }
+    for warning in handler_context::warning_events(converter, warnings, &span) {
+        result.push(warning)?;
+    }
+    Ok(())
+}
+
+/// The built-in handler for `deprecation_directive:` (`!deprecated`), run
+/// in place of a registered one: the directive's events give way to a
+/// `{type: :warning, code: :deprecated, content:, directive:, span:}`
+/// followed by its body. `content` is the directive's first argument
+/// (`"deprecated"` without one) and `span` the first element in the body,
+/// the one the directive annotates, or the directive's own without one.
+fn deprecated(
+    ruby: &Ruby,
+    converter: &Converter,
+    result: RArray,
+    frame: &Frame,
+    span: Range<usize>,
+) -> Result<(), Error> {
+    let held: RArray = result.funcall("slice!", (frame.at, result.len() - frame.at))?;
+    let (args, _) = frame.arguments.to_ruby(ruby)?;
+    let message: Value = match args.entry::<Option<Value>>(0)? {
+        Some(value) => value.funcall("to_s", ())?,
+        None => RString::new("deprecated").as_value(),
+    };
+    let annotated = frame
+        .element
+        .as_ref()
+        .map_or(span, |(extent, _)| extent.clone());
+    let warning = RHash::new();
+    warning.aset(Symbol::new("type"), Symbol::new("warning"))?;
+    warning.aset(Symbol::new("code"), Symbol::new("deprecated"))?;
+    warning.aset(Symbol::new("content"), message)?;
+    warning.aset(Symbol::new("directive"), RString::from_slice(&frame.name))?;
+    warning.aset(Symbol::new("span"), converter.spans.convert(&annotated))?;
+    result.push(warning)?;
+    // The start event and the header, then the body up to the end event.
+    let body = held.len().saturating_sub(2 + frame.header);
+    for event in held.each().skip(1 + frame.header).take(body) {
+        result.push(event?)?;
+    }
+    Ok(())
+}
+
+/// Define `UdonNative::Directives`.
+pub fn define(module: RModule) -> Result<(), Error> {
+    let directives = module.define_module("Directives")?;
+    directives.ivar_set("@handlers", RHash::new())?;
+    directives.define_singleton_method("register", function!(register, -1))?;
+    directives.define_singleton_method("unregister", function!(unregister, -1))?;
+    directives.define_singleton_method("clear", function!(clear, 0))?;
+    directives.define_singleton_method("registered", function!(registered, 0))?;
+    Ok(())
+}
@@ -0,0 +1,196 @@
+//! Per-type callbacks for `UdonNative.parse(input, on_text: ->(e) { ... })`.
+//!
+//! Only events with a handler are converted to hashes; the rest are skipped
+//! without allocating, so a caller pays only for the events it handles.
+//! `unify_directives` and the options that annotate start events at their
+//! close (`shape_hash`, `header_spans`, `mark_container`, `classify`,
+//! `precompute_extents`) carry hashes from one event to the next, so with any
+//! of them enabled every event is converted, and with the latter dispatch
+//! waits until the parse is done so the keys set at a close are present. `sort_by_span`
+//! also waits, to hand events over in span order.
+
+use magnus::{prelude::*, r_hash::ForEach, Error, RArray, RHash, Ruby, Symbol, TryConvert, Value};
+use udon_core::Event;
+
+use crate::{
+    event_parts, event_type_name, freeze, literal,
+    normalize::{check_input, normalize, rejected_nul},
+    nul_error,
+    options::{ParseOptions, ValuePlugins},
+    sort_by_span, Converter,
+};
+
+/// Event types a handler can be registered for.
+const EVENT_TYPES: &[&str] = &[
+    "element_start",
+    "element_end",
+    "embedded_start",
+    "embedded_end",
+    "directive_start",
+    "directive_end",
+    "directive",
+    "array_start",
+    "array_end",
+    "freeform_start",
+    "freeform_end",
+    "comment_start",
+    "comment_end",
+    "name",
+    "text",
+    "attr",
+    "string_value",
+    "bare_value",
+    "custom_value",
+    "bool_true",
+    "bool_false",
+    "nil",
+    "integer",
+    "float",
+    "rational",
+    "complex",
+    "interpolation",
+    "reference",
+    "raw_content",
+    "raw",
+    "warning",
+    "error",
+];
+
+/// The `on_<type>:` callables, by event type.
+#[derive(Default)]
+pub struct Handlers {
+    handlers: Vec<(&'static str, Value)>,
+}
+
+impl Handlers {
+    /// Remove the `on_<type>:` keys from `keywords` and collect their
+    /// handlers, leaving the parse options behind.
+    pub fn extract(ruby: &Ruby, keywords: RHash) -> Result<Self, Error> {
+        let mut found = Vec::new();
+        keywords.foreach(|key: Symbol, value: Value| {
+            let name = key.name()?;
+            if let Some(kind) = name.strip_prefix("on_") {
+                found.push((key, kind.to_string(), value));
+            }
+            Ok(ForEach::Continue)
+        })?;
+
+        let mut handlers = Handlers::default();
+        for (key, kind, value) in found {
+            let kind = EVENT_TYPES
+                .iter()
+                .copied()
+                .find(|&known| known == kind)
+                .ok_or_else(|| {
+                    Error::new(
+                        ruby.exception_arg_error(),
+                        format!("unknown event type for on_{}:", kind),
+                    )
+                })?;
+            if !value.respond_to("call", false)? {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    format!("on_{}: handler must respond to #call", kind),
+                ));
+            }
+            keywords.delete::<_, Value>(key)?;
+            handlers.handlers.push((kind, value));
+        }
+        Ok(handlers)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    fn get(&self, kind: &str) -> Option<Value> {
+        self.handlers
+            .iter()
+            .find(|(known, _)| *known == kind)
+            .map(|&(_, handler)| handler)
+    }
+
+    /// Call the handler for `hash`'s `:type`, if there is one, with the
+    /// event's `(index, total)` as well when `with_index:` is set.
+    fn call(&self, hash: RHash, position: Option<(usize, Option<usize>)>) -> Result<(), Error> {
+        let kind: Symbol = hash.fetch(Symbol::new("type"))?;
+        if let Some(handler) = self.get(&kind.name()?) {
+            let _: Value = match position {
+                Some((index, total)) => handler.funcall("call", (hash, index, total))?,
+                None => handler.funcall("call", (hash,))?,
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Parse `input_bytes`, passing each event to the handler for its type.
+///
+/// The first error raised by a handler stops dispatch and is returned once
+/// the parse finishes.
+pub fn parse_dispatch(
+    ruby: &Ruby,
+    input_bytes: &[u8],
+    options: &ParseOptions,
+    handlers: &Handlers,
+) -> Result<(), Error> {
+    check_input(ruby, input_bytes, options)?;
+    if let Some(at) = rejected_nul(input_bytes, options) {
+        let hash = freeze::finish(nul_error(input_bytes, at..at + 1, options), options)?;
+        return handlers.call(hash, options.with_index.then_some((0, Some(1))));
+    }
+    let (normalized, offsets) = normalize(input_bytes, options);
+    let mut converter = Converter::new(ruby, input_bytes, options, offsets);
+    // Indexes after sorting are positions among all events, so all of them
+    // have to be there to be sorted.
+    let convert_all = converter.needs_every_hash() || (options.with_index && options.sort_by_span);
+    let defer = options.annotates_on_close() || options.sort_by_span;
+    let mut deferred = RArray::new();
+    let mut starts = Vec::new();
+    let mut index = 0;
+    let mut failure: Option<Error> = None;
+
+    literal::parse(&normalized, options, |event| {
+        if failure.is_some() {
+            return;
+        }
+        // A resolved interpolation's handler is the one for its new type, and
+        // so is a string value's that may become a custom value.
+        let resolved = ((options.interpolation_resolver.is_some() || options.env.is_some())
+            && matches!(event, Event::Interpolation { .. }))
+            || (options.value_plugins != ValuePlugins::Off
+                && matches!(event, Event::BareValue { .. } | Event::StringValue { .. }));
+        if !convert_all && !resolved && handlers.get(event_type_name(&event)).is_none() {
+            converter.skip(&event);
+            index += 1;
+            return;
+        }
+        let Some(hash) = converter.convert(&event) else {
+            return;
+        };
+        if defer {
+            let _ = deferred.push(hash);
+            starts.push(event_parts(&event).0.start);
+        } else if let Err(err) = freeze::finish(hash, options)
+            .and_then(|hash| handlers.call(hash, options.with_index.then_some((index, None))))
+        {
+            failure = Some(err);
+        }
+        index += 1;
+    });
+
+    if let Some(err) = failure {
+        return Err(err);
+    }
+    converter.take_failure()?;
+    if options.sort_by_span {
+        deferred = sort_by_span(deferred, &starts)?;
+    }
+    let total = deferred.len();
+    for (index, hash) in deferred.each().enumerate() {
+        let position = options.with_index.then_some((index, Some(total)));
+        let hash = freeze::finish(RHash::try_convert(hash?)?, options)?;
+        handlers.call(hash, position)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,586 @@
+//! `UdonNative::Document` and `UdonNative::Node`: Ruby handles on a parsed
+//! tree.
+//!
+//! A Document owns its tree behind an `Arc`; every Node shares it and names
+//! its position by index, so nodes stay valid for as long as any handle does.
+
+use std::sync::{Arc, OnceLock};
+
+use magnus::{
+    encoding::RbEncoding, method, prelude::*, scan_args::get_kwargs, scan_args::scan_args, Error,
+    IntoValue, RArray, RHash, RModule, RString, Ruby, Symbol, Value,
+};
+
+use crate::{
+    coerce,
+    digest::{self, Algorithm, Ignore},
+    embedded,
+    emitter::Emitter,
+    errors,
+    handler_context::{self, HandlerContext, Names, State},
+    normalize::check_encoding,
+    options, sort,
+    span_index::SpanIndex,
+    span_to_hash,
+    tree::{self, NodeKind, Tree, ROOT},
+    writer::Interpolation,
+};
+
+#[magnus::wrap(class = "UdonNative::Document", free_immediately, size)]
+pub struct Document {
+    tree: Arc<Tree>,
+    /// Built on the first `node_at`.
+    spans: OnceLock<SpanIndex>,
+}
+
+#[magnus::wrap(class = "UdonNative::Node", free_immediately, size)]
+pub struct Node {
+    tree: Arc<Tree>,
+    index: usize,
+}
+
+/// `UdonNative.parse_document(input)`
+pub fn parse_document(ruby: &Ruby, input: Value) -> Result<Document, Error> {
+    let input = coerce::string(ruby, input)?;
+    let input = unsafe { input.as_slice() };
+    check_encoding(ruby, input)?;
+    Ok(Document::new(Tree::parse(input)))
+}
+
+/// `UdonNative.first_element(input)`: the first top-level element, parsed
+/// without the rest of the input (see `Tree::first_element`), or nil when
+/// there is none.
+pub fn first_element(ruby: &Ruby, input: Value) -> Result<Option<Node>, Error> {
+    let input = coerce::string(ruby, input)?;
+    let input = unsafe { input.as_slice() };
+    check_encoding(ruby, input)?;
+    Ok(Tree::first_element(input).map(|(tree, index)| Node {
+        tree: Arc::new(tree),
+        index,
+    }))
+}
+
+fn string(content: &[u8]) -> RString {
+    RString::enc_new(content, RbEncoding::utf8())
+}
+
+/// Convert a tree value to Ruby: numbers, booleans and nil are typed, arrays
+/// become Arrays, interpolations become `UdonNative::Interpolation`, and
+/// everything else is a String.
+pub fn value_to_ruby(ruby: &Ruby, value: &tree::Value) -> Result<Value, Error> {
+    match value {
+        tree::Value::Array(items) => {
+            let array = RArray::with_capacity(items.len());
+            for item in items {
+                array.push(value_to_ruby(ruby, item)?)?;
+            }
+            Ok(array.as_value())
+        }
+        tree::Value::Scalar { kind, content } => Ok(match *kind {
+            "bool_true" => ruby.qtrue().as_value(),
+            "bool_false" => ruby.qfalse().as_value(),
+            "nil" => ruby.qnil().as_value(),
+            "integer" => ruby
+                .module_kernel()
+                .funcall("Integer", (string(content),))?,
+            "float" => ruby.module_kernel().funcall("Float", (string(content),))?,
+            "interpolation" => Interpolation::new(String::from_utf8_lossy(content).into_owned())
+                .into_value_with(ruby),
+            _ => string(content).as_value(),
+        }),
+    }
+}
+
+/// `Node`s for `indices` in `tree`.
+pub fn nodes(tree: &Arc<Tree>, indices: &[usize]) -> RArray {
+    let array = RArray::with_capacity(indices.len());
+    for &index in indices {
+        let _ = array.push(Node {
+            tree: Arc::clone(tree),
+            index,
+        });
+    }
+    array
+}
+
+/// Read `ignore:` and `algorithm:` for `#digest`.
+fn digest_options(ruby: &Ruby, args: &[Value]) -> Result<(Ignore, Algorithm), Error> {
+    let args = scan_args::<(), (), (), (), RHash, ()>(args)?;
+    let kwargs = get_kwargs::<_, (), (Option<Vec<Symbol>>, Option<Symbol>), ()>(
+        args.keywords,
+        &[],
+        &["ignore", "algorithm"],
+    )?;
+    let (ignored, algorithm) = kwargs.optional;
+
+    let mut ignore = Ignore::default();
+    for aspect in ignored.unwrap_or_default() {
+        match aspect.name()?.as_ref() {
+            "comments" => ignore.comments = true,
+            "whitespace" => ignore.whitespace = true,
+            "attribute_order" => ignore.attribute_order = true,
+            other => {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    format!("unknown digest ignore: :{}", other),
+                ))
+            }
+        }
+    }
+    Ok((ignore, Algorithm::from_symbol(ruby, algorithm)?))
+}
+
+/// Emit the subtree at `index`, honoring the `emit` ordering options.
+fn to_udon(ruby: &Ruby, tree: &Tree, index: usize, args: &[Value]) -> Result<RString, Error> {
+    let args = scan_args::<(), (), (), (), RHash, ()>(args)?;
+    let kwargs = get_kwargs::<_, (), (Option<Value>, Option<Value>), ()>(
+        args.keywords,
+        &[],
+        &["sort_attributes", "sort_elements_by"],
+    )?;
+    let (sort_attributes, sort_elements_by) = kwargs.optional;
+    let sort = options::sort_options(ruby, sort_attributes, sort_elements_by)?;
+
+    let mut events = tree.events(index);
+    if sort.is_enabled() {
+        events = sort::sort_events(events, &sort);
+    }
+    let mut emitter = Emitter::new();
+    for (position, event) in events.iter().enumerate() {
+        emitter
+            .event(&event.kind, event.content.as_deref(), position)
+            .map_err(|err| errors::emit_error(ruby, err))?;
+    }
+    emitter
+        .finish()
+        .map_err(|err| errors::emit_error(ruby, err))?;
+    Ok(string(emitter.output()))
+}
+
+impl Document {
+    pub fn new(tree: Tree) -> Self {
+        Document {
+            tree: Arc::new(tree),
+            spans: OnceLock::new(),
+        }
+    }
+
+    /// Top-level nodes.
+    pub fn children(&self) -> RArray {
+        nodes(&self.tree, &self.tree.node(ROOT).children)
+    }
+
+    /// `bom?`: whether the input started with a UTF-8 byte order mark. It is
+    /// skipped, and spans count it.
+    pub fn bom(&self) -> bool {
+        self.tree.bom
+    }
+
+    /// Parse errors as `{code:, span:}` hashes, with the `file:` the span
+    /// indexes when it is known (see `Node#file`).
+    pub fn errors(&self) -> RArray {
+        let array = RArray::with_capacity(self.tree.errors.len());
+        for error in &self.tree.errors {
+            let hash = RHash::new();
+            let _ = hash.aset(Symbol::new("code"), Symbol::new(error.code));
+            let _ = hash.aset(Symbol::new("span"), span_to_hash(&error.span));
+            if let Some(path) = self.tree.file_path(error.file) {
+                let _ = hash.aset(Symbol::new("file"), string(path.as_bytes()));
+            }
+            let _ = array.push(hash);
+        }
+        array
+    }
+
+    /// `deprecations(name = "deprecated")`: each `!deprecated` directive in
+    /// document order as `{message:, node:, span:}`, `message` being its
+    /// first argument (`"deprecated"` without one) and `node` the first
+    /// element in its body, whose span is given; without one `node` is nil
+    /// and the span is the directive's.
+    pub fn deprecations(ruby: &Ruby, rb_self: &Document, args: &[Value]) -> Result<RArray, Error> {
+        let args = scan_args::<(), (Option<String>,), (), (), (), ()>(args)?;
+        let name = args.optional.0.unwrap_or_else(|| "deprecated".to_string());
+        let tree = &rb_self.tree;
+        let mut directives = Vec::new();
+        let mut pending = vec![ROOT];
+        while let Some(index) = pending.pop() {
+            let node = tree.node(index);
+            if node.kind == NodeKind::Directive && node.name.as_deref() == Some(name.as_bytes()) {
+                directives.push(index);
+            }
+            pending.extend(node.children.iter().rev());
+        }
+
+        let array = RArray::with_capacity(directives.len());
+        for index in directives {
+            let directive = tree.node(index);
+            let message = match directive.values.first() {
+                Some(value) => value_to_ruby(ruby, value)?.funcall("to_s", ())?,
+                None => RString::new("deprecated"),
+            };
+            let element = directive
+                .children
+                .iter()
+                .copied()
+                .find(|&child| tree.node(child).kind == NodeKind::Element);
+            let span = element.map_or(&directive.span, |child| &tree.node(child).span);
+            let hash = RHash::new();
+            hash.aset(Symbol::new("message"), message)?;
+            hash.aset(Symbol::new("span"), span_to_hash(span))?;
+            hash.aset(
+                Symbol::new("node"),
+                element.map(|index| Node {
+                    tree: Arc::clone(tree),
+                    index,
+                }),
+            )?;
+            array.push(hash)?;
+        }
+        Ok(array)
+    }
+
+    /// `digest(ignore: [], algorithm: :sha256)`
+    pub fn digest(ruby: &Ruby, rb_self: &Document, args: &[Value]) -> Result<String, Error> {
+        let (ignore, algorithm) = digest_options(ruby, args)?;
+        Ok(digest::digest(&rb_self.tree, ROOT, ignore, algorithm))
+    }
+
+    /// `to_udon(sort_attributes: false, sort_elements_by: nil)`
+    pub fn to_udon(ruby: &Ruby, rb_self: &Document, args: &[Value]) -> Result<RString, Error> {
+        to_udon(ruby, &rb_self.tree, ROOT, args)
+    }
+
+    /// `node_at(offset)`: the innermost node covering byte `offset`, or nil
+    /// outside every top-level node. An offset between children finds their
+    /// enclosing node; `Node#ancestors` gives the chain above it. Offsets
+    /// index the parsed input, so included and expanded nodes are never
+    /// found: an offset in an `!include` or `!use` finds the node around it.
+    pub fn node_at(&self, offset: usize) -> Option<Node> {
+        let spans = self.spans.get_or_init(|| {
+            let tree = &self.tree;
+            let span = |node: &tree::NodeData| {
+                let at = match (node.expanded_from, node.file) {
+                    (Some(expansion), _) => tree.expansions[expansion].site.start,
+                    (None, Some(file)) => tree.files[file].included_at.start,
+                    (None, None) => return node.span.clone(),
+                };
+                at..at
+            };
+            SpanIndex::new(
+                tree.nodes.iter().map(span).collect(),
+                tree.nodes.iter().map(|node| node.parent).collect(),
+            )
+        });
+        spans
+            .innermost(offset)
+            .filter(|&index| index != ROOT)
+            .map(|index| Node {
+                tree: Arc::clone(&self.tree),
+                index,
+            })
+    }
+}
+
+impl Node {
+    fn data(&self) -> &tree::NodeData {
+        self.tree.node(self.index)
+    }
+
+    pub fn kind(&self) -> Symbol {
+        Symbol::new(self.data().kind.name())
+    }
+
+    pub fn name(&self) -> Option<RString> {
+        self.data().name.as_deref().map(string)
+    }
+
+    /// `attributes(metadata: false)`: attributes as a Hash; a key given more
+    /// than once maps to an Array of its values. With `metadata: true` each
+    /// value is `{value:, type:, span:}`, where `type` is the UDON value type
+    /// (`:integer`, `:string_value`, `:array`, ...) and `span` covers the
+    /// value in the source (the key, for a flag).
+    pub fn attributes(ruby: &Ruby, rb_self: &Node, args: &[Value]) -> Result<RHash, Error> {
+        let args = scan_args::<(), (), (), (), RHash, ()>(args)?;
+        let kwargs = get_kwargs::<_, (), (Option<bool>,), ()>(args.keywords, &[], &["metadata"])?;
+        let metadata = kwargs.optional.0.unwrap_or(false);
+        let data = rb_self.data();
+        let entry = |index: usize| -> Result<Value, Error> {
+            let (_, value) = &data.attrs[index];
+            let coerced = value_to_ruby(ruby, value)?;
+            if !metadata {
+                return Ok(coerced);
+            }
+            let kind = match value {
+                tree::Value::Scalar { kind, .. } => kind,
+                tree::Value::Array(_) => "array",
+            };
+            let hash = RHash::new();
+            hash.aset(Symbol::new("value"), coerced)?;
+            hash.aset(Symbol::new("type"), Symbol::new(kind))?;
+            hash.aset(Symbol::new("span"), span_to_hash(&data.attr_spans[index]))?;
+            Ok(hash.as_value())
+        };
+        let hash = RHash::new();
+        for (index, (key, _)) in data.attrs.iter().enumerate() {
+            let repeats: Vec<_> = (0..data.attrs.len())
+                .filter(|&i| &data.attrs[i].0 == key)
+                .collect();
+            if repeats.len() == 1 {
+                hash.aset(string(key), entry(index)?)?;
+            } else if hash.get(string(key)).is_none() {
+                let array = RArray::new();
+                for i in repeats {
+                    array.push(entry(i)?)?;
+                }
+                hash.aset(string(key), array)?;
+            }
+        }
+        Ok(hash)
+    }
+
+    /// The first value of attribute `key`, or nil.
+    pub fn attribute(ruby: &Ruby, rb_self: &Node, key: String) -> Result<Value, Error> {
+        match rb_self.data().attr(key.as_bytes()) {
+            Some(value) => value_to_ruby(ruby, value),
+            None => Ok(ruby.qnil().as_value()),
+        }
+    }
+
+    /// The attribute a method call names on a node of a tree parsed with
+    /// `as_objects: true`: `host` for `node.host`, or `max-age` for
+    /// `node.max_age` when there is no `max_age`.
+    fn method_attribute(&self, method: &str) -> Option<&tree::Value> {
+        if !self.tree.objects {
+            return None;
+        }
+        let data = self.data();
+        data.attr(method.as_bytes())
+            .or_else(|| data.attr(method.replace('_', "-").as_bytes()))
+    }
+
+    /// `method_missing(name, *args)`: the first value of the attribute
+    /// `name` names (see `method_attribute`), as with `[]`.
+    pub fn method_missing(ruby: &Ruby, rb_self: &Node, args: &[Value]) -> Result<Value, Error> {
+        if let [name] = args {
+            if let Some(name) = Symbol::from_value(*name) {
+                if let Some(value) = rb_self.method_attribute(&name.name()?) {
+                    return value_to_ruby(ruby, value);
+                }
+            }
+        }
+        ruby.call_super(args)
+    }
+
+    /// `respond_to_missing?(name, include_private)`
+    pub fn respond_to_missing(
+        ruby: &Ruby,
+        rb_self: &Node,
+        name: Symbol,
+        include_private: Value,
+    ) -> Result<bool, Error> {
+        if rb_self.method_attribute(&name.name()?).is_some() {
+            return Ok(true);
+        }
+        ruby.call_super((name, include_private))
+    }
+
+    /// Positional values, e.g. directive arguments.
+    pub fn values(ruby: &Ruby, rb_self: &Node) -> Result<RArray, Error> {
+        let array = RArray::new();
+        for value in &rb_self.data().values {
+            array.push(value_to_ruby(ruby, value)?)?;
+        }
+        Ok(array)
+    }
+
+    pub fn children(&self) -> RArray {
+        nodes(&self.tree, &self.data().children)
+    }
+
+    /// Enclosing nodes, innermost first, up to a top-level node.
+    pub fn ancestors(&self) -> RArray {
+        let mut chain = Vec::new();
+        let mut current = self.data().parent;
+        while let Some(index) = current.filter(|&index| index != ROOT) {
+            chain.push(index);
+            current = self.tree.node(index).parent;
+        }
+        nodes(&self.tree, &chain)
+    }
+
+    /// The enclosing node, or nil at the top level.
+    pub fn parent(&self) -> Option<Node> {
+        self.data()
+            .parent
+            .filter(|&parent| parent != ROOT)
+            .map(|index| Node {
+                tree: Arc::clone(&self.tree),
+                index,
+            })
+    }
+
+    /// The document the node belongs to: for `first_element`, only the part
+    /// of the input that was parsed.
+    pub fn document(&self) -> Document {
+        Document {
+            tree: Arc::clone(&self.tree),
+            spans: OnceLock::new(),
+        }
+    }
+
+    /// Content of text, comment, interpolation, reference and freeform nodes.
+    pub fn content(&self) -> Option<RString> {
+        let data = self.data();
+        match data.kind {
+            NodeKind::Element | NodeKind::Embedded | NodeKind::Directive | NodeKind::Document => {
+                None
+            }
+            _ => Some(string(&data.content)),
+        }
+    }
+
+    /// `value(dedent_raw: false, user_context: nil)`: for an embedded
+    /// element, what the handler registered for its name returns for its
+    /// content (see `embedded`), or the content itself without one; nil for
+    /// other nodes. The handler runs on every call, and what it raises is
+    /// raised. Warnings it emits through its `HandlerContext` have nowhere
+    /// to go in a tree and are dropped.
+    pub fn value(ruby: &Ruby, rb_self: &Node, args: &[Value]) -> Result<Value, Error> {
+        let args = scan_args::<(), (), (), (), RHash, ()>(args)?;
+        let kwargs = get_kwargs::<_, (), (Option<bool>, Option<Value>), ()>(
+            args.keywords,
+            &[],
+            &["dedent_raw", "user_context"],
+        )?;
+        let (dedent_raw, user_context) = kwargs.optional;
+        let data = rb_self.data();
+        let (NodeKind::Embedded, Some(name)) = (data.kind, &data.name) else {
+            return Ok(ruby.qnil().as_value());
+        };
+        let source = rb_self.tree.file_source(data.file);
+        let written = source.get(data.span.clone()).unwrap_or_default();
+        let content = string(&embedded::content(
+            written,
+            name,
+            dedent_raw.unwrap_or(false),
+        ));
+        let Some(handler) = embedded::handler(ruby, name)? else {
+            return Ok(content.as_value());
+        };
+        let span = span_to_hash(&data.span);
+        let mut names = Names::default();
+        let mut path = Vec::new();
+        let mut parent = data.parent;
+        while let Some(index) = parent.filter(|&index| index != ROOT) {
+            let node = rb_self.tree.node(index);
+            if node.kind == NodeKind::Element {
+                path.push(node.name.as_deref().map(|name| names.get(name)));
+            }
+            parent = node.parent;
+        }
+        path.reverse();
+        let source_slice = string(written);
+        source_slice.freeze();
+        let state = State {
+            path,
+            span: span.as_value(),
+            source: source_slice,
+            user_context: user_context.filter(|value| !value.is_nil()),
+        };
+        let (outcome, _) = HandlerContext::scope(state, |context| {
+            match handler_context::accepts(handler.as_value(), 3) {
+                true => handler.call((content, span, context)),
+                false => handler.call((content, span)),
+            }
+        });
+        outcome
+    }
+
+    /// `text_content(interpolations: false)`: the text of this node and all
+    /// descendants; see `Tree::text_content`.
+    pub fn text_content(rb_self: &Node, args: &[Value]) -> Result<RString, Error> {
+        let args = scan_args::<(), (), (), (), RHash, ()>(args)?;
+        let kwargs =
+            get_kwargs::<_, (), (Option<bool>,), ()>(args.keywords, &[], &["interpolations"])?;
+        let interpolations = kwargs.optional.0.unwrap_or(false);
+        Ok(string(
+            &rb_self.tree.text_content(rb_self.index, interpolations),
+        ))
+    }
+
+    pub fn span(&self) -> RHash {
+        span_to_hash(&self.data().span)
+    }
+
+    /// The file the node was read from, which its span indexes: an included
+    /// file, or the path given to `parse_tree`. Nil for a parsed String.
+    pub fn file(&self) -> Option<RString> {
+        self.tree
+            .file_path(self.data().file)
+            .map(|path| string(path.as_bytes()))
+    }
+
+    /// `{name:, span:}` of the `!use` that copied the node out of a
+    /// definition, with the `file:` the span indexes when it is known; nil
+    /// for a node written where it stands.
+    pub fn expanded_from(&self) -> Option<RHash> {
+        let expansion = &self.tree.expansions[self.data().expanded_from?];
+        let hash = RHash::new();
+        let _ = hash.aset(Symbol::new("name"), string(&expansion.name));
+        let _ = hash.aset(Symbol::new("span"), span_to_hash(&expansion.span));
+        if let Some(path) = self.tree.file_path(expansion.file) {
+            let _ = hash.aset(Symbol::new("file"), string(path.as_bytes()));
+        }
+        Some(hash)
+    }
+
+    /// `digest(ignore: [], algorithm: :sha256)` of this subtree.
+    pub fn digest(ruby: &Ruby, rb_self: &Node, args: &[Value]) -> Result<String, Error> {
+        let (ignore, algorithm) = digest_options(ruby, args)?;
+        Ok(digest::digest(
+            &rb_self.tree,
+            rb_self.index,
+            ignore,
+            algorithm,
+        ))
+    }
+
+    /// `to_udon(sort_attributes: false, sort_elements_by: nil)` of this subtree.
+    pub fn to_udon(ruby: &Ruby, rb_self: &Node, args: &[Value]) -> Result<RString, Error> {
+        to_udon(ruby, &rb_self.tree, rb_self.index, args)
+    }
+}
+
+/// Define `UdonNative::Document` and `UdonNative::Node`.
+pub fn define(ruby: &Ruby, module: RModule) -> Result<(), Error> {
+    let class = module.define_class("Document", ruby.class_object())?;
+    class.define_method("children", method!(Document::children, 0))?;
+    class.define_method("errors", method!(Document::errors, 0))?;
+    class.define_method("bom?", method!(Document::bom, 0))?;
+    class.define_method("deprecations", method!(Document::deprecations, -1))?;
+    class.define_method("digest", method!(Document::digest, -1))?;
+    class.define_method("to_udon", method!(Document::to_udon, -1))?;
+    class.define_method("node_at", method!(Document::node_at, 1))?;
+
+    let class = module.define_class("Node", ruby.class_object())?;
+    class.define_method("type", method!(Node::kind, 0))?;
+    class.define_method("name", method!(Node::name, 0))?;
+    class.define_method("attributes", method!(Node::attributes, -1))?;
+    class.define_method("[]", method!(Node::attribute, 1))?;
+    class.define_method("method_missing", method!(Node::method_missing, -1))?;
+    class.define_method("respond_to_missing?", method!(Node::respond_to_missing, 2))?;
+    class.define_method("values", method!(Node::values, 0))?;
+    class.define_method("children", method!(Node::children, 0))?;
+    class.define_method("parent", method!(Node::parent, 0))?;
+    class.define_method("ancestors", method!(Node::ancestors, 0))?;
+    class.define_method("document", method!(Node::document, 0))?;
+    class.define_method("content", method!(Node::content, 0))?;
+    class.define_method("text_content", method!(Node::text_content, -1))?;
+    class.define_method("value", method!(Node::value, -1))?;
+    class.define_method("span", method!(Node::span, 0))?;
+    class.define_method("file", method!(Node::file, 0))?;
+    class.define_method("expanded_from", method!(Node::expanded_from, 0))?;
+    class.define_method("digest", method!(Node::digest, -1))?;
+    class.define_method("to_udon", method!(Node::to_udon, -1))?;
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! `UdonNative.parse_each_element`: a document's events streamed one
+//! top-level element at a time, so a record can be stored and its events
+//! released before the next is converted.
+
+use magnus::{scan_args::scan_args, Error, RArray, RHash, Ruby, Value};
+use udon_core::Event;
+
+use crate::{
+    coerce, embedded, freeze, literal,
+    normalize::{check_input, normalize, rejected_nul},
+    nul_error,
+    options::ParseOptions,
+    Converter,
+};
+
+/// `UdonNative.parse_each_element(input, **options) { |events| ... }`
+///
+/// Yields the events of each top-level element or embedded element as
+/// soon as its end event is converted, and returns the number of arrays
+/// yielded. Events outside any element (comments, text, directives at
+/// column 0) go with the element after them; any after the last element
+/// are yielded as a final array. A parse error is in the array of the
+/// element it occurred in, so each element's errors can be reported with
+/// it. With `with_index: true` the array's index and a nil total are
+/// yielded too.
+///
+/// Takes the options of `parse` that shape single events; those that work
+/// on the whole array (`sort_by_span:`, `emit_document_bounds:`,
+/// `structured_directives:`, `conditions:`, ...) do not apply.
+pub fn parse_each_element(ruby: &Ruby, args: &[Value]) -> Result<usize, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::bytes(ruby, input)?;
+    let options = ParseOptions::from_hash(ruby, args.keywords)?;
+    if !ruby.block_given() {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "parse_each_element requires a block",
+        ));
+    }
+    let input_bytes = &input[..];
+    check_input(ruby, input_bytes, &options)?;
+
+    let mut batches = 0;
+    let mut yield_batch = |events: RArray| -> Result<(), Error> {
+        let events = freeze::finish(events, &options)?;
+        let _: Value = if options.with_index {
+            ruby.yield_values((events, batches, ruby.qnil()))?
+        } else {
+            ruby.yield_value(events)?
+        };
+        batches += 1;
+        Ok(())
+    };
+
+    if let Some(at) = rejected_nul(input_bytes, &options) {
+        let events = RArray::new();
+        events.push(nul_error(input_bytes, at..at + 1, &options))?;
+        yield_batch(events)?;
+        return Ok(batches);
+    }
+    let (normalized, offsets) = normalize(input_bytes, &options);
+    let mut converter = Converter::new(ruby, input_bytes, &options, offsets);
+    let mut embedded = embedded::Values::new(ruby, &normalized)?;
+    if embedded.is_some() {
+        converter.track_path();
+    }
+    let mut failure: Option<Error> = None;
+    let mut batch = RArray::new();
+    let mut depth = 0usize;
+
+    literal::parse(&normalized, &options, |event| {
+        // Once the block raises or breaks, the rest is only parsed.
+        if failure.is_some() {
+            return;
+        }
+        let values = embedded
+            .as_mut()
+            .map_or_else(Vec::new, |values| values.event(&event, &converter));
+        let converted = converter.convert(&event);
+        let warnings = converter.take_warnings();
+        for hash in values.into_iter().chain(converted).chain(warnings) {
+            let _ = batch.push(hash);
+        }
+        match event {
+            Event::ElementStart { .. } | Event::EmbeddedStart { .. } => depth += 1,
+            Event::ElementEnd { .. } | Event::EmbeddedEnd { .. } => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    if let Err(error) = yield_batch(std::mem::replace(&mut batch, RArray::new())) {
+                        failure = Some(error);
+                    }
+                }
+            }
+            _ => {}
+        }
+    });
+    if let Some(error) = failure {
+        return Err(error);
+    }
+    if let Some(values) = embedded {
+        values.finish()?;
+    }
+    converter.take_failure()?;
+    if !batch.is_empty() {
+        yield_batch(batch)?;
+    }
+    Ok(batches)
+}
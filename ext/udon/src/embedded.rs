@@ -0,0 +1,266 @@
+//! `UdonNative::Embedded`: content handlers for embedded elements by name.
+//!
+//! An embedded element's name doubles as the language of its content:
+//! `|{json {"a": 1}}` is JSON. Handlers live in a Hash on the module, keyed
+//! by name, and are called with the element's content, span and handler
+//! context (see `handler_context`). In `parse` output a `:embedded_value`
+//! event carrying the handler's return value comes just before the
+//! element's `:embedded_end`, followed by the warnings the handler emitted;
+//! a handler that raises gives an `embedded_failed` error event over the
+//! element instead. In a
+//! document tree, `Node#value` runs the handler. Elements whose name has no
+//! handler are left as they are.
+//!
+//! The content is the source between the name and the closing `}`, less
+//! the space or line break that separates it from the name, so it reaches
+//! the handler unparsed. With `dedent_raw: true` the common indentation of
+//! its lines is removed first.
+
+use std::ops::Range;
+
+use magnus::{
+    block::Proc, function, prelude::*, value::Lazy, Error, RArray, RHash, RModule, RString, Ruby,
+    Symbol, Value,
+};
+use udon_core::Event;
+
+use crate::{
+    handler_context::{self, HandlerContext, State},
+    Converter,
+};
+
+static EMBEDDED: Lazy<RModule> = Lazy::new(|ruby| {
+    let module: RModule = ruby
+        .class_object()
+        .const_get("UdonNative")
+        .expect("UdonNative is defined at init");
+    module
+        .const_get("Embedded")
+        .expect("Embedded is defined at init")
+});
+
+fn handlers(ruby: &Ruby) -> Result<RHash, Error> {
+    ruby.get_inner(&EMBEDDED).ivar_get("@handlers")
+}
+
+/// The handler registered for embedded elements named `name`.
+pub fn handler(ruby: &Ruby, name: &[u8]) -> Result<Option<Proc>, Error> {
+    let key = String::from_utf8_lossy(name).into_owned();
+    Ok(handlers(ruby)?.get(key).and_then(Proc::from_value))
+}
+
+/// `UdonNative::Embedded.register(name) { |content, span, context| ... }`
+///
+/// Replaces any handler already registered for the name.
+fn register(ruby: &Ruby, name: Value) -> Result<(), Error> {
+    let name: String = name.funcall("to_s", ())?;
+    if !ruby.block_given() {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "Embedded.register requires a block",
+        ));
+    }
+    handlers(ruby)?.aset(name, ruby.block_proc()?)
+}
+
+/// `UdonNative::Embedded.unregister(name)`: whether a handler was
+/// registered.
+fn unregister(ruby: &Ruby, name: Value) -> Result<bool, Error> {
+    let name: String = name.funcall("to_s", ())?;
+    Ok(!handlers(ruby)?.delete::<_, Value>(name)?.is_nil())
+}
+
+/// `UdonNative::Embedded.clear`
+fn clear(ruby: &Ruby) -> Result<(), Error> {
+    let _: Value = handlers(ruby)?.funcall("clear", ())?;
+    Ok(())
+}
+
+/// `UdonNative::Embedded.registered`: the registered names.
+fn registered(ruby: &Ruby) -> Result<RArray, Error> {
+    handlers(ruby)?.funcall("keys", ())
+}
+
+/// The content of the embedded element `written` (its source from `|{` to
+/// `}`) named `name`; see the module docs.
+pub fn content(written: &[u8], name: &[u8], dedent_raw: bool) -> Vec<u8> {
+    let after = memchr::memmem::find(written, name).map_or(written.len(), |at| at + name.len());
+    let mut body = &written[after..];
+    body = body.strip_suffix(b"}").unwrap_or(body);
+    for separator in [b"\r\n".as_slice(), b"\n", b" ", b"\t"] {
+        if let Some(rest) = body.strip_prefix(separator) {
+            body = rest;
+            break;
+        }
+    }
+    if dedent_raw {
+        dedent(body)
+    } else {
+        body.to_vec()
+    }
+}
+
+/// `text` without the leading whitespace all its non-blank lines share.
+fn dedent(text: &[u8]) -> Vec<u8> {
+    let indent = |line: &[u8]| {
+        line.iter()
+            .take_while(|&&b| b == b' ' || b == b'\t')
+            .count()
+    };
+    let common = text
+        .split(|&b| b == b'\n')
+        .filter(|line| line.iter().any(|b| !b.is_ascii_whitespace()))
+        .map(indent)
+        .min()
+        .unwrap_or(0);
+    let mut out = Vec::with_capacity(text.len());
+    for (index, line) in text.split(|&b| b == b'\n').enumerate() {
+        if index > 0 {
+            out.push(b'\n');
+        }
+        out.extend_from_slice(&line[indent(line).min(common)..]);
+    }
+    out
+}
+
+/// An open embedded element.
+struct Open {
+    start: Range<usize>,
+    name: Vec<u8>,
+    handler: Option<Proc>,
+}
+
+/// Runs handlers over the embedded elements of one parse, given the input
+/// the parser read.
+pub struct Values<'a> {
+    ruby: &'a Ruby,
+    source: &'a [u8],
+    handlers: RHash,
+    open: Vec<Open>,
+    /// The last event opened an embedded element, whose name is next.
+    awaiting_name: bool,
+    /// A `break` or `throw` out of a handler, raised once the parse is done.
+    failure: Option<Error>,
+}
+
+impl<'a> Values<'a> {
+    /// `None` when no handler is registered, so there is nothing to do.
+    pub fn new(ruby: &'a Ruby, source: &'a [u8]) -> Result<Option<Self>, Error> {
+        let handlers = handlers(ruby)?;
+        if handlers.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Values {
+            ruby,
+            source,
+            handlers,
+            open: Vec::new(),
+            awaiting_name: false,
+            failure: None,
+        }))
+    }
+
+    /// Follow `event`; at the end of an embedded element with a handler,
+    /// the `:embedded_value` or error hash and the handler's warnings, to
+    /// put before its end event.
+    pub fn event(&mut self, event: &Event, converter: &Converter) -> Vec<RHash> {
+        let awaiting_name = std::mem::take(&mut self.awaiting_name);
+        match event {
+            Event::EmbeddedStart { span } => {
+                self.open.push(Open {
+                    start: span.clone(),
+                    name: Vec::new(),
+                    handler: None,
+                });
+                self.awaiting_name = true;
+            }
+            Event::Name { content, .. } if awaiting_name => {
+                if let Some(open) = self.open.last_mut() {
+                    open.name = content.to_vec();
+                    let key = String::from_utf8_lossy(content).into_owned();
+                    open.handler = self.handlers.get(key).and_then(Proc::from_value);
+                }
+            }
+            Event::EmbeddedEnd { span } => {
+                let Some(Open {
+                    start,
+                    name,
+                    handler: Some(handler),
+                }) = self.open.pop()
+                else {
+                    return Vec::new();
+                };
+                if self.failure.is_some() {
+                    return Vec::new();
+                }
+                let span = start.start..span.end.max(start.end);
+                return self.run(converter, handler, &name, span);
+            }
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    fn run(
+        &mut self,
+        converter: &Converter,
+        handler: Proc,
+        name: &[u8],
+        span: Range<usize>,
+    ) -> Vec<RHash> {
+        let written = self.source.get(span.clone()).unwrap_or_default();
+        let content = RString::from_slice(&content(written, name, converter.options.dedent_raw));
+        let reported = converter.spans.convert(&span);
+        let state = State::new(converter, &span);
+        let (outcome, warnings) = HandlerContext::scope(state, |context| {
+            match handler_context::accepts(handler.as_value(), 3) {
+                true => handler.call::<_, Value>((content, reported, context)),
+                false => handler.call((content, reported)),
+            }
+        });
+        let hash = RHash::new();
+        match outcome {
+            Ok(value) => {
+                let _ = hash.aset(Symbol::new("type"), Symbol::new("embedded_value"));
+                let _ = hash.aset(Symbol::new("name"), RString::from_slice(name));
+                let _ = hash.aset(Symbol::new("value"), value);
+            }
+            Err(error) => {
+                let Some(exception) = error.value() else {
+                    self.failure = Some(error);
+                    return Vec::new();
+                };
+                let message = exception
+                    .funcall::<_, _, Value>("message", ())
+                    .unwrap_or_else(|_| self.ruby.qnil().as_value());
+                let _ = hash.aset(Symbol::new("type"), Symbol::new("error"));
+                let _ = hash.aset(Symbol::new("code"), Symbol::new("embedded_failed"));
+                let _ = hash.aset(Symbol::new("message"), message);
+                let _ = hash.aset(Symbol::new("name"), RString::from_slice(name));
+                if converter.options.severity {
+                    let _ = hash.aset(Symbol::new("severity"), Symbol::new("recoverable"));
+                }
+            }
+        }
+        let _ = hash.aset(Symbol::new("span"), reported);
+        let mut events = vec![hash];
+        events.extend(handler_context::warning_events(converter, warnings, &span));
+        events
+    }
+
+    /// Raise what broke out of a handler, if anything did.
+    pub fn finish(self) -> Result<(), Error> {
+        self.failure.map_or(Ok(()), Err)
+    }
+}
+
+/// Define `UdonNative::Embedded`.
+pub fn define(module: RModule) -> Result<(), Error> {
+    let embedded = module.define_module("Embedded")?;
+    embedded.ivar_set("@handlers", RHash::new())?;
+    embedded.define_singleton_method("register", function!(register, 1))?;
+    embedded.define_singleton_method("unregister", function!(unregister, 1))?;
+    embedded.define_singleton_method("clear", function!(clear, 0))?;
+    embedded.define_singleton_method("registered", function!(registered, 0))?;
+    Ok(())
+}
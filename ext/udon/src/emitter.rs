@@ -0,0 +1,573 @@
+//! Serialize an event stream back into UDON text.
+//!
+//! The emitter is fed one event at a time and validates structure as it goes:
+//! every end event must close the innermost open bracket of the same kind,
+//! names and values must appear where the grammar allows them, and nothing may
+//! be left open at `finish`. Errors carry the index of the originating event.
+
+/// Bracket kinds that open a nesting level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Bracket {
+    Element,
+    Embedded,
+    Directive,
+    Array,
+    Freeform,
+    Comment,
+}
+
+impl Bracket {
+    fn name(self) -> &'static str {
+        match self {
+            Bracket::Element => "element",
+            Bracket::Embedded => "embedded",
+            Bracket::Directive => "directive",
+            Bracket::Array => "array",
+            Bracket::Freeform => "freeform",
+            Bracket::Comment => "comment",
+        }
+    }
+
+    /// Brackets that own indented child lines.
+    fn is_block(self) -> bool {
+        matches!(self, Bracket::Element | Bracket::Directive)
+    }
+
+    /// Brackets that may carry a name, attributes and values.
+    fn has_header(self) -> bool {
+        matches!(
+            self,
+            Bracket::Element | Bracket::Embedded | Bracket::Directive
+        )
+    }
+}
+
+struct Frame {
+    bracket: Bracket,
+    /// Index of the event that opened this bracket.
+    origin: usize,
+    /// Whether the name slot is closed (a name or attribute was written).
+    named: bool,
+    /// Whether output has moved past the header line into indented children.
+    in_body: bool,
+    /// Items written so far (array separators, embedded text spacing).
+    items: usize,
+}
+
+/// A structural error, tagged with the index of the event that caused it.
+#[derive(Debug)]
+pub struct EmitError {
+    pub index: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for EmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "event {}: {}", index, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Incremental UDON writer with structural validation.
+pub struct Emitter {
+    out: Vec<u8>,
+    stack: Vec<Frame>,
+    /// An attribute was written and its value has not arrived yet.
+    awaiting_value: bool,
+    /// The last write was inline content, so following inline content joins it.
+    inline: bool,
+    at_line_start: bool,
+    /// Whitespace written per nesting level.
+    indent_unit: Vec<u8>,
+}
+
+impl Default for Emitter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Emitter {
+    pub fn new() -> Self {
+        Emitter {
+            out: Vec::new(),
+            stack: Vec::new(),
+            awaiting_value: false,
+            inline: false,
+            at_line_start: true,
+            indent_unit: b"  ".to_vec(),
+        }
+    }
+
+    /// Indent each nesting level with `unit` instead of two spaces.
+    pub fn with_indent(mut self, unit: Vec<u8>) -> Self {
+        self.indent_unit = unit;
+        self
+    }
+
+    /// Bytes written so far.
+    pub fn buffered(&self) -> usize {
+        self.out.len()
+    }
+
+    /// Bytes written so far, without taking them.
+    pub fn output(&self) -> &[u8] {
+        &self.out
+    }
+
+    /// Take the bytes written so far, leaving the emitter state intact.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.out)
+    }
+
+    /// Feed one event. `index` identifies the originating event in errors.
+    pub fn event(
+        &mut self,
+        kind: &str,
+        content: Option<&[u8]>,
+        index: usize,
+    ) -> Result<(), EmitError> {
+        let content = content.unwrap_or(b"");
+
+        match kind {
+            "element_start" => self.open_block(Bracket::Element, b"|", index),
+            "directive_start" => self.open_block(Bracket::Directive, b"!", index),
+            "embedded_start" => {
+                self.check_inline_allowed(kind, index)?;
+                self.begin_inline();
+                self.write(b"|{");
+                self.push(Bracket::Embedded, index);
+                Ok(())
+            }
+            "array_start" => {
+                self.begin_value(kind, index)?;
+                self.write(b"[");
+                self.push(Bracket::Array, index);
+                Ok(())
+            }
+            "freeform_start" => {
+                self.check_block_allowed(kind, index)?;
+                self.start_line();
+                self.write(b"```\n");
+                self.push(Bracket::Freeform, index);
+                Ok(())
+            }
+            "comment_start" => {
+                self.check_block_allowed(kind, index)?;
+                self.start_line();
+                self.write(b";");
+                self.push(Bracket::Comment, index);
+                Ok(())
+            }
+
+            "element_end" => self.close(Bracket::Element, kind, index),
+            "directive_end" => self.close(Bracket::Directive, kind, index),
+            "embedded_end" => {
+                self.close(Bracket::Embedded, kind, index)?;
+                self.write(b"}");
+                self.inline = true;
+                Ok(())
+            }
+            "array_end" => {
+                self.close(Bracket::Array, kind, index)?;
+                self.write(b"]");
+                Ok(())
+            }
+            "freeform_end" => {
+                self.close(Bracket::Freeform, kind, index)?;
+                if !self.at_line_start {
+                    self.write(b"\n");
+                }
+                self.indent(self.block_depth());
+                self.write(b"```");
+                self.inline = false;
+                Ok(())
+            }
+            "comment_end" => {
+                self.close(Bracket::Comment, kind, index)?;
+                self.inline = false;
+                Ok(())
+            }
+
+            "name" => {
+                match self.stack.last_mut() {
+                    Some(frame) if frame.bracket.has_header() && !frame.named => {
+                        frame.named = true;
+                    }
+                    _ => {
+                        return Err(error(
+                            index,
+                            "name is only allowed directly after a start event",
+                        ))
+                    }
+                }
+                self.write(content);
+                Ok(())
+            }
+
+            "attr" => {
+                let in_body = match self.stack.last_mut() {
+                    Some(frame) if frame.bracket.has_header() => {
+                        frame.named = true;
+                        frame.in_body
+                    }
+                    _ => {
+                        return Err(error(
+                            index,
+                            "attr is only allowed inside an element, embedded or directive",
+                        ))
+                    }
+                };
+                if in_body {
+                    self.start_line();
+                } else {
+                    self.write(b" ");
+                }
+                self.write(b":");
+                self.write(content);
+                self.awaiting_value = true;
+                self.inline = false;
+                Ok(())
+            }
+
+            "string_value" => {
+                self.begin_value(kind, index)?;
+                self.write_quoted(content);
+                Ok(())
+            }
+            "bare_value" | "integer" | "float" | "rational" | "complex" => {
+                self.begin_value(kind, index)?;
+                self.write(content);
+                Ok(())
+            }
+            "bool_true" | "bool_false" | "nil" => {
+                // A flag attribute carries no source text; keep it bare.
+                if content.is_empty() && self.awaiting_value && kind == "bool_true" {
+                    self.awaiting_value = false;
+                    return Ok(());
+                }
+                self.begin_value(kind, index)?;
+                if content.is_empty() {
+                    self.write(match kind {
+                        "bool_true" => b"true".as_slice(),
+                        "bool_false" => b"false".as_slice(),
+                        _ => b"null".as_slice(),
+                    });
+                } else {
+                    self.write(content);
+                }
+                Ok(())
+            }
+
+            "text" => self.text(content, index),
+            "interpolation" => {
+                let in_array =
+                    matches!(self.stack.last(), Some(frame) if frame.bracket == Bracket::Array);
+                let as_value = self.awaiting_value || in_array;
+                if as_value {
+                    // An interpolated attribute value or array item.
+                    self.begin_value(kind, index)?;
+                } else {
+                    self.begin_inline();
+                }
+                self.write(b"!{{");
+                self.write(content);
+                self.write(b"}}");
+                self.inline = !as_value;
+                Ok(())
+            }
+            "reference" => {
+                self.check_inline_allowed(kind, index)?;
+                self.begin_inline();
+                self.write(b"@[");
+                self.write(content);
+                self.write(b"]");
+                self.inline = true;
+                Ok(())
+            }
+            "raw_content" | "raw" => {
+                self.write(content);
+                self.inline = true;
+                Ok(())
+            }
+
+            // Diagnostics are not part of the document, embedded values are
+            // derived from the content already written, and the document's
+            // bounds are where the output starts and ends anyway.
+            "warning" | "error" | "embedded_value" => Ok(()),
+            "document_start" | "document_end" => Ok(()),
+
+            other => Err(error(index, format!("unknown event type :{}", other))),
+        }
+    }
+
+    /// Write an inline directive, `!{name content}`, into the text flow.
+    pub fn inline_directive(
+        &mut self,
+        name: &[u8],
+        content: &[u8],
+        index: usize,
+    ) -> Result<(), EmitError> {
+        self.check_inline_allowed("inline directive", index)?;
+        self.begin_inline();
+        self.write(b"!{");
+        self.write(name);
+        if !content.is_empty() {
+            self.write(b" ");
+            self.write(content);
+        }
+        self.write(b"}");
+        self.inline = true;
+        Ok(())
+    }
+
+    /// Check that every bracket was closed.
+    pub fn finish(&mut self) -> Result<(), EmitError> {
+        if let Some(frame) = self.stack.last() {
+            return Err(error(
+                frame.origin,
+                format!("{} opened here is never closed", frame.bracket.name()),
+            ));
+        }
+        if !self.at_line_start {
+            self.write(b"\n");
+        }
+        Ok(())
+    }
+
+    // ========== Structure ==========
+
+    fn push(&mut self, bracket: Bracket, origin: usize) {
+        self.stack.push(Frame {
+            bracket,
+            origin,
+            named: false,
+            in_body: false,
+            items: 0,
+        });
+        self.awaiting_value = false;
+        self.inline = false;
+    }
+
+    fn close(&mut self, bracket: Bracket, kind: &str, index: usize) -> Result<(), EmitError> {
+        match self.stack.last() {
+            Some(frame) if frame.bracket == bracket => {
+                self.stack.pop();
+                self.awaiting_value = false;
+                self.inline = false;
+                if let Some(parent) = self.stack.last_mut() {
+                    parent.items += 1;
+                }
+                Ok(())
+            }
+            Some(frame) => Err(error(
+                index,
+                format!(
+                    "{} does not match the open {} from event {}",
+                    kind,
+                    frame.bracket.name(),
+                    frame.origin
+                ),
+            )),
+            None => Err(error(
+                index,
+                format!("{} without an open {}", kind, bracket.name()),
+            )),
+        }
+    }
+
+    fn open_block(
+        &mut self,
+        bracket: Bracket,
+        sigil: &[u8],
+        index: usize,
+    ) -> Result<(), EmitError> {
+        let kind = if bracket == Bracket::Element {
+            "element_start"
+        } else {
+            "directive_start"
+        };
+        self.check_block_allowed(kind, index)?;
+        self.start_line();
+        self.write(sigil);
+        self.push(bracket, index);
+        Ok(())
+    }
+
+    fn check_block_allowed(&self, kind: &str, index: usize) -> Result<(), EmitError> {
+        match self.stack.last() {
+            None => Ok(()),
+            Some(frame) if frame.bracket.is_block() => Ok(()),
+            Some(frame) => Err(error(
+                index,
+                format!("{} is not allowed inside {}", kind, frame.bracket.name()),
+            )),
+        }
+    }
+
+    fn check_inline_allowed(&self, kind: &str, index: usize) -> Result<(), EmitError> {
+        match self.stack.last() {
+            Some(frame) if frame.bracket == Bracket::Array => Err(error(
+                index,
+                format!("{} is not allowed inside array", kind),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Start a value slot: after an attribute, inside an array, or as a
+    /// positional directive argument.
+    fn begin_value(&mut self, kind: &str, index: usize) -> Result<(), EmitError> {
+        match self.stack.last_mut() {
+            Some(frame) if frame.bracket == Bracket::Array => {
+                if frame.items > 0 {
+                    self.out.push(b' ');
+                }
+                frame.items += 1;
+                Ok(())
+            }
+            Some(frame) if self.awaiting_value || frame.bracket == Bracket::Directive => {
+                frame.named = true;
+                self.awaiting_value = false;
+                self.write(b" ");
+                Ok(())
+            }
+            _ => Err(error(index, format!("{} without a preceding attr", kind))),
+        }
+    }
+
+    // ========== Text ==========
+
+    fn text(&mut self, content: &[u8], index: usize) -> Result<(), EmitError> {
+        let bracket = self.stack.last().map(|frame| frame.bracket);
+        match bracket {
+            Some(Bracket::Array) => Err(error(index, "text is not allowed inside array")),
+            // Freeform content is verbatim, indentation included.
+            Some(Bracket::Freeform) => {
+                self.write(content);
+                Ok(())
+            }
+            Some(Bracket::Comment) => {
+                let depth = self.block_depth();
+                let content = content.strip_suffix(b"\n").unwrap_or(content);
+                for (i, line) in content.split(|&b| b == b'\n').enumerate() {
+                    if i > 0 {
+                        self.write(b"\n");
+                        self.indent(depth);
+                        self.write(b";");
+                    }
+                    self.write(line);
+                }
+                Ok(())
+            }
+            Some(Bracket::Embedded) => {
+                let frame = self.stack.last_mut().expect("embedded frame");
+                frame.named = true;
+                if !self.inline {
+                    self.out.push(b' ');
+                }
+                self.write(content);
+                self.inline = true;
+                Ok(())
+            }
+            _ => {
+                let content = content.strip_suffix(b"\n").unwrap_or(content);
+                for (i, line) in content.split(|&b| b == b'\n').enumerate() {
+                    if i == 0 && self.inline {
+                        self.write(line);
+                    } else if i == 0 && self.in_header() {
+                        self.write(b" ");
+                        self.write(line);
+                    } else {
+                        self.start_line();
+                        self.write_text_line(line);
+                    }
+                }
+                self.inline = true;
+                Ok(())
+            }
+        }
+    }
+
+    fn write_text_line(&mut self, line: &[u8]) {
+        if matches!(line.first(), Some(b'|' | b':' | b';' | b'!' | b'\\')) {
+            self.write(b"\\");
+        }
+        self.write(line);
+    }
+
+    fn write_quoted(&mut self, content: &[u8]) {
+        self.out.push(b'"');
+        for &byte in content {
+            match byte {
+                b'"' => self.out.extend_from_slice(b"\\\""),
+                b'\\' => self.out.extend_from_slice(b"\\\\"),
+                b'\n' => self.out.extend_from_slice(b"\\n"),
+                _ => self.out.push(byte),
+            }
+        }
+        self.out.push(b'"');
+        self.at_line_start = false;
+    }
+
+    // ========== Layout ==========
+
+    fn block_depth(&self) -> usize {
+        self.stack.iter().filter(|f| f.bracket.is_block()).count()
+    }
+
+    fn in_header(&self) -> bool {
+        matches!(self.stack.last(), Some(frame) if frame.bracket.is_block() && !frame.in_body)
+    }
+
+    /// Begin a new line at the current block depth, moving the enclosing
+    /// block into its body.
+    fn start_line(&mut self) {
+        if let Some(frame) = self.stack.last_mut() {
+            if frame.bracket.is_block() {
+                frame.in_body = true;
+            }
+        }
+        if !self.at_line_start {
+            self.write(b"\n");
+        }
+        self.indent(self.block_depth());
+        self.inline = false;
+    }
+
+    /// Position for inline content (text flow, embedded, interpolation).
+    fn begin_inline(&mut self) {
+        if self.inline {
+            return;
+        }
+        match self.stack.last() {
+            Some(frame) if frame.bracket == Bracket::Embedded => self.write(b" "),
+            _ if self.in_header() => self.write(b" "),
+            _ => self.start_line(),
+        }
+    }
+
+    fn indent(&mut self, depth: usize) {
+        for _ in 0..depth {
+            self.out.extend_from_slice(&self.indent_unit);
+        }
+        self.at_line_start = depth == 0;
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.out.extend_from_slice(bytes);
+        self.at_line_start = bytes.last() == Some(&b'\n');
+    }
+}
+
+fn error(index: usize, message: impl Into<String>) -> EmitError {
+    EmitError {
+        index: Some(index),
+        message: message.into(),
+    }
+}
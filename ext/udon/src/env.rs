@@ -0,0 +1,106 @@
+//! `interpolations: :env`: interpolations resolved from the process
+//! environment, without calling into Ruby.
+//!
+//! An expression of the form `env.NAME` (the prefix is `env_prefix:`)
+//! becomes a `:string_value` event holding the variable's value, with the
+//! interpolation's span, `interpolated: true` and the `:expression`, as
+//! `interpolation_resolver` results are reported. Other expressions are
+//! left as `:interpolation` events. A variable that is unset, or not in
+//! `env_allowlist:` when one is given (it is then never read), is handled
+//! per `missing_env:`. Values go into the events and nowhere else.
+
+use std::{collections::HashSet, env};
+
+use magnus::{prelude::*, Error, RHash, RString, Ruby, Symbol, Value};
+
+/// What a variable that cannot be read turns into (`missing_env:`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingEnv {
+    /// An `env_missing` error event.
+    #[default]
+    Error,
+    /// An empty string value.
+    Empty,
+    /// The `:interpolation` event, unchanged.
+    Keep,
+}
+
+pub struct EnvOptions {
+    pub prefix: String,
+    pub missing: MissingEnv,
+    /// The variables that may be read; all of them without one.
+    pub allowlist: Option<HashSet<String>>,
+}
+
+impl Default for EnvOptions {
+    fn default() -> Self {
+        EnvOptions {
+            prefix: "env.".to_string(),
+            missing: MissingEnv::default(),
+            allowlist: None,
+        }
+    }
+}
+
+impl EnvOptions {
+    /// The value of the variable `expression` names, if it names one:
+    /// `Some(None)` when it cannot be read.
+    fn lookup<'e>(&self, expression: &'e str) -> Option<(&'e str, Option<String>)> {
+        let name = expression.trim().strip_prefix(self.prefix.as_str())?;
+        if name.is_empty() {
+            return None;
+        }
+        let allowed = self
+            .allowlist
+            .as_ref()
+            .is_none_or(|allowlist| allowlist.contains(name));
+        let value = allowed
+            .then(|| env::var_os(name))
+            .flatten()
+            .map(|value| value.to_string_lossy().into_owned());
+        Some((name, value))
+    }
+
+    /// Resolve the `:interpolation` event `hash` with `expression` in place.
+    pub fn apply(&self, ruby: &Ruby, expression: &[u8], hash: RHash, severity: bool) {
+        let Ok(expression) = std::str::from_utf8(expression) else {
+            return;
+        };
+        let Some((name, value)) = self.lookup(expression) else {
+            return;
+        };
+        let value = match (value, self.missing) {
+            (Some(value), _) => value,
+            (None, MissingEnv::Empty) => String::new(),
+            (None, MissingEnv::Keep) => return,
+            (None, MissingEnv::Error) => {
+                let _ = hash.aset(Symbol::new("type"), Symbol::new("error"));
+                let _ = hash.delete::<_, Value>(Symbol::new("content"));
+                let _ = hash.aset(Symbol::new("code"), Symbol::new("env_missing"));
+                let _ = hash.aset(Symbol::new("expression"), expression);
+                let _ = hash.aset(Symbol::new("name"), name);
+                if severity {
+                    let _ = hash.aset(Symbol::new("severity"), Symbol::new("recoverable"));
+                }
+                return;
+            }
+        };
+        let _ = hash.aset(Symbol::new("type"), Symbol::new("string_value"));
+        let _ = hash.aset(Symbol::new("content"), ruby.str_new(&value));
+        let _ = hash.aset(Symbol::new("expression"), RString::new(expression));
+        let _ = hash.aset(Symbol::new("interpolated"), true);
+    }
+}
+
+/// Read `env_allowlist:`, an Array of variable names.
+pub fn allowlist(value: Value) -> Result<Option<HashSet<String>>, Error> {
+    if value.is_nil() {
+        return Ok(None);
+    }
+    let names: Vec<Value> = value.funcall("to_a", ())?;
+    names
+        .into_iter()
+        .map(|name| name.funcall("to_s", ()))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
@@ -0,0 +1,113 @@
+//! Exception classes raised by the extension.
+//!
+//! All live under `UdonNative` and inherit from `UdonNative::Error`.
+
+use magnus::{prelude::*, value::Lazy, Error, ExceptionClass, RModule, Ruby};
+
+use crate::emitter::EmitError;
+
+static ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| native_error(ruby, "Error"));
+static EMIT_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| native_error(ruby, "EmitError"));
+static FRAME_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| native_error(ruby, "FrameError"));
+static YAML_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| native_error(ruby, "YamlError"));
+static ENCODING_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| native_error(ruby, "EncodingError"));
+static IO_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| native_error(ruby, "IOError"));
+static MERGE_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| native_error(ruby, "MergeError"));
+static INTERPOLATION_ERROR: Lazy<ExceptionClass> =
+    Lazy::new(|ruby| native_error(ruby, "InterpolationError"));
+static INCLUDE_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| native_error(ruby, "IncludeError"));
+static CONDITION_ERROR: Lazy<ExceptionClass> =
+    Lazy::new(|ruby| native_error(ruby, "ConditionError"));
+static DIRECTIVE_ERROR: Lazy<ExceptionClass> =
+    Lazy::new(|ruby| native_error(ruby, "DirectiveError"));
+
+/// Look up an exception class defined by `define`.
+fn native_error(ruby: &Ruby, name: &str) -> ExceptionClass {
+    let module: RModule = ruby
+        .class_object()
+        .const_get("UdonNative")
+        .expect("UdonNative is defined at init");
+    module
+        .const_get(name)
+        .expect("exception classes are defined at init")
+}
+
+/// Define the exception hierarchy under `module`.
+pub fn define(ruby: &Ruby, module: RModule) -> Result<(), Error> {
+    let base = module.define_error("Error", ruby.exception_standard_error())?;
+    module.define_error("EmitError", base)?;
+    module.define_error("FrameError", base)?;
+    module.define_error("YamlError", base)?;
+    module.define_error("EncodingError", base)?;
+    module.define_error("IOError", base)?;
+    module.define_error("MergeError", base)?;
+    module.define_error("InterpolationError", base)?;
+    module.define_error("IncludeError", base)?;
+    module.define_error("ConditionError", base)?;
+    module.define_error("DirectiveError", base)?;
+    Ok(())
+}
+
+/// Raise a general `UdonNative::Error`, e.g. for misuse of a stateful object.
+pub fn error(ruby: &Ruby, message: String) -> Error {
+    Error::new(ruby.get_inner(&ERROR), message)
+}
+
+/// Raise an emitter structure error as `UdonNative::EmitError`.
+pub fn emit_error(ruby: &Ruby, err: EmitError) -> Error {
+    Error::new(ruby.get_inner(&EMIT_ERROR), err.to_string())
+}
+
+/// Raise a framing problem (truncated length prefix or body) as
+/// `UdonNative::FrameError`.
+pub fn frame_error(ruby: &Ruby, message: String) -> Error {
+    Error::new(ruby.get_inner(&FRAME_ERROR), message)
+}
+
+/// Raise a YAML input problem (syntax, rejected alias, unmappable shape) as
+/// `UdonNative::YamlError`.
+pub fn yaml_error(ruby: &Ruby, message: String) -> Error {
+    Error::new(ruby.get_inner(&YAML_ERROR), message)
+}
+
+/// Raise input in an encoding the parser cannot read (UTF-16) as
+/// `UdonNative::EncodingError`.
+pub fn encoding_error(ruby: &Ruby, message: String) -> Error {
+    Error::new(ruby.get_inner(&ENCODING_ERROR), message)
+}
+
+/// Raise unreadable input (a corrupt or truncated gzip stream) as
+/// `UdonNative::IOError`.
+pub fn io_error(ruby: &Ruby, message: String) -> Error {
+    Error::new(ruby.get_inner(&IO_ERROR), message)
+}
+
+/// Raise documents that cannot be merged (a parse error, a duplicate base
+/// id) as `UdonNative::MergeError`.
+pub fn merge_error(ruby: &Ruby, message: String) -> Error {
+    Error::new(ruby.get_inner(&MERGE_ERROR), message)
+}
+
+/// Raise an `interpolation_resolver` failure under `strict_interpolation:
+/// true` as `UdonNative::InterpolationError`.
+pub fn interpolation_error(ruby: &Ruby, message: String) -> Error {
+    Error::new(ruby.get_inner(&INTERPOLATION_ERROR), message)
+}
+
+/// Raise an include that cannot be resolved (missing file, a path outside
+/// the include base, a cycle, too deep) as `UdonNative::IncludeError`.
+pub fn include_error(ruby: &Ruby, message: String) -> Error {
+    Error::new(ruby.get_inner(&INCLUDE_ERROR), message)
+}
+
+/// Raise a condition directive that cannot be evaluated (bad syntax, an
+/// unknown variable) as `UdonNative::ConditionError`.
+pub fn condition_error(ruby: &Ruby, message: String) -> Error {
+    Error::new(ruby.get_inner(&CONDITION_ERROR), message)
+}
+
+/// Raise an unknown directive under `strict_directives: true` as
+/// `UdonNative::DirectiveError`.
+pub fn directive_error(ruby: &Ruby, message: String) -> Error {
+    Error::new(ruby.get_inner(&DIRECTIVE_ERROR), message)
+}
@@ -0,0 +1,113 @@
+//! `UdonNative::EventLines`: which events start on which line, for editor
+//! decorations that ask for "everything on lines N..M" as the view scrolls.
+//!
+//! Built once in a pass over the events and stored as one flat array of
+//! event indexes grouped by line, with per-line offsets into it, so a query
+//! is a slice copy and never rescans the events.
+
+use std::ops::Range;
+
+use magnus::{
+    function, method, prelude::*, Error, RArray, RHash, RModule, Ruby, Symbol, TryConvert, Value,
+};
+
+use crate::{coerce, line_index::LineIndex, span::span_of};
+
+#[magnus::wrap(class = "UdonNative::EventLines", free_immediately, size)]
+pub struct EventLines {
+    /// Indexes of the events starting on 0-based line `l` are
+    /// `events[starts[l]..starts[l + 1]]`, in event order.
+    starts: Vec<usize>,
+    events: Vec<usize>,
+}
+
+impl EventLines {
+    /// `EventLines.new(events, input)`; `events` from `UdonNative.parse` of
+    /// `input`, with any span format.
+    fn new(ruby: &Ruby, events: RArray, input: Value) -> Result<Self, Error> {
+        let input = coerce::string(ruby, input)?;
+        let source = unsafe { input.as_slice() };
+        let index = LineIndex::new(source);
+        let mut lines = Vec::with_capacity(events.len());
+        for event in events.each() {
+            let event = RHash::try_convert(event?)?;
+            let span = event.fetch::<_, Value>(Symbol::new("span"))?;
+            let line = match RArray::from_value(span) {
+                // spans: :line_col_packed already has the 0-based line.
+                Some(packed) => packed.entry::<usize>(0)?,
+                None => index.line_of(span_of(ruby, span)?.start),
+            };
+            lines.push(line);
+        }
+
+        let line_count = lines
+            .iter()
+            .map(|&line| line + 1)
+            .fold(index.line_count(), usize::max);
+        let mut starts = vec![0; line_count + 1];
+        for &line in &lines {
+            starts[line + 1] += 1;
+        }
+        for line in 0..line_count {
+            starts[line + 1] += starts[line];
+        }
+        let mut next = starts.clone();
+        let mut grouped = vec![0; lines.len()];
+        for (event, &line) in lines.iter().enumerate() {
+            grouped[next[line]] = event;
+            next[line] += 1;
+        }
+        Ok(EventLines {
+            starts,
+            events: grouped,
+        })
+    }
+
+    fn line_count(&self) -> usize {
+        self.starts.len() - 1
+    }
+
+    /// Event indexes on 0-based `lines`, clamped to the indexed lines.
+    fn indexes(&self, lines: Range<usize>) -> RArray {
+        let end = lines.end.min(self.line_count());
+        let start = lines.start.min(end);
+        let events = &self.events[self.starts[start]..self.starts[end]];
+        let array = RArray::with_capacity(events.len());
+        for &event in events {
+            let _ = array.push(event);
+        }
+        array
+    }
+
+    /// `on_line(n)`: indexes of the events starting on 1-based line `n`.
+    fn on_line(&self, line: usize) -> RArray {
+        match line.checked_sub(1) {
+            Some(line) => self.indexes(line..line + 1),
+            None => RArray::new(),
+        }
+    }
+
+    /// `in_lines(range)`: indexes of the events starting on any 1-based line
+    /// in `range`, in line order. Beginless and endless ranges are open.
+    fn in_lines(&self, range: magnus::Range) -> Result<RArray, Error> {
+        let first: Option<usize> = range.beg()?;
+        let last: Option<usize> = range.end()?;
+        let start = first.unwrap_or(1).saturating_sub(1);
+        let end = match last {
+            Some(last) if range.excl() => last.saturating_sub(1),
+            Some(last) => last,
+            None => usize::MAX,
+        };
+        Ok(self.indexes(start..end.max(start)))
+    }
+}
+
+/// Define `UdonNative::EventLines`.
+pub fn define(ruby: &Ruby, module: RModule) -> Result<(), Error> {
+    let class = module.define_class("EventLines", ruby.class_object())?;
+    class.define_singleton_method("new", function!(EventLines::new, 2))?;
+    class.define_method("on_line", method!(EventLines::on_line, 1))?;
+    class.define_method("in_lines", method!(EventLines::in_lines, 1))?;
+    class.define_method("line_count", method!(EventLines::line_count, 0))?;
+    Ok(())
+}
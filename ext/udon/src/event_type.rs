@@ -0,0 +1,111 @@
+//! Integer tags for event types (`type_tag: true`), and the
+//! `UdonNative::EventType` constants naming them.
+//!
+//! An event's tag is the position of its `:type` among libudon's `Event`
+//! variants, in declaration order, so `EventType::ELEMENT_START` is 0 and
+//! `EventType::ERROR` is 29. The tag follows the `:type` an event ends up
+//! with: an interpolation an `interpolation_resolver` turns into an
+//! `:integer` gets the `:integer` tag. Types libudon has no variant for
+//! (`:directive`, `:custom_value`, and the events this library adds, such
+//! as `:embedded_value`) get no tag.
+
+use magnus::{prelude::*, Error, RHash, RModule, Symbol};
+use udon_core::Event;
+
+/// Each event type, at its tag.
+pub const NAMES: [&str; 30] = [
+    "element_start",
+    "element_end",
+    "embedded_start",
+    "embedded_end",
+    "directive_start",
+    "directive_end",
+    "array_start",
+    "array_end",
+    "freeform_start",
+    "freeform_end",
+    "comment_start",
+    "comment_end",
+    "name",
+    "text",
+    "attr",
+    "string_value",
+    "bare_value",
+    "bool_true",
+    "bool_false",
+    "nil",
+    "integer",
+    "float",
+    "rational",
+    "complex",
+    "interpolation",
+    "reference",
+    "raw_content",
+    "raw",
+    "warning",
+    "error",
+];
+
+/// The tag of `event`'s variant.
+pub fn tag(event: &Event) -> u8 {
+    match event {
+        Event::ElementStart { .. } => 0,
+        Event::ElementEnd { .. } => 1,
+        Event::EmbeddedStart { .. } => 2,
+        Event::EmbeddedEnd { .. } => 3,
+        Event::DirectiveStart { .. } => 4,
+        Event::DirectiveEnd { .. } => 5,
+        Event::ArrayStart { .. } => 6,
+        Event::ArrayEnd { .. } => 7,
+        Event::FreeformStart { .. } => 8,
+        Event::FreeformEnd { .. } => 9,
+        Event::CommentStart { .. } => 10,
+        Event::CommentEnd { .. } => 11,
+        Event::Name { .. } => 12,
+        Event::Text { .. } => 13,
+        Event::Attr { .. } => 14,
+        Event::StringValue { .. } => 15,
+        Event::BareValue { .. } => 16,
+        Event::BoolTrue { .. } => 17,
+        Event::BoolFalse { .. } => 18,
+        Event::Nil { .. } => 19,
+        Event::Integer { .. } => 20,
+        Event::Float { .. } => 21,
+        Event::Rational { .. } => 22,
+        Event::Complex { .. } => 23,
+        Event::Interpolation { .. } => 24,
+        Event::Reference { .. } => 25,
+        Event::RawContent { .. } => 26,
+        Event::Raw { .. } => 27,
+        Event::Warning { .. } => 28,
+        Event::Error { .. } => 29,
+    }
+}
+
+/// The tag of the event type called `name`, if libudon has one.
+pub fn tag_of(name: &str) -> Option<u8> {
+    NAMES
+        .iter()
+        .position(|&candidate| candidate == name)
+        .map(|tag| tag as u8)
+}
+
+/// Set `hash`'s `:tag` from its `:type`.
+pub fn attach(hash: RHash) {
+    let Ok(Some(kind)) = hash.lookup::<_, Option<Symbol>>(Symbol::new("type")) else {
+        return;
+    };
+    if let Some(tag) = kind.name().ok().and_then(|name| tag_of(&name)) {
+        let _ = hash.aset(Symbol::new("tag"), tag);
+    }
+}
+
+/// Define `UdonNative::EventType`, one constant per tag.
+pub fn define(module: RModule) -> Result<(), Error> {
+    let event_type = module.define_module("EventType")?;
+    for (tag, name) in NAMES.iter().enumerate() {
+        event_type.const_set(&*name.to_ascii_uppercase(), tag)?;
+    }
+    event_type.freeze();
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! Length-prefixed framed streams of documents.
+//!
+//! Each frame is a 4-byte big-endian length followed by that many bytes of
+//! UDON. Frames are read from any IO responding to `#read(n)`.
+
+use magnus::{prelude::*, scan_args::scan_args, Error, RHash, RString, Ruby, Symbol, Value};
+
+use crate::{coerce, errors, freeze, options::ParseOptions, parse_bytes};
+
+/// Most bytes asked of the IO at once. A length prefix is untrusted, so the
+/// body buffer only grows as bytes arrive.
+const CHUNK: usize = 64 * 1024;
+
+/// Read exactly `len` bytes, or fewer if the IO hits EOF.
+fn read_exact(io: Value, len: usize) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::with_capacity(len.min(CHUNK));
+    while buf.len() < len {
+        let want = (len - buf.len()).min(CHUNK);
+        let chunk: Option<RString> = io.funcall("read", (want,))?;
+        match chunk {
+            Some(chunk) if chunk.len() > 0 => buf.extend_from_slice(unsafe { chunk.as_slice() }),
+            _ => break,
+        }
+    }
+    Ok(buf)
+}
+
+/// `UdonNative.parse_framed(io, **options) { |events| ... }`
+///
+/// Yields the event array of each frame in turn until a clean EOF and returns
+/// the number of frames read. With `with_index: true` the frame's index and
+/// a nil total are yielded too. A truncated length prefix or body, or with
+/// `max_frame_bytes: n` a length over `n`, raises `UdonNative::FrameError`.
+pub fn parse_framed(ruby: &Ruby, args: &[Value]) -> Result<usize, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (io,) = args.required;
+    let io = coerce::reader(ruby, io)?;
+    let keywords = args.keywords;
+    let max = keywords.delete::<_, Option<usize>>(Symbol::new("max_frame_bytes"))?;
+    let options = ParseOptions::from_hash(ruby, keywords)?;
+    if !ruby.block_given() {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "parse_framed requires a block",
+        ));
+    }
+
+    let mut frames = 0;
+    loop {
+        let prefix = read_exact(io, 4)?;
+        if prefix.is_empty() {
+            return Ok(frames);
+        }
+        if prefix.len() < 4 {
+            return Err(errors::frame_error(
+                ruby,
+                format!(
+                    "frame {}: incomplete length prefix ({} of 4 bytes) at EOF",
+                    frames,
+                    prefix.len()
+                ),
+            ));
+        }
+
+        let len = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+        if let Some(max) = max.filter(|&max| len > max) {
+            return Err(errors::frame_error(
+                ruby,
+                format!(
+                    "frame {}: length {} exceeds max_frame_bytes ({})",
+                    frames, len, max
+                ),
+            ));
+        }
+        let body = read_exact(io, len)?;
+        if body.len() < len {
+            return Err(errors::frame_error(
+                ruby,
+                format!(
+                    "frame {}: incomplete body ({} of {} bytes) at EOF",
+                    frames,
+                    body.len(),
+                    len
+                ),
+            ));
+        }
+
+        let events = freeze::finish(parse_bytes(ruby, &body, &options)?, &options)?;
+        let _: Value = if options.with_index {
+            // The number of frames is only known at EOF.
+            ruby.yield_values((events, frames, ruby.qnil()))?
+        } else {
+            ruby.yield_value(events)?
+        };
+        frames += 1;
+    }
+}
@@ -0,0 +1,39 @@
+//! Deep freezing of parse results (`immutable: true`).
+//!
+//! Results are built mutable and frozen afterwards in one walk: Arrays and
+//! Hashes have their elements, keys and values frozen before themselves,
+//! and every other object (Strings, `Udon::Span`s) is frozen as it is.
+//! Interned keys are frozen already; Symbols, Integers and the like always
+//! are.
+
+use magnus::{prelude::*, r_hash::ForEach, Error, RArray, RHash, Value};
+
+use crate::options::ParseOptions;
+
+/// Freeze `value` and everything it holds.
+pub fn deep_freeze(value: Value) -> Result<(), Error> {
+    if value.is_frozen() {
+        return Ok(());
+    }
+    if let Some(array) = RArray::from_value(value) {
+        for item in array.each() {
+            deep_freeze(item?)?;
+        }
+    } else if let Some(hash) = RHash::from_value(value) {
+        hash.foreach(|key: Value, item: Value| {
+            deep_freeze(key)?;
+            deep_freeze(item)?;
+            Ok(ForEach::Continue)
+        })?;
+    }
+    value.freeze();
+    Ok(())
+}
+
+/// `result`, deeply frozen if `options` ask for `immutable: true`.
+pub fn finish<T: ReprValue>(result: T, options: &ParseOptions) -> Result<T, Error> {
+    if options.immutable {
+        deep_freeze(result.as_value())?;
+    }
+    Ok(result)
+}
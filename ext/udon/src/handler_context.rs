@@ -0,0 +1,283 @@
+//! `UdonNative::HandlerContext`: what a directive handler, embedded
+//! handler or `interpolation_resolver` can see of the parse it runs in.
+//!
+//! A context is passed as the last argument to each callback, after the
+//! ones it always gets: `(args, span, ctx, context)` for directives,
+//! `(content, span, context)` for embedded elements and
+//! `(expression, context)` for the resolver. Blocks take it or leave it;
+//! a lambda or method that takes no extra argument is called without it.
+//! It answers `#path` (the names of the enclosing elements, outermost
+//! first, nil for an element without one), `#span`, `#source_slice` (the
+//! input the span covers), `#depth`, `#user_context` (the `user_context:`
+//! option) and `#emit_warning(code, message)`, whose warnings follow the
+//! callback's output as `{type: :warning, code:, content:, span:}` events.
+//!
+//! The path is the `Path` the converter keeps while callbacks may run (and
+//! for `parent_name: true`): element names are one frozen String per name
+//! for the parse (see `Names`), so a context only copies the stack's
+//! references. A context is only good while its callback runs; using it
+//! after the callback returns raises `UdonNative::Error`.
+
+use std::{cell::RefCell, collections::HashMap, ops::Range};
+
+use magnus::{
+    block::Proc, gc::Marker, method, prelude::*, typed_data::Obj, DataTypeFunctions, Error, RArray,
+    RHash, RModule, RString, Ruby, Symbol, Value,
+};
+use udon_core::Event;
+
+use crate::{errors, Converter};
+
+/// One frozen String per distinct element name, for one parse.
+///
+/// The Strings are also kept in an Array, so the GC sees them while only
+/// Rust refers to them; a `Names` must itself live on the stack (as the
+/// converter does), where the GC finds the Array.
+pub struct Names {
+    strings: RArray,
+    index: HashMap<Box<[u8]>, RString>,
+}
+
+impl Default for Names {
+    fn default() -> Self {
+        Names {
+            strings: RArray::new(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl Names {
+    /// The String for `name`.
+    pub fn get(&mut self, name: &[u8]) -> RString {
+        if let Some(&string) = self.index.get(name) {
+            return string;
+        }
+        let string = RString::from_slice(name);
+        string.freeze();
+        let _ = self.strings.push(string);
+        self.index.insert(name.into(), string);
+        string
+    }
+}
+
+/// The names of the open elements.
+#[derive(Default)]
+pub struct Path {
+    names: Vec<Option<RString>>,
+    strings: Names,
+    /// The last event opened an element, whose name is next.
+    awaiting_name: bool,
+}
+
+impl Path {
+    /// The name of the element `event` is in, before following it: the
+    /// enclosing one for an element's own start, name and end events.
+    pub fn parent(&self, event: &Event) -> Option<RString> {
+        let open = match event {
+            Event::Name { .. } if self.awaiting_name => self.names.len().saturating_sub(1),
+            Event::ElementEnd { .. } => self.names.len().saturating_sub(1),
+            _ => self.names.len(),
+        };
+        self.names[..open].last().copied().flatten()
+    }
+
+    /// Follow `event`.
+    pub fn event(&mut self, event: &Event) {
+        let awaiting_name = std::mem::take(&mut self.awaiting_name);
+        match event {
+            Event::ElementStart { .. } => {
+                self.names.push(None);
+                self.awaiting_name = true;
+            }
+            Event::Name { content, .. } if awaiting_name => {
+                if let Some(name) = self.names.last_mut() {
+                    *name = Some(self.strings.get(content));
+                }
+            }
+            Event::ElementEnd { .. } => {
+                self.names.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// What a context answers while its callback runs.
+pub struct State {
+    pub path: Vec<Option<RString>>,
+    pub span: Value,
+    pub source: RString,
+    pub user_context: Option<Value>,
+}
+
+impl State {
+    /// The state for a callback over `span` in `converter`'s parse.
+    pub fn new(converter: &Converter, span: &Range<usize>) -> Self {
+        let original = converter.spans.original(span);
+        let source = RString::from_slice(&converter.spans.source[original]);
+        source.freeze();
+        State {
+            path: converter
+                .path
+                .as_ref()
+                .map_or_else(Vec::new, |path| path.names.clone()),
+            span: converter.spans.convert(span),
+            source,
+            user_context: converter.options.user_context,
+        }
+    }
+}
+
+#[magnus::wrap(class = "UdonNative::HandlerContext", free_immediately, size, mark)]
+pub struct HandlerContext {
+    /// `None` once the callback has returned.
+    state: RefCell<Option<State>>,
+    /// `[code, message]` of each `emit_warning`.
+    warnings: RefCell<Vec<(Symbol, RString)>>,
+}
+
+impl DataTypeFunctions for HandlerContext {
+    fn mark(&self, marker: &Marker) {
+        if let Ok(state) = self.state.try_borrow() {
+            if let Some(state) = state.as_ref() {
+                for name in state.path.iter().flatten() {
+                    marker.mark(*name);
+                }
+                marker.mark(state.span);
+                marker.mark(state.source);
+                if let Some(value) = state.user_context {
+                    marker.mark(value);
+                }
+            }
+        }
+        if let Ok(warnings) = self.warnings.try_borrow() {
+            for (code, message) in warnings.iter() {
+                marker.mark(*code);
+                marker.mark(*message);
+            }
+        }
+    }
+}
+
+impl HandlerContext {
+    /// Call `call` with a context holding `state`, then retire the
+    /// context; what `call` returned and the warnings emitted through it.
+    pub fn scope<T>(state: State, call: impl FnOnce(Value) -> T) -> (T, Vec<(Symbol, RString)>) {
+        let context = Obj::wrap(HandlerContext {
+            state: RefCell::new(Some(state)),
+            warnings: RefCell::new(Vec::new()),
+        });
+        let outcome = call(context.as_value());
+        context.state.borrow_mut().take();
+        let warnings = context.warnings.take();
+        (outcome, warnings)
+    }
+
+    fn with<T>(&self, ruby: &Ruby, read: impl FnOnce(&State) -> T) -> Result<T, Error> {
+        match self.state.borrow().as_ref() {
+            Some(state) => Ok(read(state)),
+            None => Err(errors::error(
+                ruby,
+                "handler context used after its callback returned".to_string(),
+            )),
+        }
+    }
+
+    /// The names of the enclosing elements, outermost first.
+    pub fn path(ruby: &Ruby, rb_self: &Self) -> Result<RArray, Error> {
+        rb_self.with(ruby, |state| {
+            let path = RArray::with_capacity(state.path.len());
+            for name in &state.path {
+                let _ = path.push(*name);
+            }
+            path
+        })
+    }
+
+    pub fn span(ruby: &Ruby, rb_self: &Self) -> Result<Value, Error> {
+        rb_self.with(ruby, |state| state.span)
+    }
+
+    /// The input the span covers, frozen.
+    pub fn source_slice(ruby: &Ruby, rb_self: &Self) -> Result<RString, Error> {
+        rb_self.with(ruby, |state| state.source)
+    }
+
+    /// How many elements enclose the callback's span.
+    pub fn depth(ruby: &Ruby, rb_self: &Self) -> Result<usize, Error> {
+        rb_self.with(ruby, |state| state.path.len())
+    }
+
+    /// The `user_context:` option, or nil.
+    pub fn user_context(ruby: &Ruby, rb_self: &Self) -> Result<Option<Value>, Error> {
+        rb_self.with(ruby, |state| state.user_context)
+    }
+
+    /// `emit_warning(code, message)`
+    pub fn emit_warning(
+        ruby: &Ruby,
+        rb_self: &Self,
+        code: Value,
+        message: Value,
+    ) -> Result<(), Error> {
+        rb_self.with(ruby, |_| ())?;
+        let code: Symbol = code.funcall("to_sym", ())?;
+        let message: RString = message.funcall("to_s", ())?;
+        rb_self.warnings.borrow_mut().push((code, message));
+        Ok(())
+    }
+}
+
+/// The `:warning` events for `warnings` emitted by a callback over `span`.
+pub fn warning_events(
+    converter: &Converter,
+    warnings: Vec<(Symbol, RString)>,
+    span: &Range<usize>,
+) -> Vec<RHash> {
+    warnings
+        .into_iter()
+        .map(|(code, message)| {
+            let hash = RHash::new();
+            let _ = hash.aset(Symbol::new("type"), Symbol::new("warning"));
+            let _ = hash.aset(Symbol::new("code"), code);
+            let _ = hash.aset(Symbol::new("content"), message);
+            let _ = hash.aset(Symbol::new("span"), converter.spans.convert(span));
+            hash
+        })
+        .collect()
+}
+
+/// Whether `callable` can be called with `count` arguments, so a context
+/// can follow the ones it always gets. A proc that is not a lambda takes
+/// any number.
+pub fn accepts(callable: Value, count: usize) -> bool {
+    if let Some(proc) = Proc::from_value(callable) {
+        if !proc.is_lambda() {
+            return true;
+        }
+    }
+    let arity: Result<i64, Error> = match Proc::from_value(callable) {
+        Some(_) => callable.funcall("arity", ()),
+        None => callable
+            .funcall::<_, _, Value>("method", (Symbol::new("call"),))
+            .and_then(|method| method.funcall("arity", ())),
+    };
+    match arity {
+        Ok(arity) if arity >= 0 => arity as usize >= count,
+        Ok(arity) => (-arity - 1) as usize <= count,
+        Err(_) => false,
+    }
+}
+
+/// Define `UdonNative::HandlerContext`.
+pub fn define(ruby: &Ruby, module: RModule) -> Result<(), Error> {
+    let class = module.define_class("HandlerContext", ruby.class_object())?;
+    class.define_method("path", method!(HandlerContext::path, 0))?;
+    class.define_method("span", method!(HandlerContext::span, 0))?;
+    class.define_method("source_slice", method!(HandlerContext::source_slice, 0))?;
+    class.define_method("depth", method!(HandlerContext::depth, 0))?;
+    class.define_method("user_context", method!(HandlerContext::user_context, 0))?;
+    class.define_method("emit_warning", method!(HandlerContext::emit_warning, 2))?;
+    Ok(())
+}
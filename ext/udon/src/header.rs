@@ -0,0 +1,112 @@
+//! Header/body split of elements for `header_spans: true`.
+//!
+//! An element's header is its `|name` plus the attributes and values that
+//! follow directly; its body starts at the first child, text or other
+//! content event and runs to the end of the element. Comments are neither:
+//! a trailing comment on the header line does not start the body.
+
+use std::ops::Range;
+
+use udon_core::Event;
+
+use crate::event_parts;
+
+struct Frame {
+    start: usize,
+    header_end: usize,
+    body_start: Option<usize>,
+    comments: usize,
+    /// An attribute is waiting for its value, or an array value is open.
+    in_value: bool,
+    arrays: usize,
+}
+
+/// Header and body spans of a closed element.
+pub struct Split {
+    pub header: Range<usize>,
+    pub body: Range<usize>,
+}
+
+#[derive(Default)]
+pub struct Headers {
+    stack: Vec<Frame>,
+}
+
+impl Headers {
+    /// Feed one event; returns the split when it closes an element.
+    pub fn observe(&mut self, event: &Event) -> Option<Split> {
+        if let Event::ElementStart { span } = event {
+            if let Some(parent) = self.stack.last_mut() {
+                parent.content(span.start);
+            }
+            self.stack.push(Frame {
+                start: span.start,
+                header_end: span.end,
+                body_start: None,
+                comments: 0,
+                in_value: false,
+                arrays: 0,
+            });
+            return None;
+        }
+        if let Event::ElementEnd { span } = event {
+            let frame = self.stack.pop()?;
+            let body = match frame.body_start {
+                Some(start) => start..span.end.max(start),
+                None => frame.header_end..frame.header_end,
+            };
+            return Some(Split {
+                header: frame.start..frame.header_end,
+                body,
+            });
+        }
+
+        let frame = self.stack.last_mut()?;
+        let (span, _) = event_parts(event);
+        match event {
+            Event::CommentStart { .. } => frame.comments += 1,
+            Event::CommentEnd { .. } => frame.comments = frame.comments.saturating_sub(1),
+            _ if frame.comments > 0 || frame.body_start.is_some() => {}
+            Event::Error { .. } | Event::Warning { .. } => {}
+            Event::Name { .. } | Event::Attr { .. } => {
+                frame.header_end = span.end;
+                frame.in_value = matches!(event, Event::Attr { .. });
+            }
+            Event::ArrayStart { .. } => {
+                frame.header_end = span.end;
+                frame.arrays += 1;
+            }
+            Event::ArrayEnd { .. } => {
+                frame.header_end = span.end;
+                frame.arrays = frame.arrays.saturating_sub(1);
+                frame.in_value = frame.arrays > 0;
+            }
+            Event::StringValue { .. }
+            | Event::BareValue { .. }
+            | Event::BoolTrue { .. }
+            | Event::BoolFalse { .. }
+            | Event::Nil { .. }
+            | Event::Integer { .. }
+            | Event::Float { .. }
+            | Event::Rational { .. }
+            | Event::Complex { .. } => {
+                frame.header_end = span.end;
+                frame.in_value = frame.arrays > 0;
+            }
+            Event::Interpolation { .. } if frame.in_value => {
+                frame.header_end = span.end;
+                frame.in_value = frame.arrays > 0;
+            }
+            _ => frame.content(span.start),
+        }
+        None
+    }
+}
+
+impl Frame {
+    fn content(&mut self, start: usize) {
+        if self.comments == 0 && self.body_start.is_none() {
+            self.body_start = Some(start);
+        }
+    }
+}
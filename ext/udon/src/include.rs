@@ -0,0 +1,370 @@
+//! `UdonNative.parse_tree`: a document tree with `!include` directives
+//! resolved.
+//!
+//! `!include "path"` is replaced by the top-level nodes of the file it
+//! names, which may include further files in turn. A path is relative to
+//! the directory of the file it appears in (the include base for a parsed
+//! String) and has to stay inside the include base unless
+//! `allow_absolute: true`. Files are read from the filesystem, or from a
+//! `loader:` callable given the resolved path.
+//!
+//! Spliced nodes keep their spans, which index their own file, and record
+//! which file that is (`NodeData::file`); the included files' parse errors
+//! are recorded the same way. An include that cannot be resolved raises
+//! `UdonNative::IncludeError`.
+
+use std::{
+    env, fs,
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+
+use magnus::{
+    prelude::*, scan_args::get_kwargs, scan_args::scan_args, Error, RClass, RHash, RString, Ruby,
+    Value,
+};
+
+use crate::{
+    coerce, definitions,
+    document::Document,
+    errors,
+    normalize::check_encoding,
+    tree::{self, NodeData, NodeKind, SourceFile, Tree, TreeError, ROOT},
+};
+
+const DEFAULT_MAX_DEPTH: usize = 16;
+
+/// `path` with `.` and `..` resolved without touching the filesystem; a
+/// `..` with nothing left to remove is kept.
+fn lexical(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match out.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    out.pop();
+                }
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                _ => out.push(".."),
+            },
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+fn is_include(node: &NodeData) -> bool {
+    node.kind == NodeKind::Directive && node.name.as_deref() == Some(b"include")
+}
+
+struct Includes<'a> {
+    ruby: &'a Ruby,
+    base: PathBuf,
+    loader: Option<Value>,
+    max_depth: usize,
+    allow_absolute: bool,
+    out: Tree,
+    /// Files being included, outermost first, for finding cycles; starts
+    /// with the parsed file, if it was one.
+    chain: Vec<PathBuf>,
+    /// Includes open around the current one.
+    depth: usize,
+}
+
+impl Includes<'_> {
+    fn error(&self, message: String) -> Error {
+        errors::include_error(self.ruby, message)
+    }
+
+    /// `path` as reported: relative to the base when inside it.
+    fn display(&self, path: &Path) -> String {
+        path.strip_prefix(&self.base)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Where `span` of a node from `file` starts, for messages.
+    fn location(&self, file: Option<usize>, span: &Range<usize>) -> String {
+        match self.out.file_path(file) {
+            Some(path) => format!("{} byte {}", path, span.start),
+            None => format!("byte {}", span.start),
+        }
+    }
+
+    /// The path `target` names from a file in `dir`, kept inside the base.
+    fn resolve(&self, dir: &Path, target: &str) -> Result<PathBuf, Error> {
+        if self.allow_absolute {
+            return Ok(lexical(&dir.join(target)));
+        }
+        if Path::new(target).is_absolute() {
+            return Err(self.error(format!(
+                "include {:?} is an absolute path; pass allow_absolute: true to allow it",
+                target
+            )));
+        }
+        let path = lexical(&dir.join(target));
+        let escapes = path
+            .components()
+            .any(|component| component == Component::ParentDir);
+        if escapes || !path.starts_with(&self.base) {
+            return Err(self.error(format!(
+                "include {:?} is outside the include base {:?}",
+                target,
+                self.base.to_string_lossy()
+            )));
+        }
+        Ok(path)
+    }
+
+    /// The contents of the file at `path`.
+    fn load(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        let name = self.display(path);
+        if let Some(loader) = self.loader {
+            let content: Value = loader.funcall("call", (path.to_string_lossy().into_owned(),))?;
+            if content.is_nil() {
+                return Err(self.error(format!("include {} not found by the loader", name)));
+            }
+            let content = coerce::string(self.ruby, content)?;
+            return Ok(unsafe { content.as_slice() }.to_vec());
+        }
+        let unreadable =
+            |err: std::io::Error| self.error(format!("cannot read include {}: {}", name, err));
+        if !self.allow_absolute {
+            // A symlink inside the base can still point out of it.
+            let real = fs::canonicalize(path).map_err(unreadable)?;
+            let base = fs::canonicalize(&self.base).unwrap_or_else(|_| self.base.clone());
+            if !real.starts_with(base) {
+                return Err(self.error(format!(
+                    "include {} links outside the include base {:?}",
+                    name,
+                    self.base.to_string_lossy()
+                )));
+            }
+        }
+        fs::read(path).map_err(unreadable)
+    }
+
+    /// Copy the children of `tree`'s node `index`, read from `file` in
+    /// `dir`, under `parent`, splicing in what they include.
+    fn copy(
+        &mut self,
+        tree: &Tree,
+        file: Option<usize>,
+        index: usize,
+        parent: usize,
+        dir: &Path,
+    ) -> Result<(), Error> {
+        for &child in &tree.node(index).children {
+            let node = tree.node(child);
+            if is_include(node) {
+                self.include(node, file, dir, parent)?;
+                continue;
+            }
+            let mut copy = node.clone();
+            copy.parent = Some(parent);
+            copy.children = Vec::new();
+            copy.file = file;
+            let at = self.out.nodes.len();
+            self.out.nodes.push(copy);
+            self.out.nodes[parent].children.push(at);
+            self.copy(tree, file, child, at, dir)?;
+        }
+        Ok(())
+    }
+
+    /// Splice the file named by the include directive `node` under `parent`.
+    fn include(
+        &mut self,
+        node: &NodeData,
+        from: Option<usize>,
+        dir: &Path,
+        parent: usize,
+    ) -> Result<(), Error> {
+        let target = match node.values.first() {
+            Some(tree::Value::Scalar { content, .. }) if !content.is_empty() => {
+                String::from_utf8_lossy(content).into_owned()
+            }
+            _ => {
+                return Err(self.error(format!(
+                    "!include at {} needs a path",
+                    self.location(from, &node.span)
+                )))
+            }
+        };
+        let path = self.resolve(dir, &target)?;
+        if let Some(at) = self.chain.iter().position(|open| *open == path) {
+            let cycle: Vec<String> = self.chain[at..]
+                .iter()
+                .chain([&path])
+                .map(|path| self.display(path))
+                .collect();
+            return Err(self.error(format!("include cycle: {}", cycle.join(" -> "))));
+        }
+        if self.depth >= self.max_depth {
+            return Err(self.error(format!(
+                "includes nested more than {} deep at {}",
+                self.max_depth,
+                self.location(from, &node.span)
+            )));
+        }
+
+        let source = self.load(&path)?;
+        check_encoding(self.ruby, &source)?;
+        let included = Tree::parse(&source);
+        let file = self.out.files.len();
+        self.out
+            .errors
+            .extend(included.errors.iter().map(|error| TreeError {
+                file: Some(file),
+                ..error.clone()
+            }));
+        let included_at = match from {
+            Some(from) => self.out.files[from].included_at.clone(),
+            None => node.span.clone(),
+        };
+        self.out.files.push(SourceFile {
+            path: self.display(&path),
+            source,
+            included_at,
+        });
+        let dir = path
+            .parent()
+            .map_or_else(|| self.base.clone(), Path::to_path_buf);
+        self.chain.push(path);
+        self.depth += 1;
+        self.copy(&included, Some(file), ROOT, parent, &dir)?;
+        self.depth -= 1;
+        self.chain.pop();
+        Ok(())
+    }
+}
+
+/// `UdonNative.parse_tree(source, resolve_includes: false, include_base: nil,
+/// loader: nil, max_include_depth: 16, allow_absolute: false,
+/// expand_definitions: false, max_expansion_depth: 16, as_objects: false)`
+///
+/// `source` is UDON text, or a file to read if it responds to `#to_path`
+/// (a Pathname). Without `resolve_includes: true` this is `parse_document`
+/// with the file read for you. The include base defaults to the file's
+/// directory, or the current directory for text; with a `loader:` and no
+/// base, paths are left relative. Definitions (see `definitions`) are
+/// expanded after includes are resolved, so included files can define and
+/// use them too. With `as_objects: true` the nodes answer their attribute
+/// keys as methods (see `Node#method_missing`).
+pub fn parse_tree(ruby: &Ruby, args: &[Value]) -> Result<Document, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (source,) = args.required;
+    let kwargs = get_kwargs::<
+        _,
+        (),
+        (
+            Option<bool>,
+            Option<Value>,
+            Option<Value>,
+            Option<usize>,
+            Option<bool>,
+            Option<bool>,
+            Option<usize>,
+            Option<bool>,
+        ),
+        (),
+    >(
+        args.keywords,
+        &[],
+        &[
+            "resolve_includes",
+            "include_base",
+            "loader",
+            "max_include_depth",
+            "allow_absolute",
+            "expand_definitions",
+            "max_expansion_depth",
+            "as_objects",
+        ],
+    )?;
+    let (
+        resolve,
+        include_base,
+        loader,
+        max_depth,
+        allow_absolute,
+        expand_definitions,
+        max_expansion_depth,
+        as_objects,
+    ) = kwargs.optional;
+    let finish = |mut tree: Tree| {
+        tree.objects = as_objects.unwrap_or(false);
+        if !expand_definitions.unwrap_or(false) {
+            return Document::new(tree);
+        }
+        let max_depth = max_expansion_depth.unwrap_or(definitions::DEFAULT_MAX_DEPTH);
+        Document::new(definitions::expand(&tree, max_depth))
+    };
+
+    let (input, path) =
+        if RString::from_value(source).is_none() && source.respond_to("to_path", false)? {
+            let path = coerce::path(ruby, source)?.to_string()?;
+            let file: RClass = ruby.class_object().const_get("File")?;
+            let input: RString = file.funcall("binread", (path.as_str(),))?;
+            (input, Some(path))
+        } else {
+            (coerce::string(ruby, source)?, None)
+        };
+    let input = unsafe { input.as_slice() };
+    check_encoding(ruby, input)?;
+    let mut tree = Tree::parse(input);
+    tree.path = path.clone();
+    if !resolve.unwrap_or(false) {
+        return Ok(finish(tree));
+    }
+
+    let loader = loader.filter(|loader| !loader.is_nil());
+    if let Some(loader) = loader {
+        if !loader.respond_to("call", false)? {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "loader must respond to #call",
+            ));
+        }
+    }
+    let include_base = match include_base.filter(|base| !base.is_nil()) {
+        Some(base) => Some(PathBuf::from(coerce::path(ruby, base)?.to_string()?)),
+        None => None,
+    };
+    let absolute = |path: PathBuf| -> Result<PathBuf, Error> {
+        if loader.is_some() || path.is_absolute() {
+            return Ok(lexical(&path));
+        }
+        let cwd = env::current_dir().map_err(|err| {
+            errors::include_error(ruby, format!("cannot resolve include base: {}", err))
+        })?;
+        Ok(lexical(&cwd.join(path)))
+    };
+    let main = path.map(|path| absolute(PathBuf::from(path))).transpose()?;
+    let base = match (include_base, &main) {
+        (Some(base), _) => absolute(base)?,
+        (None, Some(main)) => main.parent().map(Path::to_path_buf).unwrap_or_default(),
+        (None, None) => absolute(PathBuf::new())?,
+    };
+    let dir = main
+        .as_ref()
+        .and_then(|main| main.parent())
+        .map_or_else(|| base.clone(), Path::to_path_buf);
+
+    let mut includes = Includes {
+        ruby,
+        base,
+        loader,
+        max_depth: max_depth.unwrap_or(DEFAULT_MAX_DEPTH),
+        allow_absolute: allow_absolute.unwrap_or(false),
+        out: tree.clone(),
+        chain: main.into_iter().collect(),
+        depth: 0,
+    };
+    includes.out.nodes.truncate(1);
+    includes.out.nodes[ROOT].children.clear();
+    includes.copy(&tree, None, ROOT, ROOT, &dir)?;
+    Ok(finish(includes.out))
+}
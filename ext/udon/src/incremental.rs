@@ -0,0 +1,138 @@
+//! `UdonNative::Parser`: an incremental front end that accepts input in
+//! chunks.
+//!
+//! udon-core parses a complete buffer, so chunks are accumulated and parsed
+//! on `finish`. Spans are therefore offsets into the whole stream, never into
+//! the chunk that happened to carry the bytes, and an event whose source
+//! straddles a chunk boundary gets the same span as in a single-feed parse.
+//! Likewise a UTF-8 character split between two chunks is whole again
+//! before the parser sees it: no chunk boundary ever reaches udon-core.
+
+use std::cell::{Cell, RefCell};
+
+use magnus::{
+    function, method, prelude::*, scan_args::scan_args, typed_data::Obj, Error, RHash, RModule,
+    Ruby, Symbol, Value,
+};
+
+use crate::{
+    coerce,
+    digest::{self, Algorithm},
+    errors, freeze,
+    normalize::normalize,
+    options::ParseOptions,
+    parse_bytes,
+};
+
+#[magnus::wrap(class = "UdonNative::Parser", free_immediately, size)]
+pub struct IncrementalParser {
+    options: ParseOptions,
+    buffer: RefCell<Vec<u8>>,
+    finished: Cell<bool>,
+    /// Most events held in memory at once, recorded by `finish`.
+    peak_buffered_events: Cell<usize>,
+}
+
+impl IncrementalParser {
+    /// `Parser.new(**options)`; accepts the same options as `UdonNative.parse`.
+    pub fn new(ruby: &Ruby, args: &[Value]) -> Result<Self, Error> {
+        let args = scan_args::<(), (), (), (), RHash, ()>(args)?;
+        Ok(IncrementalParser {
+            options: ParseOptions::from_hash(ruby, args.keywords)?,
+            buffer: RefCell::new(Vec::new()),
+            finished: Cell::new(false),
+            peak_buffered_events: Cell::new(0),
+        })
+    }
+
+    fn check_open(&self, ruby: &Ruby) -> Result<(), Error> {
+        if self.finished.get() {
+            return Err(errors::error(
+                ruby,
+                "parser is already finished".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// `feed(chunk)`; returns the parser so calls can be chained.
+    pub fn feed(ruby: &Ruby, rb_self: Obj<Self>, chunk: Value) -> Result<Obj<Self>, Error> {
+        rb_self.check_open(ruby)?;
+        let chunk = coerce::string(ruby, chunk)?;
+        rb_self
+            .buffer
+            .borrow_mut()
+            .extend_from_slice(unsafe { chunk.as_slice() });
+        Ok(rb_self)
+    }
+
+    /// Total bytes fed so far; the offset the next chunk starts at.
+    pub fn bytes_fed(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    /// `finish`: parse everything fed and return the events, or yield each
+    /// one (with its index and the total under `with_index:`) if a block is
+    /// given. The parser cannot be fed afterwards; the
+    /// input is kept for `source_digest`.
+    pub fn finish(ruby: &Ruby, rb_self: &Self) -> Result<Value, Error> {
+        rb_self.check_open(ruby)?;
+        rb_self.finished.set(true);
+        let events = parse_bytes(ruby, &rb_self.buffer.borrow(), &rb_self.options)?;
+        let events = freeze::finish(events, &rb_self.options)?;
+        rb_self.peak_buffered_events.set(events.len());
+        if !ruby.block_given() {
+            return Ok(events.as_value());
+        }
+        let total = events.len();
+        for (index, event) in events.each().enumerate() {
+            let _: Value = if rb_self.options.with_index {
+                ruby.yield_values((event?, index, total))?
+            } else {
+                ruby.yield_value(event?)?
+            };
+        }
+        Ok(ruby.qnil().as_value())
+    }
+
+    /// `peak_buffered_events`: the most events the parser held at once, or
+    /// nil before `finish`. `feed` only buffers bytes and `finish` builds
+    /// every event before yielding any, so after `finish` this is the event
+    /// count; size for it when tuning memory for streamed input.
+    pub fn peak_buffered_events(&self) -> Option<usize> {
+        self.finished.get().then(|| self.peak_buffered_events.get())
+    }
+
+    /// `source_digest(algorithm = :sha256)`: hex digest of the bytes the
+    /// parser saw, after `strip_bom:`/`normalize_newlines:`, computed without
+    /// copying them into a Ruby string. Available after `finish`.
+    pub fn source_digest(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<String, Error> {
+        let args = scan_args::<(), (Option<Symbol>,), (), (), (), ()>(args)?;
+        let algorithm = Algorithm::from_symbol(ruby, args.optional.0)?;
+        if !rb_self.finished.get() {
+            return Err(errors::error(ruby, "parser is not finished".to_string()));
+        }
+        let buffer = rb_self.buffer.borrow();
+        let (source, _) = normalize(&buffer, &rb_self.options);
+        Ok(digest::hash_hex(&source, algorithm))
+    }
+}
+
+/// Define `UdonNative::Parser`.
+pub fn define(ruby: &Ruby, module: RModule) -> Result<(), Error> {
+    let class = module.define_class("Parser", ruby.class_object())?;
+    class.define_singleton_method("new", function!(IncrementalParser::new, -1))?;
+    class.define_method("feed", method!(IncrementalParser::feed, 1))?;
+    class.define_method("<<", method!(IncrementalParser::feed, 1))?;
+    class.define_method("bytes_fed", method!(IncrementalParser::bytes_fed, 0))?;
+    class.define_method("finish", method!(IncrementalParser::finish, 0))?;
+    class.define_method(
+        "peak_buffered_events",
+        method!(IncrementalParser::peak_buffered_events, 0),
+    )?;
+    class.define_method(
+        "source_digest",
+        method!(IncrementalParser::source_digest, -1),
+    )?;
+    Ok(())
+}
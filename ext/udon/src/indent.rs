@@ -0,0 +1,245 @@
+//! Indentation style detection (`UdonNative.detect_indentation`) and the
+//! `indent:` option of `emit` and `transform`.
+//!
+//! Each line's leading whitespace is compared with the enclosing level's:
+//! a deeper line contributes its extra whitespace as a step. The most
+//! common step is the unit. Lines inside freeform blocks, raw directive
+//! content and embedded elements are skipped, as their indentation is
+//! content, and so are blank lines.
+
+use std::ops::Range;
+
+use magnus::{prelude::*, Error, Integer, RArray, RHash, RString, Ruby, Symbol, Value};
+use udon_core::{Event, Parser};
+
+use crate::{coerce, normalize::check_encoding, span_to_hash};
+
+/// Offending lines reported in full; the rest only clear `consistent`.
+const MAX_OFFENDERS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Problem {
+    /// Tabs and spaces in one line's indentation.
+    Mixed,
+    /// Tabs where the unit is spaces, or the other way round.
+    Style,
+    /// A step that is not the unit.
+    Step,
+}
+
+impl Problem {
+    fn name(self) -> &'static str {
+        match self {
+            Problem::Mixed => "mixed",
+            Problem::Style => "style",
+            Problem::Step => "step",
+        }
+    }
+}
+
+struct Line {
+    number: usize,
+    /// The line's leading whitespace.
+    indent: Range<usize>,
+    /// Whitespace added over the enclosing level, if the line is deeper.
+    step: Option<Range<usize>>,
+}
+
+pub struct Indentation {
+    /// The most common step; `None` without indented lines.
+    pub unit: Option<Vec<u8>>,
+    /// Each distinct step with how many lines took it, most common first.
+    pub samples: Vec<(Vec<u8>, usize)>,
+    pub consistent: bool,
+    /// The first `MAX_OFFENDERS` offending lines: number, indentation span
+    /// and problem.
+    offenders: Vec<(usize, Range<usize>, Problem)>,
+}
+
+/// Byte ranges whose lines are content rather than structure.
+fn verbatim_ranges(input: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    Parser::new(input).parse(|event| match event {
+        Event::FreeformStart { span } | Event::EmbeddedStart { span } => open.push(span.start),
+        Event::FreeformEnd { span } | Event::EmbeddedEnd { span } => {
+            if let Some(start) = open.pop() {
+                ranges.push(start..span.end);
+            }
+        }
+        Event::RawContent { span, .. } | Event::Raw { span, .. } => ranges.push(span),
+        _ => {}
+    });
+    ranges
+}
+
+/// Detect the indentation style of `input`.
+pub fn detect(input: &[u8]) -> Indentation {
+    let verbatim = verbatim_ranges(input);
+    let inside = |at: usize| {
+        verbatim
+            .iter()
+            .any(|range| range.start < at && at < range.end)
+    };
+
+    let mut lines = Vec::new();
+    // Indentation widths of the enclosing levels.
+    let mut levels: Vec<usize> = vec![0];
+    let mut start = 0;
+    let mut number = 0;
+    while start < input.len() {
+        let end = memchr::memchr(b'\n', &input[start..]).map_or(input.len(), |i| start + i);
+        let line = &input[start..end];
+        let width = line
+            .iter()
+            .take_while(|&&b| b == b' ' || b == b'\t')
+            .count();
+        let blank = line[width..].iter().all(|&b| b == b'\r');
+        if !blank && !inside(start) {
+            while levels.last().is_some_and(|&level| level > width) {
+                levels.pop();
+            }
+            let level = levels.last().copied().unwrap_or(0);
+            let step = (width > level).then(|| start + level..start + width);
+            if step.is_some() {
+                levels.push(width);
+            }
+            lines.push(Line {
+                number,
+                indent: start..start + width,
+                step,
+            });
+        }
+        start = end + 1;
+        number += 1;
+    }
+
+    let mut samples: Vec<(Vec<u8>, usize)> = Vec::new();
+    for step in lines.iter().filter_map(|line| line.step.clone()) {
+        let step = &input[step];
+        match samples.iter_mut().find(|(sample, _)| sample == step) {
+            Some((_, count)) => *count += 1,
+            None => samples.push((step.to_vec(), 1)),
+        }
+    }
+    // Stable, so ties go to the step seen first.
+    samples.sort_by(|a, b| b.1.cmp(&a.1));
+    let unit = samples.first().map(|(unit, _)| unit.clone());
+
+    let mut consistent = true;
+    let mut offenders = Vec::new();
+    if let Some(unit) = &unit {
+        let unit_tabs = unit.contains(&b'\t');
+        for line in &lines {
+            let indent = &input[line.indent.clone()];
+            let tabs = indent.contains(&b'\t');
+            let problem = if tabs && indent.contains(&b' ') {
+                Some(Problem::Mixed)
+            } else if !indent.is_empty() && tabs != unit_tabs {
+                Some(Problem::Style)
+            } else if line
+                .step
+                .as_ref()
+                .is_some_and(|step| input[step.clone()] != unit[..])
+            {
+                Some(Problem::Step)
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                consistent = false;
+                if offenders.len() < MAX_OFFENDERS {
+                    offenders.push((line.number, line.indent.clone(), problem));
+                }
+            }
+        }
+    }
+
+    Indentation {
+        unit,
+        samples,
+        consistent,
+        offenders,
+    }
+}
+
+/// `UdonNative.detect_indentation(input)`
+///
+/// `{unit:, consistent:, samples:, offenders:}`: the most common step (nil
+/// without indented lines), whether every line follows it, each step with
+/// its line count, and up to five offending lines as `{line:, span:,
+/// problem:}` with 0-based `line`, the span of the line's indentation, and
+/// `:mixed`, `:style` or `:step`.
+pub fn detect_indentation(ruby: &Ruby, input: Value) -> Result<RHash, Error> {
+    let input = coerce::string(ruby, input)?;
+    let input_bytes = unsafe { input.as_slice() };
+    check_encoding(ruby, input_bytes)?;
+    let detected = detect(input_bytes);
+
+    let samples = RHash::new();
+    for (step, count) in &detected.samples {
+        samples.aset(RString::from_slice(step), *count)?;
+    }
+    let offenders = RArray::new();
+    for (line, span, problem) in &detected.offenders {
+        let offender = RHash::new();
+        offender.aset(Symbol::new("line"), *line)?;
+        offender.aset(Symbol::new("span"), span_to_hash(span))?;
+        offender.aset(Symbol::new("problem"), Symbol::new(problem.name()))?;
+        offenders.push(offender)?;
+    }
+
+    let result = RHash::new();
+    result.aset(
+        Symbol::new("unit"),
+        detected.unit.as_deref().map(RString::from_slice),
+    )?;
+    result.aset(Symbol::new("consistent"), detected.consistent)?;
+    result.aset(Symbol::new("samples"), samples)?;
+    result.aset(Symbol::new("offenders"), offenders)?;
+    Ok(result)
+}
+
+/// The indentation unit an `indent:` option asks for: a String of spaces or
+/// tabs, a number of spaces, or `:detect` for the unit of `source` (two
+/// spaces if it has none). `None` for the default of two spaces.
+pub fn indent_option(
+    ruby: &Ruby,
+    value: Option<Value>,
+    source: Option<&[u8]>,
+) -> Result<Option<Vec<u8>>, Error> {
+    let Some(value) = value.filter(|value| !value.is_nil()) else {
+        return Ok(None);
+    };
+    let invalid = || {
+        Error::new(
+            ruby.exception_arg_error(),
+            "indent must be a String of spaces or tabs, a number of spaces or :detect",
+        )
+    };
+    if let Some(symbol) = Symbol::from_value(value) {
+        if symbol.name()? != "detect" {
+            return Err(invalid());
+        }
+        let Some(source) = source else {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "indent: :detect needs the source text; use transform",
+            ));
+        };
+        return Ok(Some(detect(source).unit.unwrap_or_else(|| b"  ".to_vec())));
+    }
+    if let Some(count) = Integer::from_value(value) {
+        let count = count.to_usize().map_err(|_| invalid())?;
+        return match count {
+            0 => Err(invalid()),
+            count => Ok(Some(vec![b' '; count])),
+        };
+    }
+    let unit = RString::from_value(value).ok_or_else(invalid)?;
+    let unit = unsafe { unit.as_slice() }.to_vec();
+    if unit.is_empty() || unit.iter().any(|&b| b != b' ' && b != b'\t') {
+        return Err(invalid());
+    }
+    Ok(Some(unit))
+}
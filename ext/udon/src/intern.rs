@@ -0,0 +1,52 @@
+//! Process-wide pool of attribute key strings (`intern_keys: :global`).
+//!
+//! A pooled key is one frozen String shared by every parse that sees it, so
+//! a server parsing the same schema over and over allocates `id`, `class`
+//! and `href` once rather than once per attribute. Pooled Strings live as
+//! long as the process, which is why the pool is bounded: keys longer than
+//! `MAX_KEY_BYTES` are never pooled, and once `MAX_KEYS` are held new keys
+//! are allocated per parse as without the option.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock, PoisonError},
+};
+
+use magnus::{gc, prelude::*, value::Opaque, RString, Ruby};
+
+/// Most keys the pool holds.
+pub const MAX_KEYS: usize = 4096;
+
+/// Longest key, in bytes, that is pooled.
+pub const MAX_KEY_BYTES: usize = 64;
+
+type Pool = HashMap<Box<[u8]>, Opaque<RString>>;
+
+fn pool() -> &'static Mutex<Pool> {
+    static POOL: OnceLock<Mutex<Pool>> = OnceLock::new();
+    POOL.get_or_init(Mutex::default)
+}
+
+/// The frozen pooled String for `key`, pooling it if there is room; a new
+/// String if `key` is too long or the pool is full.
+pub fn key(ruby: &Ruby, key: &[u8]) -> RString {
+    if key.len() > MAX_KEY_BYTES {
+        return RString::from_slice(key);
+    }
+    let mut pool = pool().lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(string) = pool.get(key) {
+        return ruby.get_inner(*string);
+    }
+    let string = RString::from_slice(key);
+    if pool.len() < MAX_KEYS {
+        string.freeze();
+        gc::register_mark_object(string);
+        pool.insert(key.into(), string.into());
+    }
+    string
+}
+
+/// `UdonNative.interned_key_count`: how many keys the pool holds.
+pub fn len() -> usize {
+    pool().lock().unwrap_or_else(PoisonError::into_inner).len()
+}
@@ -2,10 +2,82 @@
 //!
 //! Maps udon-core events directly to Ruby hashes.
 
-use magnus::{function, prelude::*, Error, RArray, RHash, RString, Ruby, Symbol};
+mod bench;
+mod build;
+mod canonical;
+mod coerce;
+mod columnar;
+mod conditions;
+mod csv;
+mod definitions;
+mod diagnostics;
+mod digest;
+mod directives;
+mod dispatch;
+mod document;
+mod each_element;
+mod embedded;
+mod emitter;
+mod env;
+mod errors;
+mod event_lines;
+mod event_type;
+mod framed;
+mod freeze;
+mod handler_context;
+mod header;
+mod include;
+mod incremental;
+mod indent;
+mod intern;
+mod line_index;
+mod literal;
+mod mapped;
+mod merge;
+mod namespaces;
+mod normalize;
+mod options;
+mod packed;
+mod reconstruct;
+mod records;
+mod references;
+mod scan;
+mod segments;
+mod sort;
+mod span;
+mod span_index;
+mod stats;
+mod stdin;
+mod stream;
+mod times;
+mod transcode;
+mod tree;
+mod unknown_directives;
+mod utf16;
+mod value_types;
+mod writer;
+mod yaml;
+
+use magnus::{
+    encoding::RbEncoding,
+    function,
+    prelude::*,
+    scan_args::{get_kwargs, scan_args},
+    Error, Float, Integer, IntoValue, RArray, RHash, RString, Ruby, Symbol, Value,
+};
 use udon_core::{Event, ParseErrorCode, Parser};
 
+use conditions::Admit;
+use emitter::Emitter;
+use line_index::LineIndex;
+use normalize::OffsetMap;
+use options::{ParseOptions, SpanMode, UnknownDirectives, ValuePlugins};
+use utf16::Utf16Index;
+
 /// Create a span hash { start: n, end: n }.
+///
+/// Offsets stay `usize` from the parser to here, so there is no 32-bit limit
+/// on input size; `i64` holds any offset a real input can have.
 fn span_to_hash(span: &std::ops::Range<usize>) -> RHash {
     let hash = RHash::new();
     let _ = hash.aset(Symbol::new("start"), span.start as i64);
@@ -13,188 +85,335 @@ fn span_to_hash(span: &std::ops::Range<usize>) -> RHash {
     hash
 }
 
+/// Tab stop width for `capture_column: true` columns when no `tab_width:`
+/// is given.
+const COLUMN_TAB_WIDTH: usize = 8;
+
+/// Converts byte spans into the representation selected by the `spans:` option.
+///
+/// Spans from a normalized input are first mapped back to offsets in the
+/// original `source`, then moved by `span_base:`.
+struct SpanFormatter<'a> {
+    mode: SpanMode,
+    source: &'a [u8],
+    lines: Option<LineIndex>,
+    /// Built for `spans: :utf16`.
+    utf16: Option<Utf16Index>,
+    offsets: Option<OffsetMap>,
+    /// Added to every reported offset (`span_base:`).
+    base: usize,
+    /// Built for `capture_column: true`, with tabs expanded.
+    columns: Option<LineIndex>,
+}
+
+impl<'a> SpanFormatter<'a> {
+    fn new(
+        mode: SpanMode,
+        tab_width: Option<usize>,
+        source: &'a [u8],
+        offsets: Option<OffsetMap>,
+    ) -> Self {
+        let lines = match mode {
+            SpanMode::LineColPacked => Some(LineIndex::new(source).with_tab_width(tab_width)),
+            _ => None,
+        };
+        let utf16 = (mode == SpanMode::Utf16).then(|| Utf16Index::new(source));
+        SpanFormatter {
+            mode,
+            source,
+            lines,
+            utf16,
+            offsets,
+            base: 0,
+            columns: None,
+        }
+    }
+
+    fn with_base(mut self, base: usize) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Index the input for `column`, tabs advancing to the next multiple of
+    /// `tab_width`.
+    fn with_columns(mut self, tab_width: usize) -> Self {
+        self.columns = Some(LineIndex::new(self.source).with_tab_width(Some(tab_width)));
+        self
+    }
+
+    /// `span` as offsets into the original source, clamped so that
+    /// `0 <= start <= end <= source.len()` always holds.
+    fn original(&self, span: &std::ops::Range<usize>) -> std::ops::Range<usize> {
+        let span = match &self.offsets {
+            Some(offsets) => offsets.original(span.start)..offsets.original(span.end),
+            None => span.clone(),
+        };
+        let end = span.end.min(self.source.len());
+        span.start.min(end)..end
+    }
+
+    /// The number of spaces and tabs before `span` on its line in the
+    /// original source, or `None` when something else precedes it there.
+    fn indent(&self, span: &std::ops::Range<usize>) -> Option<usize> {
+        let start = self.original(span).start;
+        let line = &self.source[..start];
+        let width = line
+            .iter()
+            .rev()
+            .take_while(|&&b| b == b' ' || b == b'\t')
+            .count();
+        let before = &line[..start - width];
+        let at_line_start = before.is_empty()
+            || before.ends_with(b"\n")
+            || before.ends_with(b"\r")
+            || before == normalize::BOM;
+        at_line_start.then_some(width)
+    }
+
+    /// The 0-based display column `span` starts at in the original source,
+    /// if `with_columns` was called. A byte order mark takes no column.
+    fn column(&self, span: &std::ops::Range<usize>) -> Option<usize> {
+        let columns = self.columns.as_ref()?;
+        let start = self.original(span).start;
+        let (line, column) = columns.line_col(self.source, start);
+        let bom = line == 0 && start > 0 && self.source.starts_with(normalize::BOM);
+        Some(column - usize::from(bom))
+    }
+
+    /// `span` as reported to Ruby: `original`, in UTF-16 code units with
+    /// `spans: :utf16`, plus the `span_base:`.
+    fn reported(&self, span: &std::ops::Range<usize>) -> std::ops::Range<usize> {
+        self.shifted(self.original(span))
+    }
+
+    /// `span` of the original source in UTF-16 code units with
+    /// `spans: :utf16`, plus the `span_base:`.
+    fn shifted(&self, span: std::ops::Range<usize>) -> std::ops::Range<usize> {
+        let span = match &self.utf16 {
+            Some(utf16) => utf16.offset(span.start)..utf16.offset(span.end),
+            None => span,
+        };
+        span.start + self.base..span.end + self.base
+    }
+
+    /// An attribute key's `span` narrowed to the key text, should it also
+    /// cover the `:` sigil or blanks around the key. The narrowed span is in
+    /// the same (parser) coordinates as `span`.
+    fn key_span(
+        &self,
+        span: &std::ops::Range<usize>,
+        key: &[u8],
+    ) -> Option<std::ops::Range<usize>> {
+        key_span(&self.source[self.original(span)], span, key)
+    }
+
+    fn convert(&self, span: &std::ops::Range<usize>) -> Value {
+        self.format(&self.reported(span))
+    }
+
+    /// `convert` for a span that already indexes the original source.
+    fn convert_original(&self, span: &std::ops::Range<usize>) -> Value {
+        self.format(&self.shifted(span.clone()))
+    }
+
+    /// A reported `span` in the `spans:` representation.
+    fn format(&self, span: &std::ops::Range<usize>) -> Value {
+        match (self.mode, &self.lines) {
+            (SpanMode::LineColPacked, Some(lines)) => {
+                let (start_line, start_col) = lines.line_col(self.source, span.start);
+                let (end_line, end_col) = lines.line_col(self.source, span.end);
+                let packed = RArray::with_capacity(4);
+                let _ = packed.push(start_line as i64);
+                let _ = packed.push(start_col as i64);
+                let _ = packed.push(end_line as i64);
+                let _ = packed.push(end_col as i64);
+                packed.into_value()
+            }
+            (SpanMode::Object, _) => span::Span::from(span.clone()).into_value(),
+            _ => span_to_hash(span).into_value(),
+        }
+    }
+}
+
 /// Convert content bytes to Ruby string.
 fn content_to_rstring(content: &std::borrow::Cow<'_, [u8]>) -> RString {
     RString::from_slice(content.as_ref())
 }
 
 /// Convert a UDON Event to a Ruby hash.
-fn event_to_ruby_hash(ruby: &Ruby, event: &Event) -> RHash {
+fn event_to_ruby_hash(ruby: &Ruby, event: &Event, spans: &SpanFormatter) -> RHash {
     let hash = RHash::new();
 
     match event {
         // ========== Bracket Events (Start/End pairs) ==========
-
         Event::ElementStart { span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("element_start"));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::ElementEnd { span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("element_end"));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::EmbeddedStart { span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("embedded_start"));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::EmbeddedEnd { span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("embedded_end"));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::DirectiveStart { span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("directive_start"));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::DirectiveEnd { span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("directive_end"));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::ArrayStart { span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("array_start"));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::ArrayEnd { span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("array_end"));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::FreeformStart { span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("freeform_start"));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::FreeformEnd { span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("freeform_end"));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::CommentStart { span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("comment_start"));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::CommentEnd { span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("comment_end"));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         // ========== Content Events ==========
-
         Event::Name { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("name"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::Text { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("text"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::Attr { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("attr"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::StringValue { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("string_value"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::BareValue { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("bare_value"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::BoolTrue { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("bool_true"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::BoolFalse { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("bool_false"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::Nil { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("nil"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::Integer { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("integer"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::Float { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("float"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::Rational { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("rational"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::Complex { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("complex"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::Interpolation { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("interpolation"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::Reference { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("reference"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::RawContent { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("raw_content"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::Raw { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("raw"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         Event::Warning { content, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("warning"));
             let _ = hash.aset(Symbol::new("content"), content_to_rstring(content));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
 
         // ========== Error Event ==========
-
         Event::Error { code, span } => {
             let _ = hash.aset(Symbol::new("type"), Symbol::new("error"));
             let _ = hash.aset(Symbol::new("code"), Symbol::new(error_code_name(code)));
-            let _ = hash.aset(Symbol::new("span"), span_to_hash(span));
+            let _ = hash.aset(Symbol::new("span"), spans.convert(span));
         }
     }
 
@@ -216,24 +435,1269 @@ fn error_code_name(code: &ParseErrorCode) -> &'static str {
     }
 }
 
+/// How bad an error is, for `severity: true`:
+///
+/// - `fatal`: the rest of the document cannot be trusted (input cut short,
+///   or swallowed by a string or freeform block that never closes);
+/// - `recoverable`: the structure around the error is intact and parsing
+///   carried on with a best guess;
+/// - `stylistic`: the document parses as intended but breaks a rule.
+fn error_severity(code: &ParseErrorCode) -> &'static str {
+    match code {
+        ParseErrorCode::UnexpectedEof => "fatal",
+        ParseErrorCode::UnclosedStringValue => "fatal",
+        ParseErrorCode::UnclosedFreeform => "fatal",
+        ParseErrorCode::UnexpectedChar => "recoverable",
+        ParseErrorCode::Unclosed => "recoverable",
+        ParseErrorCode::UnclosedArray => "recoverable",
+        ParseErrorCode::UnclosedText => "recoverable",
+        ParseErrorCode::UnclosedInterpolation => "recoverable",
+        ParseErrorCode::NoTabs => "stylistic",
+    }
+}
+
+/// The `:type` an event converts to (before any option rewrites it).
+fn event_type_name(event: &Event) -> &'static str {
+    event_type::NAMES[usize::from(event_type::tag(event))]
+}
+
+/// Span and content (if any) of an event.
+fn event_parts<'a>(event: &'a Event) -> (&'a std::ops::Range<usize>, Option<&'a [u8]>) {
+    match event {
+        Event::ElementStart { span }
+        | Event::ElementEnd { span }
+        | Event::EmbeddedStart { span }
+        | Event::EmbeddedEnd { span }
+        | Event::DirectiveStart { span }
+        | Event::DirectiveEnd { span }
+        | Event::ArrayStart { span }
+        | Event::ArrayEnd { span }
+        | Event::FreeformStart { span }
+        | Event::FreeformEnd { span }
+        | Event::CommentStart { span }
+        | Event::CommentEnd { span }
+        | Event::Error { span, .. } => (span, None),
+        Event::Name { content, span }
+        | Event::Text { content, span }
+        | Event::Attr { content, span }
+        | Event::StringValue { content, span }
+        | Event::BareValue { content, span }
+        | Event::BoolTrue { content, span }
+        | Event::BoolFalse { content, span }
+        | Event::Nil { content, span }
+        | Event::Integer { content, span }
+        | Event::Float { content, span }
+        | Event::Rational { content, span }
+        | Event::Complex { content, span }
+        | Event::Interpolation { content, span }
+        | Event::Reference { content, span }
+        | Event::RawContent { content, span }
+        | Event::Raw { content, span }
+        | Event::Warning { content, span } => (span, Some(content.as_ref())),
+    }
+}
+
+/// An attribute key's `span` narrowed to `key`, given the source text the
+/// span covers, should it also cover the `:` sigil or blanks around the key;
+/// `None` when there is nothing to narrow or the key cannot be placed.
+fn key_span(
+    written: &[u8],
+    span: &std::ops::Range<usize>,
+    key: &[u8],
+) -> Option<std::ops::Range<usize>> {
+    if written == key {
+        return None;
+    }
+    let at = memchr::memmem::find(written, key)?;
+    // `at` counts source bytes; it only holds in parser coordinates if
+    // `normalize_newlines` dropped no `\r` in between.
+    if written[..at].contains(&b'\r') {
+        return None;
+    }
+    Some(span.start + at..span.start + at + key.len())
+}
+
 /// Parse UDON input and return an array of event hashes.
-fn parse(ruby: &Ruby, input: RString) -> Result<RArray, Error> {
-    let input_bytes = unsafe { input.as_slice() };
+///
+/// Accepts keyword options; see `ParseOptions`. `on_<type>:` keywords switch
+/// to callback dispatch instead (see `dispatch`), and the result is nil.
+/// `format: :columnar` returns a Hash of columns instead (see `columnar`),
+/// and `directives: :dispatch` runs registered directive handlers over the
+/// events (see `directives`).
+fn parse(ruby: &Ruby, args: &[Value]) -> Result<Value, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let input = coerce::bytes(ruby, args.required.0)?;
+    let handlers = dispatch::Handlers::extract(ruby, args.keywords)?;
+    let columnar = columnar::take_format(ruby, args.keywords)?;
+    let dispatch_directives = directives::take_mode(ruby, args.keywords)?;
+    let options = ParseOptions::from_hash(ruby, args.keywords)?;
+
+    let input_bytes = &input[..];
+    if options.conditions.is_some() && (dispatch_directives || !handlers.is_empty()) {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "conditions cannot be combined with directives: :dispatch or handlers",
+        ));
+    }
+    if options.emit_document_bounds && (dispatch_directives || !handlers.is_empty()) {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "emit_document_bounds cannot be combined with directives: :dispatch or handlers",
+        ));
+    }
+    if options.unknown_directives != UnknownDirectives::Allow
+        && (dispatch_directives || !handlers.is_empty())
+    {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "unknown_directives cannot be combined with directives: :dispatch or handlers",
+        ));
+    }
+    if options.directive_namespaces.is_some() && (dispatch_directives || !handlers.is_empty()) {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "directive_namespaces cannot be combined with directives: :dispatch or handlers",
+        ));
+    }
+    if options.structured_directives && (dispatch_directives || !handlers.is_empty()) {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "directive_args: :structured cannot be combined with directives: :dispatch or \
+             handlers",
+        ));
+    }
+    if dispatch_directives {
+        if columnar || !handlers.is_empty() {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "directives: :dispatch cannot be combined with format: :columnar or handlers",
+            ));
+        }
+        let events = directives::parse_dispatching(ruby, input_bytes, &options)?;
+        return Ok(freeze::finish(events, &options)?.as_value());
+    }
+    if columnar {
+        if !handlers.is_empty() {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "format: :columnar cannot be combined with on_<type>: handlers",
+            ));
+        }
+        let columns = columnar::parse_columnar(ruby, input_bytes, &options)?;
+        return Ok(freeze::finish(columns, &options)?.as_value());
+    }
+    if !handlers.is_empty() {
+        dispatch::parse_dispatch(ruby, input_bytes, &options, &handlers)?;
+        return Ok(ruby.qnil().as_value());
+    }
+    let events = parse_bytes(ruby, input_bytes, &options)?;
+    Ok(freeze::finish(events, &options)?.as_value())
+}
+
+/// Parse a byte buffer into an array of event hashes.
+fn parse_bytes(ruby: &Ruby, input_bytes: &[u8], options: &ParseOptions) -> Result<RArray, Error> {
+    parse_segment(ruby, input_bytes, 0..input_bytes.len(), options)
+}
+
+/// Parse `source[segment]` on its own, with spans given as offsets into all
+/// of `source`.
+fn parse_segment(
+    ruby: &Ruby,
+    source: &[u8],
+    segment: std::ops::Range<usize>,
+    options: &ParseOptions,
+) -> Result<RArray, Error> {
+    normalize::check_input(ruby, &source[segment.clone()], options)?;
+    if let Some(at) = normalize::rejected_nul(&source[segment.clone()], options) {
+        let result = RArray::new();
+        result.push(nul_error(
+            source,
+            segment.start + at..segment.start + at + 1,
+            options,
+        ))?;
+        return Ok(result);
+    }
+    let (normalized, offsets) = normalize::normalize(&source[segment.clone()], options);
+    let offsets = match segment.start {
+        0 => offsets,
+        start => Some(offsets.unwrap_or_default().shifted(start)),
+    };
+    parse_normalized(ruby, source, &normalized, offsets, options)
+}
+
+/// Parse `normalized`, with `offsets` mapping its spans back into `source`.
+fn parse_normalized(
+    ruby: &Ruby,
+    source: &[u8],
+    normalized: &[u8],
+    offsets: Option<OffsetMap>,
+    options: &ParseOptions,
+) -> Result<RArray, Error> {
+    let mut converter = Converter::new(ruby, source, options, offsets);
+    let mut conditions = options
+        .conditions
+        .as_ref()
+        .map(|context| conditions::Filter::new(context, normalized));
+
+    let mut embedded = embedded::Values::new(ruby, normalized)?;
+    if embedded.is_some() {
+        converter.track_path();
+    }
+    let mut unknown = unknown_directives::Check::new(ruby, &converter)?;
+    let mut namespaces = namespaces::Check::new(&converter);
+    let mut structured = options
+        .structured_directives
+        .then(directives::Structured::default);
+    let mut failure: Option<Error> = None;
 
     let result = RArray::new();
+    let mut starts = Vec::new();
+    let bounds = options
+        .emit_document_bounds
+        .then(|| document_bounds(&converter, source, normalized));
+    if let Some((start, _)) = bounds {
+        result.push(start)?;
+        starts.push(0);
+    }
 
-    Parser::new(input_bytes).parse(|event| {
-        let hash = event_to_ruby_hash(ruby, &event);
-        let _ = result.push(hash);
+    literal::parse(normalized, options, |event| {
+        let held = match conditions.as_mut().map(|filter| filter.admit(&event)) {
+            Some(Admit::Skip) => return converter.skip(&event),
+            Some(Admit::AfterHeld(span)) => Some(Event::DirectiveStart { span }),
+            _ => None,
+        };
+        for event in held.iter().chain([&event]) {
+            let values = embedded
+                .as_mut()
+                .map_or_else(Vec::new, |values| values.event(event, &converter));
+            let converted = converter.convert(event);
+            let index = converted.map(|_| result.len() + values.len());
+            let warnings = converter.take_warnings();
+            for hash in values.into_iter().chain(converted).chain(warnings) {
+                let _ = result.push(hash);
+                if options.sort_by_span {
+                    starts.push(event_parts(event).0.start);
+                }
+            }
+            if let Some(directives) = structured.as_mut() {
+                if let Err(error) = directives.event(ruby, &converter, result, event, index) {
+                    failure.get_or_insert(error);
+                }
+            }
+            let diagnostics = [
+                unknown
+                    .as_mut()
+                    .and_then(|check| check.event(event, &converter)),
+                namespaces
+                    .as_mut()
+                    .and_then(|check| check.event(event, &converter)),
+            ];
+            for hash in diagnostics.into_iter().flatten() {
+                let _ = result.push(hash);
+                if options.sort_by_span {
+                    starts.push(event_parts(event).0.start);
+                }
+            }
+        }
     });
+    if let Some(filter) = &conditions {
+        filter.finish(ruby, &converter)?;
+    }
+    if let Some(values) = embedded {
+        values.finish()?;
+    }
+    converter.take_failure()?;
+    if let Some(error) = failure {
+        return Err(error);
+    }
+    if let Some(check) = unknown {
+        check.finish(ruby)?;
+    }
 
+    let result = if options.sort_by_span {
+        sort_by_span(result, &starts)?
+    } else {
+        result
+    };
+    if let Some((_, end)) = bounds {
+        result.push(end)?;
+    }
     Ok(result)
 }
 
+/// The `:document_start` and `:document_end` events bracketing the events
+/// of `normalized` (`emit_document_bounds: true`): empty spans at the start
+/// and the end of the document in `source`.
+fn document_bounds(converter: &Converter, source: &[u8], normalized: &[u8]) -> (RHash, RHash) {
+    let mut extent = converter.spans.original(&(0..normalized.len()));
+    // A byte order mark skipped before parsing is still part of the document.
+    if source[..extent.start].ends_with(normalize::BOM) {
+        extent.start -= normalize::BOM.len();
+    }
+    let bound = |kind: &str, at: usize| {
+        let hash = RHash::new();
+        let _ = hash.aset(Symbol::new("type"), Symbol::new(kind));
+        let _ = hash.aset(
+            Symbol::new("span"),
+            converter.spans.convert_original(&(at..at)),
+        );
+        hash
+    };
+    (
+        bound("document_start", extent.start),
+        bound("document_end", extent.end),
+    )
+}
+
+/// The `:nul_byte` error event, spanning the NUL at `span` of `source`, that
+/// parsing produces instead of any other event for input `nul_bytes: :error`
+/// rejects.
+fn nul_error(source: &[u8], span: std::ops::Range<usize>, options: &ParseOptions) -> RHash {
+    let spans = SpanFormatter::new(options.spans, options.tab_width, source, None)
+        .with_base(options.span_base);
+    let hash = RHash::new();
+    let _ = hash.aset(Symbol::new("type"), Symbol::new("error"));
+    let _ = hash.aset(Symbol::new("code"), Symbol::new("nul_byte"));
+    let _ = hash.aset(Symbol::new("span"), spans.convert(&span));
+    if options.severity {
+        let _ = hash.aset(Symbol::new("severity"), Symbol::new("fatal"));
+    }
+    hash
+}
+
+/// `events` reordered by span start (`starts[i]` belongs to `events[i]`),
+/// keeping emission order on ties.
+fn sort_by_span(events: RArray, starts: &[usize]) -> Result<RArray, Error> {
+    if starts.windows(2).all(|pair| pair[0] <= pair[1]) {
+        return Ok(events);
+    }
+    let mut order: Vec<usize> = (0..starts.len()).collect();
+    order.sort_by_key(|&index| starts[index]);
+    let sorted = RArray::with_capacity(order.len());
+    for index in order {
+        sorted.push(events.entry::<Value>(index as isize)?)?;
+    }
+    Ok(sorted)
+}
+
+/// Per-parse conversion state: the options plus whatever context the enabled
+/// options need to carry from one event to the next.
+struct Converter<'a> {
+    ruby: &'a Ruby,
+    options: &'a ParseOptions,
+    spans: SpanFormatter<'a>,
+    /// The previous event was an attribute key.
+    after_attr: bool,
+    /// The previous event opened an element or embedded element.
+    after_element_start: bool,
+    /// Unified `:directive` hash still waiting for its name, with the span
+    /// of its `directive_start`.
+    pending_directive: Option<(RHash, std::ops::Range<usize>)>,
+    /// Shape digests in progress (`shape_hash: true`), with the
+    /// `:element_start` hash of each open node that should receive one.
+    shapes: digest::Shapes,
+    shape_targets: Vec<Option<RHash>>,
+    /// The last rewritten event was cut to `max_value_bytes`.
+    truncated: bool,
+    /// Header/body tracking (`header_spans: true`), with the
+    /// `:element_start` hash of each open element.
+    headers: header::Headers,
+    header_targets: Vec<Option<RHash>>,
+    /// The `:element_start` hash of each open element and whether a child
+    /// element or array has opened in it (`mark_container`, `classify`).
+    containers: Vec<(Option<RHash>, bool)>,
+    /// The start hash and start offset of each open structure
+    /// (`precompute_extents: true`).
+    extents: Vec<(Option<RHash>, usize)>,
+    /// The custom value types to try (`value_plugins:`), loaded when the
+    /// parse starts.
+    plugins: Option<value_types::Plugins>,
+    /// `Time` and `Date` (`parse_times: true`), loaded when the parse starts.
+    times: Option<times::Times>,
+    /// The first `interpolation_resolver` failure to raise once the parse
+    /// is done: any under `strict_interpolation: true`, and a `break` or
+    /// `throw` out of the resolver or a value type's block always.
+    failure: Option<Error>,
+    /// The open elements' names, while callbacks or `parent_name:` need them
+    /// (see `handler_context`).
+    path: Option<handler_context::Path>,
+    /// `:warning` events the resolver emitted, for the caller to add after
+    /// the event's hash.
+    warnings: Vec<RHash>,
+}
+
+impl<'a> Converter<'a> {
+    /// `input_bytes` is the caller's input; `offsets` maps spans back to it
+    /// when the parser was given a normalized copy.
+    fn new(
+        ruby: &'a Ruby,
+        input_bytes: &'a [u8],
+        options: &'a ParseOptions,
+        offsets: Option<OffsetMap>,
+    ) -> Self {
+        let mut spans = SpanFormatter::new(options.spans, options.tab_width, input_bytes, offsets)
+            .with_base(options.span_base);
+        if options.capture_column {
+            spans = spans.with_columns(options.tab_width.unwrap_or(COLUMN_TAB_WIDTH));
+        }
+        Converter {
+            ruby,
+            options,
+            spans,
+            after_attr: false,
+            after_element_start: false,
+            pending_directive: None,
+            shapes: digest::Shapes::default(),
+            shape_targets: Vec::new(),
+            truncated: false,
+            headers: header::Headers::default(),
+            header_targets: Vec::new(),
+            containers: Vec::new(),
+            extents: Vec::new(),
+            plugins: None,
+            times: None,
+            failure: None,
+            path: (options.interpolation_resolver.is_some() || options.parent_name)
+                .then(handler_context::Path::default),
+            warnings: Vec::new(),
+        }
+        .with_plugins()
+    }
+
+    /// Load the custom value types if `value_plugins:` asks for them, and
+    /// the timestamp classes if `parse_times:` does.
+    fn with_plugins(mut self) -> Self {
+        if self.options.value_plugins != ValuePlugins::Off {
+            match value_types::Plugins::load(self.ruby) {
+                Ok(plugins) => self.plugins = Some(plugins),
+                Err(error) => self.failure = Some(error),
+            }
+        }
+        if self.options.parse_times {
+            match times::Times::load(self.ruby) {
+                Ok(times) => self.times = Some(times),
+                Err(error) => self.failure = Some(error),
+            }
+        }
+        self
+    }
+
+    /// Keep the open elements' names for handler contexts.
+    fn track_path(&mut self) {
+        self.path.get_or_insert_with(handler_context::Path::default);
+    }
+
+    /// The `:warning` events emitted since the last call.
+    fn take_warnings(&mut self) -> Vec<RHash> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// The failure to raise in place of the parse's result, if any.
+    fn take_failure(&mut self) -> Result<(), Error> {
+        self.failure.take().map_or(Ok(()), Err)
+    }
+
+    /// Whether later events depend on the hashes built for earlier ones, so
+    /// no event may skip conversion.
+    fn needs_every_hash(&self) -> bool {
+        self.options.unify_directives || self.options.annotates_on_close()
+    }
+
+    /// Account for an event that is not converted, keeping the state the next
+    /// event's conversion reads.
+    fn skip(&mut self, event: &Event) {
+        self.after_attr = matches!(event, Event::Attr { .. });
+        self.after_element_start = matches!(
+            event,
+            Event::ElementStart { .. } | Event::EmbeddedStart { .. }
+        );
+        if let Some(path) = &mut self.path {
+            path.event(event);
+        }
+    }
+
+    /// Convert one event; `None` when it was folded into an earlier hash.
+    fn convert(&mut self, event: &Event) -> Option<RHash> {
+        if self.options.unify_directives {
+            if let Some(folded) = self.unify_directive(event) {
+                if self.options.shape_hash {
+                    self.track_shape(event, folded);
+                }
+                if self.options.header_spans {
+                    self.track_header(event, folded);
+                }
+                if self.options.precompute_extents {
+                    self.track_extent(event, folded);
+                }
+                if let (true, Event::DirectiveStart { .. }, Some(hash)) =
+                    (self.options.classify, event, folded)
+                {
+                    let _ = hash.aset(Symbol::new("kind"), Symbol::new("directive"));
+                }
+                if let (Event::DirectiveStart { span }, Some(hash)) = (event, folded) {
+                    if let Some(column) = self.spans.column(span) {
+                        let _ = hash.aset(Symbol::new("col"), column);
+                    }
+                }
+                return folded;
+            }
+        }
+
+        let parent = match (self.options.parent_name, &self.path) {
+            (true, Some(path)) => Some(path.parent(event)),
+            _ => None,
+        };
+        let rewritten = self.rewrite(event);
+        let converted = rewritten.as_ref().unwrap_or(event);
+        let hash = event_to_ruby_hash(self.ruby, converted, &self.spans);
+        if let (Event::Name { content, .. }, Some(_)) = (event, &rewritten) {
+            let _ = hash.aset(Symbol::new("original_name"), RString::from_slice(content));
+        }
+        if let (true, Event::Attr { content, .. }) = (self.options.intern_keys, converted) {
+            let _ = hash.aset(Symbol::new("content"), intern::key(self.ruby, content));
+        }
+        if self.truncated {
+            let _ = hash.aset(Symbol::new("truncated"), true);
+        }
+        if let (true, Event::ElementStart { span }) = (self.options.capture_indent, converted) {
+            let _ = hash.aset(Symbol::new("indent"), self.spans.indent(span));
+        }
+        if let Some(column) = self.spans.column(event_parts(converted).0) {
+            let _ = hash.aset(Symbol::new("col"), column);
+        }
+        if let Some(parent) = parent {
+            let _ = hash.aset(Symbol::new("parent_name"), parent);
+        }
+        if let (
+            true,
+            Event::Text { content, .. }
+            | Event::RawContent { content, .. }
+            | Event::Raw { content, .. }
+            | Event::StringValue { content, .. }
+            | Event::BareValue { content, .. },
+        ) = (self.options.content_lengths, event)
+        {
+            let _ = hash.aset(Symbol::new("content_bytes"), content.len());
+        }
+        if let (true, Event::Error { code, .. }) = (self.options.severity, converted) {
+            let _ = hash.aset(Symbol::new("severity"), Symbol::new(error_severity(code)));
+        }
+        if let (Some(resolver), Event::Interpolation { content, span }) =
+            (self.options.interpolation_resolver, event)
+        {
+            self.resolve(resolver, content, span, hash);
+        }
+        if let (Some(env), Event::Interpolation { content, .. }) = (&self.options.env, event) {
+            env.apply(self.ruby, content, hash, self.options.severity);
+        }
+        if let (true, Event::Interpolation { content, span }) =
+            (self.options.structured_interpolations, converted)
+        {
+            let _ = hash.aset(
+                Symbol::new("parts"),
+                self.interpolation_parts(content, span),
+            );
+        }
+        let timed = match (&self.times, converted) {
+            (
+                Some(times),
+                Event::BareValue { content, .. } | Event::StringValue { content, .. },
+            ) => times.attach(self.ruby, content, hash),
+            _ => false,
+        };
+        if let (false, Some(plugins)) = (timed, &self.plugins) {
+            let content = match converted {
+                Event::BareValue { content, .. } => Some(content),
+                Event::StringValue { content, .. }
+                    if self.options.value_plugins == ValuePlugins::All =>
+                {
+                    Some(content)
+                }
+                _ => None,
+            };
+            let severity = self.options.severity;
+            if let Some(error) =
+                content.and_then(|content| plugins.apply(self.ruby, content, hash, severity))
+            {
+                self.failure.get_or_insert(error);
+            }
+        }
+        if is_synthetic(converted) {
+            let _ = hash.aset(Symbol::new("synthetic"), true);
+        }
+        if self.options.split_interpolations {
+            if let Event::StringValue { content, .. } | Event::Text { content, .. } = converted {
+                if let Some(segments) = segments::to_ruby(content) {
+                    let _ = hash.aset(Symbol::new("segments"), segments);
+                }
+            }
+        }
+        if self.options.shape_hash {
+            self.track_shape(converted, Some(hash));
+        }
+        if self.options.header_spans {
+            self.track_header(converted, Some(hash));
+        }
+        if self.options.mark_container || self.options.classify {
+            self.track_container(converted, hash);
+        }
+        if self.options.precompute_extents {
+            self.track_extent(converted, Some(hash));
+        }
+        if self.options.type_tag {
+            event_type::attach(hash);
+        }
+        Some(hash)
+    }
+
+    /// `[{text:, span:}, ...]` for the path segments of the interpolated
+    /// `expression` whose event spans `span`, with spans into the original
+    /// input.
+    fn interpolation_parts(&self, expression: &[u8], span: &std::ops::Range<usize>) -> RArray {
+        let original = self.spans.original(span);
+        let written = &self.spans.source[original.clone()];
+        let at = original.start
+            + memchr::memmem::find(written, expression).unwrap_or(segments::OPEN.len());
+        let parts = RArray::new();
+        for part in segments::path_parts(expression) {
+            let hash = RHash::new();
+            let _ = hash.aset(
+                Symbol::new("text"),
+                RString::from_slice(&expression[part.clone()]),
+            );
+            let part = (at + part.start).min(original.end)..(at + part.end).min(original.end);
+            let _ = hash.aset(Symbol::new("span"), self.spans.convert_original(&part));
+            let _ = parts.push(hash);
+        }
+        parts
+    }
+
+    /// Turn the `:interpolation` `hash` into the value event for what
+    /// `resolver` returns for `expression`, keeping its span, or into an
+    /// `:interpolation_failed` error event if the resolver raises.
+    fn resolve(
+        &mut self,
+        resolver: Value,
+        expression: &[u8],
+        span: &std::ops::Range<usize>,
+        hash: RHash,
+    ) {
+        let _ = hash.aset(Symbol::new("expression"), RString::from_slice(expression));
+        let expression_string = RString::from_slice(expression);
+        let state = handler_context::State::new(self, span);
+        let (result, warnings) =
+            handler_context::HandlerContext::scope(
+                state,
+                |context| match handler_context::accepts(resolver, 2) {
+                    true => resolver.funcall::<_, _, Value>("call", (expression_string, context)),
+                    false => resolver.funcall("call", (expression_string,)),
+                },
+            );
+        let warnings = handler_context::warning_events(self, warnings, span);
+        self.warnings.extend(warnings);
+        let error = match result {
+            Ok(value) => {
+                let (kind, content) = resolved_value(self.ruby, value);
+                let _ = hash.aset(Symbol::new("type"), Symbol::new(kind));
+                let _ = hash.aset(Symbol::new("content"), content);
+                let _ = hash.aset(Symbol::new("interpolated"), true);
+                return;
+            }
+            Err(error) => error,
+        };
+        let Some(exception) = error.value() else {
+            self.failure.get_or_insert(error);
+            return;
+        };
+        let message: String = exception
+            .funcall("message", ())
+            .unwrap_or_else(|_| error.to_string());
+        if self.options.strict_interpolation && self.failure.is_none() {
+            let span = self.spans.reported(span);
+            self.failure = Some(errors::interpolation_error(
+                self.ruby,
+                format!(
+                    "interpolation_resolver failed for {:?} at {}...{}: {}",
+                    String::from_utf8_lossy(expression),
+                    span.start,
+                    span.end,
+                    message
+                ),
+            ));
+        }
+        let _ = hash.aset(Symbol::new("type"), Symbol::new("error"));
+        let _ = hash.delete::<_, Value>(Symbol::new("content"));
+        let _ = hash.aset(Symbol::new("code"), Symbol::new("interpolation_failed"));
+        let _ = hash.aset(Symbol::new("message"), message);
+        if self.options.severity {
+            let _ = hash.aset(Symbol::new("severity"), Symbol::new("recoverable"));
+        }
+    }
+
+    /// The event as changed by `canonicalize`, `downcase_names` or
+    /// `max_value_bytes`, if any of them applies to it, or an attribute key
+    /// with its span narrowed to the key text.
+    fn rewrite<'e>(&mut self, event: &'e Event<'_>) -> Option<Event<'e>> {
+        let mut rewritten = if let Event::Attr { content, span } = event {
+            self.spans.key_span(span, content).map(|span| Event::Attr {
+                content: std::borrow::Cow::Borrowed(content.as_ref()),
+                span,
+            })
+        } else if self.options.canonicalize && self.after_attr {
+            canonical::canonicalize(event, &self.options.boolean_tokens)
+        } else if self.options.downcase_names && self.after_element_start {
+            downcase_name(event)
+        } else {
+            None
+        };
+        self.skip(event);
+
+        self.truncated = false;
+        if let Some(max) = self.options.max_value_bytes {
+            rewritten = rewritten.or_else(|| borrow_value(event)).map(|value| {
+                let (value, cut) = truncate_value(value, max);
+                self.truncated = cut;
+                value
+            });
+        }
+        rewritten
+    }
+
+    /// Feed the shape tracker; on a close, set `:shape` on the start hash.
+    fn track_shape(&mut self, event: &Event, hash: Option<RHash>) {
+        match event {
+            Event::ElementStart { .. } => {
+                self.shapes.open(b'e');
+                self.shape_targets.push(hash);
+            }
+            Event::EmbeddedStart { .. } | Event::DirectiveStart { .. } => {
+                let kind = if matches!(event, Event::EmbeddedStart { .. }) {
+                    b'm'
+                } else {
+                    b'd'
+                };
+                self.shapes.open(kind);
+                self.shape_targets.push(None);
+            }
+            Event::Name { content, .. } => self.shapes.name(content),
+            Event::Attr { content, .. } => self.shapes.key(content),
+            Event::ElementEnd { .. } | Event::EmbeddedEnd { .. } | Event::DirectiveEnd { .. } => {
+                let shape = self.shapes.close();
+                if let (Some(Some(start)), Some(shape)) = (self.shape_targets.pop(), shape) {
+                    let _ = start.aset(Symbol::new("shape"), shape);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Feed the header tracker; on an element's close, set `:header_span`
+    /// and `:body_span` on its start hash.
+    fn track_header(&mut self, event: &Event, hash: Option<RHash>) {
+        if matches!(event, Event::ElementStart { .. }) {
+            self.header_targets.push(hash);
+        }
+        if let Some(split) = self.headers.observe(event) {
+            if let Some(Some(start)) = self.header_targets.pop() {
+                let _ = start.aset(
+                    Symbol::new("header_span"),
+                    self.spans.convert(&split.header),
+                );
+                let _ = start.aset(Symbol::new("body_span"), self.spans.convert(&split.body));
+            }
+        }
+    }
+
+    /// Track child elements and arrays; on an element's close, set
+    /// `:has_children` (`mark_container`) and `:kind` (`classify`) on its
+    /// start hash. Embedded and directive starts get their `:kind` at once.
+    fn track_container(&mut self, event: &Event, hash: RHash) {
+        let kind = |hash: RHash, kind: &str| {
+            let _ = hash.aset(Symbol::new("kind"), Symbol::new(kind));
+        };
+        match event {
+            Event::EmbeddedStart { .. } if self.options.classify => kind(hash, "embedded"),
+            Event::DirectiveStart { .. } if self.options.classify => kind(hash, "directive"),
+            Event::ElementStart { .. } | Event::ArrayStart { .. } => {
+                if let Some((_, has_children)) = self.containers.last_mut() {
+                    *has_children = true;
+                }
+                if matches!(event, Event::ElementStart { .. }) {
+                    self.containers.push((Some(hash), false));
+                }
+            }
+            Event::ElementEnd { .. } => {
+                if let Some((Some(start), has_children)) = self.containers.pop() {
+                    if self.options.mark_container {
+                        let _ = start.aset(Symbol::new("has_children"), has_children);
+                    }
+                    if self.options.classify {
+                        kind(start, if has_children { "container" } else { "leaf" });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// On a structure's close, set `:extent` on its start hash: from the
+    /// start of the start event to the end of the end event.
+    fn track_extent(&mut self, event: &Event, hash: Option<RHash>) {
+        match event {
+            Event::ElementStart { span }
+            | Event::EmbeddedStart { span }
+            | Event::DirectiveStart { span }
+            | Event::FreeformStart { span }
+            | Event::ArrayStart { span } => self.extents.push((hash, span.start)),
+            Event::ElementEnd { span }
+            | Event::EmbeddedEnd { span }
+            | Event::DirectiveEnd { span }
+            | Event::FreeformEnd { span }
+            | Event::ArrayEnd { span } => {
+                if let Some((Some(start), from)) = self.extents.pop() {
+                    let extent = from..span.end.max(from);
+                    let _ = start.aset(Symbol::new("extent"), self.spans.convert(&extent));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// With `unify_directives: true`, turn `directive_start` into a
+    /// `:directive` hash and fold the following `name` event into it.
+    /// Returns `None` for events it leaves to the normal conversion.
+    fn unify_directive(&mut self, event: &Event) -> Option<Option<RHash>> {
+        let pending = self.pending_directive.take();
+        match event {
+            Event::DirectiveStart { span } => {
+                let hash = event_to_ruby_hash(self.ruby, event, &self.spans);
+                let _ = hash.aset(Symbol::new("type"), Symbol::new("directive"));
+                let _ = hash.aset(Symbol::new("name"), self.ruby.qnil());
+                let _ = hash.aset(Symbol::new("namespace"), self.ruby.qnil());
+                let _ = hash.aset(Symbol::new("inline"), false);
+                self.pending_directive = Some((hash, span.clone()));
+                self.after_attr = false;
+                Some(Some(hash))
+            }
+            Event::Name { content, span } => {
+                let (hash, start) = pending?;
+                // The merged event covers both of its sources.
+                let union = start.start.min(span.start)..start.end.max(span.end);
+                let _ = hash.aset(Symbol::new("span"), self.spans.convert(&union));
+                let (namespace, name) = match content.iter().position(|&b| b == b':') {
+                    Some(colon) => (Some(&content[..colon]), &content[colon + 1..]),
+                    None => (None, content.as_ref()),
+                };
+                let _ = hash.aset(Symbol::new("name"), RString::from_slice(name));
+                if let Some(namespace) = namespace {
+                    let _ = hash.aset(Symbol::new("namespace"), RString::from_slice(namespace));
+                }
+                Some(None)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The value event type and content for a value an
+/// `interpolation_resolver` returned, typed as `Writer` types values.
+/// Anything that is not a String, Symbol, number, boolean or nil becomes a
+/// `:string_value` of its `to_s`.
+fn resolved_value(ruby: &Ruby, value: Value) -> (&'static str, RString) {
+    let kind = if value.is_nil() {
+        return ("nil", RString::new("null"));
+    } else if value.is_kind_of(ruby.class_true_class()) {
+        "bool_true"
+    } else if value.is_kind_of(ruby.class_false_class()) {
+        "bool_false"
+    } else if Symbol::from_value(value).is_some() {
+        "bare_value"
+    } else if Integer::from_value(value).is_some() {
+        "integer"
+    } else if Float::from_value(value).is_some() {
+        "float"
+    } else if value.is_kind_of(ruby.class_rational()) {
+        "rational"
+    } else if value.is_kind_of(ruby.class_complex()) {
+        "complex"
+    } else {
+        "string_value"
+    };
+    let content = value
+        .funcall::<_, _, RString>("to_s", ())
+        .unwrap_or_else(|_| RString::new(""));
+    (kind, content)
+}
+
+/// Whether the parser made up `event` rather than reading it: a closing
+/// bracket supplied for an unterminated embedded element, array or freeform
+/// block carries an empty span at the recovery point. Element and directive
+/// ends are always implied by indentation and never count.
+fn is_synthetic(event: &Event) -> bool {
+    match event {
+        Event::EmbeddedEnd { span } | Event::ArrayEnd { span } | Event::FreeformEnd { span } => {
+            span.is_empty()
+        }
+        _ => false,
+    }
+}
+
+/// ASCII-lowercased copy of a name event, if it has any uppercase letters.
+fn downcase_name<'e>(event: &Event<'e>) -> Option<Event<'e>> {
+    match event {
+        Event::Name { content, span } if content.iter().any(u8::is_ascii_uppercase) => {
+            Some(Event::Name {
+                content: std::borrow::Cow::Owned(content.to_ascii_lowercase()),
+                span: span.clone(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// A borrowed copy of a string, text or raw event, the values
+/// `max_value_bytes` applies to.
+fn borrow_value<'e>(event: &'e Event<'_>) -> Option<Event<'e>> {
+    use std::borrow::Cow::Borrowed;
+    Some(match event {
+        Event::StringValue { content, span } => Event::StringValue {
+            content: Borrowed(content.as_ref()),
+            span: span.clone(),
+        },
+        Event::BareValue { content, span } => Event::BareValue {
+            content: Borrowed(content.as_ref()),
+            span: span.clone(),
+        },
+        Event::Text { content, span } => Event::Text {
+            content: Borrowed(content.as_ref()),
+            span: span.clone(),
+        },
+        Event::RawContent { content, span } => Event::RawContent {
+            content: Borrowed(content.as_ref()),
+            span: span.clone(),
+        },
+        Event::Raw { content, span } => Event::Raw {
+            content: Borrowed(content.as_ref()),
+            span: span.clone(),
+        },
+        _ => return None,
+    })
+}
+
+/// Cut the content of a string, text or raw event to at most `max` bytes,
+/// backing up to a UTF-8 character boundary. Returns whether it was cut.
+fn truncate_value(event: Event<'_>, max: usize) -> (Event<'_>, bool) {
+    use std::borrow::Cow;
+
+    fn cut(content: Cow<'_, [u8]>, max: usize) -> (Cow<'_, [u8]>, bool) {
+        if content.len() <= max {
+            return (content, false);
+        }
+        let mut end = max;
+        while end > 0 && (content[end] & 0xC0) == 0x80 {
+            end -= 1;
+        }
+        let content = match content {
+            Cow::Borrowed(bytes) => Cow::Borrowed(&bytes[..end]),
+            Cow::Owned(mut bytes) => {
+                bytes.truncate(end);
+                Cow::Owned(bytes)
+            }
+        };
+        (content, true)
+    }
+
+    match event {
+        Event::StringValue { content, span } => {
+            let (content, cut) = cut(content, max);
+            (Event::StringValue { content, span }, cut)
+        }
+        Event::BareValue { content, span } => {
+            let (content, cut) = cut(content, max);
+            (Event::BareValue { content, span }, cut)
+        }
+        Event::Text { content, span } => {
+            let (content, cut) = cut(content, max);
+            (Event::Text { content, span }, cut)
+        }
+        Event::RawContent { content, span } => {
+            let (content, cut) = cut(content, max);
+            (Event::RawContent { content, span }, cut)
+        }
+        Event::Raw { content, span } => {
+            let (content, cut) = cut(content, max);
+            (Event::Raw { content, span }, cut)
+        }
+        other => (other, false),
+    }
+}
+
+// ========== Emitting ==========
+
+/// Buffered output is written to an IO once it grows past this size.
+const OUTPUT_FLUSH_BYTES: usize = 64 * 1024;
+
+/// Write generated UTF-8 text to an IO via `#write`.
+fn write_to_io(io: Value, bytes: Vec<u8>) -> Result<(), Error> {
+    let chunk = RString::enc_new(bytes, RbEncoding::utf8());
+    let _: Value = io.funcall("write", (chunk,))?;
+    Ok(())
+}
+
+/// The type and content the emitter gets for an event hash. A custom value
+/// goes back as the string it was made from.
+fn emitted(event: RHash) -> Result<(String, Option<RString>), Error> {
+    let kind: Symbol = event.fetch(Symbol::new("type"))?;
+    let kind = kind.name()?;
+    if kind == "custom_value" {
+        return Ok(("string_value".into(), event.lookup(Symbol::new("raw"))?));
+    }
+    Ok((kind.into_owned(), event.lookup(Symbol::new("content"))?))
+}
+
+/// Feed one Ruby event hash into the emitter.
+fn emit_event_hash(
+    ruby: &Ruby,
+    emitter: &mut Emitter,
+    event: RHash,
+    index: usize,
+) -> Result<(), Error> {
+    let (kind, content) = emitted(event)?;
+    let content = content.map(|content| unsafe { content.as_slice() });
+    emitter
+        .event(&kind, content, index)
+        .map_err(|err| errors::emit_error(ruby, err))
+}
+
+/// Feed a transform block's return value: a Hash, an Array of Hashes, or nil.
+fn emit_replacement(
+    ruby: &Ruby,
+    emitter: &mut Emitter,
+    replacement: Value,
+    index: usize,
+) -> Result<(), Error> {
+    if replacement.is_nil() {
+        return Ok(());
+    }
+    if let Some(event) = RHash::from_value(replacement) {
+        return emit_event_hash(ruby, emitter, event, index);
+    }
+    if let Some(events) = RArray::from_value(replacement) {
+        for event in events.to_vec::<RHash>()? {
+            emit_event_hash(ruby, emitter, event, index)?;
+        }
+        return Ok(());
+    }
+    Err(Error::new(
+        ruby.exception_type_error(),
+        format!(
+            "transform block must return a Hash, an Array of Hashes, or nil (event {})",
+            index
+        ),
+    ))
+}
+
+/// Write buffered output to `io` if there is one and enough has accumulated.
+fn flush_emitter(emitter: &mut Emitter, io: Option<Value>, force: bool) -> Result<(), Error> {
+    if let Some(io) = io {
+        if force || emitter.buffered() >= OUTPUT_FLUSH_BYTES {
+            write_to_io(io, emitter.take_output())?;
+        }
+    }
+    Ok(())
+}
+
+/// Validate the end of the stream and return the output (or the IO).
+fn finish_emitter(ruby: &Ruby, mut emitter: Emitter, io: Option<Value>) -> Result<Value, Error> {
+    emitter
+        .finish()
+        .map_err(|err| errors::emit_error(ruby, err))?;
+    match io {
+        Some(io) => {
+            flush_emitter(&mut emitter, Some(io), true)?;
+            Ok(io)
+        }
+        None => Ok(RString::enc_new(emitter.take_output(), RbEncoding::utf8()).as_value()),
+    }
+}
+
+/// Serialize an array of event hashes to UDON text.
+///
+/// Returns the text, or writes it to `io` (anything with `#write`) and
+/// returns the IO. Raises `UdonNative::EmitError` on malformed structure.
+///
+/// `sort_attributes:` orders attributes by key (an Array of keys puts those
+/// first); `sort_elements_by:` orders sibling elements by an attribute value.
+/// Sorting buffers the whole stream before writing. `quote: :preserve` is
+/// accepted and is what emit always does: values keep their quoting.
+fn emit(ruby: &Ruby, args: &[Value]) -> Result<Value, Error> {
+    let args = scan_args::<(RArray,), (Option<Value>,), (), (), RHash, ()>(args)?;
+    let (events,) = args.required;
+    let io = args.optional.0.filter(|io| !io.is_nil());
+    let kwargs =
+        get_kwargs::<_, (), (Option<Value>, Option<Value>, Option<Value>, Option<Symbol>), ()>(
+            args.keywords,
+            &[],
+            &["sort_attributes", "sort_elements_by", "indent", "quote"],
+        )?;
+    let (sort_attributes, sort_elements_by, indent, quote) = kwargs.optional;
+    let sort = options::sort_options(ruby, sort_attributes, sort_elements_by)?;
+    options::check_quote(ruby, quote)?;
+
+    let mut emitter = Emitter::new();
+    if let Some(unit) = indent::indent_option(ruby, indent, None)? {
+        emitter = emitter.with_indent(unit);
+    }
+    if sort.is_enabled() {
+        let mut owned = Vec::with_capacity(events.len());
+        for (index, event) in events.to_vec::<RHash>()?.into_iter().enumerate() {
+            owned.push(owned_event(event, index)?);
+        }
+        for event in sort::sort_events(owned, &sort) {
+            emitter
+                .event(&event.kind, event.content.as_deref(), event.index)
+                .map_err(|err| errors::emit_error(ruby, err))?;
+            flush_emitter(&mut emitter, io, false)?;
+        }
+        return finish_emitter(ruby, emitter, io);
+    }
+    for (index, event) in events.to_vec::<RHash>()?.into_iter().enumerate() {
+        emit_event_hash(ruby, &mut emitter, event, index)?;
+        flush_emitter(&mut emitter, io, false)?;
+    }
+    finish_emitter(ruby, emitter, io)
+}
+
+/// Copy an event hash out of Ruby so it can be reordered.
+fn owned_event(event: RHash, index: usize) -> Result<sort::OwnedEvent, Error> {
+    let (kind, content) = emitted(event)?;
+    Ok(sort::OwnedEvent {
+        kind,
+        content: content.map(|content| unsafe { content.as_slice() }.to_vec()),
+        index,
+    })
+}
+
+/// Parse, pass each event through the block, and emit the results.
+///
+/// The block returns the event (possibly modified), an Array of replacement
+/// events, or nil to drop it. Structure errors name the index of the parsed
+/// event whose replacement broke the stream. `indent:` is as for `emit`, and
+/// may also be `:detect` to keep the input's own indentation unit.
+fn transform(ruby: &Ruby, args: &[Value]) -> Result<Value, Error> {
+    let args = scan_args::<(Value,), (Option<Value>,), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::bytes(ruby, input)?;
+    let io = args.optional.0.filter(|io| !io.is_nil());
+    let kwargs = get_kwargs::<_, (), (Option<Value>,), ()>(args.keywords, &[], &["indent"])?;
+    let (indent,) = kwargs.optional;
+    if !ruby.block_given() {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "transform requires a block",
+        ));
+    }
+    let block = ruby.block_proc()?;
+
+    let input_bytes = &input[..];
+    let options = ParseOptions::default();
+    let mut converter = Converter::new(ruby, input_bytes, &options, None);
+
+    let mut emitter = Emitter::new();
+    if let Some(unit) = indent::indent_option(ruby, indent, Some(input_bytes))? {
+        emitter = emitter.with_indent(unit);
+    }
+    let mut failure: Option<Error> = None;
+    let mut index = 0;
+
+    Parser::new(input_bytes).parse(|event| {
+        if failure.is_some() {
+            return;
+        }
+        let Some(hash) = converter.convert(&event) else {
+            index += 1;
+            return;
+        };
+        let result = block
+            .call::<_, Value>((hash,))
+            .and_then(|replacement| emit_replacement(ruby, &mut emitter, replacement, index))
+            .and_then(|_| flush_emitter(&mut emitter, io, false));
+        if let Err(err) = result {
+            failure = Some(err);
+        }
+        index += 1;
+    });
+
+    if let Some(err) = failure {
+        return Err(err);
+    }
+    finish_emitter(ruby, emitter, io)
+}
+
 /// Initialize the Ruby extension.
 #[magnus::init]
 fn init(ruby: &Ruby) -> Result<(), Error> {
     let module = ruby.define_module("UdonNative")?;
-    module.define_singleton_method("parse", function!(parse, 1))?;
+    errors::define(ruby, module)?;
+    writer::define(ruby, module)?;
+    document::define(ruby, module)?;
+    directives::define(module)?;
+    embedded::define(module)?;
+    handler_context::define(ruby, module)?;
+    value_types::define(ruby, module)?;
+    line_index::define(ruby, module)?;
+    span::define(ruby, module)?;
+    event_lines::define(ruby, module)?;
+    event_type::define(module)?;
+    incremental::define(ruby, module)?;
+    module.define_singleton_method("parse", function!(parse, -1))?;
+    module.define_singleton_method("emit", function!(emit, -1))?;
+    module.define_singleton_method("transform", function!(transform, -1))?;
+    module.define_singleton_method("to_csv", function!(csv::to_csv, -1))?;
+    module.define_singleton_method("parse_framed", function!(framed::parse_framed, -1))?;
+    module.define_singleton_method(
+        "parse_each_element",
+        function!(each_element::parse_each_element, -1),
+    )?;
+    module.define_singleton_method("comment_spans", function!(scan::comment_spans, 1))?;
+    module.define_singleton_method("attribute_keys", function!(scan::attribute_keys, 1))?;
+    module.define_singleton_method("count_elements", function!(scan::count_elements, -1))?;
+    module.define_singleton_method(
+        "directive_namespaces",
+        function!(namespaces::directive_namespaces, 1),
+    )?;
+    module.define_singleton_method("skeleton", function!(scan::skeleton, -1))?;
+    module.define_singleton_method(
+        "detect_indentation",
+        function!(indent::detect_indentation, 1),
+    )?;
+    module.define_singleton_method("find_element", function!(scan::find_element, -1))?;
+    module.define_singleton_method("select_elements", function!(scan::select_elements, -1))?;
+    module.define_singleton_method("pluck", function!(scan::pluck, -1))?;
+    module.define_singleton_method("build", function!(build::build, 2))?;
+    module.define_singleton_method("merge", function!(merge::merge, -1))?;
+    module.define_singleton_method(
+        "check_references",
+        function!(references::check_references, -1),
+    )?;
+    module.define_singleton_method("records", function!(records::records, 1))?;
+    module.define_singleton_method("diagnostics", function!(diagnostics::diagnostics, -1))?;
+    module.define_singleton_method("to_yaml", function!(yaml::to_yaml, -1))?;
+    module.define_singleton_method("from_yaml", function!(yaml::from_yaml, -1))?;
+    module.define_singleton_method("parse_document", function!(document::parse_document, 1))?;
+    module.define_singleton_method("first_element", function!(document::first_element, 1))?;
+    module.define_singleton_method("parse_tree", function!(include::parse_tree, -1))?;
+    module.define_singleton_method("parse_with_stats", function!(stats::parse_with_stats, -1))?;
+    module.define_singleton_method("bench_parse", function!(bench::bench_parse, 1))?;
+    module.define_singleton_method("parse_packed", function!(packed::parse_packed, -1))?;
+    module.define_singleton_method("snippet", function!(line_index::snippet, -1))?;
+    module.define_singleton_method("line_widths", function!(line_index::line_widths, -1))?;
+    module.define_singleton_method("event_at", function!(span_index::event_at, 2))?;
+    module.define_singleton_method("parse_stream", function!(stream::parse_stream, -1))?;
+    module.define_singleton_method("reconstruct", function!(reconstruct::reconstruct, -1))?;
+    module.define_singleton_method("parse_io", function!(transcode::parse_io, -1))?;
+    module.define_singleton_method("parse_file", function!(transcode::parse_file, -1))?;
+    module.define_singleton_method("parse_stdin", function!(stdin::parse_stdin, -1))?;
+    module.define_singleton_method("interned_key_count", function!(intern::len, 0))?;
     Ok(())
 }
@@ -0,0 +1,410 @@
+//! Newline index for converting byte offsets into line/column positions.
+//!
+//! `\n`, `\r\n` and a lone `\r` all end a line, as in LSP. The index is also
+//! exposed to Ruby as `UdonNative::LineIndex` for error display.
+//!
+//! Columns count UTF-8 characters. With a tab width set, a tab instead
+//! advances to the next multiple of that width, so columns match what an
+//! editor displays. `line_widths` goes one step further for terminals and
+//! measures each line in display columns, with wide (CJK, emoji) characters
+//! taking two and combining marks none.
+
+use std::ops::Range;
+
+use magnus::{
+    encoding::RbEncoding, function, method, prelude::*, scan_args::get_kwargs,
+    scan_args::scan_args, Error, RArray, RHash, RModule, RString, Ruby, Symbol, TryConvert, Value,
+};
+use unicode_width::UnicodeWidthChar;
+
+use crate::{
+    coerce,
+    normalize::{check_input, normalize},
+    options::ParseOptions,
+    span::span_of,
+};
+
+/// Byte offsets of every line start in a source buffer.
+///
+/// Built once per input in a single pass; lookups are a binary search over
+/// the line starts plus a scan of the (short) line prefix for the column.
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+    len: usize,
+    tab_width: Option<usize>,
+}
+
+impl LineIndex {
+    /// Index the line starts of `source`.
+    pub fn new(source: &[u8]) -> Self {
+        let mut line_starts = Vec::with_capacity(source.len() / 32 + 1);
+        line_starts.push(0);
+        let mut crlf_newline = None;
+        for i in memchr::memchr2_iter(b'\n', b'\r', source) {
+            if source[i] == b'\r' {
+                if source.get(i + 1) == Some(&b'\n') {
+                    crlf_newline = Some(i + 1);
+                    line_starts.push(i + 2);
+                } else {
+                    line_starts.push(i + 1);
+                }
+            } else if crlf_newline != Some(i) {
+                line_starts.push(i + 1);
+            }
+        }
+        LineIndex {
+            line_starts,
+            len: source.len(),
+            tab_width: None,
+        }
+    }
+
+    /// Expand tabs to `tab_width` columns when computing columns; `None`
+    /// counts a tab as one character. A width of 0 is treated as `None`.
+    pub fn with_tab_width(mut self, tab_width: Option<usize>) -> Self {
+        self.tab_width = tab_width.filter(|&width| width > 0);
+        self
+    }
+
+    /// Number of lines; a trailing newline starts a final empty line.
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// 0-based line containing `offset` (clamped to the input length).
+    pub fn line_of(&self, offset: usize) -> usize {
+        let offset = offset.min(self.len);
+        self.line_starts.partition_point(|&start| start <= offset) - 1
+    }
+
+    /// 0-based (line, column) of `offset`, with the column counted in UTF-8
+    /// characters from the start of the line (tabs expanded if a tab width
+    /// is set).
+    pub fn line_col(&self, source: &[u8], offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.len);
+        let line = self.line_of(offset);
+        let start = self.line_starts[line];
+        let prefix = &source[start..offset];
+        let column = match self.tab_width {
+            Some(width) if prefix.contains(&b'\t') => tab_columns(prefix, width),
+            _ => char_count(prefix),
+        };
+        (line, column)
+    }
+
+    /// Byte range of 0-based `line`, excluding its line terminator.
+    pub fn line_range(&self, source: &[u8], line: usize) -> Option<Range<usize>> {
+        let start = *self.line_starts.get(line)?;
+        let end = match self.line_starts.get(line + 1) {
+            Some(&next) if next >= 2 && source[next - 2..next] == *b"\r\n" => next - 2,
+            Some(&next) => next - 1,
+            None => self.len,
+        };
+        Some(start..end)
+    }
+
+    /// Display width of every line, in terminal columns; see
+    /// `display_width`.
+    pub fn line_widths(&self, source: &[u8]) -> Vec<usize> {
+        (0..self.line_count())
+            .filter_map(|line| self.line_range(source, line))
+            .map(|range| display_width(&source[range], self.tab_width))
+            .collect()
+    }
+
+    /// The lines around `span` with a gutter of 1-based line numbers and the
+    /// span underlined with `^`; see `SnippetStyle`. A span reaching past the
+    /// input is clamped and a note line says so.
+    pub fn snippet(&self, source: &[u8], span: Range<usize>, style: &SnippetStyle) -> String {
+        let start = span.start.min(self.len);
+        let end = span.end.clamp(start, self.len);
+        let first = self.line_of(start);
+        // An exclusive end at a line start belongs to the previous line.
+        let last = if end > start {
+            self.line_of(end - 1)
+        } else {
+            first
+        };
+        let from = first.saturating_sub(style.context);
+        let to = (last + style.context).min(self.line_count() - 1);
+        let width = (to + 1).to_string().len();
+        let (gutter, marker, reset) = if style.color {
+            ("\x1b[34m", "\x1b[1;31m", "\x1b[0m")
+        } else {
+            ("", "", "")
+        };
+
+        let mut out = String::new();
+        for line in from..=to {
+            let range = self.line_range(source, line).expect("line in range");
+            let chars: Vec<char> = String::from_utf8_lossy(&source[range.clone()])
+                .chars()
+                .collect();
+            let marked = line >= first && line <= last;
+            // Marked region of this line, in characters.
+            let (from_byte, to_byte) = (
+                start.clamp(range.start, range.end),
+                end.clamp(range.start, range.end),
+            );
+            let mark_start = char_count(&source[range.start..from_byte]);
+            let mark_len = char_count(&source[from_byte..to_byte]);
+
+            let (lo, hi) = match style.max_width {
+                Some(max) if chars.len() > max => {
+                    if marked {
+                        let hi = (mark_start.saturating_sub(max / 3) + max).min(chars.len());
+                        (hi - max, hi)
+                    } else {
+                        (0, max)
+                    }
+                }
+                _ => (0, chars.len()),
+            };
+            let text: String = chars[lo..hi].iter().collect();
+            out.push_str(&format!(
+                "{gutter}{:>width$} |{reset} {}{}{}\n",
+                line + 1,
+                if lo > 0 { "…" } else { "" },
+                text,
+                if hi < chars.len() { "…" } else { "" },
+                width = width
+            ));
+            if !marked {
+                continue;
+            }
+
+            let mark_from = mark_start.clamp(lo, hi);
+            // Keep tabs in the padding so the marks line up under the text.
+            let mut padding: String = chars[lo..mark_from]
+                .iter()
+                .map(|&c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            if lo > 0 {
+                padding.insert(0, ' ');
+            }
+            let marks = (mark_start + mark_len)
+                .min(hi)
+                .saturating_sub(mark_from)
+                .max(1);
+            out.push_str(&format!(
+                "{gutter}{:>width$} |{reset} {}{marker}{}{reset}\n",
+                "",
+                padding,
+                "^".repeat(marks),
+                width = width
+            ));
+        }
+        if span.end > self.len || span.start > self.len {
+            out.push_str(&format!(
+                "{gutter}{:>width$} ={reset} note: span {}..{} is past the end of the input \
+                 ({} bytes); clamped\n",
+                "",
+                span.start,
+                span.end,
+                self.len,
+                width = width
+            ));
+        }
+        out
+    }
+}
+
+/// How `LineIndex::snippet` renders.
+pub struct SnippetStyle {
+    /// Lines of context before and after the span.
+    pub context: usize,
+    /// Color the gutter and underline with ANSI escapes.
+    pub color: bool,
+    /// Longest line shown, in characters; longer lines are cut to a window
+    /// around the span with `…` marking the cuts.
+    pub max_width: Option<usize>,
+}
+
+impl Default for SnippetStyle {
+    fn default() -> Self {
+        SnippetStyle {
+            context: 1,
+            color: false,
+            max_width: Some(120),
+        }
+    }
+}
+
+/// Count UTF-8 characters by skipping continuation bytes.
+fn char_count(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&b| (b & 0xC0) != 0x80).count()
+}
+
+/// Display columns of a line prefix with tab stops every `width` columns.
+fn tab_columns(bytes: &[u8], width: usize) -> usize {
+    bytes.iter().fold(0, |column, &b| match b {
+        b'\t' => (column / width + 1) * width,
+        _ if (b & 0xC0) == 0x80 => column,
+        _ => column + 1,
+    })
+}
+
+/// Terminal columns taken by `line`, by the East Asian Width tables that
+/// `wcwidth` uses: 2 for wide and fullwidth characters, 0 for combining
+/// marks, zero-width characters and other control characters, 1 otherwise.
+/// A tab advances to the next tab stop with a tab width, or counts 1
+/// without one, as in `line_col`. Invalid UTF-8 counts 1 per bad sequence.
+fn display_width(line: &[u8], tab_width: Option<usize>) -> usize {
+    String::from_utf8_lossy(line)
+        .chars()
+        .fold(0, |column, c| match (c, tab_width) {
+            ('\t', Some(width)) => (column / width + 1) * width,
+            ('\t', None) => column + 1,
+            _ if c.is_control() => column,
+            _ => column + c.width().unwrap_or(0),
+        })
+}
+
+/// `UdonNative::LineIndex`: a line index plus its own copy of the source.
+#[magnus::wrap(class = "UdonNative::LineIndex", free_immediately, size)]
+pub struct RubyLineIndex {
+    source: Vec<u8>,
+    index: LineIndex,
+}
+
+impl RubyLineIndex {
+    /// `new(input, tab_width: nil)`
+    pub fn new(ruby: &Ruby, args: &[Value]) -> Result<Self, Error> {
+        let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+        let (input,) = args.required;
+        let input = coerce::string(ruby, input)?;
+        let kwargs =
+            get_kwargs::<_, (), (Option<Option<usize>>,), ()>(args.keywords, &[], &["tab_width"])?;
+        let tab_width = kwargs.optional.0.flatten();
+
+        let source = unsafe { input.as_slice() }.to_vec();
+        let index = LineIndex::new(&source).with_tab_width(tab_width);
+        Ok(RubyLineIndex { source, index })
+    }
+
+    /// `locate(byte_offset)` => `{line:, column:}`, both 1-based; columns
+    /// honor `tab_width:`.
+    pub fn locate(&self, offset: usize) -> RHash {
+        let (line, column) = self.index.line_col(&self.source, offset);
+        let hash = RHash::new();
+        let _ = hash.aset(Symbol::new("line"), line + 1);
+        let _ = hash.aset(Symbol::new("column"), column + 1);
+        hash
+    }
+
+    /// `line_range(line)` => `{start:, end:}` byte span of 1-based `line`,
+    /// without its terminator.
+    pub fn line_range(ruby: &Ruby, rb_self: &Self, line: usize) -> Result<RHash, Error> {
+        line.checked_sub(1)
+            .and_then(|line| rb_self.index.line_range(&rb_self.source, line))
+            .map(|range| crate::span_to_hash(&range))
+            .ok_or_else(|| {
+                Error::new(
+                    ruby.exception_index_error(),
+                    format!(
+                        "line {} out of range 1..{}",
+                        line,
+                        rb_self.index.line_count()
+                    ),
+                )
+            })
+    }
+
+    /// `line_count`
+    pub fn line_count(&self) -> usize {
+        self.index.line_count()
+    }
+
+    /// `snippet(span, context: 1, color: false, max_width: 120)`; `span` is a
+    /// `{start:, end:}` hash or a Range of byte offsets.
+    pub fn snippet(ruby: &Ruby, rb_self: &Self, args: &[Value]) -> Result<RString, Error> {
+        let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+        let (span,) = args.required;
+        let kwargs = get_kwargs::<_, (), (Option<usize>, Option<bool>, Option<Option<usize>>), ()>(
+            args.keywords,
+            &[],
+            &["context", "color", "max_width"],
+        )?;
+        let (context, color, max_width) = kwargs.optional;
+        let defaults = SnippetStyle::default();
+        let style = SnippetStyle {
+            context: context.unwrap_or(defaults.context),
+            color: color.unwrap_or(defaults.color),
+            max_width: max_width.unwrap_or(defaults.max_width),
+        };
+
+        let span = span_range(ruby, span)?;
+        let text = rb_self.index.snippet(&rb_self.source, span, &style);
+        Ok(RString::enc_new(text, RbEncoding::utf8()))
+    }
+}
+
+/// `UdonNative.snippet(input, span, context_lines: 2, color: false, max_width: 120)`
+///
+/// One-shot form of `LineIndex#snippet` for error reporting.
+pub fn snippet(ruby: &Ruby, args: &[Value]) -> Result<RString, Error> {
+    let args = scan_args::<(Value, Value), (), (), (), RHash, ()>(args)?;
+    let (input, span) = args.required;
+    let input = coerce::string(ruby, input)?;
+    let kwargs = get_kwargs::<_, (), (Option<usize>, Option<bool>, Option<Option<usize>>), ()>(
+        args.keywords,
+        &[],
+        &["context_lines", "color", "max_width"],
+    )?;
+    let (context, color, max_width) = kwargs.optional;
+    let style = SnippetStyle {
+        context: context.unwrap_or(2),
+        color: color.unwrap_or(false),
+        max_width: max_width.unwrap_or(SnippetStyle::default().max_width),
+    };
+
+    let source = unsafe { input.as_slice() };
+    let span = span_range(ruby, span)?;
+    let text = LineIndex::new(source).snippet(source, span, &style);
+    Ok(RString::enc_new(text, RbEncoding::utf8()))
+}
+
+/// `UdonNative.line_widths(input, **options)`
+///
+/// The display width of each line of `input` after the normalization
+/// `parse` applies with the same options (a leading BOM is skipped by
+/// default); `tab_width:` expands tabs to tab stops.
+pub fn line_widths(ruby: &Ruby, args: &[Value]) -> Result<RArray, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::string(ruby, input)?;
+    let options = ParseOptions::from_hash(ruby, args.keywords)?;
+    let input = unsafe { input.as_slice() };
+    check_input(ruby, input, &options)?;
+    let (source, _) = normalize(input, &options);
+    let widths = LineIndex::new(&source)
+        .with_tab_width(options.tab_width)
+        .line_widths(&source);
+    Ok(RArray::from_vec(widths))
+}
+
+/// Read a Range, a Span or a `{start:, end:}` hash as a byte range.
+fn span_range(ruby: &Ruby, span: Value) -> Result<Range<usize>, Error> {
+    if let Ok(range) = magnus::Range::try_convert(span) {
+        let start: usize = range.beg()?;
+        let end: Option<usize> = range.end()?;
+        let end = end.unwrap_or(usize::MAX);
+        return Ok(if range.excl() {
+            start..end
+        } else {
+            start..end.saturating_add(1)
+        });
+    }
+    span_of(ruby, span)
+}
+
+/// Define `UdonNative::LineIndex`.
+pub fn define(ruby: &Ruby, module: RModule) -> Result<(), Error> {
+    let class = module.define_class("LineIndex", ruby.class_object())?;
+    class.define_singleton_method("new", function!(RubyLineIndex::new, -1))?;
+    class.define_method("locate", method!(RubyLineIndex::locate, 1))?;
+    class.define_method("line_range", method!(RubyLineIndex::line_range, 1))?;
+    class.define_method("line_count", method!(RubyLineIndex::line_count, 0))?;
+    class.define_method("snippet", method!(RubyLineIndex::snippet, -1))?;
+    Ok(())
+}
@@ -0,0 +1,118 @@
+//! Interpolations kept as literal text (`interpolation: false`).
+//!
+//! libudon always recognizes `!{{expr}}`, so this happens between the
+//! parser and the conversion: an `:interpolation` in the text flow is
+//! merged with the `:text` events right before and after it into one
+//! `:text` event whose content is the input bytes of the whole run, and
+//! whose span covers all of it. An interpolation in value position (an
+//! attribute value or array item) becomes a `:bare_value` of its input
+//! bytes. Either way the output holds the characters as written, so it
+//! emits back the same (except that, as for any text line starting with
+//! `!`, the emitter escapes an interpolation that begins a line).
+//!
+//! Text that is not next to an interpolation passes through unchanged.
+
+use std::{borrow::Cow, ops::Range};
+
+use udon_core::{Event, Parser};
+
+use crate::options::ParseOptions;
+
+/// A `:text` event held back in case an interpolation follows.
+struct Pending {
+    span: Range<usize>,
+    /// The parser's content, for when nothing is merged into it.
+    content: Vec<u8>,
+    merged: bool,
+}
+
+/// Merges interpolations into the text around them.
+struct Merge<'a> {
+    input: &'a [u8],
+    pending: Option<Pending>,
+    /// The last event was an attribute key, so a value comes next.
+    after_attr: bool,
+    arrays: usize,
+}
+
+impl Merge<'_> {
+    fn event(&mut self, event: Event, f: &mut impl FnMut(Event)) {
+        let after_attr =
+            std::mem::replace(&mut self.after_attr, matches!(event, Event::Attr { .. }));
+        match event {
+            Event::ArrayStart { .. } => self.arrays += 1,
+            Event::ArrayEnd { .. } => self.arrays = self.arrays.saturating_sub(1),
+            _ => {}
+        }
+        match event {
+            Event::Interpolation { span, .. } if after_attr || self.arrays > 0 => {
+                self.flush(f);
+                let content = Cow::Borrowed(&self.input[span.clone()]);
+                f(Event::BareValue { content, span });
+            }
+            Event::Interpolation { span, .. } => match &mut self.pending {
+                Some(pending) if pending.span.end == span.start => {
+                    pending.span.end = span.end;
+                    pending.merged = true;
+                }
+                _ => {
+                    self.flush(f);
+                    self.pending = Some(Pending {
+                        span,
+                        content: Vec::new(),
+                        merged: true,
+                    });
+                }
+            },
+            Event::Text { content, span } => match &mut self.pending {
+                Some(pending) if pending.merged && pending.span.end == span.start => {
+                    pending.span.end = span.end;
+                }
+                _ => {
+                    self.flush(f);
+                    self.pending = Some(Pending {
+                        span,
+                        content: content.into_owned(),
+                        merged: false,
+                    });
+                }
+            },
+            event => {
+                self.flush(f);
+                f(event);
+            }
+        }
+    }
+
+    /// Pass on the held text.
+    fn flush(&mut self, f: &mut impl FnMut(Event)) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let content = match pending.merged {
+            true => Cow::Borrowed(&self.input[pending.span.clone()]),
+            false => Cow::Owned(pending.content),
+        };
+        f(Event::Text {
+            content,
+            span: pending.span,
+        });
+    }
+}
+
+/// Parse `input`, handing each event to `f`, with interpolations merged
+/// into literal text under `interpolation: false`.
+pub fn parse(input: &[u8], options: &ParseOptions, mut f: impl FnMut(Event)) {
+    if !options.literal_interpolations {
+        Parser::new(input).parse(f);
+        return;
+    }
+    let mut merge = Merge {
+        input,
+        pending: None,
+        after_attr: false,
+        arrays: 0,
+    };
+    Parser::new(input).parse(|event| merge.event(event, &mut f));
+    merge.flush(&mut f);
+}
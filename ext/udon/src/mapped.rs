@@ -0,0 +1,117 @@
+//! `parse_file(path, mmap: true)`: parsing a file from a memory mapping.
+//!
+//! The mapping is handed to the parser as it is, so a UTF-8 file is never
+//! copied into a Ruby String, nor into a Rust buffer unless normalization
+//! changes something. The core parser takes its whole input as one slice,
+//! so the mapping lives for exactly the parse; the event hashes own their
+//! content and never point into it.
+//!
+//! A file truncated while mapped makes reads past its new end fault
+//! (SIGBUS). The input is validated in `SLICE`-sized steps with the file's
+//! length checked before each and again after the parse, so truncation is
+//! raised as `UdonNative::IOError` whenever it is seen in time; a
+//! truncation in the middle of the core parse itself cannot be caught.
+//!
+//! Anything the mapping cannot serve goes down the buffered `parse_io`
+//! path instead: a file that cannot be mapped (pipes and other special
+//! files, empty files, platforms without mmap), gzip, UTF-16 and UTF-32
+//! input, and invalid UTF-8 under `invalid_utf8: :replace`.
+
+use std::fs::File;
+
+use memmap2::Mmap;
+
+use magnus::{prelude::*, Error, RArray, Ruby, Symbol, Value};
+
+use crate::{
+    errors,
+    normalize::{invalid_sequence, normalize, rejected_nul},
+    nul_error, parse_normalized,
+    stats::{parse_counted, ParseStats},
+    transcode::{with_stats, Compression, Encoding, Invalid, ReadOptions, GZIP_MAGIC},
+};
+
+/// Bytes validated between checks of the file's length.
+const SLICE: usize = 1 << 20;
+
+/// Parse the file at `path` from a mapping; `None` to use the buffered path.
+pub fn parse_mapped(
+    ruby: &Ruby,
+    path: &str,
+    compression: Compression,
+    read: &ReadOptions,
+) -> Result<Option<Value>, Error> {
+    if read
+        .encoding
+        .is_some_and(|encoding| encoding != Encoding::Utf8)
+    {
+        return Ok(None);
+    }
+    // Open errors are left for `File.open` to raise as `Errno` errors.
+    let Ok(file) = File::open(path) else {
+        return Ok(None);
+    };
+    let len = match file.metadata() {
+        Ok(metadata) if metadata.is_file() && metadata.len() > 0 => metadata.len() as usize,
+        _ => return Ok(None),
+    };
+    let Ok(map) = (unsafe { Mmap::map(&file) }) else {
+        return Ok(None);
+    };
+    let input = &map[..len.min(map.len())];
+    let gzip = match compression {
+        Compression::None => false,
+        Compression::Gzip => true,
+        Compression::Auto => input.starts_with(GZIP_MAGIC),
+    };
+    if gzip || (read.encoding.is_none() && Encoding::sniff(input).0 != Encoding::Utf8) {
+        return Ok(None);
+    }
+
+    let check_length = || match file.metadata() {
+        Ok(metadata) if metadata.len() as usize >= len => Ok(()),
+        _ => Err(errors::io_error(
+            ruby,
+            format!("{} was truncated while being parsed", path),
+        )),
+    };
+    let mut at = 0;
+    while at < input.len() {
+        check_length()?;
+        let end = (at + SLICE).min(input.len());
+        match std::str::from_utf8(&input[at..end]) {
+            Ok(_) => at = end,
+            // A character split by the slice end; the next slice starts at it.
+            Err(error) if error.error_len().is_none() && end < input.len() => {
+                at += error.valid_up_to()
+            }
+            Err(_) if read.invalid == Invalid::Replace => return Ok(None),
+            Err(error) => {
+                let start = at + error.valid_up_to();
+                let len = error.error_len().unwrap_or(input.len() - start);
+                return Err(invalid_sequence(ruby, "UTF-8", start, &input[start..], len));
+            }
+        }
+    }
+
+    let options = &read.parse;
+    let stats_hash = |stats: ParseStats, input_bytes: usize| {
+        let hash = stats.to_hash(input.len(), input_bytes);
+        let _ = hash.aset(Symbol::new("compressed_bytes"), input.len());
+        hash
+    };
+    if let Some(at) = rejected_nul(input, options) {
+        let events = RArray::new();
+        events.push(nul_error(input, at..at + 1, options))?;
+        return with_stats(read, events, || stats_hash(ParseStats::rejected(), 0)).map(Some);
+    }
+    let (normalized, offsets) = normalize(input, options);
+    let result = if read.with_stats {
+        let (events, stats) = parse_counted(ruby, input, &normalized, offsets, options)?;
+        with_stats(read, events, || stats_hash(stats, normalized.len()))?
+    } else {
+        parse_normalized(ruby, input, &normalized, offsets, options)?.as_value()
+    };
+    check_length()?;
+    Ok(Some(result))
+}
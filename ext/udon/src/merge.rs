@@ -0,0 +1,218 @@
+//! `UdonNative.merge`: layering one document over another by element id.
+//!
+//! Both documents are parsed into trees and the override is walked into a
+//! copy of the base:
+//!
+//! - An element whose id (the `by` attribute) is also in the base is merged
+//!   into that base element wherever it is: the override's attributes
+//!   replace the base's values for the same keys and new keys are appended.
+//! - An element without an id is lined up with the first base sibling of
+//!   the same name that has no id either, so wrappers such as `|config`
+//!   present in both documents merge instead of repeating.
+//! - The children of a merged element are merged the same way. Anything
+//!   else in the override (elements found only there, text, comments,
+//!   directives) is appended after the base's own children.
+//! - Elements found only in the base are kept as they are.
+//!
+//! Matching ids are a conflict when the two elements differ in kind
+//! (element or embedded) or name; the override element then replaces the
+//! base element and its subtree at the base's position, unmerged.
+
+use std::collections::HashMap;
+
+use magnus::{
+    encoding::RbEncoding, scan_args::get_kwargs, scan_args::scan_args, Error, RHash, RString, Ruby,
+    Value,
+};
+
+use crate::{
+    coerce,
+    emitter::Emitter,
+    errors,
+    normalize::check_encoding,
+    tree::{self, NodeKind, Tree, ROOT},
+};
+
+pub const DEFAULT_KEY: &str = "id";
+
+fn is_element(node: &tree::NodeData) -> bool {
+    matches!(node.kind, NodeKind::Element | NodeKind::Embedded)
+}
+
+/// An element's id as bytes; `None` without one or for an array value.
+pub fn id_of<'a>(node: &'a tree::NodeData, by: &[u8]) -> Option<&'a [u8]> {
+    if !is_element(node) {
+        return None;
+    }
+    match node.attr(by)? {
+        tree::Value::Scalar { content, .. } => Some(content),
+        tree::Value::Array(_) => None,
+    }
+}
+
+struct Merge<'a> {
+    out: Tree,
+    layer: &'a Tree,
+    by: &'a [u8],
+    /// Base elements by id; removed when an element is replaced.
+    ids: HashMap<Vec<u8>, usize>,
+}
+
+impl Merge<'_> {
+    /// Copy the override subtree at `index` under `parent`.
+    fn graft(&mut self, index: usize, parent: usize) -> usize {
+        let layer = self.layer;
+        let mut node = layer.node(index).clone();
+        node.parent = Some(parent);
+        node.children = Vec::new();
+        let copy = self.out.nodes.len();
+        self.out.nodes.push(node);
+        for &child in &layer.node(index).children {
+            let child = self.graft(child, copy);
+            self.out.nodes[copy].children.push(child);
+        }
+        copy
+    }
+
+    /// Drop the ids of the base subtree at `index` from the index.
+    fn forget(&mut self, index: usize) {
+        if let Some(id) = id_of(&self.out.nodes[index], self.by) {
+            let id = id.to_vec();
+            self.ids.remove(&id);
+        }
+        for child in self.out.nodes[index].children.clone() {
+            self.forget(child);
+        }
+    }
+
+    /// Replace the base element `target` with a copy of the override's
+    /// `index`.
+    fn replace(&mut self, target: usize, index: usize) {
+        self.forget(target);
+        let parent = self.out.nodes[target].parent.unwrap_or(ROOT);
+        let copy = self.graft(index, parent);
+        let children = &mut self.out.nodes[parent].children;
+        if let Some(slot) = children.iter_mut().find(|child| **child == target) {
+            *slot = copy;
+        }
+    }
+
+    /// The base element an override element merges into, if any.
+    fn counterpart(&self, index: usize, target: usize, matched: &[usize]) -> Option<usize> {
+        let node = self.layer.node(index);
+        if !is_element(node) {
+            return None;
+        }
+        if let Some(id) = id_of(node, self.by) {
+            return self.ids.get(id).copied();
+        }
+        self.out.nodes[target]
+            .children
+            .iter()
+            .copied()
+            .find(|&child| {
+                let base = &self.out.nodes[child];
+                base.kind == node.kind
+                    && base.name == node.name
+                    && id_of(base, self.by).is_none()
+                    && !matched.contains(&child)
+            })
+    }
+
+    /// Merge the children of the override's `index` into `target`.
+    fn children(&mut self, index: usize, target: usize) {
+        let layer = self.layer;
+        let mut matched = Vec::new();
+        for &child in &layer.node(index).children {
+            let Some(base) = self.counterpart(child, target, &matched) else {
+                let copy = self.graft(child, target);
+                self.out.nodes[target].children.push(copy);
+                continue;
+            };
+            matched.push(base);
+            let node = layer.node(child);
+            if self.out.nodes[base].kind != node.kind || self.out.nodes[base].name != node.name {
+                self.replace(base, child);
+                continue;
+            }
+            self.out.nodes[base].override_attrs(node);
+            self.children(child, base);
+        }
+    }
+}
+
+/// Merge `layer` over `base`; `Err` names a duplicate id in the base.
+pub fn merge_trees(base: &Tree, layer: &Tree, by: &[u8]) -> Result<Tree, String> {
+    let mut ids = HashMap::new();
+    for (index, node) in base.nodes.iter().enumerate() {
+        if let Some(id) = id_of(node, by) {
+            if ids.insert(id.to_vec(), index).is_some() {
+                return Err(format!(
+                    "base has more than one element with {} {:?}",
+                    String::from_utf8_lossy(by),
+                    String::from_utf8_lossy(id)
+                ));
+            }
+        }
+    }
+    let mut merge = Merge {
+        out: base.clone(),
+        layer,
+        by,
+        ids,
+    };
+    merge.children(ROOT, ROOT);
+    Ok(merge.out)
+}
+
+/// Parse one side of a merge; its parse errors are raised.
+fn parse_side(ruby: &Ruby, input: RString, side: &str) -> Result<Tree, Error> {
+    let input = unsafe { input.as_slice() };
+    check_encoding(ruby, input)?;
+    let tree = Tree::parse(input);
+    match tree.errors.first() {
+        Some(error) => Err(errors::merge_error(
+            ruby,
+            format!(
+                "{} does not parse: {} at byte {}",
+                side, error.code, error.span.start
+            ),
+        )),
+        None => Ok(tree),
+    }
+}
+
+/// `UdonNative.merge(base, override, by: "id")`
+///
+/// The UDON text of `override` layered over `base`; see the module docs
+/// for the rules. Raises `UdonNative::MergeError` if either document has a
+/// parse error or two base elements share an id.
+pub fn merge(ruby: &Ruby, args: &[Value]) -> Result<RString, Error> {
+    let args = scan_args::<(Value, Value), (), (), (), RHash, ()>(args)?;
+    let (base, layer) = args.required;
+    let (base, layer) = (coerce::string(ruby, base)?, coerce::string(ruby, layer)?);
+    let kwargs = get_kwargs::<_, (), (Option<String>,), ()>(args.keywords, &[], &["by"])?;
+    let by = kwargs.optional.0.unwrap_or_else(|| DEFAULT_KEY.to_string());
+    if by.is_empty() {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "by must be a non-empty attribute name",
+        ));
+    }
+
+    let base = parse_side(ruby, base, "base")?;
+    let layer = parse_side(ruby, layer, "override")?;
+    let merged = merge_trees(&base, &layer, by.as_bytes())
+        .map_err(|message| errors::merge_error(ruby, message))?;
+
+    let mut emitter = Emitter::new();
+    for (position, event) in merged.events(ROOT).iter().enumerate() {
+        emitter
+            .event(&event.kind, event.content.as_deref(), position)
+            .map_err(|err| errors::emit_error(ruby, err))?;
+    }
+    emitter
+        .finish()
+        .map_err(|err| errors::emit_error(ruby, err))?;
+    Ok(RString::enc_new(emitter.output(), RbEncoding::utf8()))
+}
@@ -0,0 +1,127 @@
+//! Declared directive namespaces (`directive_namespaces:`), and
+//! `UdonNative.directive_namespaces` listing the ones a document uses.
+//!
+//! With `directive_namespaces: ["app", "deploy"]`, a directive whose
+//! namespace is not in the list (`!db:migrate`) is followed, after its
+//! name, by `{type: :error, code: :undeclared_namespace, namespace:, name:,
+//! message:, span:}` over the name. A directive without a namespace is
+//! accepted unless `allow_unnamespaced: false`, when it gets the same
+//! error with a nil `:namespace`. libudon reports only block directives, so
+//! there are no inline directives to check yet.
+
+use std::collections::HashSet;
+
+use magnus::{Error, RHash, RString, Ruby, Symbol};
+use udon_core::{Event, Parser};
+
+use crate::{
+    coerce,
+    normalize::{bom_len, check_encoding},
+    span_to_hash, Converter,
+};
+
+/// `namespace` and `name` of a directive name, split at its first `:`.
+fn split(content: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    match content.iter().position(|&b| b == b':') {
+        Some(colon) => (Some(&content[..colon]), &content[colon + 1..]),
+        None => (None, content),
+    }
+}
+
+/// Checks the directives of one parse against the declared namespaces.
+pub struct Check {
+    declared: HashSet<Vec<u8>>,
+    allow_unnamespaced: bool,
+    /// The last event opened a directive, whose name is next.
+    awaiting_name: bool,
+}
+
+impl Check {
+    /// `None` when no namespaces are declared, so there is nothing to do.
+    pub fn new(converter: &Converter) -> Option<Self> {
+        let declared = converter.options.directive_namespaces.as_ref()?;
+        Some(Check {
+            declared: declared
+                .iter()
+                .map(|name| name.as_bytes().to_vec())
+                .collect(),
+            allow_unnamespaced: converter.options.allow_unnamespaced,
+            awaiting_name: false,
+        })
+    }
+
+    /// Follow `event`; the error to put after it when it names a directive
+    /// outside the declared namespaces.
+    pub fn event(&mut self, event: &Event, converter: &Converter) -> Option<RHash> {
+        let awaiting_name = std::mem::take(&mut self.awaiting_name);
+        let Event::Name { content, span } = event else {
+            self.awaiting_name = matches!(event, Event::DirectiveStart { .. });
+            return None;
+        };
+        if !awaiting_name {
+            return None;
+        }
+        let (namespace, name) = split(content);
+        let message = match namespace {
+            Some(namespace) if self.declared.contains(namespace) => return None,
+            None if self.allow_unnamespaced => return None,
+            Some(namespace) => format!(
+                "directive !{} uses undeclared namespace {:?}",
+                String::from_utf8_lossy(content),
+                String::from_utf8_lossy(namespace)
+            ),
+            None => format!(
+                "directive !{} has no namespace",
+                String::from_utf8_lossy(content)
+            ),
+        };
+        let hash = RHash::new();
+        let _ = hash.aset(Symbol::new("type"), Symbol::new("error"));
+        let _ = hash.aset(Symbol::new("code"), Symbol::new("undeclared_namespace"));
+        let _ = hash.aset(Symbol::new("namespace"), namespace.map(RString::from_slice));
+        let _ = hash.aset(Symbol::new("name"), RString::from_slice(name));
+        let _ = hash.aset(Symbol::new("message"), RString::new(&message));
+        let _ = hash.aset(Symbol::new("span"), converter.spans.convert(span));
+        if converter.options.severity {
+            let _ = hash.aset(Symbol::new("severity"), Symbol::new("recoverable"));
+        }
+        Some(hash)
+    }
+}
+
+/// `UdonNative.directive_namespaces(input)`
+///
+/// The namespaces the document's directives use, as a Hash from each
+/// namespace to the byte span of the first directive name using it, in
+/// first-use order. Only directive names are looked at; no event is
+/// converted.
+pub fn directive_namespaces(ruby: &Ruby, input: magnus::Value) -> Result<RHash, Error> {
+    let input = coerce::string(ruby, input)?;
+    let input_bytes = unsafe { input.as_slice() };
+    check_encoding(ruby, input_bytes)?;
+    let skip = bom_len(input_bytes);
+    let result = RHash::new();
+    let mut seen = HashSet::new();
+    let mut after_start = false;
+
+    Parser::new(&input_bytes[skip..]).parse(|event| {
+        let awaiting_name = std::mem::replace(
+            &mut after_start,
+            matches!(event, Event::DirectiveStart { .. }),
+        );
+        let Event::Name { content, span } = event else {
+            return;
+        };
+        if !awaiting_name {
+            return;
+        }
+        if let (Some(namespace), _) = split(&content) {
+            if seen.insert(namespace.to_vec()) {
+                let span = span.start + skip..span.end + skip;
+                let _ = result.aset(RString::from_slice(namespace), span_to_hash(&span));
+            }
+        }
+    });
+
+    Ok(result)
+}
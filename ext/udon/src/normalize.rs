@@ -0,0 +1,230 @@
+//! Input normalization for `strip_bom:`, `normalize_newlines:` and
+//! `nul_bytes:`, and the encoding checks every parse entry point makes
+//! first.
+//!
+//! The contract is that spans always index the caller's original bytes. The
+//! parser sees the normalized copy, so every span is mapped back through an
+//! `OffsetMap` before it reaches Ruby; event content comes from the
+//! normalized copy.
+
+use std::{borrow::Cow, ops::Range};
+
+use magnus::{Error, Ruby};
+use udon_core::{Event, Parser};
+
+use crate::{
+    errors,
+    options::{NulBytes, ParseOptions},
+    transcode::SourceMap,
+};
+
+pub const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Length of the UTF-8 byte order mark `input` starts with: the bytes a
+/// scan skips, and adds back to its spans.
+pub fn bom_len(input: &[u8]) -> usize {
+    if input.starts_with(BOM) {
+        BOM.len()
+    } else {
+        0
+    }
+}
+
+/// The UTF-16 variant `input` declares with a byte order mark, if any.
+pub fn utf16_bom(input: &[u8]) -> Option<&'static str> {
+    match input {
+        [0xFF, 0xFE, ..] => Some("UTF-16LE"),
+        [0xFE, 0xFF, ..] => Some("UTF-16BE"),
+        _ => None,
+    }
+}
+
+/// Refuse input that starts with a UTF-16 byte order mark; the parser
+/// would only produce errors for it.
+pub fn check_encoding(ruby: &Ruby, input: &[u8]) -> Result<(), Error> {
+    match utf16_bom(input) {
+        Some(encoding) => Err(errors::encoding_error(
+            ruby,
+            format!(
+                "input starts with a {} byte order mark; UDON is parsed as UTF-8, so \
+                 transcode it first, e.g. input.encode(\"UTF-8\", \"{}\")",
+                encoding, encoding
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Bytes shown after an invalid sequence in encoding errors.
+const CONTEXT: usize = 8;
+
+/// `bytes` as hex, the first `len` (an invalid sequence) in brackets:
+/// `[ff] 0a 7c 62`.
+fn hex_dump(bytes: &[u8], len: usize) -> String {
+    let hex = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let len = len.min(bytes.len());
+    let rest = &bytes[len..bytes.len().min(len + CONTEXT)];
+    match rest.is_empty() {
+        true => format!("[{}]", hex(&bytes[..len])),
+        false => format!("[{}] {}", hex(&bytes[..len]), hex(rest)),
+    }
+}
+
+/// `UdonNative::EncodingError` for an invalid `len`-byte sequence at
+/// offset `at`, `bytes` starting with it.
+pub fn invalid_sequence(ruby: &Ruby, encoding: &str, at: usize, bytes: &[u8], len: usize) -> Error {
+    errors::encoding_error(
+        ruby,
+        format!(
+            "invalid {} at byte {}: {}",
+            encoding,
+            at,
+            hex_dump(bytes, len)
+        ),
+    )
+}
+
+/// Raise unless all of `input` is valid UTF-8.
+pub fn validate_utf8(ruby: &Ruby, input: &[u8]) -> Result<(), Error> {
+    match std::str::from_utf8(input) {
+        Ok(_) => Ok(()),
+        Err(error) => {
+            let at = error.valid_up_to();
+            let len = error.error_len().unwrap_or(input.len() - at);
+            Err(invalid_sequence(ruby, "UTF-8", at, &input[at..], len))
+        }
+    }
+}
+
+/// `check_encoding`, then with `expect_encoding: "UTF-8"` the whole input
+/// validated before anything is parsed.
+pub fn check_input(ruby: &Ruby, input: &[u8], options: &ParseOptions) -> Result<(), Error> {
+    check_encoding(ruby, input)?;
+    if options.expect_utf8 {
+        validate_utf8(ruby, input)?;
+    }
+    Ok(())
+}
+
+/// Maps offsets in the normalized input back to the original input.
+#[derive(Debug, Default)]
+pub struct OffsetMap {
+    /// Bytes dropped before the first normalized byte (a stripped BOM).
+    base: usize,
+    /// Normalized offsets at which one original byte (the `\r` of a `\r\n`,
+    /// or a stripped NUL) was dropped just before, in ascending order.
+    removed: Vec<usize>,
+    /// Where the normalized input was transcoded from, for
+    /// `spans: :original`.
+    source: Option<SourceMap>,
+}
+
+impl OffsetMap {
+    /// Original offset of normalized `offset`.
+    ///
+    /// A dropped `\r` counts as part of the line break that follows it, so a
+    /// span starting at a normalized `\n` starts at the original `\r`, and a
+    /// span ending there ends before it.
+    pub fn original(&self, offset: usize) -> usize {
+        let offset = self.base + offset + self.removed.partition_point(|&at| at < offset);
+        match &self.source {
+            Some(source) => source.original(offset),
+            None => offset,
+        }
+    }
+
+    /// The same map for input that starts `by` bytes into a larger buffer.
+    pub fn shifted(mut self, by: usize) -> Self {
+        self.base += by;
+        self
+    }
+
+    /// The same map for input that was itself transcoded from other bytes.
+    pub fn through(mut self, source: SourceMap) -> Self {
+        self.source = Some(source);
+        self
+    }
+}
+
+/// Offset of the first NUL in `input` when `nul_bytes: :error` rejects it.
+pub fn rejected_nul(input: &[u8], options: &ParseOptions) -> Option<usize> {
+    match options.nul_bytes {
+        NulBytes::Error => memchr::memchr(0, input),
+        NulBytes::Strip | NulBytes::Allow => None,
+    }
+}
+
+/// Spans of raw content in `input`, in order, which `normalize` leaves
+/// byte-exact unless `normalize_raw: true`.
+///
+/// Found with a parse of the unnormalized input; a `\r` only ever ends a
+/// line, so it does not move where raw content starts or ends.
+fn raw_regions(input: &[u8]) -> Vec<Range<usize>> {
+    let mut regions = Vec::new();
+    Parser::new(input).parse(|event| match event {
+        Event::RawContent { span, .. } | Event::Raw { span, .. } => regions.push(span),
+        _ => {}
+    });
+    regions
+}
+
+/// The bytes to parse, and the map back to `input` if they differ from it.
+pub fn normalize<'a>(
+    input: &'a [u8],
+    options: &ParseOptions,
+) -> (Cow<'a, [u8]>, Option<OffsetMap>) {
+    let mut map = OffsetMap::default();
+    let mut bytes = input;
+    if options.strip_bom && bytes.starts_with(BOM) {
+        map.base = BOM.len();
+        bytes = &bytes[BOM.len()..];
+    }
+    let newlines = options.normalize_newlines && memchr::memchr(b'\r', bytes).is_some();
+    let nuls = options.nul_bytes == NulBytes::Strip && memchr::memchr(0, bytes).is_some();
+    if !newlines && !nuls {
+        let map = (map.base > 0).then_some(map);
+        return (Cow::Borrowed(bytes), map);
+    }
+
+    let raw = if !newlines || options.normalize_raw {
+        Vec::new()
+    } else {
+        raw_regions(bytes)
+    };
+    let mut raw = raw.iter().peekable();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut last = 0;
+    for i in memchr::memchr2_iter(b'\r', 0, bytes) {
+        if bytes[i] == 0 {
+            if nuls {
+                out.extend_from_slice(&bytes[last..i]);
+                map.removed.push(out.len());
+                last = i + 1;
+            }
+            continue;
+        }
+        if !newlines {
+            continue;
+        }
+        while raw.next_if(|region| region.end <= i).is_some() {}
+        if raw.peek().is_some_and(|region| region.start <= i) {
+            continue;
+        }
+        out.extend_from_slice(&bytes[last..i]);
+        if bytes.get(i + 1) != Some(&b'\n') {
+            // A lone `\r` becomes `\n` in place; offsets are unchanged.
+            out.push(b'\n');
+        } else {
+            map.removed.push(out.len());
+        }
+        last = i + 1;
+    }
+    out.extend_from_slice(&bytes[last..]);
+    (Cow::Owned(out), Some(map))
+}
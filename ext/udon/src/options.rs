@@ -0,0 +1,622 @@
+//! Keyword options accepted by `UdonNative.parse` and `UdonNative.emit`.
+
+use magnus::{
+    prelude::*, r_hash::ForEach, Error, RArray, RHash, RString, Ruby, Symbol, TryConvert, Value,
+};
+
+use crate::{
+    canonical::BooleanTokens,
+    conditions::Conditions,
+    env::{self, EnvOptions, MissingEnv},
+    sort::SortOptions,
+};
+
+/// How event spans are represented in the Ruby output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpanMode {
+    /// `{start: n, end: n}` byte offsets.
+    #[default]
+    Hash,
+    /// `[start_line, start_col, end_line, end_col]`, all 0-based, columns in
+    /// characters (code points). LSP counts a position's character in UTF-16
+    /// units, which differ past the BMP; `Utf16` gives those offsets.
+    LineColPacked,
+    /// `UdonNative::Span` objects over the same byte offsets as `Hash`.
+    Object,
+    /// `{start: n, end: n}` in UTF-16 code units, as JavaScript strings and
+    /// LSP positions count them.
+    Utf16,
+}
+
+/// What parsing does with NUL bytes in the input (`nul_bytes:`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NulBytes {
+    /// Produce only a `:nul_byte` error event at the first NUL.
+    #[default]
+    Error,
+    /// Drop them before parsing; spans still index the original bytes.
+    Strip,
+    /// Parse them as content.
+    Allow,
+}
+
+/// Which string values custom value types apply to (`value_plugins:`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ValuePlugins {
+    #[default]
+    Off,
+    /// Bare (unquoted) strings only.
+    Bare,
+    /// Quoted strings too.
+    All,
+}
+
+/// What becomes of directives nobody handles (`unknown_directives:`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnknownDirectives {
+    /// Pass them through like any other.
+    #[default]
+    Allow,
+    /// Add a `:warning` event.
+    Warn,
+    /// Add an `:error` event.
+    Error,
+}
+
+/// Parsed parse options.
+#[derive(Default)]
+pub struct ParseOptions {
+    pub spans: SpanMode,
+    /// Trim attribute values and map boolean tokens to `true`/`false`.
+    pub canonicalize: bool,
+    pub boolean_tokens: BooleanTokens,
+    /// Report directives as single `:directive` events carrying `:name`,
+    /// `:namespace` and `:inline` instead of `:directive_start` + `:name`.
+    pub unify_directives: bool,
+    /// Attach a `:shape` digest of the structural skeleton to each
+    /// `:element_start`.
+    pub shape_hash: bool,
+    /// Attach `:header_span` and `:body_span` to each `:element_start`.
+    pub header_spans: bool,
+    /// Attach `:extent`, the span through the matching end event, to each
+    /// element, embedded, directive, freeform and array start.
+    pub precompute_extents: bool,
+    /// Attach `:has_children` to each `:element_start`: whether a child
+    /// element or array opens before the element ends.
+    pub mark_container: bool,
+    /// Attach `:kind` to each `:element_start` (`:container` or `:leaf`, as
+    /// `:has_children` would be), `:embedded_start` and `:directive_start`.
+    pub classify: bool,
+    /// ASCII-lowercase element and embedded element names, keeping the
+    /// original as `:original_name` when it changes.
+    pub downcase_names: bool,
+    /// Tab stop width for `spans: :line_col_packed` columns; `None` counts a
+    /// tab as one character.
+    pub tab_width: Option<usize>,
+    /// Drop a leading UTF-8 byte order mark before parsing. On unless
+    /// `strip_bom: false` is passed.
+    pub strip_bom: bool,
+    /// Parse `\r\n` and lone `\r` line breaks as `\n`. Spans still index
+    /// the original bytes; see `normalize`. Also set by
+    /// `line_endings: :normalize`.
+    pub normalize_newlines: bool,
+    /// With `normalize_newlines`, normalize inside raw content (freeform
+    /// blocks and raw directives) too instead of keeping it byte-exact.
+    pub normalize_raw: bool,
+    pub nul_bytes: NulBytes,
+    /// Cut string, text and raw content to at most this many bytes, marking
+    /// the event `truncated: true`. Spans keep the full extent.
+    pub max_value_bytes: Option<usize>,
+    /// Attach `:segments` (literal and `!{{expr}}` parts) to string and text
+    /// values that contain an interpolation.
+    pub split_interpolations: bool,
+    /// Yield `(event, index, total)` to blocks and handlers instead of the
+    /// event alone; `total` is nil while the count is not yet known.
+    pub with_index: bool,
+    /// Return events ordered by span start, stable on ties. The parser emits
+    /// in source order today, so this only costs the buffering.
+    pub sort_by_span: bool,
+    /// Take attribute keys from the process-wide pool in `intern`
+    /// (`intern_keys: :global`) rather than allocating them per parse.
+    pub intern_keys: bool,
+    /// Added to every span offset, for parsing a tail of a larger document
+    /// on its own; in code units with `spans: :utf16`. Not available with
+    /// `spans: :line_col_packed`.
+    pub span_base: usize,
+    /// Attach `:severity` (`:fatal`, `:recoverable` or `:stylistic`) to
+    /// each `:error` event.
+    pub severity: bool,
+    /// Validate the whole input as UTF-8 before parsing
+    /// (`expect_encoding: "UTF-8"`).
+    pub expect_utf8: bool,
+    /// Called with each interpolation's expression; its value replaces the
+    /// `:interpolation` event.
+    pub interpolation_resolver: Option<Value>,
+    /// What callbacks get from `HandlerContext#user_context`.
+    pub user_context: Option<Value>,
+    /// Keep interpolations as the literal text they were written as
+    /// (`interpolation: false`, see `literal`).
+    pub literal_interpolations: bool,
+    /// Attach the path segments of each interpolation's expression as
+    /// `:parts` (`interpolations: :structured`, see `segments::path_parts`).
+    pub structured_interpolations: bool,
+    /// Raise a resolver's exception after the parse instead of reporting it
+    /// as an `:interpolation_failed` error event.
+    pub strict_interpolation: bool,
+    /// Deeply freeze the result (see `freeze`).
+    pub immutable: bool,
+    /// Context for evaluating `!if`/`!unless` directives (see `conditions`).
+    pub conditions: Option<Conditions>,
+    /// Interpolation open and close delimiters (`interpolation_delimiters:`),
+    /// for handing to the parser. libudon only recognizes `!{{`/`}}` so far,
+    /// so anything else is refused in `from_hash`.
+    pub interpolation_delimiters: Option<(Vec<u8>, Vec<u8>)>,
+    /// Remove the common indentation of embedded content before it is
+    /// passed to an embedded handler (see `embedded`).
+    pub dedent_raw: bool,
+    /// Bracket the events with `:document_start` and `:document_end`.
+    pub emit_document_bounds: bool,
+    /// Match string values against `UdonNative::ValueTypes` (see
+    /// `value_types`).
+    pub value_plugins: ValuePlugins,
+    /// Resolve `env.NAME` interpolations from the process environment
+    /// (`interpolations: :env`, see `env`).
+    pub env: Option<EnvOptions>,
+    /// Attach a `Time` or `Date` to ISO-8601 string values (see `times`).
+    pub parse_times: bool,
+    /// Report directives that are not known (see `unknown_directives`).
+    pub unknown_directives: UnknownDirectives,
+    /// Directive names (`"name"` or `"namespace:name"`) to treat as known.
+    pub known_directives: Vec<String>,
+    /// Raise the first unknown directive under `unknown_directives: :error`
+    /// after the parse.
+    pub strict_directives: bool,
+    /// Give each `:element_start` the `:indent` before it on its line.
+    pub capture_indent: bool,
+    /// Give each event the display `:col` it starts at (see
+    /// `SpanFormatter::column`).
+    pub capture_column: bool,
+    /// Give each event the `:parent_name` of the element it is in (see
+    /// `handler_context::Path::parent`).
+    pub parent_name: bool,
+    /// Give text, raw, string and bare value events the `:content_bytes` of
+    /// their content as parsed, before `max_value_bytes` cuts it.
+    pub content_lengths: bool,
+    /// Give each event the integer `:tag` of its type (see `event_type`).
+    pub type_tag: bool,
+    /// Fold each directive into one `:directive` event with its arguments
+    /// (`directive_args: :structured`, see `directives::Structured`).
+    pub structured_directives: bool,
+    /// The namespaces directives may use (see `namespaces`).
+    pub directive_namespaces: Option<Vec<String>>,
+    /// Accept directives without a namespace under `directive_namespaces:`.
+    pub allow_unnamespaced: bool,
+    /// The directive `directives: :dispatch` turns into a `:deprecated`
+    /// warning when no handler is registered for it (see `directives`).
+    pub deprecation_directive: Option<String>,
+}
+
+impl ParseOptions {
+    /// Build options from a keyword hash, rejecting unknown keys.
+    pub fn from_hash(ruby: &Ruby, hash: RHash) -> Result<Self, Error> {
+        let mut options = ParseOptions {
+            strip_bom: true,
+            allow_unnamespaced: true,
+            deprecation_directive: Some("deprecated".to_string()),
+            ..ParseOptions::default()
+        };
+        let mut unknown_conditions_false = false;
+        let mut env_enabled = false;
+        let mut env_options = EnvOptions::default();
+
+        hash.foreach(|key: Symbol, value: Value| {
+            match key.name()?.as_ref() {
+                "spans" => {
+                    options.spans = match symbol_name(ruby, "spans", value)?.as_str() {
+                        "hash" => SpanMode::Hash,
+                        "line_col_packed" => SpanMode::LineColPacked,
+                        "object" => SpanMode::Object,
+                        "utf16" => SpanMode::Utf16,
+                        other => return Err(invalid_value(ruby, "spans", other)),
+                    }
+                }
+                "canonicalize" => options.canonicalize = value.to_bool(),
+                "boolean_tokens" => options.boolean_tokens = boolean_tokens(ruby, value)?,
+                "unify_directives" => options.unify_directives = value.to_bool(),
+                "shape_hash" => options.shape_hash = value.to_bool(),
+                "header_spans" => options.header_spans = value.to_bool(),
+                "mark_container" => options.mark_container = value.to_bool(),
+                "classify" => options.classify = value.to_bool(),
+                "precompute_extents" => options.precompute_extents = value.to_bool(),
+                "downcase_names" => options.downcase_names = value.to_bool(),
+                "tab_width" => options.tab_width = Option::<usize>::try_convert(value)?,
+                "strip_bom" => options.strip_bom = value.to_bool(),
+                "normalize_newlines" => options.normalize_newlines = value.to_bool(),
+                "line_endings" => {
+                    options.normalize_newlines =
+                        match symbol_name(ruby, "line_endings", value)?.as_str() {
+                            "preserve" => false,
+                            "normalize" => true,
+                            other => return Err(invalid_value(ruby, "line_endings", other)),
+                        }
+                }
+                "normalize_raw" => options.normalize_raw = value.to_bool(),
+                "nul_bytes" => {
+                    options.nul_bytes = match symbol_name(ruby, "nul_bytes", value)?.as_str() {
+                        "error" => NulBytes::Error,
+                        "strip" => NulBytes::Strip,
+                        "allow" => NulBytes::Allow,
+                        other => return Err(invalid_value(ruby, "nul_bytes", other)),
+                    }
+                }
+                "sort_by_span" => options.sort_by_span = value.to_bool(),
+                "expect_encoding" => {
+                    let name: String = value.funcall("to_s", ())?;
+                    if !name.eq_ignore_ascii_case("UTF-8") {
+                        return Err(Error::new(
+                            ruby.exception_arg_error(),
+                            format!(
+                                "expect_encoding: parse reads UTF-8 only, not {}; \
+                                 use parse_io for other encodings",
+                                name
+                            ),
+                        ));
+                    }
+                    options.expect_utf8 = true;
+                }
+                "severity" => options.severity = value.to_bool(),
+                "interpolation_resolver" => {
+                    if value.is_nil() {
+                        options.interpolation_resolver = None;
+                    } else if value.respond_to("call", false)? {
+                        options.interpolation_resolver = Some(value);
+                    } else {
+                        return Err(Error::new(
+                            ruby.exception_arg_error(),
+                            "interpolation_resolver must respond to #call",
+                        ));
+                    }
+                }
+                "strict_interpolation" => options.strict_interpolation = value.to_bool(),
+                "interpolation" => options.literal_interpolations = !value.to_bool(),
+                "immutable" => options.immutable = value.to_bool(),
+                "dedent_raw" => options.dedent_raw = value.to_bool(),
+                "emit_document_bounds" => options.emit_document_bounds = value.to_bool(),
+                "parse_times" => options.parse_times = value.to_bool(),
+                "unknown_directives" => {
+                    options.unknown_directives =
+                        match symbol_name(ruby, "unknown_directives", value)?.as_str() {
+                            "allow" => UnknownDirectives::Allow,
+                            "warn" => UnknownDirectives::Warn,
+                            "error" => UnknownDirectives::Error,
+                            other => return Err(invalid_value(ruby, "unknown_directives", other)),
+                        }
+                }
+                "known_directives" => {
+                    options.known_directives =
+                        Option::<Vec<String>>::try_convert(value)?.unwrap_or_default()
+                }
+                "strict_directives" => options.strict_directives = value.to_bool(),
+                "directive_namespaces" => {
+                    options.directive_namespaces = Option::<Vec<String>>::try_convert(value)?
+                }
+                "allow_unnamespaced" => options.allow_unnamespaced = value.to_bool(),
+                "user_context" => {
+                    options.user_context = Some(value).filter(|value| !value.is_nil())
+                }
+                "deprecation_directive" => {
+                    options.deprecation_directive = match value.to_bool() {
+                        true => Some(value.funcall("to_s", ())?),
+                        false => None,
+                    }
+                }
+                "capture_indent" => options.capture_indent = value.to_bool(),
+                "capture_column" => options.capture_column = value.to_bool(),
+                "parent_name" => options.parent_name = value.to_bool(),
+                "content_lengths" => options.content_lengths = value.to_bool(),
+                "type_tag" => options.type_tag = value.to_bool(),
+                "directive_args" => {
+                    options.structured_directives =
+                        match symbol_name(ruby, "directive_args", value)?.as_str() {
+                            "events" => false,
+                            "structured" => true,
+                            other => return Err(invalid_value(ruby, "directive_args", other)),
+                        }
+                }
+                // libudon reports only block directives, so there is no
+                // inline directive content to parse yet; refuse `:parse`
+                // rather than quietly leaving it raw.
+                "inline_directive_content" => {
+                    match symbol_name(ruby, "inline_directive_content", value)?.as_str() {
+                        "raw" => {}
+                        "parse" => {
+                            return Err(Error::new(
+                                ruby.exception_not_imp_error(),
+                                "inline_directive_content: :parse is not supported yet; the \
+                                 parser does not report inline directives",
+                            ))
+                        }
+                        other => {
+                            return Err(invalid_value(ruby, "inline_directive_content", other))
+                        }
+                    }
+                }
+                // libudon's `Parser` has no chunk arena to size: events
+                // borrow their content from the input, and only unescaped
+                // content is copied, one allocation per value. A size is
+                // refused rather than accepted and ignored.
+                "arena_chunk_size" => match Option::<usize>::try_convert(value)? {
+                    None => {}
+                    Some(0) => {
+                        return Err(Error::new(
+                            ruby.exception_arg_error(),
+                            "arena_chunk_size must be a positive Integer",
+                        ))
+                    }
+                    Some(size) => {
+                        return Err(Error::new(
+                            ruby.exception_not_imp_error(),
+                            format!(
+                                "arena_chunk_size: {} is not supported; the parser allocates no \
+                                 arena",
+                                size
+                            ),
+                        ))
+                    }
+                },
+                "value_plugins" => {
+                    options.value_plugins = match Symbol::from_value(value) {
+                        Some(symbol) => match symbol.name()?.as_ref() {
+                            "all" => ValuePlugins::All,
+                            other => return Err(invalid_value(ruby, "value_plugins", other)),
+                        },
+                        None if value.to_bool() => ValuePlugins::Bare,
+                        None => ValuePlugins::Off,
+                    }
+                }
+                "interpolations" => {
+                    (env_enabled, options.structured_interpolations) =
+                        match symbol_name(ruby, "interpolations", value)?.as_str() {
+                            "events" => (false, false),
+                            "env" => (true, false),
+                            "structured" => (false, true),
+                            other => return Err(invalid_value(ruby, "interpolations", other)),
+                        }
+                }
+                "env_prefix" => env_options.prefix = String::try_convert(value)?,
+                "missing_env" => {
+                    env_options.missing = match symbol_name(ruby, "missing_env", value)?.as_str() {
+                        "error" => MissingEnv::Error,
+                        "empty" => MissingEnv::Empty,
+                        "keep" => MissingEnv::Keep,
+                        other => return Err(invalid_value(ruby, "missing_env", other)),
+                    }
+                }
+                "env_allowlist" => env_options.allowlist = env::allowlist(value)?,
+                "conditions" => {
+                    options.conditions = match Option::<RHash>::try_convert(value)? {
+                        Some(hash) => Some(Conditions::from_hash(hash)?),
+                        None => None,
+                    }
+                }
+                "unknown_conditions" => {
+                    unknown_conditions_false =
+                        match symbol_name(ruby, "unknown_conditions", value)?.as_str() {
+                            "error" => false,
+                            "false" => true,
+                            other => return Err(invalid_value(ruby, "unknown_conditions", other)),
+                        }
+                }
+                "interpolation_delimiters" => {
+                    options.interpolation_delimiters = interpolation_delimiters(ruby, value)?
+                }
+                "span_base" => options.span_base = usize::try_convert(value)?,
+                "intern_keys" => {
+                    options.intern_keys = match symbol_name(ruby, "intern_keys", value)?.as_str() {
+                        "none" => false,
+                        "global" => true,
+                        other => return Err(invalid_value(ruby, "intern_keys", other)),
+                    }
+                }
+                "split_interpolations" => options.split_interpolations = value.to_bool(),
+                "with_index" => options.with_index = value.to_bool(),
+                "max_value_bytes" => options.max_value_bytes = Option::<usize>::try_convert(value)?,
+                other => {
+                    return Err(Error::new(
+                        ruby.exception_arg_error(),
+                        format!("unknown option: {}", other),
+                    ))
+                }
+            }
+            Ok(ForEach::Continue)
+        })?;
+
+        if let Some(conditions) = &mut options.conditions {
+            conditions.unknown_false = unknown_conditions_false;
+            if options.unify_directives {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    "conditions cannot be combined with unify_directives",
+                ));
+            }
+        }
+        if env_enabled {
+            if options.interpolation_resolver.is_some() {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    "interpolations: :env cannot be combined with interpolation_resolver",
+                ));
+            }
+            options.env = Some(env_options);
+        }
+        if options.structured_interpolations && options.interpolation_resolver.is_some() {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "interpolations: :structured cannot be combined with interpolation_resolver",
+            ));
+        }
+        if options.literal_interpolations
+            && (options.interpolation_resolver.is_some() || options.env.is_some())
+        {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "interpolation: false cannot be combined with interpolation_resolver or \
+                 interpolations: :env",
+            ));
+        }
+        if options.structured_directives && (options.unify_directives || options.sort_by_span) {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "directive_args: :structured cannot be combined with unify_directives or \
+                 sort_by_span",
+            ));
+        }
+        if let Some((open, close)) = &options.interpolation_delimiters {
+            if (open.as_slice(), close.as_slice()) != DEFAULT_INTERPOLATION_DELIMITERS {
+                return Err(Error::new(
+                    ruby.exception_not_imp_error(),
+                    format!(
+                        "interpolation_delimiters: [{:?}, {:?}] is not supported by the \
+                         parser yet; only [\"!{{{{\", \"}}}}\"] is",
+                        String::from_utf8_lossy(open),
+                        String::from_utf8_lossy(close)
+                    ),
+                ));
+            }
+        }
+        if options.span_base > 0 && options.spans == SpanMode::LineColPacked {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "span_base is not available with spans: :line_col_packed",
+            ));
+        }
+        Ok(options)
+    }
+
+    /// Whether a start event's hash gets keys that are only known once its
+    /// structure closes.
+    pub fn annotates_on_close(&self) -> bool {
+        self.shape_hash
+            || self.header_spans
+            || self.mark_container
+            || self.classify
+            || self.precompute_extents
+    }
+}
+
+/// Build emit ordering options from `sort_attributes:` (true, false, or an
+/// Array of keys to put first) and `sort_elements_by:` (an attribute name).
+pub fn sort_options(
+    ruby: &Ruby,
+    sort_attributes: Option<Value>,
+    sort_elements_by: Option<Value>,
+) -> Result<SortOptions, Error> {
+    let mut options = SortOptions::default();
+    if let Some(value) = sort_attributes.filter(|v| !v.is_nil()) {
+        if let Some(priority) = RArray::from_value(value) {
+            options.attributes = true;
+            options.priority = priority
+                .to_vec::<Value>()?
+                .into_iter()
+                .map(|key| {
+                    key.funcall::<_, _, String>("to_s", ())
+                        .map(String::into_bytes)
+                })
+                .collect::<Result<_, _>>()?;
+        } else {
+            options.attributes = value.to_bool();
+        }
+    }
+    if let Some(value) = sort_elements_by.filter(|v| !v.is_nil()) {
+        if RString::from_value(value).is_none() && Symbol::from_value(value).is_none() {
+            return Err(Error::new(
+                ruby.exception_type_error(),
+                "sort_elements_by must be a String or Symbol",
+            ));
+        }
+        let key: String = value.funcall("to_s", ())?;
+        options.elements_by = Some(key.into_bytes());
+    }
+    Ok(options)
+}
+
+/// Check `quote:` for `emit` and `Writer.new`. `:preserve` (or nil) is the
+/// only mode: quoted and bare values are distinct events, so each value is
+/// written back with the quoting it was parsed with.
+pub fn check_quote(ruby: &Ruby, quote: Option<Symbol>) -> Result<(), Error> {
+    match quote {
+        None => Ok(()),
+        Some(quote) => match quote.name()?.as_ref() {
+            "preserve" => Ok(()),
+            other => Err(Error::new(
+                ruby.exception_arg_error(),
+                format!("invalid value for quote: :{}", other),
+            )),
+        },
+    }
+}
+
+/// The delimiters libudon recognizes around an interpolated expression.
+const DEFAULT_INTERPOLATION_DELIMITERS: (&[u8], &[u8]) = (b"!{{", b"}}");
+
+/// Read `interpolation_delimiters: [open, close]`; nil means the default.
+fn interpolation_delimiters(
+    ruby: &Ruby,
+    value: Value,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+    if value.is_nil() {
+        return Ok(None);
+    }
+    let invalid = || {
+        Error::new(
+            ruby.exception_arg_error(),
+            "interpolation_delimiters must be an Array of two non-empty Strings",
+        )
+    };
+    let pair = RArray::from_value(value).ok_or_else(invalid)?;
+    let [open, close] = pair.to_vec::<Value>()?[..] else {
+        return Err(invalid());
+    };
+    let delimiter = |value: Value| match RString::from_value(value) {
+        Some(string) if !string.is_empty() => Ok(unsafe { string.as_slice() }.to_vec()),
+        _ => Err(invalid()),
+    };
+    Ok(Some((delimiter(open)?, delimiter(close)?)))
+}
+
+/// Read a Symbol option value as a string.
+fn symbol_name(ruby: &Ruby, option: &str, value: Value) -> Result<String, Error> {
+    let symbol = Symbol::try_convert(value).map_err(|_| {
+        Error::new(
+            ruby.exception_type_error(),
+            format!("{} must be a Symbol", option),
+        )
+    })?;
+    Ok(symbol.name()?.into_owned())
+}
+
+/// Read `boolean_tokens: { true: [...], false: [...] }`; a missing side keeps
+/// its defaults.
+fn boolean_tokens(ruby: &Ruby, value: Value) -> Result<BooleanTokens, Error> {
+    let hash = RHash::try_convert(value).map_err(|_| {
+        Error::new(
+            ruby.exception_type_error(),
+            "boolean_tokens must be a Hash with :true and :false token lists",
+        )
+    })?;
+    let mut tokens = BooleanTokens::default();
+    if let Some(truthy) = hash.lookup::<_, Option<Vec<String>>>(Symbol::new("true"))? {
+        tokens.truthy = truthy.into_iter().map(String::into_bytes).collect();
+    }
+    if let Some(falsy) = hash.lookup::<_, Option<Vec<String>>>(Symbol::new("false"))? {
+        tokens.falsy = falsy.into_iter().map(String::into_bytes).collect();
+    }
+    Ok(tokens)
+}
+
+fn invalid_value(ruby: &Ruby, option: &str, value: &str) -> Error {
+    Error::new(
+        ruby.exception_arg_error(),
+        format!("invalid value for {}: :{}", option, value),
+    )
+}
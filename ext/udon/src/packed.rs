@@ -0,0 +1,134 @@
+//! `UdonNative.parse_packed`: events as one binary String of fixed-size
+//! records, for handing a parse to other native code without building a
+//! Ruby object per event.
+//!
+//! All integers are little-endian. The buffer is a 16-byte header, then
+//! `count` records of `RECORD_SIZE` bytes, then the string table:
+//!
+//! ```text
+//! header   0  4  magic "UDNP"
+//!          4  2  u16 version (1)
+//!          6  2  u16 record size (20)
+//!          8  4  u32 record count
+//!         12  4  u32 string table offset, from the start of the buffer
+//! record   0  1  u8  type tag (UdonNative::EventType)
+//!          1  3  zero
+//!          4  4  u32 span start
+//!          8  4  u32 span end
+//!         12  4  u32 content offset into the string table, or NO_CONTENT
+//!         16  4  u32 content length
+//! ```
+//!
+//! Spans are byte offsets into the input as given. Content is the event's
+//! `:content` bytes (UTF-8, no terminator); an `:error` record's is its
+//! code's name (`unclosed`, `nul_byte`, ...), and events without content
+//! have offset `NO_CONTENT` and length 0. Inputs of 4 GiB or more do not
+//! fit the 32-bit fields and raise `ArgumentError`.
+
+use magnus::{scan_args::scan_args, Error, RHash, RString, Ruby, Value};
+use udon_core::Event;
+
+use crate::{
+    coerce, error_code_name, event_parts, event_type, literal,
+    normalize::{check_input, normalize, rejected_nul},
+    options::{ParseOptions, SpanMode},
+    SpanFormatter,
+};
+
+const MAGIC: &[u8; 4] = b"UDNP";
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 16;
+const RECORD_SIZE: usize = 20;
+/// The content offset of a record without content.
+const NO_CONTENT: u32 = u32::MAX;
+
+/// Records and string table being filled.
+#[derive(Default)]
+struct Packer {
+    records: Vec<u8>,
+    strings: Vec<u8>,
+    count: u32,
+}
+
+impl Packer {
+    fn push(&mut self, tag: u8, span: std::ops::Range<usize>, content: Option<&[u8]>) {
+        self.records.extend_from_slice(&[tag, 0, 0, 0]);
+        self.records
+            .extend_from_slice(&(span.start as u32).to_le_bytes());
+        self.records
+            .extend_from_slice(&(span.end as u32).to_le_bytes());
+        let (offset, len) = match content {
+            Some(content) => {
+                let offset = self.strings.len() as u32;
+                self.strings.extend_from_slice(content);
+                (offset, content.len() as u32)
+            }
+            None => (NO_CONTENT, 0),
+        };
+        self.records.extend_from_slice(&offset.to_le_bytes());
+        self.records.extend_from_slice(&len.to_le_bytes());
+        self.count += 1;
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let strings_at = HEADER_SIZE + self.records.len();
+        let mut buffer = Vec::with_capacity(strings_at + self.strings.len());
+        buffer.extend_from_slice(MAGIC);
+        buffer.extend_from_slice(&VERSION.to_le_bytes());
+        buffer.extend_from_slice(&(RECORD_SIZE as u16).to_le_bytes());
+        buffer.extend_from_slice(&self.count.to_le_bytes());
+        buffer.extend_from_slice(&(strings_at as u32).to_le_bytes());
+        buffer.extend_from_slice(&self.records);
+        buffer.extend_from_slice(&self.strings);
+        buffer
+    }
+}
+
+/// `UdonNative.parse_packed(input, **options)`
+///
+/// Takes the input options of `parse` (`strip_bom:`, `normalize_newlines:`,
+/// `nul_bytes:`, `interpolation:`, ...); options that shape event hashes do
+/// not apply. Returns a binary String laid out as the module docs describe.
+pub fn parse_packed(ruby: &Ruby, args: &[Value]) -> Result<RString, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::string(ruby, input)?;
+    let options = ParseOptions::from_hash(ruby, args.keywords)?;
+    let input_bytes = unsafe { input.as_slice() };
+
+    check_input(ruby, input_bytes, &options)?;
+    if u32::try_from(input_bytes.len()).is_err() {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "parse_packed: input of 4 GiB or more does not fit 32-bit offsets",
+        ));
+    }
+    let mut packer = Packer::default();
+    if let Some(at) = rejected_nul(input_bytes, &options) {
+        packer.push(
+            event_type::tag_of("error").unwrap_or(0),
+            at..at + 1,
+            Some(b"nul_byte"),
+        );
+        return Ok(RString::from_slice(&packer.finish()));
+    }
+    let (normalized, offsets) = normalize(input_bytes, &options);
+    let spans = SpanFormatter::new(SpanMode::Hash, None, input_bytes, offsets);
+
+    literal::parse(&normalized, &options, |event| {
+        let (span, content) = event_parts(&event);
+        let content = match &event {
+            Event::Error { code, .. } => Some(error_code_name(code).as_bytes()),
+            _ => content,
+        };
+        packer.push(event_type::tag(&event), spans.original(span), content);
+    });
+
+    if u32::try_from(packer.strings.len()).is_err() {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "parse_packed: events hold 4 GiB or more of content",
+        ));
+    }
+    Ok(RString::from_slice(&packer.finish()))
+}
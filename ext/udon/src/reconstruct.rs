@@ -0,0 +1,95 @@
+//! Source reconstruction from event spans, as a round-trip self-check.
+//!
+//! The output is built from the input bytes that event spans cover, in
+//! order. Bytes no span covers are kept only if they are layout or syntax
+//! that the event types already imply (whitespace, sigils, brackets and
+//! quotes); anything else in such a gap is content the event stream does not
+//! carry, and is dropped so that the output differs from the input.
+
+use magnus::{encoding::RbEncoding, scan_args::scan_args, Error, RHash, RString, Ruby, Value};
+
+use crate::{
+    coerce, errors, event_parts, literal,
+    normalize::{check_input, normalize, rejected_nul, BOM},
+    options::{ParseOptions, SpanMode},
+    SpanFormatter,
+};
+
+/// Bytes an uncovered gap may contain without counting as lost content.
+fn implied(byte: u8) -> bool {
+    matches!(
+        byte,
+        b' ' | b'\t'
+            | b'\r'
+            | b'\n'
+            | b'|'
+            | b':'
+            | b'['
+            | b']'
+            | b'{'
+            | b'}'
+            | b'"'
+            | b'\''
+            | b'!'
+            | b';'
+    )
+}
+
+/// The input as rebuilt from the spans of its events.
+fn rebuild(input: &[u8], options: &ParseOptions) -> Vec<u8> {
+    let (normalized, offsets) = normalize(input, options);
+    let spans = SpanFormatter::new(SpanMode::Hash, None, input, offsets);
+    let mut covered = Vec::new();
+    literal::parse(&normalized, options, |event| {
+        covered.push(spans.original(event_parts(&event).0));
+    });
+    covered.sort_by_key(|span| span.start);
+
+    let mut out = Vec::with_capacity(input.len());
+    let mut cursor = 0;
+    if input.starts_with(BOM) {
+        out.extend_from_slice(BOM);
+        cursor = BOM.len();
+    }
+    let gap = |out: &mut Vec<u8>, bytes: &[u8]| {
+        out.extend(bytes.iter().copied().filter(|&byte| implied(byte)));
+    };
+    for span in covered {
+        if span.start > cursor {
+            gap(&mut out, &input[cursor..span.start]);
+        }
+        if span.end > cursor {
+            out.extend_from_slice(&input[span.start.max(cursor)..span.end]);
+            cursor = span.end;
+        }
+    }
+    gap(&mut out, &input[cursor..]);
+    out
+}
+
+/// `UdonNative.reconstruct(input, **options)`
+///
+/// For a well-formed document the result byte-equals `input`; a difference
+/// marks input the events lost. Only `strip_bom:`, `normalize_newlines:`
+/// and `nul_bytes:` among the parse options change what the parser sees;
+/// input `nul_bytes: :error` rejects raises `UdonNative::Error`, as there
+/// are no events to rebuild it from.
+pub fn reconstruct(ruby: &Ruby, args: &[Value]) -> Result<RString, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::string(ruby, input)?;
+    let options = ParseOptions::from_hash(ruby, args.keywords)?;
+    let input = unsafe { input.as_slice() };
+    check_input(ruby, input, &options)?;
+    if let Some(at) = rejected_nul(input, &options) {
+        return Err(errors::error(
+            ruby,
+            format!(
+                "NUL byte at offset {}; pass nul_bytes: :strip or :allow to reconstruct it",
+                at
+            ),
+        ));
+    }
+    let out = rebuild(input, &options);
+    Ok(RString::enc_new(out, RbEncoding::utf8()))
+}
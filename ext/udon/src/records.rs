@@ -0,0 +1,131 @@
+//! `UdonNative.records`: a document flattened to one record per attribute
+//! value, for loading into a table.
+//!
+//! Each record is `{path:, type:, value:, span:}`. The path is the trail
+//! of element names down to the attribute, joined with `/`, the attribute
+//! last as `@key`: `config/server/@port`. An element with a same-named
+//! sibling gets its 0-based position among them (`server[1]`), so every
+//! path names one place in the document; so do a key given more than once
+//! (`@class[1]`) and each item of an array value (`@tags[0]`), whose
+//! records share the array's span. Directives
+//! are transparent: the elements in their bodies are walked as if they
+//! were the directive's siblings. `type` and `value` are the value's type
+//! and Ruby value as in `Node#attributes(metadata: true)`.
+
+use std::collections::HashMap;
+
+use magnus::{Error, RArray, RHash, RString, Ruby, Symbol};
+
+use crate::{
+    coerce,
+    document::value_to_ruby,
+    normalize::check_encoding,
+    span_to_hash,
+    tree::{self, NodeKind, Tree, ROOT},
+};
+
+/// Appends the records under one node.
+struct Flatten<'a> {
+    ruby: &'a Ruby,
+    tree: &'a Tree,
+    records: RArray,
+}
+
+impl Flatten<'_> {
+    /// Records for the attributes of the elements among `children`, and
+    /// everything below them, with `prefix` the trail so far.
+    fn children(&self, children: &[usize], prefix: &str) -> Result<(), Error> {
+        let elements = self.elements(children);
+        let name = |index: usize| self.tree.node(index).name.as_deref().unwrap_or_default();
+        let mut totals: HashMap<&[u8], usize> = HashMap::new();
+        for &index in &elements {
+            *totals.entry(name(index)).or_default() += 1;
+        }
+        let mut seen: HashMap<&[u8], usize> = HashMap::new();
+        for &index in &elements {
+            let segment = String::from_utf8_lossy(name(index));
+            let nth = seen.entry(name(index)).or_default();
+            let path = if totals[name(index)] > 1 {
+                format!("{}{}[{}]", prefix, segment, nth)
+            } else {
+                format!("{}{}", prefix, segment)
+            };
+            *nth += 1;
+            self.element(index, &path)?;
+        }
+        Ok(())
+    }
+
+    /// The elements among `children`, with those of directive bodies in
+    /// the directives' places.
+    fn elements(&self, children: &[usize]) -> Vec<usize> {
+        let mut elements = Vec::new();
+        for &index in children {
+            match self.tree.node(index).kind {
+                NodeKind::Element | NodeKind::Embedded => elements.push(index),
+                NodeKind::Directive => {
+                    elements.extend(self.elements(&self.tree.node(index).children))
+                }
+                _ => {}
+            }
+        }
+        elements
+    }
+
+    fn element(&self, index: usize, path: &str) -> Result<(), Error> {
+        let node = self.tree.node(index);
+        let mut totals: HashMap<&[u8], usize> = HashMap::new();
+        for (key, _) in &node.attrs {
+            *totals.entry(key.as_slice()).or_default() += 1;
+        }
+        let mut seen: HashMap<&[u8], usize> = HashMap::new();
+        for ((key, value), span) in node.attrs.iter().zip(&node.attr_spans) {
+            let nth = seen.entry(key.as_slice()).or_default();
+            let mut path = format!("{}/@{}", path, String::from_utf8_lossy(key));
+            if totals[key.as_slice()] > 1 {
+                path.push_str(&format!("[{}]", nth));
+            }
+            *nth += 1;
+            self.value(&path, value, span)?;
+        }
+        self.children(&node.children, &format!("{}/", path))
+    }
+
+    fn value(
+        &self,
+        path: &str,
+        value: &tree::Value,
+        span: &std::ops::Range<usize>,
+    ) -> Result<(), Error> {
+        let kind = match value {
+            tree::Value::Array(items) => {
+                for (position, item) in items.iter().enumerate() {
+                    self.value(&format!("{}[{}]", path, position), item, span)?;
+                }
+                return Ok(());
+            }
+            tree::Value::Scalar { kind, .. } => kind,
+        };
+        let record = RHash::new();
+        record.aset(Symbol::new("path"), RString::new(path))?;
+        record.aset(Symbol::new("type"), Symbol::new(kind))?;
+        record.aset(Symbol::new("value"), value_to_ruby(self.ruby, value)?)?;
+        record.aset(Symbol::new("span"), span_to_hash(span))?;
+        self.records.push(record)
+    }
+}
+
+/// `UdonNative.records(input)`
+pub fn records(ruby: &Ruby, input: magnus::Value) -> Result<RArray, Error> {
+    let input = coerce::string(ruby, input)?;
+    let input = unsafe { input.as_slice() };
+    check_encoding(ruby, input)?;
+    let tree = Tree::parse(input);
+    let flatten = Flatten {
+        ruby,
+        tree: &tree,
+        records: RArray::new(),
+    };
+    flatten.children(&tree.node(ROOT).children, "")?;
+    Ok(flatten.records)
+}
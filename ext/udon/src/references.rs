@@ -0,0 +1,60 @@
+//! `UdonNative.check_references`: references that name no element.
+//!
+//! Two passes over the document tree: the first collects the id (the `by`
+//! attribute, `id` by default, as `|name[id]` writes it) of every element
+//! and embedded element, the second reports each `@[id]` reference whose id
+//! is not among them, in document order. A reference may come before the
+//! element it names.
+
+use std::collections::HashSet;
+
+use magnus::{
+    scan_args::get_kwargs, scan_args::scan_args, Error, RArray, RHash, RString, Ruby, Symbol, Value,
+};
+
+use crate::{
+    coerce,
+    merge::{id_of, DEFAULT_KEY},
+    normalize::check_encoding,
+    span_to_hash,
+    tree::{NodeKind, Tree},
+};
+
+/// `UdonNative.check_references(input, by: "id")`
+///
+/// `{id:, span:}` for every dangling reference, `span` covering the
+/// reference. Parse errors are not reported; the references the parser
+/// recovered are still checked.
+pub fn check_references(ruby: &Ruby, args: &[Value]) -> Result<RArray, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::string(ruby, input)?;
+    let kwargs = get_kwargs::<_, (), (Option<String>,), ()>(args.keywords, &[], &["by"])?;
+    let by = kwargs.optional.0.unwrap_or_else(|| DEFAULT_KEY.to_string());
+    if by.is_empty() {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "by must be a non-empty attribute name",
+        ));
+    }
+    let input = unsafe { input.as_slice() };
+    check_encoding(ruby, input)?;
+    let tree = Tree::parse(input);
+
+    let ids: HashSet<&[u8]> = tree
+        .nodes
+        .iter()
+        .filter_map(|node| id_of(node, by.as_bytes()))
+        .collect();
+    let dangling = RArray::new();
+    for node in &tree.nodes {
+        if node.kind != NodeKind::Reference || ids.contains(node.content.as_slice()) {
+            continue;
+        }
+        let hash = RHash::new();
+        hash.aset(Symbol::new("id"), RString::from_slice(&node.content))?;
+        hash.aset(Symbol::new("span"), span_to_hash(&node.span))?;
+        dangling.push(hash)?;
+    }
+    Ok(dangling)
+}
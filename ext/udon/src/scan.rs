@@ -0,0 +1,666 @@
+//! Single-pass scans that answer one question about a document without
+//! building the full array of event hashes.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    sync::Arc,
+};
+
+use magnus::{
+    prelude::*, scan_args::get_kwargs, scan_args::scan_args, Error, IntoValue, RArray, RHash,
+    RString, Ruby, Symbol, Value,
+};
+use udon_core::{Event, Parser};
+
+use crate::{
+    coerce,
+    document::{self, value_to_ruby},
+    event_parts, freeze, intern, literal,
+    normalize::{bom_len, check_encoding, check_input, normalize, rejected_nul},
+    nul_error,
+    options::ParseOptions,
+    span_to_hash,
+    tree::{self, scalar_kind, Tree},
+    Converter, SpanFormatter,
+};
+
+/// `UdonNative.comment_spans(input)`
+///
+/// Byte spans covering each comment from its opening `;` through the end of
+/// its content, suitable for splicing comments out with `byteslice`.
+pub fn comment_spans(ruby: &Ruby, input: Value) -> Result<RArray, Error> {
+    let input = coerce::string(ruby, input)?;
+    let input_bytes = unsafe { input.as_slice() };
+    check_encoding(ruby, input_bytes)?;
+    let skip = bom_len(input_bytes);
+    let result = RArray::new();
+    let mut open: Option<usize> = None;
+
+    Parser::new(&input_bytes[skip..]).parse(|event| match event {
+        Event::CommentStart { span } => open = Some(span.start + skip),
+        Event::CommentEnd { span } => {
+            if let Some(start) = open.take() {
+                let _ = result.push(span_to_hash(&(start..span.end + skip)));
+            }
+        }
+        _ => {}
+    });
+
+    Ok(result)
+}
+
+/// `UdonNative.attribute_keys(input)`
+///
+/// Every distinct attribute key in the document, in first-seen order. Keys
+/// are frozen Strings from the `intern_keys: :global` pool.
+pub fn attribute_keys(ruby: &Ruby, input: Value) -> Result<RArray, Error> {
+    let input = coerce::string(ruby, input)?;
+    let input_bytes = unsafe { input.as_slice() };
+    check_encoding(ruby, input_bytes)?;
+    let skip = bom_len(input_bytes);
+    let result = RArray::new();
+    let mut seen = HashSet::new();
+
+    Parser::new(&input_bytes[skip..]).parse(|event| {
+        if let Event::Attr { content, .. } = event {
+            if seen.insert(content.to_vec()) {
+                let _ = result.push(intern::key(ruby, &content));
+            }
+        }
+    });
+
+    Ok(result)
+}
+
+/// `UdonNative.count_elements(input, only: nil)`
+///
+/// How many elements and embedded elements have each name: a Hash from
+/// name to count in first-seen order, the names frozen Strings and those
+/// without a name counted under nil.
+/// With `only: name` just that name's count, building no Ruby object at
+/// all.
+pub fn count_elements(ruby: &Ruby, args: &[Value]) -> Result<Value, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::string(ruby, input)?;
+    let kwargs = get_kwargs::<_, (), (Option<RString>,), ()>(args.keywords, &[], &["only"])?;
+    let (only,) = kwargs.optional;
+    let input_bytes = unsafe { input.as_slice() };
+    check_encoding(ruby, input_bytes)?;
+    let skip = bom_len(input_bytes);
+    // Each name seen with its count, and where it is in `counts`.
+    let mut counts: Vec<(Option<Vec<u8>>, usize)> = Vec::new();
+    let mut index: HashMap<Option<Vec<u8>>, usize> = HashMap::new();
+    let only = only.as_ref().map(|only| unsafe { only.as_slice() });
+    let mut total = 0usize;
+    let mut after_start = false;
+
+    let mut count = |name: Option<&[u8]>| {
+        if let Some(only) = only {
+            total += usize::from(name == Some(only));
+            return;
+        }
+        let name = name.map(<[u8]>::to_vec);
+        match index.get(&name) {
+            Some(&at) => counts[at].1 += 1,
+            None => {
+                index.insert(name.clone(), counts.len());
+                counts.push((name, 1));
+            }
+        }
+    };
+    Parser::new(&input_bytes[skip..]).parse(|event| {
+        if std::mem::take(&mut after_start) {
+            match &event {
+                Event::Name { content, .. } => return count(Some(&content[..])),
+                _ => count(None),
+            }
+        }
+        after_start = matches!(
+            event,
+            Event::ElementStart { .. } | Event::EmbeddedStart { .. }
+        );
+    });
+    if after_start {
+        count(None);
+    }
+
+    if only.is_some() {
+        return Ok(total.into_value_with(ruby));
+    }
+    let result = RHash::new();
+    for (name, count) in counts {
+        let name = name.map(|name| {
+            let name = RString::from_slice(&name);
+            name.freeze();
+            name
+        });
+        result.aset(name, count)?;
+    }
+    Ok(result.as_value())
+}
+
+/// `UdonNative.skeleton(input, spans: false)`
+///
+/// The document's structure without its content: `:element_start` and
+/// `:embedded_start` with the `:name` that follows them, `:attr` with its
+/// key, `:array_start`, and the matching end events. Values, text,
+/// comments and directives are dropped without being converted, so two
+/// documents of the same shape give equal skeletons. `spans: true` adds
+/// each event's byte `:span`.
+pub fn skeleton(ruby: &Ruby, args: &[Value]) -> Result<RArray, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::string(ruby, input)?;
+    let kwargs = get_kwargs::<_, (), (Option<bool>,), ()>(args.keywords, &[], &["spans"])?;
+    let with_spans = kwargs.optional.0.unwrap_or(false);
+    let input_bytes = unsafe { input.as_slice() };
+    check_encoding(ruby, input_bytes)?;
+    let skip = bom_len(input_bytes);
+    let result = RArray::new();
+    let mut after_start = false;
+
+    Parser::new(&input_bytes[skip..]).parse(|event| {
+        let (kind, content) = match &event {
+            Event::ElementStart { .. } => ("element_start", None),
+            Event::ElementEnd { .. } => ("element_end", None),
+            Event::EmbeddedStart { .. } => ("embedded_start", None),
+            Event::EmbeddedEnd { .. } => ("embedded_end", None),
+            Event::ArrayStart { .. } => ("array_start", None),
+            Event::ArrayEnd { .. } => ("array_end", None),
+            Event::Attr { content, .. } => ("attr", Some(intern::key(ruby, content))),
+            Event::Name { content, .. } if after_start => {
+                ("name", Some(RString::from_slice(content)))
+            }
+            _ => {
+                after_start = false;
+                return;
+            }
+        };
+        after_start = matches!(
+            event,
+            Event::ElementStart { .. } | Event::EmbeddedStart { .. }
+        );
+        let hash = RHash::new();
+        let _ = hash.aset(Symbol::new("type"), Symbol::new(kind));
+        if let Some(content) = content {
+            let _ = hash.aset(Symbol::new("content"), content);
+        }
+        if with_spans {
+            let span = event_parts(&event).0;
+            let span = span.start + skip..span.end + skip;
+            let _ = hash.aset(Symbol::new("span"), span_to_hash(&span));
+        }
+        let _ = result.push(hash);
+    });
+
+    Ok(result)
+}
+
+/// `UdonNative.find_element(input, name, **options)`
+///
+/// `{span:, events:}` for the first element or embedded element called
+/// `name`: the span from its start through its end, and its events as
+/// `parse` would give them with `options`. Nil when there is none. For
+/// input `nul_bytes: :error` rejects, the span is the first NUL's and the
+/// events are just its `:nul_byte` error.
+///
+/// No event outside the match is converted to a hash, and the input is
+/// parsed only as far as `match_extent` needs to be sure of the match.
+pub fn find_element(ruby: &Ruby, args: &[Value]) -> Result<Option<RHash>, Error> {
+    let args = scan_args::<(Value, RString), (), (), (), RHash, ()>(args)?;
+    let (input, name) = args.required;
+    let input = coerce::bytes(ruby, input)?;
+    let options = ParseOptions::from_hash(ruby, args.keywords)?;
+    let input_bytes = &input[..];
+    let name = unsafe { name.as_slice() }.to_vec();
+    let name = &name[..];
+
+    check_input(ruby, input_bytes, &options)?;
+    if let Some(at) = rejected_nul(input_bytes, &options) {
+        let spans = SpanFormatter::new(options.spans, options.tab_width, input_bytes, None)
+            .with_base(options.span_base);
+        let result = RHash::new();
+        result.aset(
+            Symbol::new("span"),
+            span_to_hash(&spans.shifted(at..at + 1)),
+        )?;
+        result.aset(
+            Symbol::new("events"),
+            RArray::from_vec(vec![nul_error(input_bytes, at..at + 1, &options)]),
+        )?;
+        return Ok(Some(freeze::finish(result, &options)?));
+    }
+    let (normalized, offsets) = normalize(input_bytes, &options);
+    let Some(extent) = match_extent(&normalized, name, &options) else {
+        return Ok(None);
+    };
+    let mut converter = Converter::new(ruby, input_bytes, &options, offsets);
+    let events = RArray::new();
+    // The start of the structure whose name comes next, and whether it is
+    // embedded; then the match's start offset and open depth once found.
+    let mut opening: Option<(Range<usize>, bool)> = None;
+    let mut found: Option<(usize, usize)> = None;
+    let mut span: Option<Range<usize>> = None;
+
+    literal::parse(&normalized[..extent], &options, |event| {
+        if span.is_some() {
+            return;
+        }
+        let Some((start, ref mut depth)) = found else {
+            // A start event waits for the next one, which may be the name
+            // that makes it the match; events before the match are only
+            // followed, so `parent_name` and handler paths see them.
+            if let Some((start, embedded)) = opening.take() {
+                let open = match embedded {
+                    false => Event::ElementStart {
+                        span: start.clone(),
+                    },
+                    true => Event::EmbeddedStart {
+                        span: start.clone(),
+                    },
+                };
+                if matches!(&event, Event::Name { content, .. } if &content[..] == name) {
+                    for event in [&open, &event] {
+                        if let Some(hash) = converter.convert(event) {
+                            let _ = events.push(hash);
+                        }
+                    }
+                    found = Some((start.start, 1));
+                    return;
+                }
+                converter.skip(&open);
+            }
+            match event {
+                Event::ElementStart { span } => opening = Some((span, false)),
+                Event::EmbeddedStart { span } => opening = Some((span, true)),
+                event => converter.skip(&event),
+            }
+            return;
+        };
+        match event {
+            Event::ElementStart { .. } | Event::EmbeddedStart { .. } => *depth += 1,
+            Event::ElementEnd { .. } | Event::EmbeddedEnd { .. } => *depth -= 1,
+            _ => {}
+        }
+        if let Some(hash) = converter.convert(&event) {
+            let _ = events.push(hash);
+        }
+        if *depth == 0 {
+            span = Some(start..event_end(&event));
+        }
+    });
+    converter.take_failure()?;
+
+    let Some(span) = span else {
+        return Ok(None);
+    };
+    let result = RHash::new();
+    result.aset(
+        Symbol::new("span"),
+        span_to_hash(&converter.spans.reported(&span)),
+    )?;
+    result.aset(Symbol::new("events"), events)?;
+    Ok(Some(freeze::finish(result, &options)?))
+}
+
+fn event_end(event: &Event) -> usize {
+    crate::event_parts(event).0.end
+}
+
+/// Length of the prefix of `normalized` (see `tree::by_prefixes`) that
+/// holds the first element or embedded element called `name` for sure, or
+/// `None` when there is none.
+fn match_extent(normalized: &[u8], name: &[u8], options: &ParseOptions) -> Option<usize> {
+    tree::by_prefixes(normalized, |prefix, whole| {
+        let mut depth = 0usize;
+        let mut after_start = false;
+        // The match's open depth once its name is seen, whether it has
+        // ended, and whether an event the cut cannot make came after.
+        let mut found: Option<usize> = None;
+        let mut ended = false;
+        let mut confirmed = false;
+
+        literal::parse(prefix, options, |event| {
+            if ended {
+                confirmed |= !closed_by_cut(&event);
+                return;
+            }
+            let awaiting_name = std::mem::replace(
+                &mut after_start,
+                matches!(
+                    event,
+                    Event::ElementStart { .. } | Event::EmbeddedStart { .. }
+                ),
+            );
+            match event {
+                Event::ElementStart { .. } | Event::EmbeddedStart { .. } => depth += 1,
+                Event::ElementEnd { .. } | Event::EmbeddedEnd { .. } => {
+                    ended = found == Some(depth);
+                    depth = depth.saturating_sub(1);
+                }
+                Event::Name { ref content, .. }
+                    if awaiting_name && found.is_none() && &content[..] == name =>
+                {
+                    found = Some(depth)
+                }
+                _ => {}
+            }
+        });
+        match found {
+            Some(_) if ended && confirmed => Some(Some(prefix.len())),
+            _ if whole => Some(found.map(|_| prefix.len())),
+            _ => None,
+        }
+    })
+}
+
+/// Whether the end of a cut prefix can have made `event`: the ends of the
+/// structures open at the cut, and errors and warnings for those that
+/// cannot end there.
+fn closed_by_cut(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::ElementEnd { .. }
+            | Event::EmbeddedEnd { .. }
+            | Event::DirectiveEnd { .. }
+            | Event::ArrayEnd { .. }
+            | Event::FreeformEnd { .. }
+            | Event::CommentEnd { .. }
+            | Event::Error { .. }
+            | Event::Warning { .. }
+    )
+}
+
+/// `UdonNative.select_elements(input, name, format: :nodes, nested: :include, **options)`
+///
+/// Every element or embedded element called `name`, in document order:
+/// as `UdonNative::Node`s of a tree holding only the matches (`format:
+/// :nodes`), or as arrays of their events as `parse` would give them with
+/// `options` (`format: :events`; the `:nodes` form takes no options). A
+/// match inside another is returned too with `nested: :include`, and only
+/// as part of the outer one with `nested: :skip`. For input `nul_bytes:
+/// :error` rejects, `format: :events` gives one array holding just the
+/// `:nul_byte` error.
+///
+/// Nothing outside a match is converted or added to the tree, but unlike
+/// `find_element` the whole input is scanned.
+pub fn select_elements(ruby: &Ruby, args: &[Value]) -> Result<RArray, Error> {
+    let args = scan_args::<(Value, RString), (), (), (), RHash, ()>(args)?;
+    let (input, name) = args.required;
+    let input = coerce::bytes(ruby, input)?;
+    let keywords = args.keywords;
+    let events = match keywords.delete::<_, Option<Symbol>>(Symbol::new("format"))? {
+        None => false,
+        Some(format) => match format.name()?.as_ref() {
+            "nodes" => false,
+            "events" => true,
+            other => {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    format!("invalid value for format: :{}", other),
+                ))
+            }
+        },
+    };
+    let nested = match keywords.delete::<_, Option<Symbol>>(Symbol::new("nested"))? {
+        None => true,
+        Some(nested) => match nested.name()?.as_ref() {
+            "include" => true,
+            "skip" => false,
+            other => {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    format!("invalid value for nested: :{}", other),
+                ))
+            }
+        },
+    };
+    let input_bytes = &input[..];
+    let name = unsafe { name.as_slice() }.to_vec();
+    let name = &name[..];
+
+    if !events {
+        if !keywords.is_empty() {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "select_elements: parse options apply to format: :events only",
+            ));
+        }
+        check_encoding(ruby, input_bytes)?;
+        let (tree, matches) = Tree::select(input_bytes, name, nested);
+        return Ok(document::nodes(&Arc::new(tree), &matches));
+    }
+
+    let options = ParseOptions::from_hash(ruby, keywords)?;
+    check_input(ruby, input_bytes, &options)?;
+    let result = RArray::new();
+    if let Some(at) = rejected_nul(input_bytes, &options) {
+        result.push(RArray::from_vec(vec![nul_error(
+            input_bytes,
+            at..at + 1,
+            &options,
+        )]))?;
+        return freeze::finish(result, &options);
+    }
+    let (normalized, offsets) = normalize(input_bytes, &options);
+    let mut converter = Converter::new(ruby, input_bytes, &options, offsets);
+    // The open matches' events and how many structures are open inside
+    // each's start; the start of the structure whose name comes next, and
+    // whether it is embedded; and how many structures are open.
+    let mut open: Vec<(RArray, usize)> = Vec::new();
+    let mut opening: Option<(Range<usize>, bool)> = None;
+    let mut depth = 0;
+
+    literal::parse(&normalized, &options, |event| {
+        // A start event waits for the next one, which may be the name that
+        // makes it a match.
+        if let Some((start, embedded)) = opening.take() {
+            let start = match embedded {
+                false => Event::ElementStart { span: start },
+                true => Event::EmbeddedStart { span: start },
+            };
+            let matched = matches!(&event, Event::Name { content, .. } if &content[..] == name)
+                && (nested || open.is_empty());
+            depth += 1;
+            if matched {
+                let events = RArray::new();
+                let _ = result.push(events);
+                open.push((events, depth));
+            }
+            push(&mut converter, &open, &start);
+        }
+        match event {
+            Event::ElementStart { span } => opening = Some((span, false)),
+            Event::EmbeddedStart { span } => opening = Some((span, true)),
+            Event::ElementEnd { .. } | Event::EmbeddedEnd { .. } => {
+                push(&mut converter, &open, &event);
+                if open.last().is_some_and(|&(_, level)| level == depth) {
+                    open.pop();
+                }
+                depth = depth.saturating_sub(1);
+            }
+            event => push(&mut converter, &open, &event),
+        }
+    });
+    converter.take_failure()?;
+    freeze::finish(result, &options)
+}
+
+/// Convert `event` into each open match's events, or only follow it when
+/// no match is open.
+fn push(converter: &mut Converter, open: &[(RArray, usize)], event: &Event) {
+    if open.is_empty() {
+        converter.skip(event);
+        return;
+    }
+    if let Some(hash) = converter.convert(event) {
+        for (events, _) in open {
+            let _ = events.push(hash);
+        }
+    }
+}
+
+/// Follows the parse for `pluck`.
+struct Pluck<'a> {
+    /// The input the parser's offsets index.
+    source: &'a [u8],
+    attribute: &'a [u8],
+    element: Option<&'a [u8]>,
+    flatten: bool,
+    /// The open structures, innermost last: whether each is an element or
+    /// embedded element, and its name once seen.
+    open: Vec<(bool, Option<Vec<u8>>)>,
+    /// The last event opened a structure, whose name is next.
+    after_start: bool,
+    /// The key span of a wanted attribute whose value is next.
+    wanted: Option<Range<usize>>,
+    /// The wanted value's open arrays, with the offsets they start at.
+    arrays: Vec<(Vec<tree::Value>, usize)>,
+    /// Values found, with their spans.
+    values: Vec<(tree::Value, Range<usize>)>,
+}
+
+impl Pluck<'_> {
+    fn event(&mut self, event: &Event) {
+        if !self.arrays.is_empty() {
+            return self.array_event(event);
+        }
+        if let Some(key) = self.wanted.take() {
+            if let Event::ArrayStart { span } = event {
+                return self.arrays.push((Vec::new(), span.start));
+            }
+            if let Some(value) = attr_value(event) {
+                return self.values.push((value, event_parts(event).0.clone()));
+            }
+            self.values.push((tree::Value::flag(), key));
+        }
+
+        let after_start = std::mem::take(&mut self.after_start);
+        match event {
+            Event::ElementStart { .. } | Event::EmbeddedStart { .. } => {
+                self.open.push((true, None));
+                self.after_start = true;
+            }
+            Event::DirectiveStart { .. } => {
+                self.open.push((false, None));
+                self.after_start = true;
+            }
+            Event::ElementEnd { .. } | Event::EmbeddedEnd { .. } | Event::DirectiveEnd { .. } => {
+                self.open.pop();
+            }
+            Event::Name { content, .. } if after_start => {
+                if let Some((_, name)) = self.open.last_mut() {
+                    *name = Some(content.to_vec());
+                }
+            }
+            Event::Attr { content, span } if &content[..] == self.attribute => {
+                let in_scope = match self.element {
+                    None => true,
+                    Some(element) => matches!(
+                        self.open.last(),
+                        Some((true, Some(name))) if &name[..] == element
+                    ),
+                };
+                if in_scope {
+                    let written = &self.source[span.clone()];
+                    self.wanted = Some(
+                        crate::key_span(written, span, content).unwrap_or_else(|| span.clone()),
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// An event inside the wanted value's arrays.
+    fn array_event(&mut self, event: &Event) {
+        match event {
+            Event::ArrayStart { span } => self.arrays.push((Vec::new(), span.start)),
+            Event::ArrayEnd { span } => {
+                let (items, start) = self.arrays.pop().expect("open array");
+                let array = tree::Value::Array(items);
+                match self.arrays.last_mut() {
+                    Some((outer, _)) => outer.push(array),
+                    None if self.flatten => {}
+                    None => self.values.push((array, start..span.end.max(start))),
+                }
+            }
+            event => {
+                if let Some(value) = attr_value(event) {
+                    match self.arrays.last_mut() {
+                        Some((items, _)) if !self.flatten => items.push(value),
+                        _ => self.values.push((value, event_parts(event).0.clone())),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The value `event` gives an attribute, if it is a value event.
+fn attr_value(event: &Event) -> Option<tree::Value> {
+    let (kind, content) = match event {
+        Event::Interpolation { content, .. } => ("interpolation", &content[..]),
+        event => scalar_kind(event)?,
+    };
+    Some(tree::Value::Scalar {
+        kind,
+        content: content.to_vec(),
+    })
+}
+
+/// `UdonNative.pluck(input, attribute:, element: nil, with_spans: false, flatten: false)`
+///
+/// The value of every `attribute` in the document, in order, typed as in
+/// `Node#attributes`; with `element:` only those of elements or embedded
+/// elements of that name. `with_spans: true` gives `[value, span]` pairs;
+/// a flag attribute's span is its key's. An array value is one Array, or
+/// with `flatten: true` its items one by one (those of nested arrays too),
+/// each with its own span.
+pub fn pluck(ruby: &Ruby, args: &[Value]) -> Result<RArray, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::string(ruby, input)?;
+    let kwargs = get_kwargs::<_, (RString,), (Option<RString>, Option<bool>, Option<bool>), ()>(
+        args.keywords,
+        &["attribute"],
+        &["element", "with_spans", "flatten"],
+    )?;
+    let (attribute,) = kwargs.required;
+    let (element, with_spans, flatten) = kwargs.optional;
+    let input_bytes = unsafe { input.as_slice() };
+    check_encoding(ruby, input_bytes)?;
+    let skip = bom_len(input_bytes);
+
+    let mut pluck = Pluck {
+        source: &input_bytes[skip..],
+        attribute: unsafe { attribute.as_slice() },
+        element: element
+            .as_ref()
+            .map(|element| unsafe { element.as_slice() }),
+        flatten: flatten.unwrap_or(false),
+        open: Vec::new(),
+        after_start: false,
+        wanted: None,
+        arrays: Vec::new(),
+        values: Vec::new(),
+    };
+    Parser::new(&input_bytes[skip..]).parse(|event| pluck.event(&event));
+    if let Some(key) = pluck.wanted.take() {
+        pluck.values.push((tree::Value::flag(), key));
+    }
+
+    let result = RArray::with_capacity(pluck.values.len());
+    for (value, span) in &pluck.values {
+        let value = value_to_ruby(ruby, value)?;
+        if with_spans.unwrap_or(false) {
+            let span = span_to_hash(&(span.start + skip..span.end + skip));
+            result.push((value, span))?;
+        } else {
+            result.push(value)?;
+        }
+    }
+    Ok(result)
+}
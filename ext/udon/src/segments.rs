@@ -0,0 +1,91 @@
+//! Literal/expression segments of interpolated values, for
+//! `split_interpolations: true`.
+//!
+//! The parser reports `!{{expr}}` in text and value positions as
+//! `:interpolation` events, but inside a quoted string it is part of the
+//! `:string_value` content. This splits such content the same way, so a
+//! renderer gets the template already tokenized.
+//!
+//! `path_parts` goes one level further down for `interpolations:
+//! :structured`, splitting an expression into the segments of its path.
+
+use std::ops::Range;
+
+use magnus::{RArray, RHash, RString, Symbol};
+
+pub const OPEN: &[u8] = b"!{{";
+const CLOSE: &[u8] = b"}}";
+
+pub enum Segment<'a> {
+    Literal(&'a [u8]),
+    /// The text between `!{{` and `}}`, as an `:interpolation` event's
+    /// content would be.
+    Expr(&'a [u8]),
+}
+
+/// Segments of `content` in order, or `None` when it has no complete
+/// `!{{...}}`. Empty literals are left out; an unclosed `!{{` is literal.
+pub fn split(content: &[u8]) -> Option<Vec<Segment<'_>>> {
+    let mut segments = Vec::new();
+    let mut rest = content;
+    while let Some(open) = memchr::memmem::find(rest, OPEN) {
+        let inner = &rest[open + OPEN.len()..];
+        let Some(close) = memchr::memmem::find(inner, CLOSE) else {
+            break;
+        };
+        if open > 0 {
+            segments.push(Segment::Literal(&rest[..open]));
+        }
+        segments.push(Segment::Expr(&inner[..close]));
+        rest = &inner[close + CLOSE.len()..];
+    }
+    if segments.is_empty() {
+        return None;
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest));
+    }
+    Some(segments)
+}
+
+/// `[{literal: "a "}, {expr: "x"}, ...]` for `content`, or `None` when it is
+/// not interpolated.
+pub fn to_ruby(content: &[u8]) -> Option<RArray> {
+    let segments = split(content)?;
+    let array = RArray::with_capacity(segments.len());
+    for segment in segments {
+        let hash = RHash::new();
+        let _ = match segment {
+            Segment::Literal(text) => hash.aset(Symbol::new("literal"), RString::from_slice(text)),
+            Segment::Expr(expr) => hash.aset(Symbol::new("expr"), RString::from_slice(expr)),
+        };
+        let _ = array.push(hash);
+    }
+    Some(array)
+}
+
+/// The ranges of the segments of the path `expression`, in order.
+///
+/// Expressions have no grammar of their own, so the split is a plain one
+/// at every `.`: `user.address.city` is three segments, `a..b` has an empty
+/// one in the middle, and brackets or backslashes are part of the segment
+/// they are in. Blanks around a segment are not part of it.
+pub fn path_parts(expression: &[u8]) -> Vec<Range<usize>> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for end in memchr::memchr_iter(b'.', expression).chain([expression.len()]) {
+        let segment = &expression[start..end];
+        let leading = segment
+            .iter()
+            .take_while(|b| b.is_ascii_whitespace())
+            .count();
+        let trailing = segment[leading..]
+            .iter()
+            .rev()
+            .take_while(|b| b.is_ascii_whitespace())
+            .count();
+        parts.push(start + leading..end - trailing);
+        start = end + 1;
+    }
+    parts
+}
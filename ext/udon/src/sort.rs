@@ -0,0 +1,268 @@
+//! Deterministic ordering of attributes and sibling elements for emitting.
+//!
+//! The flat event stream is folded into blocks (a start event, its name, its
+//! attribute groups, its body, and its end event), reordered, and flattened
+//! again. Sorts are stable, so re-sorting sorted output is a no-op.
+
+use std::cmp::Ordering;
+
+/// An event copied out of its Ruby hash.
+pub struct OwnedEvent {
+    pub kind: String,
+    pub content: Option<Vec<u8>>,
+    /// Position in the original event array, for error reporting.
+    pub index: usize,
+}
+
+#[derive(Default)]
+pub struct SortOptions {
+    /// Sort attributes by key.
+    pub attributes: bool,
+    /// Keys that sort first, in this order, ahead of the lexicographic rest.
+    pub priority: Vec<Vec<u8>>,
+    /// Order sibling elements by the value of this attribute.
+    pub elements_by: Option<Vec<u8>>,
+}
+
+impl SortOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.attributes || self.elements_by.is_some()
+    }
+}
+
+enum Node {
+    Event(OwnedEvent),
+    Block(Block),
+}
+
+struct Block {
+    /// The start event plus its name event, if any.
+    open: Vec<OwnedEvent>,
+    /// One group per attribute: the key event followed by its value events.
+    attrs: Vec<Vec<OwnedEvent>>,
+    body: Vec<Node>,
+    close: Option<OwnedEvent>,
+}
+
+impl Block {
+    fn kind(&self) -> &str {
+        self.open.first().map(|e| e.kind.as_str()).unwrap_or("")
+    }
+
+    fn attr_value(&self, key: &[u8]) -> Option<&OwnedEvent> {
+        self.attrs
+            .iter()
+            .find(|group| group[0].content.as_deref() == Some(key))
+            .and_then(|group| group.get(1))
+    }
+}
+
+fn is_start(kind: &str) -> bool {
+    kind.ends_with("_start")
+}
+
+fn is_end(kind: &str) -> bool {
+    kind.ends_with("_end")
+}
+
+fn has_name(kind: &str) -> bool {
+    matches!(kind, "element_start" | "embedded_start" | "directive_start")
+}
+
+/// Reorder `events` per `options`. Structure is not validated here; unbalanced
+/// input comes back in an order the emitter will still reject.
+pub fn sort_events(events: Vec<OwnedEvent>, options: &SortOptions) -> Vec<OwnedEvent> {
+    let mut nodes = build(events);
+    sort_nodes(&mut nodes, options);
+    let mut out = Vec::new();
+    flatten(nodes, &mut out);
+    out
+}
+
+fn build(events: Vec<OwnedEvent>) -> Vec<Node> {
+    let mut root: Vec<Node> = Vec::new();
+    let mut stack: Vec<Block> = Vec::new();
+    // Attribute group being collected, and the array depth inside it.
+    let mut group: Option<Vec<OwnedEvent>> = None;
+    let mut group_depth = 0usize;
+
+    for event in events {
+        if let Some(current) = group.as_mut() {
+            let kind = event.kind.as_str();
+            let continues =
+                group_depth > 0 || (current.len() == 1 && kind != "attr" && !is_end(kind));
+            if continues && !matches!(kind, "element_start" | "comment_start") {
+                if kind == "array_start" {
+                    group_depth += 1;
+                } else if kind == "array_end" {
+                    group_depth = group_depth.saturating_sub(1);
+                }
+                current.push(event);
+                continue;
+            }
+            let finished = group.take().expect("group");
+            group_depth = 0;
+            if let Some(block) = stack.last_mut() {
+                block.attrs.push(finished);
+            }
+        }
+
+        let kind = event.kind.clone();
+        if kind == "attr" && !stack.is_empty() {
+            group = Some(vec![event]);
+        } else if kind == "name"
+            && stack
+                .last()
+                .is_some_and(|b| b.open.len() == 1 && b.attrs.is_empty() && has_name(b.kind()))
+        {
+            stack.last_mut().expect("block").open.push(event);
+        } else if is_start(&kind) {
+            stack.push(Block {
+                open: vec![event],
+                attrs: Vec::new(),
+                body: Vec::new(),
+                close: None,
+            });
+        } else if is_end(&kind) && !stack.is_empty() {
+            let mut block = stack.pop().expect("block");
+            block.close = Some(event);
+            push_node(&mut stack, &mut root, Node::Block(block));
+        } else {
+            push_node(&mut stack, &mut root, Node::Event(event));
+        }
+    }
+
+    if let Some(finished) = group.take() {
+        if let Some(block) = stack.last_mut() {
+            block.attrs.push(finished);
+        }
+    }
+    // Unclosed blocks are flattened as-is so the emitter can report them.
+    while let Some(block) = stack.pop() {
+        push_node(&mut stack, &mut root, Node::Block(block));
+    }
+    root
+}
+
+fn push_node(stack: &mut [Block], root: &mut Vec<Node>, node: Node) {
+    match stack.last_mut() {
+        Some(parent) => parent.body.push(node),
+        None => root.push(node),
+    }
+}
+
+fn sort_nodes(nodes: &mut Vec<Node>, options: &SortOptions) {
+    for node in nodes.iter_mut() {
+        if let Node::Block(block) = node {
+            if options.attributes {
+                block
+                    .attrs
+                    .sort_by(|a, b| compare_keys(key_of(a), key_of(b), &options.priority));
+            }
+            sort_nodes(&mut block.body, options);
+        }
+    }
+    if let Some(key) = &options.elements_by {
+        sort_siblings(nodes, key);
+    }
+}
+
+fn key_of(group: &[OwnedEvent]) -> &[u8] {
+    group[0].content.as_deref().unwrap_or(b"")
+}
+
+fn compare_keys(a: &[u8], b: &[u8], priority: &[Vec<u8>]) -> Ordering {
+    let rank = |key: &[u8]| {
+        priority
+            .iter()
+            .position(|p| p.as_slice() == key)
+            .unwrap_or(priority.len())
+    };
+    rank(a).cmp(&rank(b)).then_with(|| a.cmp(b))
+}
+
+/// Stable-sort runs of sibling elements by an attribute value. Comments
+/// directly preceding an element move with it; any other node (text, values)
+/// ends the run and stays in place.
+fn sort_siblings(nodes: &mut Vec<Node>, key: &[u8]) {
+    let mut result: Vec<Node> = Vec::with_capacity(nodes.len());
+    // Units of the current run: leading comments plus one element.
+    let mut run: Vec<Vec<Node>> = Vec::new();
+    let mut pending: Vec<Node> = Vec::new();
+
+    for node in nodes.drain(..) {
+        match &node {
+            Node::Block(block) if block.kind() == "comment_start" => pending.push(node),
+            Node::Block(block) if block.kind() == "element_start" => {
+                pending.push(node);
+                run.push(std::mem::take(&mut pending));
+            }
+            _ => {
+                flush_run(&mut run, key, &mut result);
+                result.append(&mut pending);
+                result.push(node);
+            }
+        }
+    }
+    flush_run(&mut run, key, &mut result);
+    result.append(&mut pending);
+    *nodes = result;
+}
+
+fn flush_run(run: &mut Vec<Vec<Node>>, key: &[u8], result: &mut Vec<Node>) {
+    run.sort_by(|a, b| compare_values(sort_value(a, key), sort_value(b, key)));
+    for unit in run.drain(..) {
+        result.extend(unit);
+    }
+}
+
+fn sort_value<'a>(unit: &'a [Node], key: &[u8]) -> Option<&'a OwnedEvent> {
+    match unit.last() {
+        Some(Node::Block(block)) => block.attr_value(key),
+        _ => None,
+    }
+}
+
+/// Numbers compare numerically, everything else byte-wise; missing values sort last.
+fn compare_values(a: Option<&OwnedEvent>, b: Option<&OwnedEvent>) -> Ordering {
+    match (a, b) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(a), Some(b)) => {
+            let a_bytes = a.content.as_deref().unwrap_or(b"");
+            let b_bytes = b.content.as_deref().unwrap_or(b"");
+            match (as_number(a), as_number(b)) {
+                (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+                _ => a_bytes.cmp(b_bytes),
+            }
+        }
+    }
+}
+
+fn as_number(event: &OwnedEvent) -> Option<f64> {
+    if !matches!(event.kind.as_str(), "integer" | "float") {
+        return None;
+    }
+    std::str::from_utf8(event.content.as_deref()?)
+        .ok()?
+        .replace('_', "")
+        .parse()
+        .ok()
+}
+
+fn flatten(nodes: Vec<Node>, out: &mut Vec<OwnedEvent>) {
+    for node in nodes {
+        match node {
+            Node::Event(event) => out.push(event),
+            Node::Block(block) => {
+                out.extend(block.open);
+                for group in block.attrs {
+                    out.extend(group);
+                }
+                flatten(block.body, out);
+                out.extend(block.close);
+            }
+        }
+    }
+}
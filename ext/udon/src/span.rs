@@ -0,0 +1,164 @@
+//! `UdonNative::Span`: byte spans as value objects, for `spans: :object`.
+//!
+//! A Span is two offsets, so it is built during event conversion at the
+//! same cost as a `{start:, end:}` hash. It compares equal to such a hash,
+//! so code written against hash spans keeps working.
+
+use std::ops::Range;
+
+use magnus::{
+    function, method, prelude::*, Error, RHash, RModule, RString, Ruby, Symbol, TryConvert, Value,
+};
+
+use crate::span_to_hash;
+
+#[magnus::wrap(class = "UdonNative::Span", free_immediately, size)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Span {
+    start: usize,
+    end: usize,
+}
+
+impl From<Range<usize>> for Span {
+    fn from(range: Range<usize>) -> Self {
+        Span {
+            start: range.start,
+            end: range.end,
+        }
+    }
+}
+
+impl Span {
+    /// `Span.new(start, end)`
+    fn new(ruby: &Ruby, start: usize, end: usize) -> Result<Self, Error> {
+        if start > end {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                format!("span start {} is after its end {}", start, end),
+            ));
+        }
+        Ok(Span { start, end })
+    }
+
+    fn start(&self) -> usize {
+        self.start
+    }
+
+    fn end(&self) -> usize {
+        self.end
+    }
+
+    fn length(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// `cover?(offset)` or `cover?(span)`; like `to_range.cover?`, the end is
+    /// exclusive, so an empty span covers no offset.
+    fn cover(ruby: &Ruby, rb_self: &Self, other: Value) -> Result<bool, Error> {
+        if let Ok(offset) = usize::try_convert(other) {
+            return Ok(rb_self.start <= offset && offset < rb_self.end);
+        }
+        let other = span_of(ruby, other)?;
+        Ok(rb_self.start <= other.start && other.end <= rb_self.end)
+    }
+
+    /// The smallest span covering both.
+    fn union(ruby: &Ruby, rb_self: &Self, other: Value) -> Result<Self, Error> {
+        let other = span_of(ruby, other)?;
+        Ok(Span {
+            start: rb_self.start.min(other.start),
+            end: rb_self.end.max(other.end),
+        })
+    }
+
+    /// The overlap of both, or nil when they are disjoint. Spans that only
+    /// touch give an empty span at the shared offset.
+    fn intersect(ruby: &Ruby, rb_self: &Self, other: Value) -> Result<Option<Self>, Error> {
+        let other = span_of(ruby, other)?;
+        let start = rb_self.start.max(other.start);
+        let end = rb_self.end.min(other.end);
+        Ok((start <= end).then_some(Span { start, end }))
+    }
+
+    /// `start...end`
+    fn to_range(ruby: &Ruby, rb_self: &Self) -> Result<magnus::Range, Error> {
+        ruby.range_new(rb_self.start, rb_self.end, true)
+    }
+
+    /// The spanned bytes of `input`, as `input.byteslice` returns them.
+    fn slice(&self, input: RString) -> Result<Value, Error> {
+        input.funcall("byteslice", (self.start, self.length()))
+    }
+
+    fn to_h(&self) -> RHash {
+        span_to_hash(&(self.start..self.end))
+    }
+
+    /// Equal to a Span or a `{start:, end:}` Hash with the same offsets.
+    fn equal(&self, other: Value) -> Result<bool, Error> {
+        if let Ok(other) = <&Span>::try_convert(other) {
+            return Ok(self == other);
+        }
+        let Some(hash) = RHash::from_value(other) else {
+            return Ok(false);
+        };
+        if hash.len() != 2 {
+            return Ok(false);
+        }
+        let start: Option<Value> = hash.lookup(Symbol::new("start"))?;
+        let end: Option<Value> = hash.lookup(Symbol::new("end"))?;
+        let offset = |value: Option<Value>| value.and_then(|v| usize::try_convert(v).ok());
+        Ok(offset(start) == Some(self.start) && offset(end) == Some(self.end))
+    }
+
+    /// `eql?` is Span-only, so that `hash` can stay consistent with it.
+    fn eql(&self, other: Value) -> bool {
+        <&Span>::try_convert(other).is_ok_and(|other| self == other)
+    }
+
+    fn ruby_hash(&self) -> i64 {
+        use std::hash::{BuildHasher, BuildHasherDefault, DefaultHasher};
+        BuildHasherDefault::<DefaultHasher>::default().hash_one(self) as i64
+    }
+
+    fn inspect(&self) -> String {
+        format!("#<UdonNative::Span {}...{}>", self.start, self.end)
+    }
+}
+
+/// Read a Span or a `{start:, end:}` hash as a byte range.
+pub fn span_of(ruby: &Ruby, span: Value) -> Result<Range<usize>, Error> {
+    if let Ok(span) = <&Span>::try_convert(span) {
+        return Ok(span.start..span.end);
+    }
+    if let Some(hash) = RHash::from_value(span) {
+        let start: usize = hash.fetch(Symbol::new("start"))?;
+        let end: usize = hash.fetch(Symbol::new("end"))?;
+        return Ok(start..end);
+    }
+    Err(Error::new(
+        ruby.exception_type_error(),
+        "span must be a UdonNative::Span or a {start:, end:} Hash",
+    ))
+}
+
+/// Define `UdonNative::Span`.
+pub fn define(ruby: &Ruby, module: RModule) -> Result<(), Error> {
+    let class = module.define_class("Span", ruby.class_object())?;
+    class.define_singleton_method("new", function!(Span::new, 2))?;
+    class.define_method("start", method!(Span::start, 0))?;
+    class.define_method("end", method!(Span::end, 0))?;
+    class.define_method("length", method!(Span::length, 0))?;
+    class.define_method("size", method!(Span::length, 0))?;
+    class.define_method("cover?", method!(Span::cover, 1))?;
+    class.define_method("union", method!(Span::union, 1))?;
+    class.define_method("intersect", method!(Span::intersect, 1))?;
+    class.define_method("to_range", method!(Span::to_range, 0))?;
+    class.define_method("slice", method!(Span::slice, 1))?;
+    class.define_method("to_h", method!(Span::to_h, 0))?;
+    class.define_method("==", method!(Span::equal, 1))?;
+    class.define_method("eql?", method!(Span::eql, 1))?;
+    class.define_method("hash", method!(Span::ruby_hash, 0))?;
+    class.define_method("inspect", method!(Span::inspect, 0))?;
+    Ok(())
+}
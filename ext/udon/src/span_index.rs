@@ -0,0 +1,114 @@
+//! Innermost-span lookup for `Document#node_at` and `UdonNative.event_at`.
+//!
+//! Spans come in document order (a pre-order walk, so starts never
+//! decrease) and nest: each entry's span lies within its parent's. The
+//! innermost entry covering an offset is then found by a binary search for
+//! the last entry starting at or before it, followed by a walk up its
+//! parents to the first one whose span still reaches past the offset. The
+//! walk is bounded by nesting depth, not document size.
+
+use std::ops::Range;
+
+use magnus::{prelude::*, Error, RArray, RHash, Ruby, Symbol, TryConvert, Value};
+
+use crate::span::span_of;
+
+pub struct SpanIndex {
+    spans: Vec<Range<usize>>,
+    parents: Vec<Option<usize>>,
+}
+
+impl SpanIndex {
+    /// `spans[i]` is entry `i`'s span and `parents[i]` its enclosing entry;
+    /// entries must be in document order.
+    pub fn new(spans: Vec<Range<usize>>, parents: Vec<Option<usize>>) -> Self {
+        debug_assert_eq!(spans.len(), parents.len());
+        SpanIndex { spans, parents }
+    }
+
+    /// The innermost entry whose span covers `offset` (start inclusive, end
+    /// exclusive), or `None` when no span does.
+    pub fn innermost(&self, offset: usize) -> Option<usize> {
+        let after = self.spans.partition_point(|span| span.start <= offset);
+        let mut candidate = after.checked_sub(1);
+        while let Some(index) = candidate {
+            if self.spans[index].end > offset {
+                return Some(index);
+            }
+            candidate = self.parents[index];
+        }
+        None
+    }
+
+    /// Enclosing entries of `index`, innermost first.
+    pub fn ancestors(&self, index: usize) -> Vec<usize> {
+        let mut chain = Vec::new();
+        let mut current = self.parents[index];
+        while let Some(parent) = current {
+            chain.push(parent);
+            current = self.parents[parent];
+        }
+        chain
+    }
+}
+
+/// Read the byte span of an event hash.
+fn event_span(ruby: &Ruby, event: RHash) -> Result<Range<usize>, Error> {
+    let span = event.fetch::<_, Value>(Symbol::new("span"))?;
+    span_of(ruby, span).map_err(|_| {
+        Error::new(
+            ruby.exception_arg_error(),
+            "event_at needs byte offset spans (spans: :hash or :object)",
+        )
+    })
+}
+
+/// `UdonNative.event_at(events, offset)`
+///
+/// Returns `{event:, ancestors: [...]}` for the innermost event covering
+/// byte `offset`, where `ancestors` are the `*_start` (or unified
+/// `:directive`) events of the enclosing structures, innermost first. A
+/// structure covers everything from its start event to its end event, so an
+/// offset in whitespace between children finds the enclosing start event.
+/// Returns nil when nothing covers `offset`.
+pub fn event_at(ruby: &Ruby, events: RArray, offset: usize) -> Result<Option<RHash>, Error> {
+    let mut hashes = Vec::with_capacity(events.len());
+    let mut spans = Vec::with_capacity(events.len());
+    let mut parents = Vec::with_capacity(events.len());
+    let mut open: Vec<usize> = Vec::new();
+
+    for event in events.each() {
+        let event = RHash::try_convert(event?)?;
+        let kind: Symbol = event.fetch(Symbol::new("type"))?;
+        let kind = kind.name()?;
+        let span = event_span(ruby, event)?;
+
+        if kind.ends_with("_end") {
+            // Close the structure: it spans up to the end of this event.
+            if let Some(start) = open.pop() {
+                spans[start] = spans[start].start..span.end.max(spans[start].end);
+            }
+            continue;
+        }
+        let index = hashes.len();
+        hashes.push(event);
+        spans.push(span);
+        parents.push(open.last().copied());
+        if kind.ends_with("_start") || kind == "directive" {
+            open.push(index);
+        }
+    }
+
+    let index = SpanIndex::new(spans, parents);
+    let Some(found) = index.innermost(offset) else {
+        return Ok(None);
+    };
+    let ancestors = RArray::new();
+    for parent in index.ancestors(found) {
+        ancestors.push(hashes[parent])?;
+    }
+    let result = RHash::new();
+    result.aset(Symbol::new("event"), hashes[found])?;
+    result.aset(Symbol::new("ancestors"), ancestors)?;
+    Ok(Some(result))
+}
@@ -0,0 +1,149 @@
+//! `UdonNative.parse_with_stats`: parse and report simple document metrics.
+
+use magnus::{scan_args::scan_args, Error, RArray, RHash, Ruby, Symbol, Value};
+use udon_core::Event;
+
+use crate::{
+    coerce,
+    conditions::{Admit, Filter},
+    event_parts, freeze, literal,
+    normalize::{check_input, normalize, rejected_nul, OffsetMap},
+    nul_error,
+    options::ParseOptions,
+    sort_by_span, Converter,
+};
+
+/// Counters updated once per event.
+#[derive(Default)]
+pub struct ParseStats {
+    pub events: usize,
+    pub elements: usize,
+    pub errors: usize,
+    /// Current element/embedded/directive nesting depth.
+    depth: usize,
+    /// Deepest nesting seen; 1 for a document of top-level elements only.
+    pub max_depth_reached: usize,
+}
+
+impl ParseStats {
+    pub fn observe(&mut self, event: &Event) {
+        self.events += 1;
+        match event {
+            Event::ElementStart { .. }
+            | Event::EmbeddedStart { .. }
+            | Event::DirectiveStart { .. } => {
+                if matches!(
+                    event,
+                    Event::ElementStart { .. } | Event::EmbeddedStart { .. }
+                ) {
+                    self.elements += 1;
+                }
+                self.depth += 1;
+                self.max_depth_reached = self.max_depth_reached.max(self.depth);
+            }
+            Event::ElementEnd { .. } | Event::EmbeddedEnd { .. } | Event::DirectiveEnd { .. } => {
+                self.depth = self.depth.saturating_sub(1);
+            }
+            Event::Error { .. } => self.errors += 1,
+            _ => {}
+        }
+    }
+
+    /// The counts for input `nul_bytes: :error` rejected: its one error.
+    pub fn rejected() -> Self {
+        ParseStats {
+            events: 1,
+            errors: 1,
+            ..ParseStats::default()
+        }
+    }
+
+    /// `bytes` is the input's length, `input_bytes` that of what the parser
+    /// was given after `strip_bom:`/`normalize_newlines:`.
+    pub fn to_hash(&self, bytes: usize, input_bytes: usize) -> RHash {
+        let hash = RHash::new();
+        let _ = hash.aset(Symbol::new("bytes"), bytes);
+        let _ = hash.aset(Symbol::new("input_bytes"), input_bytes);
+        let _ = hash.aset(Symbol::new("events"), self.events);
+        let _ = hash.aset(Symbol::new("elements"), self.elements);
+        let _ = hash.aset(Symbol::new("errors"), self.errors);
+        let _ = hash.aset(Symbol::new("max_depth_reached"), self.max_depth_reached);
+        hash
+    }
+}
+
+/// `UdonNative.parse_with_stats(input, **options)`
+///
+/// Returns `{events: [...], stats: {bytes:, input_bytes:, events:, elements:,
+/// errors:, max_depth_reached:}}`. Accepts the same options as `parse`.
+pub fn parse_with_stats(ruby: &Ruby, args: &[Value]) -> Result<RHash, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::bytes(ruby, input)?;
+    let options = ParseOptions::from_hash(ruby, args.keywords)?;
+    let input_bytes = &input[..];
+
+    check_input(ruby, input_bytes, &options)?;
+    let result = RHash::new();
+    if let Some(at) = rejected_nul(input_bytes, &options) {
+        let events = RArray::new();
+        events.push(nul_error(input_bytes, at..at + 1, &options))?;
+        result.aset(Symbol::new("events"), events)?;
+        result.aset(
+            Symbol::new("stats"),
+            ParseStats::rejected().to_hash(input_bytes.len(), 0),
+        )?;
+        return freeze::finish(result, &options);
+    }
+    let (normalized, offsets) = normalize(input_bytes, &options);
+    let (events, stats) = parse_counted(ruby, input_bytes, &normalized, offsets, &options)?;
+    result.aset(Symbol::new("events"), events)?;
+    result.aset(
+        Symbol::new("stats"),
+        stats.to_hash(input_bytes.len(), normalized.len()),
+    )?;
+    freeze::finish(result, &options)
+}
+
+/// Parse `normalized` as `parse_normalized` does, counting events as they
+/// go by.
+pub fn parse_counted(
+    ruby: &Ruby,
+    source: &[u8],
+    normalized: &[u8],
+    offsets: Option<OffsetMap>,
+    options: &ParseOptions,
+) -> Result<(RArray, ParseStats), Error> {
+    let mut converter = Converter::new(ruby, source, options, offsets);
+    let mut stats = ParseStats::default();
+    let mut conditions = options
+        .conditions
+        .as_ref()
+        .map(|context| Filter::new(context, normalized));
+    let mut events = RArray::new();
+    let mut starts = Vec::new();
+    literal::parse(normalized, options, |event| {
+        let held = match conditions.as_mut().map(|filter| filter.admit(&event)) {
+            Some(Admit::Skip) => return converter.skip(&event),
+            Some(Admit::AfterHeld(span)) => Some(Event::DirectiveStart { span }),
+            _ => None,
+        };
+        for event in held.iter().chain([&event]) {
+            stats.observe(event);
+            if let Some(hash) = converter.convert(event) {
+                let _ = events.push(hash);
+                if options.sort_by_span {
+                    starts.push(event_parts(event).0.start);
+                }
+            }
+        }
+    });
+    if let Some(filter) = &conditions {
+        filter.finish(ruby, &converter)?;
+    }
+    converter.take_failure()?;
+    if options.sort_by_span {
+        events = sort_by_span(events, &starts)?;
+    }
+    Ok((events, stats))
+}
@@ -0,0 +1,187 @@
+//! `UdonNative.parse_stdin`: documents read from standard input in Rust.
+//!
+//! Standard input is read straight from file descriptor 0 in chunks, not
+//! through `$stdin`, and only as fast as the block consumes documents: each
+//! document is parsed and yielded as soon as the delimiter line after it
+//! arrives, and nothing more is read until the block returns. A producer
+//! writing faster than that blocks on the full pipe instead of the input
+//! piling up in memory. Without a delimiter the whole input is one document,
+//! yielded at EOF, as the core parser needs the complete buffer.
+//!
+//! While the pipe is idle the wait for input happens without the GVL, so
+//! other threads run, and Ctrl-C, `Thread#raise` and `Timeout` interrupt
+//! it as they would `IO#read`.
+
+use std::io::{IsTerminal, Read};
+
+use magnus::{
+    prelude::*, scan_args::get_kwargs, scan_args::scan_args, Error, RArray, RHash, RString, Ruby,
+    Symbol, Value,
+};
+
+use crate::{errors, event_type, freeze, options::ParseOptions, parse_bytes, SpanFormatter};
+
+/// Bytes requested from the pipe per read.
+const CHUNK: usize = 64 * 1024;
+
+/// Documents split off the bytes read so far.
+struct Splitter {
+    delimiter: Option<Vec<u8>>,
+    buffer: Vec<u8>,
+    /// Start of the first line of `buffer` not yet checked for a delimiter.
+    line_start: usize,
+    /// Whether a document has been split off yet; a blank one before the
+    /// first delimiter is dropped, as in `parse_stream`.
+    started: bool,
+}
+
+impl Splitter {
+    /// The next complete document in the buffer, if a delimiter line ends
+    /// one. Blank leading documents are skipped.
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let delimiter = self.delimiter.as_deref()?;
+        while let Some(at) = memchr::memchr(b'\n', &self.buffer[self.line_start..]) {
+            let line_end = self.line_start + at + 1;
+            if self.buffer[self.line_start..line_end].trim_ascii_end() != delimiter {
+                self.line_start = line_end;
+                continue;
+            }
+            let document = self.buffer[..self.line_start].to_vec();
+            self.buffer.drain(..line_end);
+            self.line_start = 0;
+            let blank = document.trim_ascii().is_empty();
+            if blank && !self.started {
+                continue;
+            }
+            self.started = true;
+            return Some(document);
+        }
+        None
+    }
+
+    /// What is left at EOF, unless it is blank after a delimiter.
+    fn rest(&mut self) -> Option<Vec<u8>> {
+        let document = std::mem::take(&mut self.buffer);
+        if self.started && document.trim_ascii().is_empty() {
+            return None;
+        }
+        Some(document)
+    }
+}
+
+/// The `:truncated_input` event closing `document`, whose read failed at
+/// its end. Its span has the shape `spans:` gives the document's other
+/// events.
+fn truncated_error(document: &[u8], options: &ParseOptions) -> RHash {
+    let spans = SpanFormatter::new(options.spans, options.tab_width, document, None)
+        .with_base(options.span_base);
+    let hash = RHash::new();
+    let _ = hash.aset(Symbol::new("type"), Symbol::new("error"));
+    let _ = hash.aset(Symbol::new("code"), Symbol::new("truncated_input"));
+    let _ = hash.aset(
+        Symbol::new("span"),
+        spans.convert_original(&(document.len()..document.len())),
+    );
+    if options.severity {
+        let _ = hash.aset(Symbol::new("severity"), Symbol::new("fatal"));
+    }
+    if options.type_tag {
+        event_type::attach(hash);
+    }
+    hash
+}
+
+/// `UdonNative.parse_stdin(delimiter: nil, **options) { |events| ... }`
+///
+/// Yields the event array of each document on standard input and returns
+/// the number of documents. With `delimiter:` (e.g. `"---"`) documents are
+/// separated by delimiter lines as in `parse_stream` and each one's spans
+/// start at 0; with `with_index: true` its index and a nil total are
+/// yielded too. Raises `UdonNative::IOError` if standard input is a
+/// terminal. If reading fails partway (the other end of the pipe went away
+/// uncleanly), the document read so far is yielded with a `:truncated_input`
+/// error event at its end.
+pub fn parse_stdin(ruby: &Ruby, args: &[Value]) -> Result<usize, Error> {
+    let args = scan_args::<(), (), (), (), RHash, ()>(args)?;
+    let kwargs =
+        get_kwargs::<_, (), (Option<Option<RString>>,), RHash>(args.keywords, &[], &["delimiter"])?;
+    let (delimiter,) = kwargs.optional;
+    let options = ParseOptions::from_hash(ruby, kwargs.splat)?;
+    if !ruby.block_given() {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "parse_stdin requires a block",
+        ));
+    }
+    let delimiter = delimiter
+        .flatten()
+        .map(|d| unsafe { d.as_slice() }.to_vec());
+    if delimiter
+        .as_ref()
+        .is_some_and(|d| d.is_empty() || d.contains(&b'\n'))
+    {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "delimiter must be a non-empty single line",
+        ));
+    }
+
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        return Err(errors::io_error(
+            ruby,
+            "standard input is a terminal; pipe a document in, e.g. `cat doc.udon | ...`"
+                .to_string(),
+        ));
+    }
+
+    let mut documents = 0;
+    let mut yield_document = |events: RArray| -> Result<(), Error> {
+        let events = freeze::finish(events, &options)?;
+        let _: Value = if options.with_index {
+            ruby.yield_values((events, documents, ruby.qnil()))?
+        } else {
+            ruby.yield_value(events)?
+        };
+        documents += 1;
+        Ok(())
+    };
+
+    let mut splitter = Splitter {
+        delimiter,
+        buffer: Vec::new(),
+        line_start: 0,
+        started: false,
+    };
+    // Reads of `CHUNK` bytes bypass the lock's buffer, so a wait for the
+    // descriptor to be readable never misses buffered input.
+    let mut stdin = stdin.lock();
+    let mut chunk = vec![0; CHUNK];
+    loop {
+        #[cfg(unix)]
+        ruby.thread_wait_fd(&stdin)?;
+        let n = match stdin.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {
+                ruby.thread_check_ints()?;
+                continue;
+            }
+            Err(_) => {
+                let document = std::mem::take(&mut splitter.buffer);
+                let events = parse_bytes(ruby, &document, &options)?;
+                events.push(truncated_error(&document, &options))?;
+                yield_document(events)?;
+                return Ok(documents);
+            }
+        };
+        splitter.buffer.extend_from_slice(&chunk[..n]);
+        while let Some(document) = splitter.next() {
+            yield_document(parse_bytes(ruby, &document, &options)?)?;
+        }
+    }
+    if let Some(document) = splitter.rest() {
+        yield_document(parse_bytes(ruby, &document, &options)?)?;
+    }
+    Ok(documents)
+}
@@ -0,0 +1,96 @@
+//! Delimiter-separated document streams (`---` lines, as in YAML).
+//!
+//! A delimiter is a line that, apart from its line terminator and trailing
+//! whitespace, is exactly the delimiter text and starts at column 0. Each
+//! segment between delimiters is parsed on its own.
+
+use std::ops::Range;
+
+use magnus::{
+    prelude::*, scan_args::get_kwargs, scan_args::scan_args, Error, RArray, RHash, RString, Ruby,
+    Symbol, Value,
+};
+
+use crate::{coerce, freeze, options::ParseOptions, parse_bytes, parse_segment};
+
+/// Byte ranges of the documents in `input`, without the delimiter lines.
+/// Blank segments before the first and after the last delimiter are
+/// dropped, so leading and trailing delimiters are harmless.
+pub fn segments(input: &[u8], delimiter: &[u8]) -> Vec<Range<usize>> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut line_start = 0;
+    while line_start < input.len() {
+        let line_end =
+            memchr::memchr(b'\n', &input[line_start..]).map_or(input.len(), |i| line_start + i + 1);
+        let line = input[line_start..line_end].trim_ascii_end();
+        if line == delimiter {
+            segments.push(start..line_start);
+            start = line_end;
+        }
+        line_start = line_end;
+    }
+    segments.push(start..input.len());
+
+    let blank = |range: &Range<usize>| input[range.clone()].trim_ascii().is_empty();
+    if segments.len() > 1 && segments.last().is_some_and(blank) {
+        segments.pop();
+    }
+    if segments.len() > 1 && blank(&segments[0]) {
+        segments.remove(0);
+    }
+    segments
+}
+
+/// `UdonNative.parse_stream(input, delimiter: "---", offsets: :global, **options)`
+///
+/// Returns one event array per document. With `offsets: :global` spans index
+/// the whole input; with `:segment` each document's spans start at 0.
+pub fn parse_stream(ruby: &Ruby, args: &[Value]) -> Result<RArray, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::bytes(ruby, input)?;
+    let kwargs = get_kwargs::<_, (), (Option<RString>, Option<Symbol>), RHash>(
+        args.keywords,
+        &[],
+        &["delimiter", "offsets"],
+    )?;
+    let (delimiter, offsets) = kwargs.optional;
+    let options = ParseOptions::from_hash(ruby, kwargs.splat)?;
+
+    let delimiter = match delimiter {
+        Some(delimiter) => unsafe { delimiter.as_slice() }.to_vec(),
+        None => b"---".to_vec(),
+    };
+    if delimiter.is_empty() || delimiter.contains(&b'\n') {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "delimiter must be a non-empty single line",
+        ));
+    }
+    let global = match offsets {
+        None => true,
+        Some(offsets) => match offsets.name()?.as_ref() {
+            "global" => true,
+            "segment" => false,
+            other => {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    format!("invalid value for offsets: :{}", other),
+                ))
+            }
+        },
+    };
+
+    let input_bytes = &input[..];
+    let documents = RArray::new();
+    for segment in segments(input_bytes, &delimiter) {
+        let events = if global {
+            parse_segment(ruby, input_bytes, segment, &options)?
+        } else {
+            parse_bytes(ruby, &input_bytes[segment], &options)?
+        };
+        documents.push(events)?;
+    }
+    freeze::finish(documents, &options)
+}
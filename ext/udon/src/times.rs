@@ -0,0 +1,202 @@
+//! ISO-8601 timestamps in string values, for `parse_times: true`.
+//!
+//! A `:string_value` or `:bare_value` whose whole content is a calendar date
+//! (`2024-01-02`) gets a Ruby `Date` as its `:value`, with `coerced: :date`;
+//! one that is a date and time (`2024-01-02T03:04:05Z`, `T` or a space
+//! between them, optional seconds and fraction, `Z` or a `±HH:MM` offset)
+//! gets a `Time`, with `coerced: :time`. A time without an offset is local
+//! time, as `Time.new` makes it. Anything else, including impossible dates
+//! such as `2023-02-29`, is left alone. The event keeps its type and
+//! content.
+
+use magnus::{prelude::*, Error, RClass, RHash, Ruby, Symbol, Value};
+
+/// The UTC offset written after a time.
+enum Zone {
+    Local,
+    Utc,
+    /// `+HH:MM` or `-HH:MM`, as written.
+    Offset(String),
+}
+
+enum Stamp {
+    Date {
+        year: i32,
+        month: u32,
+        day: u32,
+    },
+    Time {
+        year: i32,
+        month: u32,
+        day: u32,
+        hour: u32,
+        minute: u32,
+        second: u32,
+        /// The fraction of a second's digits, if any.
+        fraction: Option<String>,
+        zone: Zone,
+    },
+}
+
+/// Reads fixed-width fields off the front of a timestamp.
+struct Cursor<'a> {
+    rest: &'a [u8],
+}
+
+impl Cursor<'_> {
+    fn digits(&mut self, width: usize) -> Option<u32> {
+        let field = self.rest.get(..width)?;
+        if !field.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        self.rest = &self.rest[width..];
+        Some(field.iter().fold(0, |n, &b| n * 10 + u32::from(b - b'0')))
+    }
+
+    fn byte(&mut self, expected: &[u8]) -> Option<u8> {
+        let (&first, rest) = self.rest.split_first()?;
+        if !expected.contains(&first) {
+            return None;
+        }
+        self.rest = rest;
+        Some(first)
+    }
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn parse(content: &[u8]) -> Option<Stamp> {
+    let mut at = Cursor { rest: content };
+    let year = at.digits(4)? as i32;
+    at.byte(b"-")?;
+    let month = at.digits(2)?;
+    at.byte(b"-")?;
+    let day = at.digits(2)?;
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+    if at.rest.is_empty() {
+        return Some(Stamp::Date { year, month, day });
+    }
+    at.byte(b"Tt ")?;
+    let hour = at.digits(2)?;
+    at.byte(b":")?;
+    let minute = at.digits(2)?;
+    let mut second = 0;
+    let mut fraction = None;
+    if at.byte(b":").is_some() {
+        second = at.digits(2)?;
+        if at.byte(b".,").is_some() {
+            let width = at.rest.iter().take_while(|b| b.is_ascii_digit()).count();
+            if width == 0 {
+                return None;
+            }
+            fraction = Some(String::from_utf8_lossy(&at.rest[..width]).into_owned());
+            at.rest = &at.rest[width..];
+        }
+    }
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let zone = match at.byte(b"Zz+-") {
+        None => Zone::Local,
+        Some(b'Z' | b'z') => Zone::Utc,
+        Some(sign) => {
+            let hours = at.digits(2)?;
+            at.byte(b":");
+            let minutes = at.digits(2)?;
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            Zone::Offset(format!("{}{:02}:{:02}", sign as char, hours, minutes))
+        }
+    };
+    if !at.rest.is_empty() {
+        return None;
+    }
+    Some(Stamp::Time {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        fraction,
+        zone,
+    })
+}
+
+/// `Time` and `Date`, looked up when a parse with `parse_times: true`
+/// starts.
+pub struct Times {
+    time: RClass,
+    date: RClass,
+}
+
+impl Times {
+    /// Requires the `date` library.
+    pub fn load(ruby: &Ruby) -> Result<Self, Error> {
+        let _: Value = ruby.module_kernel().funcall("require", ("date",))?;
+        Ok(Times {
+            time: ruby.class_object().const_get("Time")?,
+            date: ruby.class_object().const_get("Date")?,
+        })
+    }
+
+    fn build(&self, ruby: &Ruby, stamp: Stamp) -> Result<(Value, &'static str), Error> {
+        let (year, month, day, hour, minute, second, fraction, zone) = match stamp {
+            Stamp::Date { year, month, day } => {
+                return Ok((self.date.funcall("new", (year, month, day))?, "date"));
+            }
+            Stamp::Time {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                fraction,
+                zone,
+            } => (year, month, day, hour, minute, second, fraction, zone),
+        };
+        let seconds: Value = match fraction {
+            Some(fraction) => ruby
+                .module_kernel()
+                .funcall("Rational", (format!("{}.{}", second, fraction),))?,
+            None => second.into_value_with(ruby),
+        };
+        let time = match zone {
+            Zone::Utc => self
+                .time
+                .funcall("utc", (year, month, day, hour, minute, seconds))?,
+            Zone::Local => self
+                .time
+                .funcall("new", (year, month, day, hour, minute, seconds))?,
+            Zone::Offset(offset) => self
+                .time
+                .funcall("new", (year, month, day, hour, minute, seconds, offset))?,
+        };
+        Ok((time, "time"))
+    }
+
+    /// Attach the `Date` or `Time` `content` spells to its event's `hash`.
+    /// Whether it was one.
+    pub fn attach(&self, ruby: &Ruby, content: &[u8], hash: RHash) -> bool {
+        let Some(stamp) = parse(content) else {
+            return false;
+        };
+        let Ok((value, coerced)) = self.build(ruby, stamp) else {
+            return false;
+        };
+        let _ = hash.aset(Symbol::new("value"), value);
+        let _ = hash.aset(Symbol::new("coerced"), Symbol::new(coerced));
+        true
+    }
+}
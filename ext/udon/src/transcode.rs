@@ -0,0 +1,737 @@
+//! `UdonNative.parse_io`: UDON from an IO in UTF-8, UTF-16 or UTF-32,
+//! optionally gzip-compressed.
+//!
+//! The IO is read in chunks, decompressed if need be, and each chunk is
+//! transcoded to UTF-8 as it arrives, carrying a code unit or surrogate pair
+//! split across chunks over to the next one. The parser then sees the UTF-8
+//! stream, so spans index that stream; with `spans: :original` they are
+//! mapped back to byte offsets in the (decompressed) input.
+
+use std::{io::Read, ops::Range};
+
+use flate2::read::GzDecoder;
+
+use magnus::{
+    prelude::*, scan_args::scan_args, Error, RArray, RClass, RHash, RString, Ruby, Symbol, Value,
+};
+
+use crate::{
+    coerce, errors, freeze, mapped,
+    normalize::{invalid_sequence, normalize},
+    nul_error,
+    options::{NulBytes, ParseOptions},
+    parse_normalized,
+    stats::{parse_counted, ParseStats},
+};
+
+/// Bytes requested from the IO per `read`.
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Utf32Le,
+    Utf32Be,
+}
+
+impl Encoding {
+    /// `encoding: "UTF-16LE"` (any case; an `Encoding` object also works).
+    fn from_name(ruby: &Ruby, name: &str) -> Result<Self, Error> {
+        match name.to_ascii_uppercase().as_str() {
+            "UTF-8" => Ok(Encoding::Utf8),
+            "UTF-16LE" => Ok(Encoding::Utf16Le),
+            "UTF-16BE" => Ok(Encoding::Utf16Be),
+            "UTF-32LE" => Ok(Encoding::Utf32Le),
+            "UTF-32BE" => Ok(Encoding::Utf32Be),
+            _ => Err(Error::new(
+                ruby.exception_arg_error(),
+                format!(
+                    "unsupported encoding: {} (expected UTF-8, UTF-16LE, UTF-16BE, UTF-32LE or UTF-32BE)",
+                    name
+                ),
+            )),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Utf32Le => "UTF-32LE",
+            Encoding::Utf32Be => "UTF-32BE",
+        }
+    }
+
+    /// The encoding `head` (at least four bytes, or the whole input) is in,
+    /// and the length of its byte order mark.
+    ///
+    /// Without a mark, UTF-16 and UTF-32 are recognised by the NUL bytes of
+    /// an ASCII first character, which UTF-8 UDON never contains. A UTF-8
+    /// mark is left in place for `strip_bom:` to handle.
+    pub fn sniff(head: &[u8]) -> (Self, usize) {
+        match head {
+            [0xFF, 0xFE, 0, 0, ..] => (Encoding::Utf32Le, 4),
+            [0, 0, 0xFE, 0xFF, ..] => (Encoding::Utf32Be, 4),
+            [0xFF, 0xFE, ..] => (Encoding::Utf16Le, 2),
+            [0xFE, 0xFF, ..] => (Encoding::Utf16Be, 2),
+            [a, 0, 0, 0, ..] if *a != 0 => (Encoding::Utf32Le, 0),
+            [0, 0, 0, a, ..] if *a != 0 => (Encoding::Utf32Be, 0),
+            [a, 0, ..] if *a != 0 => (Encoding::Utf16Le, 0),
+            [0, a, ..] if *a != 0 => (Encoding::Utf16Be, 0),
+            _ => (Encoding::Utf8, 0),
+        }
+    }
+
+    /// Length of this encoding's byte order mark at the start of `head`,
+    /// or 0, for input whose encoding was given explicitly.
+    fn bom_len(self, head: &[u8]) -> usize {
+        let bom: &[u8] = match self {
+            Encoding::Utf8 => return 0,
+            Encoding::Utf16Le => &[0xFF, 0xFE],
+            Encoding::Utf16Be => &[0xFE, 0xFF],
+            Encoding::Utf32Le => &[0xFF, 0xFE, 0, 0],
+            Encoding::Utf32Be => &[0, 0, 0xFE, 0xFF],
+        };
+        if head.starts_with(bom) {
+            bom.len()
+        } else {
+            0
+        }
+    }
+}
+
+/// What to do with bytes that are not valid in the input encoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Invalid {
+    /// Raise `UdonNative::EncodingError`.
+    Raise,
+    /// Substitute U+FFFD.
+    Replace,
+}
+
+/// A run of characters that each take `out_width` UTF-8 bytes and
+/// `src_width` source bytes.
+#[derive(Debug)]
+struct Run {
+    out: usize,
+    src: usize,
+    out_width: usize,
+    src_width: usize,
+    chars: usize,
+}
+
+/// Maps offsets in transcoded UTF-8 back to offsets in the source bytes.
+///
+/// Runs of same-width characters share an entry, so ASCII in UTF-16 costs
+/// one entry per run rather than one per byte.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    runs: Vec<Run>,
+    /// Source offset just past the last transcoded character.
+    end: usize,
+}
+
+impl SourceMap {
+    fn push(
+        &mut self,
+        out: usize,
+        src: usize,
+        (out_width, src_width): (usize, usize),
+        chars: usize,
+    ) {
+        match self.runs.last_mut() {
+            Some(run)
+                if run.out_width == out_width
+                    && run.src_width == src_width
+                    && run.out + run.chars * out_width == out
+                    && run.src + run.chars * src_width == src =>
+            {
+                run.chars += chars
+            }
+            _ => self.runs.push(Run {
+                out,
+                src,
+                out_width,
+                src_width,
+                chars,
+            }),
+        }
+        self.end = src + chars * src_width;
+    }
+
+    /// Source offset of UTF-8 `offset`; an offset inside a character maps to
+    /// the character's start.
+    pub fn original(&self, offset: usize) -> usize {
+        let index = self.runs.partition_point(|run| run.out <= offset);
+        let Some(run) = index.checked_sub(1).map(|i| &self.runs[i]) else {
+            return self.runs.first().map_or(self.end, |run| run.src);
+        };
+        let chars = (offset - run.out) / run.out_width;
+        if chars < run.chars {
+            return run.src + chars * run.src_width;
+        }
+        self.runs.get(index).map_or(self.end, |next| next.src)
+    }
+}
+
+/// One decoding step at the front of the undecoded bytes.
+enum Step {
+    /// A character and the source bytes it took.
+    Char(char, usize),
+    /// This many bytes are not a valid character.
+    Invalid(usize),
+    /// A character continues past the bytes read so far.
+    Incomplete,
+}
+
+fn utf16_char(rest: &[u8], last: bool, read: fn([u8; 2]) -> u16) -> Step {
+    let short = |len| {
+        if last {
+            Step::Invalid(len)
+        } else {
+            Step::Incomplete
+        }
+    };
+    if rest.len() < 2 {
+        return short(rest.len());
+    }
+    match read([rest[0], rest[1]]) {
+        high @ 0xD800..=0xDBFF => {
+            if rest.len() < 4 {
+                return short(2);
+            }
+            match read([rest[2], rest[3]]) {
+                low @ 0xDC00..=0xDFFF => {
+                    let code =
+                        0x10000 + ((u32::from(high) - 0xD800) << 10) + (u32::from(low) - 0xDC00);
+                    char::from_u32(code).map_or(Step::Invalid(4), |c| Step::Char(c, 4))
+                }
+                _ => Step::Invalid(2),
+            }
+        }
+        0xDC00..=0xDFFF => Step::Invalid(2),
+        unit => char::from_u32(u32::from(unit)).map_or(Step::Invalid(2), |c| Step::Char(c, 2)),
+    }
+}
+
+fn utf32_char(rest: &[u8], last: bool, read: fn([u8; 4]) -> u32) -> Step {
+    match rest {
+        [a, b, c, d, ..] => {
+            char::from_u32(read([*a, *b, *c, *d])).map_or(Step::Invalid(4), |c| Step::Char(c, 4))
+        }
+        _ if last => Step::Invalid(rest.len()),
+        _ => Step::Incomplete,
+    }
+}
+
+/// An invalid sequence, for `Invalid::Raise`: its encoding, source offset
+/// and length, and the bytes from it on that had arrived.
+pub struct InvalidAt(pub Encoding, pub usize, pub usize, pub Vec<u8>);
+
+/// Streaming transcoder to UTF-8.
+pub struct Transcoder {
+    /// Given, or sniffed once enough bytes arrived.
+    encoding: Option<Encoding>,
+    /// Whether the encoding is settled and its byte order mark skipped.
+    started: bool,
+    invalid: Invalid,
+    out: Vec<u8>,
+    /// Bytes not yet decoded: an incomplete character at a chunk boundary.
+    carry: Vec<u8>,
+    /// Source offset of `carry[0]`.
+    offset: usize,
+    map: Option<SourceMap>,
+    /// Stop at the first NUL, for `nul_bytes: :error`.
+    stop_at_nul: bool,
+    /// UTF-8 offset and source span of the NUL decoding stopped at.
+    nul: Option<(usize, Range<usize>)>,
+}
+
+impl Transcoder {
+    /// `encoding` is sniffed from the input when `None`. With `map`, a
+    /// `SourceMap` is kept for `spans: :original`.
+    /// With `stop_at_nul`, nothing from the first NUL on is decoded; see
+    /// `nul`.
+    pub fn new(encoding: Option<Encoding>, invalid: Invalid, map: bool, stop_at_nul: bool) -> Self {
+        Transcoder {
+            encoding,
+            started: false,
+            invalid,
+            out: Vec::new(),
+            carry: Vec::new(),
+            offset: 0,
+            map: map.then(SourceMap::default),
+            stop_at_nul,
+            nul: None,
+        }
+    }
+
+    /// UTF-8 offset and source span of the NUL decoding stopped at, if any.
+    pub fn nul(&self) -> Option<(usize, Range<usize>)> {
+        self.nul.clone()
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<(), InvalidAt> {
+        self.carry.extend_from_slice(chunk);
+        self.decode(false)
+    }
+
+    /// Decode what is left; the UTF-8 bytes, the encoding that was read and
+    /// the map back to it.
+    pub fn finish(mut self) -> Result<(Vec<u8>, Encoding, Option<SourceMap>), InvalidAt> {
+        self.decode(true)?;
+        Ok((self.out, self.encoding.unwrap_or(Encoding::Utf8), self.map))
+    }
+
+    fn decode(&mut self, last: bool) -> Result<(), InvalidAt> {
+        if self.nul.is_some() {
+            self.carry.clear();
+            return Ok(());
+        }
+        if !self.started {
+            if self.carry.len() < 4 && !last {
+                return Ok(());
+            }
+            let (encoding, bom) = match self.encoding {
+                Some(encoding) => (encoding, encoding.bom_len(&self.carry)),
+                None => Encoding::sniff(&self.carry),
+            };
+            self.carry.drain(..bom);
+            self.offset = bom;
+            self.encoding = Some(encoding);
+            self.started = true;
+        }
+        let encoding = self.encoding.unwrap_or(Encoding::Utf8);
+
+        let buf = std::mem::take(&mut self.carry);
+        let mut i = 0;
+        while i < buf.len() && self.nul.is_none() {
+            let rest = &buf[i..];
+            let step = match encoding {
+                Encoding::Utf8 => match std::str::from_utf8(rest) {
+                    Ok(_) => {
+                        self.push_utf8(rest);
+                        i = buf.len();
+                        break;
+                    }
+                    Err(error) if error.valid_up_to() > 0 => {
+                        self.push_utf8(&rest[..error.valid_up_to()]);
+                        i += error.valid_up_to();
+                        continue;
+                    }
+                    Err(error) => match error.error_len() {
+                        Some(len) => Step::Invalid(len),
+                        None if last => Step::Invalid(rest.len()),
+                        None => Step::Incomplete,
+                    },
+                },
+                Encoding::Utf16Le => utf16_char(rest, last, u16::from_le_bytes),
+                Encoding::Utf16Be => utf16_char(rest, last, u16::from_be_bytes),
+                Encoding::Utf32Le => utf32_char(rest, last, u32::from_le_bytes),
+                Encoding::Utf32Be => utf32_char(rest, last, u32::from_be_bytes),
+            };
+            let (c, len) = match step {
+                Step::Char(c, len) => (c, len),
+                Step::Invalid(len) => match self.invalid {
+                    Invalid::Raise => {
+                        return Err(InvalidAt(encoding, self.offset, len, rest.to_vec()))
+                    }
+                    Invalid::Replace => (char::REPLACEMENT_CHARACTER, len),
+                },
+                Step::Incomplete => break,
+            };
+            if c == '\0' && self.stop_at_nul {
+                self.nul = Some((self.out.len(), self.offset..self.offset + len));
+                break;
+            }
+            let out = self.out.len();
+            self.out
+                .extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            if let Some(map) = &mut self.map {
+                map.push(out, self.offset, (c.len_utf8(), len), 1);
+            }
+            self.offset += len;
+            i += len;
+        }
+        if self.nul.is_none() {
+            self.carry = buf[i..].to_vec();
+        }
+        Ok(())
+    }
+
+    /// Valid UTF-8 `bytes`, copied as they are.
+    fn push_utf8(&mut self, mut bytes: &[u8]) {
+        if self.stop_at_nul {
+            if let Some(at) = memchr::memchr(0, bytes) {
+                bytes = &bytes[..at];
+                self.nul = Some((self.out.len() + at, self.offset + at..self.offset + at + 1));
+            }
+        }
+        if let Some(map) = &mut self.map {
+            map.push(self.out.len(), self.offset, (1, 1), bytes.len());
+        }
+        self.out.extend_from_slice(bytes);
+        self.offset += bytes.len();
+    }
+}
+
+/// The options of `parse_io`/`parse_file` beyond `compression:`.
+pub struct ReadOptions {
+    pub encoding: Option<Encoding>,
+    pub invalid: Invalid,
+    /// `spans: :original`.
+    pub original: bool,
+    /// `stats: true`.
+    pub with_stats: bool,
+    pub parse: ParseOptions,
+}
+
+impl ReadOptions {
+    pub fn take(ruby: &Ruby, keywords: RHash) -> Result<Self, Error> {
+        let expected = keywords.delete::<_, Option<Value>>(Symbol::new("expect_encoding"))?;
+        let expected = match expected {
+            Some(name) => {
+                let name: String = name.funcall("to_s", ())?;
+                Some(Encoding::from_name(ruby, &name)?)
+            }
+            None => None,
+        };
+        let (mut encoding, invalid, original) = take_options(ruby, keywords)?;
+        if let Some(expected) = expected {
+            if encoding.is_some_and(|encoding| encoding != expected) {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    "encoding: and expect_encoding: disagree",
+                ));
+            }
+            if invalid == Invalid::Replace {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    "expect_encoding: cannot be combined with invalid_utf8: :replace",
+                ));
+            }
+            encoding = Some(expected);
+        }
+        let with_stats = keywords
+            .delete::<_, Option<Value>>(Symbol::new("stats"))?
+            .is_some_and(|stats| stats.to_bool());
+        Ok(ReadOptions {
+            encoding,
+            invalid,
+            original,
+            with_stats,
+            parse: ParseOptions::from_hash(ruby, keywords)?,
+        })
+    }
+}
+
+/// Read `encoding:`, `invalid_utf8:` and `spans: :original` out of
+/// `keywords`, leaving the parse options.
+fn take_options(ruby: &Ruby, keywords: RHash) -> Result<(Option<Encoding>, Invalid, bool), Error> {
+    let encoding = match keywords.delete::<_, Option<Value>>(Symbol::new("encoding"))? {
+        Some(name) => Some(Encoding::from_name(
+            ruby,
+            &name.funcall::<_, _, String>("to_s", ())?,
+        )?),
+        None => None,
+    };
+    let invalid = match keywords.delete::<_, Option<Symbol>>(Symbol::new("invalid_utf8"))? {
+        None => Invalid::Raise,
+        Some(policy) => match policy.name()?.as_ref() {
+            "raise" => Invalid::Raise,
+            "replace" => Invalid::Replace,
+            other => {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    format!("invalid value for invalid_utf8: :{}", other),
+                ))
+            }
+        },
+    };
+    let original = match keywords
+        .get(Symbol::new("spans"))
+        .and_then(Symbol::from_value)
+    {
+        Some(spans) if spans.name()? == "original" => {
+            keywords.delete::<_, Value>(Symbol::new("spans"))?;
+            true
+        }
+        _ => false,
+    };
+    Ok((encoding, invalid, original))
+}
+
+/// `compression:` for `parse_io`/`parse_file`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    /// Gzip if the input starts with the gzip magic bytes.
+    Auto,
+}
+
+pub const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+
+fn take_compression(
+    ruby: &Ruby,
+    keywords: RHash,
+    default: Compression,
+) -> Result<Compression, Error> {
+    let Some(compression) = keywords.delete::<_, Option<Symbol>>(Symbol::new("compression"))?
+    else {
+        return Ok(default);
+    };
+    match compression.name()?.as_ref() {
+        "none" => Ok(Compression::None),
+        "gzip" => Ok(Compression::Gzip),
+        "auto" => Ok(Compression::Auto),
+        other => Err(Error::new(
+            ruby.exception_arg_error(),
+            format!("invalid value for compression: :{}", other),
+        )),
+    }
+}
+
+/// `std::io::Read` over a Ruby IO's `#read(n)`, so that flate2 can pull
+/// compressed bytes from it.
+struct RubyReader {
+    io: Value,
+    /// Bytes read from the IO and not yet handed out, from `pos` on.
+    pending: Vec<u8>,
+    pos: usize,
+    /// Bytes taken from the IO so far.
+    read: usize,
+    /// An exception raised by `#read`, surfaced in place of the I/O error
+    /// that carried it out of flate2.
+    error: Option<Error>,
+}
+
+impl RubyReader {
+    fn new(io: Value) -> Self {
+        RubyReader {
+            io,
+            pending: Vec::new(),
+            pos: 0,
+            read: 0,
+            error: None,
+        }
+    }
+
+    /// Refill `pending` if it is used up; false at EOF.
+    fn fill(&mut self) -> std::io::Result<bool> {
+        if self.pos < self.pending.len() {
+            return Ok(true);
+        }
+        let chunk: Option<RString> = self.io.funcall("read", (CHUNK,)).map_err(|error| {
+            self.error = Some(error);
+            std::io::Error::other("#read raised")
+        })?;
+        self.pending = chunk
+            .map(|chunk| unsafe { chunk.as_slice() }.to_vec())
+            .unwrap_or_default();
+        self.pos = 0;
+        self.read += self.pending.len();
+        Ok(!self.pending.is_empty())
+    }
+
+    /// The first bytes of the input, without consuming them.
+    fn peek(&mut self) -> std::io::Result<&[u8]> {
+        self.fill()?;
+        Ok(&self.pending[self.pos..])
+    }
+}
+
+impl std::io::Read for RubyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.fill()? {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.pending.len() - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+enum Input {
+    Plain(RubyReader),
+    Gzip(GzDecoder<RubyReader>),
+}
+
+impl Input {
+    fn reader(&mut self) -> &mut RubyReader {
+        match self {
+            Input::Plain(reader) => reader,
+            Input::Gzip(decoder) => decoder.get_mut(),
+        }
+    }
+
+    /// The next decompressed bytes into `buf`; 0 at EOF.
+    fn read(&mut self, ruby: &Ruby, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            let result = match self {
+                Input::Plain(reader) => reader.read(buf),
+                Input::Gzip(decoder) => decoder.read(buf),
+            };
+            match result {
+                Ok(n) => return Ok(n),
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    let reader = self.reader();
+                    if let Some(raised) = reader.error.take() {
+                        return Err(raised);
+                    }
+                    return Err(errors::io_error(
+                        ruby,
+                        format!(
+                            "corrupt gzip stream near compressed byte {}: {}",
+                            reader.read, error
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// `UdonNative.parse_io(io, encoding: nil, invalid_utf8: :raise,
+/// compression: :none, stats: false, **options)`
+///
+/// Reads `io` (anything responding to `#read`) to EOF with `#read(n)`.
+/// Without `encoding:` the input is UTF-8 unless a byte order mark or the
+/// NUL bytes of its first character say UTF-16 or UTF-32.
+pub fn parse_io(ruby: &Ruby, args: &[Value]) -> Result<Value, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (io,) = args.required;
+    let io = coerce::reader(ruby, io)?;
+    let compression = take_compression(ruby, args.keywords, Compression::None)?;
+    let read = ReadOptions::take(ruby, args.keywords)?;
+    freeze::finish(parse_reader(ruby, io, compression, &read)?, &read.parse)
+}
+
+/// `UdonNative.parse_file(path, mmap: false, **options)`: `parse_io` over
+/// the file at `path` (a String or Pathname), opened with `File.open` so
+/// that missing files raise the usual `Errno` errors. `compression:`
+/// defaults to `:auto`. With `mmap: true` the file is parsed from a memory
+/// mapping when it can be; see `mapped`.
+pub fn parse_file(ruby: &Ruby, args: &[Value]) -> Result<Value, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (path,) = args.required;
+    let path = coerce::path(ruby, path)?;
+    let compression = take_compression(ruby, args.keywords, Compression::Auto)?;
+    let mmap = args
+        .keywords
+        .delete::<_, Option<Value>>(Symbol::new("mmap"))?
+        .is_some_and(|mmap| mmap.to_bool());
+    let read = ReadOptions::take(ruby, args.keywords)?;
+    if mmap {
+        if let Some(result) = mapped::parse_mapped(ruby, &path.to_string()?, compression, &read)? {
+            return freeze::finish(result, &read.parse);
+        }
+    }
+    let file_class: RClass = ruby.class_object().const_get("File")?;
+    let file: Value = file_class.funcall("open", (path, "rb"))?;
+    let result = parse_reader(ruby, file, compression, &read);
+    let _: Value = file.funcall("close", ())?;
+    freeze::finish(result?, &read.parse)
+}
+
+/// `events`, or `{events:, stats:}` if `stats: true` was asked for.
+pub fn with_stats(
+    read: &ReadOptions,
+    events: RArray,
+    stats: impl FnOnce() -> RHash,
+) -> Result<Value, Error> {
+    if !read.with_stats {
+        return Ok(events.as_value());
+    }
+    let result = RHash::new();
+    result.aset(Symbol::new("events"), events)?;
+    result.aset(Symbol::new("stats"), stats())?;
+    Ok(result.as_value())
+}
+
+/// Decompress, transcode and parse `io`. Decompressed bytes go to the
+/// transcoder a chunk at a time; only the UTF-8 the parser needs is kept
+/// whole (and the decompressed input, for `spans: :original`).
+fn parse_reader(
+    ruby: &Ruby,
+    io: Value,
+    compression: Compression,
+    read: &ReadOptions,
+) -> Result<Value, Error> {
+    let (options, original) = (&read.parse, read.original);
+
+    let mut reader = RubyReader::new(io);
+    let gzip = match compression {
+        Compression::None => false,
+        Compression::Gzip => true,
+        Compression::Auto => match reader.peek() {
+            Ok(head) => head.starts_with(GZIP_MAGIC),
+            Err(_) => {
+                return Err(reader
+                    .error
+                    .take()
+                    .expect("peek fails only when #read raises"))
+            }
+        },
+    };
+    let mut input = match gzip {
+        true => Input::Gzip(GzDecoder::new(reader)),
+        false => Input::Plain(reader),
+    };
+
+    let raise = |InvalidAt(encoding, offset, len, bytes)| {
+        invalid_sequence(ruby, encoding.name(), offset, &bytes, len)
+    };
+    let stop_at_nul = options.nul_bytes == NulBytes::Error;
+    let mut transcoder = Transcoder::new(read.encoding, read.invalid, original, stop_at_nul);
+    let mut source = Vec::new();
+    let mut buf = vec![0; CHUNK];
+    let mut decompressed = 0;
+    loop {
+        let n = input.read(ruby, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        decompressed += n;
+        if original {
+            source.extend_from_slice(&buf[..n]);
+        }
+        transcoder.feed(&buf[..n]).map_err(raise)?;
+        if transcoder.nul().is_some() {
+            break;
+        }
+    }
+    let compressed = input.reader().read;
+
+    let stats_hash = |stats: ParseStats, input_bytes: usize| {
+        let hash = stats.to_hash(decompressed, input_bytes);
+        let _ = hash.aset(Symbol::new("compressed_bytes"), compressed);
+        hash
+    };
+    let nul = transcoder.nul();
+    let (utf8, _, map) = transcoder.finish().map_err(raise)?;
+    if let Some((utf8_at, source_at)) = nul {
+        let events = RArray::new();
+        events.push(match original {
+            true => nul_error(&source, source_at, options),
+            false => nul_error(&utf8, utf8_at..utf8_at + 1, options),
+        })?;
+        return with_stats(read, events, || stats_hash(ParseStats::rejected(), 0));
+    }
+
+    let (normalized, offsets) = normalize(&utf8, options);
+    let (source, offsets) = match map {
+        Some(map) => (&source[..], Some(offsets.unwrap_or_default().through(map))),
+        None => (&utf8[..], offsets),
+    };
+    if !read.with_stats {
+        return Ok(parse_normalized(ruby, source, &normalized, offsets, options)?.as_value());
+    }
+    let (events, stats) = parse_counted(ruby, source, &normalized, offsets, options)?;
+    with_stats(read, events, || stats_hash(stats, normalized.len()))
+}
@@ -0,0 +1,715 @@
+//! An owned document tree built from parse events.
+//!
+//! Nodes live in one arena (`Tree::nodes`) and refer to each other by index;
+//! node 0 is the document itself. Values keep the event type they were
+//! parsed as (`"integer"`, `"string_value"`, ...) so the tree can be emitted
+//! back without losing how each value was written.
+
+use std::ops::Range;
+
+use udon_core::{Event, Parser};
+
+use crate::{error_code_name, event_parts, key_span, normalize::bom_len, sort::OwnedEvent};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    Document,
+    Element,
+    Embedded,
+    Directive,
+    Text,
+    Comment,
+    Interpolation,
+    Reference,
+    Freeform,
+}
+
+impl NodeKind {
+    /// The Ruby-facing type name.
+    pub fn name(self) -> &'static str {
+        match self {
+            NodeKind::Document => "document",
+            NodeKind::Element => "element",
+            NodeKind::Embedded => "embedded",
+            NodeKind::Directive => "directive",
+            NodeKind::Text => "text",
+            NodeKind::Comment => "comment",
+            NodeKind::Interpolation => "interpolation",
+            NodeKind::Reference => "reference",
+            NodeKind::Freeform => "freeform",
+        }
+    }
+
+    fn bracket(self) -> Option<&'static str> {
+        match self {
+            NodeKind::Element => Some("element"),
+            NodeKind::Embedded => Some("embedded"),
+            NodeKind::Directive => Some("directive"),
+            NodeKind::Comment => Some("comment"),
+            NodeKind::Freeform => Some("freeform"),
+            _ => None,
+        }
+    }
+}
+
+/// An attribute or directive value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// A scalar, tagged with its event type. A flag attribute is a
+    /// `bool_true` with empty content.
+    Scalar {
+        kind: &'static str,
+        content: Vec<u8>,
+    },
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn flag() -> Self {
+        Value::Scalar {
+            kind: "bool_true",
+            content: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NodeData {
+    pub kind: NodeKind,
+    pub name: Option<Vec<u8>>,
+    pub attrs: Vec<(Vec<u8>, Value)>,
+    /// Source span of each attribute's value, parallel to `attrs`: the
+    /// scalar, the whole `[...]` of an array, or the key of a flag.
+    pub attr_spans: Vec<Range<usize>>,
+    /// Positional values (directive arguments).
+    pub values: Vec<Value>,
+    /// Content of text, comment, interpolation, reference and freeform nodes.
+    pub content: Vec<u8>,
+    pub children: Vec<usize>,
+    pub parent: Option<usize>,
+    pub span: Range<usize>,
+    /// The `Tree::files` entry the node was included from; `None` for the
+    /// parsed input itself. Its span indexes that file.
+    pub file: Option<usize>,
+    /// The `Tree::expansions` entry that copied the node out of a
+    /// definition, if one did.
+    pub expanded_from: Option<usize>,
+}
+
+impl NodeData {
+    fn new(kind: NodeKind, parent: Option<usize>, span: Range<usize>) -> Self {
+        NodeData {
+            kind,
+            name: None,
+            attrs: Vec::new(),
+            attr_spans: Vec::new(),
+            values: Vec::new(),
+            content: Vec::new(),
+            children: Vec::new(),
+            parent,
+            span,
+            file: None,
+            expanded_from: None,
+        }
+    }
+
+    /// The first value for `key`.
+    pub fn attr(&self, key: &[u8]) -> Option<&Value> {
+        self.attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// Layer `other`'s attributes over these: a key already present takes
+    /// `other`'s value in place of its first value, new keys are appended.
+    pub fn override_attrs(&mut self, other: &NodeData) {
+        for ((key, value), span) in other.attrs.iter().zip(&other.attr_spans) {
+            match self.attrs.iter().position(|(k, _)| k == key) {
+                Some(at) => {
+                    self.attrs[at].1 = value.clone();
+                    self.attr_spans[at] = span.clone();
+                }
+                None => {
+                    self.attrs.push((key.clone(), value.clone()));
+                    self.attr_spans.push(span.clone());
+                }
+            }
+        }
+    }
+}
+
+/// A parse error recorded while building the tree.
+#[derive(Clone, Debug)]
+pub struct TreeError {
+    pub code: &'static str,
+    pub span: Range<usize>,
+    /// As `NodeData::file`.
+    pub file: Option<usize>,
+}
+
+/// A file spliced in by an include (see `include`).
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    /// The path as reported to Ruby: relative to the include base when it
+    /// is inside it.
+    pub path: String,
+    pub source: Vec<u8>,
+    /// Span in the parsed input of the `!include` that brought the file in,
+    /// directly or through other includes.
+    pub included_at: Range<usize>,
+}
+
+/// A use of a definition (see `definitions`).
+#[derive(Clone, Debug)]
+pub struct Expansion {
+    /// The definition's name.
+    pub name: Vec<u8>,
+    /// Span of the `!use` directive, in `file`.
+    pub span: Range<usize>,
+    /// As `NodeData::file`, for the `!use`.
+    pub file: Option<usize>,
+    /// Span in the parsed input of the `!use` that brought the nodes in,
+    /// directly or through other uses and includes.
+    pub site: Range<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Tree {
+    pub nodes: Vec<NodeData>,
+    pub errors: Vec<TreeError>,
+    /// The parsed input, for anything that needs to look between spans.
+    pub source: Vec<u8>,
+    /// The input started with a UTF-8 byte order mark, which was skipped.
+    pub bom: bool,
+    /// Where the parsed input was read from, if it was a file.
+    pub path: Option<String>,
+    /// Files spliced in by includes, in the order they were read.
+    pub files: Vec<SourceFile>,
+    /// Definition uses expanded into the tree.
+    pub expansions: Vec<Expansion>,
+    /// Nodes answer their attribute keys as methods
+    /// (`parse_tree(as_objects: true)`).
+    pub objects: bool,
+}
+
+pub const ROOT: usize = 0;
+
+/// Bytes of input `by_prefixes` tries first.
+const PREFIX_CHUNK: usize = 16 * 1024;
+/// How much longer each prefix `by_prefixes` tries is.
+const PREFIX_GROWTH: usize = 4;
+
+/// Run `attempt` on prefixes of `input` until it returns `Some`, for a
+/// question the start of a document answers: the core parser cannot stop
+/// part-way, so parsing a prefix is the only way to parse less. Each prefix
+/// is `PREFIX_GROWTH` times longer than the last and cut at a line end;
+/// `attempt` is told when the prefix is the whole input, and must answer
+/// then.
+///
+/// Structures open at the cut are closed by it, with errors for any that
+/// cannot end there, so an answer is only sure once a later event that is
+/// not one of those shows the parse went past it.
+pub fn by_prefixes<T>(input: &[u8], mut attempt: impl FnMut(&[u8], bool) -> Option<T>) -> T {
+    let mut len = PREFIX_CHUNK;
+    loop {
+        let cut = match input.get(..len) {
+            None => input.len(),
+            Some(prefix) => match memchr::memrchr(b'\n', prefix) {
+                Some(at) => at + 1,
+                None => {
+                    len = len.saturating_mul(PREFIX_GROWTH);
+                    continue;
+                }
+            },
+        };
+        let whole = cut == input.len();
+        if let Some(answer) = attempt(&input[..cut], whole) {
+            return answer;
+        }
+        assert!(!whole, "by_prefixes: no answer for the whole input");
+        len = len.saturating_mul(PREFIX_GROWTH);
+    }
+}
+
+pub fn scalar_kind(event: &Event) -> Option<(&'static str, &[u8])> {
+    Some(match event {
+        Event::StringValue { content, .. } => ("string_value", content),
+        Event::BareValue { content, .. } => ("bare_value", content),
+        Event::BoolTrue { content, .. } => ("bool_true", content),
+        Event::BoolFalse { content, .. } => ("bool_false", content),
+        Event::Nil { content, .. } => ("nil", content),
+        Event::Integer { content, .. } => ("integer", content),
+        Event::Float { content, .. } => ("float", content),
+        Event::Rational { content, .. } => ("rational", content),
+        Event::Complex { content, .. } => ("complex", content),
+        _ => return None,
+    })
+}
+
+struct TreeBuilder {
+    tree: Tree,
+    /// Open nodes, innermost last; starts with the document.
+    stack: Vec<usize>,
+    /// Key and key span of an attribute still waiting for its value.
+    pending_attr: Option<(Vec<u8>, Range<usize>)>,
+    /// Open arrays with the offset each starts at.
+    arrays: Vec<(Vec<Value>, usize)>,
+    /// Length of the skipped BOM; parser offsets index `source[skip..]`.
+    skip: usize,
+}
+
+impl TreeBuilder {
+    fn new(input: &[u8]) -> Self {
+        let skip = bom_len(input);
+        TreeBuilder {
+            tree: Tree {
+                nodes: vec![NodeData::new(NodeKind::Document, None, 0..input.len())],
+                errors: Vec::new(),
+                source: input.to_vec(),
+                bom: skip > 0,
+                path: None,
+                files: Vec::new(),
+                expansions: Vec::new(),
+                objects: false,
+            },
+            stack: vec![ROOT],
+            pending_attr: None,
+            arrays: Vec::new(),
+            skip,
+        }
+    }
+
+    /// The tree, with spans shifted past a skipped BOM.
+    fn finish(mut self) -> Tree {
+        self.settle();
+        let skip = self.skip;
+        let mut tree = self.tree;
+        if skip > 0 {
+            let shift = |span: &mut Range<usize>| *span = span.start + skip..span.end + skip;
+            for node in tree.nodes.iter_mut().skip(1) {
+                shift(&mut node.span);
+                node.attr_spans.iter_mut().for_each(shift);
+            }
+            tree.errors
+                .iter_mut()
+                .for_each(|error| shift(&mut error.span));
+        }
+        tree
+    }
+
+    fn current(&self) -> usize {
+        *self.stack.last().expect("document node")
+    }
+
+    fn node(&mut self, index: usize) -> &mut NodeData {
+        &mut self.tree.nodes[index]
+    }
+
+    fn settle(&mut self) {
+        if let Some((key, span)) = self.pending_attr.take() {
+            let current = self.current();
+            let node = self.node(current);
+            node.attrs.push((key, Value::flag()));
+            node.attr_spans.push(span);
+        }
+    }
+
+    fn add(&mut self, kind: NodeKind, span: &Range<usize>) -> usize {
+        let parent = self.current();
+        let index = self.tree.nodes.len();
+        self.tree
+            .nodes
+            .push(NodeData::new(kind, Some(parent), span.clone()));
+        self.node(parent).children.push(index);
+        index
+    }
+
+    fn open(&mut self, kind: NodeKind, span: &Range<usize>) {
+        self.settle();
+        let index = self.add(kind, span);
+        self.stack.push(index);
+    }
+
+    fn close(&mut self, span: &Range<usize>) {
+        self.settle();
+        if self.stack.len() > 1 {
+            let index = self.stack.pop().expect("open node");
+            self.node(index).span.end = span.end;
+        }
+    }
+
+    fn value(&mut self, value: Value, span: Range<usize>) {
+        if let Some((array, _)) = self.arrays.last_mut() {
+            array.push(value);
+            return;
+        }
+        let current = self.current();
+        let node = self.node(current);
+        match self.pending_attr.take() {
+            Some((key, _)) => {
+                node.attrs.push((key, value));
+                node.attr_spans.push(span);
+            }
+            None => node.values.push(value),
+        }
+    }
+
+    fn leaf(&mut self, kind: NodeKind, content: &[u8], span: &Range<usize>) {
+        self.settle();
+        let index = self.add(kind, span);
+        self.node(index).content = content.to_vec();
+    }
+
+    fn event(&mut self, event: &Event) {
+        let current = self.current();
+        let kind = self.tree.nodes[current].kind;
+        // Comment and freeform bodies accumulate into the node's content.
+        if matches!(kind, NodeKind::Comment | NodeKind::Freeform) {
+            match event {
+                Event::Text { content, .. }
+                | Event::RawContent { content, .. }
+                | Event::Raw { content, .. } => {
+                    let node = self.node(current);
+                    if kind == NodeKind::Comment && !node.content.is_empty() {
+                        node.content.push(b'\n');
+                    }
+                    node.content.extend_from_slice(content);
+                    return;
+                }
+                Event::CommentEnd { span } | Event::FreeformEnd { span } => {
+                    self.close(span);
+                    return;
+                }
+                _ => {}
+            }
+        }
+
+        match event {
+            Event::ElementStart { span } => self.open(NodeKind::Element, span),
+            Event::EmbeddedStart { span } => self.open(NodeKind::Embedded, span),
+            Event::DirectiveStart { span } => self.open(NodeKind::Directive, span),
+            Event::CommentStart { span } => self.open(NodeKind::Comment, span),
+            Event::FreeformStart { span } => self.open(NodeKind::Freeform, span),
+            Event::ElementEnd { span }
+            | Event::EmbeddedEnd { span }
+            | Event::DirectiveEnd { span }
+            | Event::CommentEnd { span }
+            | Event::FreeformEnd { span } => self.close(span),
+            Event::Name { content, .. } => {
+                let node = self.node(current);
+                if node.name.is_none() && node.attrs.is_empty() && node.children.is_empty() {
+                    node.name = Some(content.to_vec());
+                }
+            }
+            Event::Attr { content, span } => {
+                self.settle();
+                let written = &self.tree.source[self.skip..][span.clone()];
+                let key = key_span(written, span, content).unwrap_or_else(|| span.clone());
+                self.pending_attr = Some((content.to_vec(), key));
+            }
+            Event::ArrayStart { span } => self.arrays.push((Vec::new(), span.start)),
+            Event::ArrayEnd { span } => {
+                if let Some((array, start)) = self.arrays.pop() {
+                    self.value(Value::Array(array), start..span.end.max(start));
+                }
+            }
+            Event::Text { content, span }
+            | Event::RawContent { content, span }
+            | Event::Raw { content, span } => self.leaf(NodeKind::Text, content, span),
+            Event::Interpolation { content, span } => {
+                if self.pending_attr.is_some() || !self.arrays.is_empty() {
+                    self.value(
+                        Value::Scalar {
+                            kind: "interpolation",
+                            content: content.to_vec(),
+                        },
+                        span.clone(),
+                    );
+                } else {
+                    self.leaf(NodeKind::Interpolation, content, span);
+                }
+            }
+            Event::Reference { content, span } => self.leaf(NodeKind::Reference, content, span),
+            Event::Error { code, span } => self.tree.errors.push(TreeError {
+                code: error_code_name(code),
+                span: span.clone(),
+                file: None,
+            }),
+            _ => {
+                if let Some((kind, content)) = scalar_kind(event) {
+                    let span = event_parts(event).0.clone();
+                    self.value(
+                        Value::Scalar {
+                            kind,
+                            content: content.to_vec(),
+                        },
+                        span,
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Tree {
+    /// Parse `input` into a tree. Parse errors are recorded, not raised. A
+    /// leading UTF-8 byte order mark is skipped; spans still index `input`.
+    pub fn parse(input: &[u8]) -> Tree {
+        let mut builder = TreeBuilder::new(input);
+        Parser::new(&input[builder.skip..]).parse(|event| builder.event(&event));
+        builder.finish()
+    }
+
+    /// Parse `input` keeping only the elements and embedded elements named
+    /// `name`, each with everything inside it, as the top-level nodes. Their
+    /// indices in document order come with the tree, with those of matches
+    /// inside other matches when `nested` (they are not top-level nodes).
+    /// Nothing outside a match is added, including its parse errors.
+    pub fn select(input: &[u8], name: &[u8], nested: bool) -> (Tree, Vec<usize>) {
+        let mut builder = TreeBuilder::new(input);
+        let mut matches = Vec::new();
+        // The start of the structure whose name comes next, and whether it
+        // is embedded; then the elements open in the current match.
+        let mut opening: Option<(Range<usize>, bool)> = None;
+        let mut depth = 0usize;
+        let mut after_start = false;
+
+        Parser::new(&input[builder.skip..]).parse(|event| {
+            if depth == 0 {
+                match &event {
+                    Event::ElementStart { span } => opening = Some((span.clone(), false)),
+                    Event::EmbeddedStart { span } => opening = Some((span.clone(), true)),
+                    Event::Name { content, .. } => {
+                        if let Some((start, embedded)) = opening.take() {
+                            if &content[..] == name {
+                                let kind = match embedded {
+                                    false => NodeKind::Element,
+                                    true => NodeKind::Embedded,
+                                };
+                                builder.open(kind, &start);
+                                matches.push(builder.current());
+                                builder.event(&event);
+                                depth = 1;
+                            }
+                        }
+                    }
+                    _ => opening = None,
+                }
+                return;
+            }
+            let awaiting_name = std::mem::replace(
+                &mut after_start,
+                matches!(
+                    event,
+                    Event::ElementStart { .. } | Event::EmbeddedStart { .. }
+                ),
+            );
+            match &event {
+                Event::ElementStart { .. } | Event::EmbeddedStart { .. } => depth += 1,
+                Event::ElementEnd { .. } | Event::EmbeddedEnd { .. } => depth -= 1,
+                Event::Name { content, .. } if nested && awaiting_name && &content[..] == name => {
+                    matches.push(builder.current())
+                }
+                _ => {}
+            }
+            builder.event(&event);
+        });
+        (builder.finish(), matches)
+    }
+
+    /// Parse `input` only as far as its first top-level element, and that
+    /// element's index; `None` when it has none. The tree holds what comes
+    /// before the element and the element itself.
+    ///
+    /// Parsed `by_prefixes`: a top-level element ended in a prefix may only
+    /// have been closed by the cut, so it is taken once a later event that
+    /// is not an error (as the cut's are, at the top level) shows the parse
+    /// went past it, or when the prefix is the whole input.
+    pub fn first_element(input: &[u8]) -> Option<(Tree, usize)> {
+        by_prefixes(input, |prefix, whole| {
+            let mut builder = TreeBuilder::new(prefix);
+            let mut found: Option<usize> = None;
+            let mut confirmed = false;
+
+            Parser::new(&prefix[builder.skip..]).parse(|event| {
+                if found.is_some() {
+                    confirmed |= !matches!(event, Event::Error { .. });
+                    return;
+                }
+                let current = builder.current();
+                let closes = matches!(event, Event::ElementEnd { .. })
+                    && builder.stack.len() == 2
+                    && builder.tree.nodes[current].kind == NodeKind::Element;
+                builder.event(&event);
+                if closes {
+                    found = Some(current);
+                }
+            });
+            match found {
+                Some(index) if confirmed || whole => Some(Some((builder.finish(), index))),
+                None if whole => Some(None),
+                _ => None,
+            }
+        })
+    }
+
+    pub fn node(&self, index: usize) -> &NodeData {
+        &self.nodes[index]
+    }
+
+    /// The path a node or error with `file` came from, if known.
+    pub fn file_path(&self, file: Option<usize>) -> Option<&str> {
+        match file {
+            Some(file) => Some(&self.files[file].path),
+            None => self.path.as_deref(),
+        }
+    }
+
+    /// The source a node or error with `file` indexes.
+    pub fn file_source(&self, file: Option<usize>) -> &[u8] {
+        match file {
+            Some(file) => &self.files[file].source,
+            None => &self.source,
+        }
+    }
+
+    /// Concatenated text of the subtree at `index`, like the DOM's
+    /// `textContent`. Text and freeform content are included; comments are
+    /// not, and interpolations only as `!{{expr}}` when `interpolations` is
+    /// set. Pieces from different source lines are joined with a newline,
+    /// pieces on the same line are joined directly. Pieces from different
+    /// (included) files count as different lines.
+    pub fn text_content(&self, index: usize, interpolations: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut last_end: Option<(Option<usize>, usize)> = None;
+        self.collect_text(index, interpolations, &mut out, &mut last_end);
+        out
+    }
+
+    fn collect_text(
+        &self,
+        index: usize,
+        interpolations: bool,
+        out: &mut Vec<u8>,
+        last_end: &mut Option<(Option<usize>, usize)>,
+    ) {
+        let node = self.node(index);
+        let piece = match node.kind {
+            NodeKind::Text | NodeKind::Freeform => node.content.clone(),
+            NodeKind::Interpolation if interpolations => {
+                [b"!{{".as_slice(), &node.content, b"}}"].concat()
+            }
+            NodeKind::Comment | NodeKind::Interpolation | NodeKind::Reference => return,
+            _ => {
+                for &child in &node.children {
+                    self.collect_text(child, interpolations, out, last_end);
+                }
+                return;
+            }
+        };
+        if let Some((file, end)) = *last_end {
+            let gap = if file == node.file {
+                self.file_source(file)
+                    .get(end..node.span.start)
+                    .unwrap_or_default()
+            } else {
+                b"\n"
+            };
+            if gap.contains(&b'\n') {
+                out.push(b'\n');
+            }
+        }
+        out.extend_from_slice(&piece);
+        *last_end = Some((node.file, node.span.end));
+    }
+
+    /// Flatten the subtree at `index` back into events (the document node
+    /// itself contributes none).
+    pub fn events(&self, index: usize) -> Vec<OwnedEvent> {
+        let mut out = Vec::new();
+        self.push_events(index, &mut out);
+        out
+    }
+
+    fn push_events(&self, index: usize, out: &mut Vec<OwnedEvent>) {
+        let node = self.node(index);
+        let mut push = |kind: &str, content: Option<&[u8]>| {
+            out.push(OwnedEvent {
+                kind: kind.to_string(),
+                content: content.map(<[u8]>::to_vec),
+                index,
+            })
+        };
+
+        match node.kind {
+            NodeKind::Text => return push("text", Some(&node.content)),
+            NodeKind::Interpolation => return push("interpolation", Some(&node.content)),
+            NodeKind::Reference => return push("reference", Some(&node.content)),
+            NodeKind::Comment => {
+                push("comment_start", None);
+                for line in node.content.split(|&b| b == b'\n') {
+                    push("text", Some(line));
+                }
+                return push("comment_end", None);
+            }
+            NodeKind::Freeform => {
+                push("freeform_start", None);
+                push("raw_content", Some(&node.content));
+                return push("freeform_end", None);
+            }
+            _ => {}
+        }
+
+        let bracket = node.kind.bracket();
+        if let Some(bracket) = bracket {
+            push(&format!("{}_start", bracket), None);
+            if let Some(name) = &node.name {
+                push("name", Some(name));
+            }
+        }
+        for (key, value) in &node.attrs {
+            out.push(OwnedEvent {
+                kind: "attr".to_string(),
+                content: Some(key.clone()),
+                index,
+            });
+            push_value(value, index, out);
+        }
+        for value in &node.values {
+            push_value(value, index, out);
+        }
+        for &child in &node.children {
+            self.push_events(child, out);
+        }
+        if let Some(bracket) = bracket {
+            out.push(OwnedEvent {
+                kind: format!("{}_end", bracket),
+                content: None,
+                index,
+            });
+        }
+    }
+}
+
+fn push_value(value: &Value, index: usize, out: &mut Vec<OwnedEvent>) {
+    match value {
+        Value::Scalar { kind, content } => out.push(OwnedEvent {
+            kind: kind.to_string(),
+            content: Some(content.clone()),
+            index,
+        }),
+        Value::Array(items) => {
+            out.push(OwnedEvent {
+                kind: "array_start".to_string(),
+                content: None,
+                index,
+            });
+            for item in items {
+                push_value(item, index, out);
+            }
+            out.push(OwnedEvent {
+                kind: "array_end".to_string(),
+                content: None,
+                index,
+            });
+        }
+    }
+}
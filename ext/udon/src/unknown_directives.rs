@@ -0,0 +1,140 @@
+//! Diagnostics for directives nobody handles (`unknown_directives:`).
+//!
+//! A directive is known when `UdonNative::Directives` has a handler for it,
+//! when its name is in `known_directives:`, or when it is one of the
+//! directives this library interprets itself (`BUILTIN`). Names compare
+//! namespace-qualified, as `"namespace:name"`. With `:warn` an unknown
+//! directive gets a `:warning` event after its name, and with `:error` an
+//! `:error` event, both with code `unknown_directive`, the qualified
+//! `:name`, the name's span and, when a known name is within a couple of
+//! edits, a `:suggestion`. `strict_directives: true` raises the first
+//! `:error` as `UdonNative::DirectiveError` once the parse is done.
+
+use magnus::{prelude::*, Error, RHash, RString, Ruby, Symbol};
+use udon_core::Event;
+
+use crate::{directives, errors, options::UnknownDirectives, Converter};
+
+/// Directives handled by `parse_tree` and `conditions:`.
+pub const BUILTIN: &[&str] = &["define", "if", "include", "unless", "use"];
+
+/// Edits a misspelling may be from the name it is suggested for.
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+/// Levenshtein distance between `a` and `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The known name closest to `name`, if one is close enough to be a typo.
+fn suggestion<'k>(known: &'k [String], name: &str) -> Option<&'k str> {
+    known
+        .iter()
+        .map(|candidate| (distance(name, candidate), candidate))
+        // Short enough names are a couple of edits from anything.
+        .filter(|&(edits, candidate)| {
+            let longer = name.chars().count().max(candidate.chars().count());
+            edits <= MAX_SUGGESTION_DISTANCE && edits < longer
+        })
+        .min_by_key(|&(edits, _)| edits)
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+/// Checks the directives of one parse against the known names.
+pub struct Check {
+    mode: UnknownDirectives,
+    strict: bool,
+    /// Sorted, so the suggestion among equally close names is stable.
+    known: Vec<String>,
+    /// The last event opened a directive, whose name is next.
+    awaiting_name: bool,
+    /// The first unknown directive's message, under `strict_directives`.
+    failure: Option<String>,
+}
+
+impl Check {
+    /// `None` when unknown directives are allowed, so there is nothing to do.
+    pub fn new(ruby: &Ruby, converter: &Converter) -> Result<Option<Self>, Error> {
+        let options = converter.options;
+        if options.unknown_directives == UnknownDirectives::Allow {
+            return Ok(None);
+        }
+        let mut known: Vec<String> = directives::registered_keys(ruby)?;
+        known.extend(options.known_directives.iter().cloned());
+        known.extend(BUILTIN.iter().map(|name| name.to_string()));
+        known.sort();
+        known.dedup();
+        Ok(Some(Check {
+            mode: options.unknown_directives,
+            strict: options.strict_directives,
+            known,
+            awaiting_name: false,
+            failure: None,
+        }))
+    }
+
+    /// Follow `event`; the diagnostic to put after it when it names an
+    /// unknown directive.
+    pub fn event(&mut self, event: &Event, converter: &Converter) -> Option<RHash> {
+        let awaiting_name = std::mem::take(&mut self.awaiting_name);
+        let Event::Name { content, span } = event else {
+            self.awaiting_name = matches!(event, Event::DirectiveStart { .. });
+            return None;
+        };
+        let name = String::from_utf8_lossy(content);
+        let known = |name: &str| {
+            self.known
+                .binary_search_by(|candidate| candidate.as_str().cmp(name))
+                .is_ok()
+        };
+        if !awaiting_name || known(&name) {
+            return None;
+        }
+        let suggestion = suggestion(&self.known, &name);
+        let mut message = format!("unknown directive !{}", name);
+        if let Some(suggestion) = suggestion {
+            message.push_str(&format!(" (did you mean !{}?)", suggestion));
+        }
+        let hash = RHash::new();
+        let reported = converter.spans.convert(span);
+        if self.mode == UnknownDirectives::Warn {
+            let _ = hash.aset(Symbol::new("type"), Symbol::new("warning"));
+            let _ = hash.aset(Symbol::new("content"), RString::new(&message));
+        } else {
+            let _ = hash.aset(Symbol::new("type"), Symbol::new("error"));
+            let _ = hash.aset(Symbol::new("message"), RString::new(&message));
+            if converter.options.severity {
+                let _ = hash.aset(Symbol::new("severity"), Symbol::new("recoverable"));
+            }
+            if self.strict && self.failure.is_none() {
+                let span = converter.spans.reported(span);
+                self.failure = Some(format!("{} at {}...{}", message, span.start, span.end));
+            }
+        }
+        let _ = hash.aset(Symbol::new("code"), Symbol::new("unknown_directive"));
+        let _ = hash.aset(Symbol::new("name"), RString::new(&name));
+        if let Some(suggestion) = suggestion {
+            let _ = hash.aset(Symbol::new("suggestion"), RString::new(suggestion));
+        }
+        let _ = hash.aset(Symbol::new("span"), reported);
+        Some(hash)
+    }
+
+    /// Raise the first unknown directive under `strict_directives: true`.
+    pub fn finish(self, ruby: &Ruby) -> Result<(), Error> {
+        self.failure.map_or(Ok(()), |message| {
+            Err(errors::directive_error(ruby, message))
+        })
+    }
+}
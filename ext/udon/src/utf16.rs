@@ -0,0 +1,51 @@
+//! Byte offset -> UTF-16 code unit offset mapping (`spans: :utf16`).
+//!
+//! A UTF-16 offset is the byte offset less what the characters before it
+//! take beyond their UTF-16 length: 1 for each 2-byte character, 2 for each
+//! 3-byte character and 2 for each 4-byte character (a surrogate pair). The
+//! index records the running total after every multibyte character, so a
+//! lookup is a binary search over them and ASCII-only input maps for free.
+
+/// UTF-16 offsets of a UTF-8 source.
+pub struct Utf16Index {
+    /// Byte offset just past each multibyte character, with the bytes saved
+    /// by UTF-16 up to there.
+    checkpoints: Vec<(usize, usize)>,
+}
+
+impl Utf16Index {
+    pub fn new(source: &[u8]) -> Self {
+        let mut checkpoints = Vec::new();
+        let mut saved = 0;
+        let mut at = 0;
+        while at < source.len() {
+            let lead = source[at];
+            let (len, units) = match lead {
+                0x00..=0x7f => {
+                    // Skip the ASCII run in one go.
+                    at += source[at..].iter().take_while(|b| b.is_ascii()).count();
+                    continue;
+                }
+                0xc0..=0xdf => (2, 1),
+                0xe0..=0xef => (3, 1),
+                0xf0..=0xf7 => (4, 2),
+                // A stray continuation byte; counted as one unit.
+                _ => (1, 1),
+            };
+            let len = len.min(source.len() - at);
+            at += len;
+            saved += len.saturating_sub(units);
+            checkpoints.push((at, saved));
+        }
+        Utf16Index { checkpoints }
+    }
+
+    /// The UTF-16 offset of byte `offset`.
+    pub fn offset(&self, offset: usize) -> usize {
+        let passed = self.checkpoints.partition_point(|&(end, _)| end <= offset);
+        match passed {
+            0 => offset,
+            n => offset - self.checkpoints[n - 1].1,
+        }
+    }
+}
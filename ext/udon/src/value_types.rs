@@ -0,0 +1,223 @@
+//! `UdonNative::ValueTypes`: custom scalar types for string values
+//! (`value_plugins:`).
+//!
+//! A plugin pairs a kind with a pattern and a block. With `value_plugins:
+//! true`, each bare (unquoted) string value is matched against the plugins
+//! in registration order, and the first match turns the event into
+//! `{type: :custom_value, kind:, value:, raw:, span:}`, `value` being what
+//! the block returns for the string. `value_plugins: :all` includes quoted
+//! strings. A block that raises gives a `value_plugin_failed` error event
+//! in the value's place.
+//!
+//! Patterns are compiled once, on registration: a Regexp is translated to
+//! a byte regex on the Rust side (its `i`, `x` and `m` options included),
+//! and a String is a literal prefix, checked without a regex at all. Ruby
+//! only runs for values that match.
+//!
+//! A Regexp's source is read as `regex` crate syntax. `^` and `$` match at
+//! line boundaries, as they always do in Ruby; Onigmo-only syntax (`\h`,
+//! `\Z`, lookaround, backreferences) fails to compile and raises
+//! `ArgumentError` rather than matching differently.
+
+use magnus::{
+    block::Proc, function, prelude::*, typed_data::Obj, value::Lazy, Error, RArray, RHash, RModule,
+    RRegexp, RString, Ruby, Symbol, Value,
+};
+use regex::bytes::{Regex, RegexBuilder};
+
+static VALUE_TYPES: Lazy<RModule> = Lazy::new(|ruby| {
+    let module: RModule = ruby
+        .class_object()
+        .const_get("UdonNative")
+        .expect("UdonNative is defined at init");
+    module
+        .const_get("ValueTypes")
+        .expect("ValueTypes is defined at init")
+});
+
+/// Ruby's `Regexp::IGNORECASE`, `EXTENDED` and `MULTILINE` option bits.
+const IGNORECASE: i64 = 1;
+const EXTENDED: i64 = 2;
+const MULTILINE: i64 = 4;
+
+enum Pattern {
+    Regex(Regex),
+    Prefix(Vec<u8>),
+}
+
+/// A registered plugin's kind and compiled pattern; its block is kept next
+/// to it in the registry so Ruby marks it.
+#[magnus::wrap(class = "UdonNative::ValueTypes::Plugin", free_immediately, size)]
+pub struct Plugin {
+    kind: String,
+    pattern: Pattern,
+}
+
+impl Plugin {
+    fn matches(&self, value: &[u8]) -> bool {
+        match &self.pattern {
+            Pattern::Regex(regex) => regex.is_match(value),
+            Pattern::Prefix(prefix) => value.starts_with(prefix),
+        }
+    }
+}
+
+/// Registry entries, `[kind, plugin, block]` in registration order.
+fn entries(ruby: &Ruby) -> Result<RArray, Error> {
+    ruby.get_inner(&VALUE_TYPES).ivar_get("@plugins")
+}
+
+/// The position of `kind`'s entry, if it is registered.
+fn position(entries: RArray, kind: &str) -> Result<Option<usize>, Error> {
+    for (index, entry) in entries.each().enumerate() {
+        let registered: Symbol = RArray::try_convert(entry?)?.entry(0)?;
+        if registered.name()? == kind {
+            return Ok(Some(index));
+        }
+    }
+    Ok(None)
+}
+
+/// Compile a Regexp or String `pattern`.
+fn compile(ruby: &Ruby, pattern: Value) -> Result<Pattern, Error> {
+    if let Some(regexp) = RRegexp::from_value(pattern) {
+        let source: String = regexp.funcall("source", ())?;
+        let options: i64 = regexp.funcall("options", ())?;
+        return RegexBuilder::new(&source)
+            .case_insensitive(options & IGNORECASE != 0)
+            .ignore_whitespace(options & EXTENDED != 0)
+            .dot_matches_new_line(options & MULTILINE != 0)
+            .multi_line(true)
+            .build()
+            .map(Pattern::Regex)
+            .map_err(|err| {
+                Error::new(
+                    ruby.exception_arg_error(),
+                    format!("unsupported value type pattern /{}/: {}", source, err),
+                )
+            });
+    }
+    match RString::from_value(pattern) {
+        Some(prefix) => Ok(Pattern::Prefix(unsafe { prefix.as_slice() }.to_vec())),
+        None => Err(Error::new(
+            ruby.exception_type_error(),
+            "a value type pattern must be a Regexp or a String prefix",
+        )),
+    }
+}
+
+/// `UdonNative::ValueTypes.register(kind, pattern) { |string| ... }`
+///
+/// A kind registered again keeps its place in the order with the new
+/// pattern and block.
+fn register(ruby: &Ruby, kind: Symbol, pattern: Value) -> Result<(), Error> {
+    if !ruby.block_given() {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "ValueTypes.register requires a block",
+        ));
+    }
+    let kind = kind.name()?.into_owned();
+    let plugin = Plugin {
+        kind: kind.clone(),
+        pattern: compile(ruby, pattern)?,
+    };
+    let entry = RArray::from_vec(vec![
+        Symbol::new(&kind).as_value(),
+        Obj::wrap(plugin).as_value(),
+        ruby.block_proc()?.as_value(),
+    ]);
+    let entries = entries(ruby)?;
+    match position(entries, &kind)? {
+        Some(index) => entries.store(index as isize, entry),
+        None => entries.push(entry),
+    }
+}
+
+/// `UdonNative::ValueTypes.unregister(kind)`: whether it was registered.
+fn unregister(ruby: &Ruby, kind: Symbol) -> Result<bool, Error> {
+    let entries = entries(ruby)?;
+    let Some(index) = position(entries, &kind.name()?)? else {
+        return Ok(false);
+    };
+    let _: Value = entries.funcall("delete_at", (index,))?;
+    Ok(true)
+}
+
+/// `UdonNative::ValueTypes.clear`
+fn clear(ruby: &Ruby) -> Result<(), Error> {
+    entries(ruby)?.clear()
+}
+
+/// `UdonNative::ValueTypes.registered`: the kinds, in the order they are
+/// tried.
+fn registered(ruby: &Ruby) -> Result<RArray, Error> {
+    let kinds = RArray::new();
+    for entry in entries(ruby)?.each() {
+        kinds.push(RArray::try_convert(entry?)?.entry::<Value>(0)?)?;
+    }
+    Ok(kinds)
+}
+
+/// The plugins registered when a parse starts, in order.
+pub struct Plugins {
+    plugins: Vec<(Obj<Plugin>, Proc)>,
+}
+
+impl Plugins {
+    pub fn load(ruby: &Ruby) -> Result<Self, Error> {
+        let mut plugins = Vec::new();
+        for entry in entries(ruby)?.each() {
+            let entry = RArray::try_convert(entry?)?;
+            plugins.push((entry.entry(1)?, entry.entry(2)?));
+        }
+        Ok(Plugins { plugins })
+    }
+
+    /// Turn the string value event `hash` with `content` into a
+    /// `:custom_value` if a plugin matches it. Returns what broke out of the
+    /// plugin's block, if anything did.
+    pub fn apply(&self, ruby: &Ruby, content: &[u8], hash: RHash, severity: bool) -> Option<Error> {
+        let (plugin, block) = self
+            .plugins
+            .iter()
+            .find(|(plugin, _)| plugin.matches(content))?;
+        let raw = RString::from_slice(content);
+        let _ = hash.delete::<_, Value>(Symbol::new("content"));
+        let _ = hash.aset(Symbol::new("kind"), Symbol::new(&plugin.kind));
+        let _ = hash.aset(Symbol::new("raw"), raw);
+        let error = match block.call::<_, Value>((raw,)) {
+            Ok(value) => {
+                let _ = hash.aset(Symbol::new("type"), Symbol::new("custom_value"));
+                let _ = hash.aset(Symbol::new("value"), value);
+                return None;
+            }
+            Err(error) => error,
+        };
+        let Some(exception) = error.value() else {
+            return Some(error);
+        };
+        let message = exception
+            .funcall::<_, _, Value>("message", ())
+            .unwrap_or_else(|_| ruby.qnil().as_value());
+        let _ = hash.aset(Symbol::new("type"), Symbol::new("error"));
+        let _ = hash.aset(Symbol::new("code"), Symbol::new("value_plugin_failed"));
+        let _ = hash.aset(Symbol::new("message"), message);
+        if severity {
+            let _ = hash.aset(Symbol::new("severity"), Symbol::new("recoverable"));
+        }
+        None
+    }
+}
+
+/// Define `UdonNative::ValueTypes`.
+pub fn define(ruby: &Ruby, module: RModule) -> Result<(), Error> {
+    let value_types = module.define_module("ValueTypes")?;
+    value_types.ivar_set("@plugins", RArray::new())?;
+    value_types.define_class("Plugin", ruby.class_object())?;
+    value_types.define_singleton_method("register", function!(register, 2))?;
+    value_types.define_singleton_method("unregister", function!(unregister, 1))?;
+    value_types.define_singleton_method("clear", function!(clear, 0))?;
+    value_types.define_singleton_method("registered", function!(registered, 0))?;
+    Ok(())
+}
@@ -0,0 +1,248 @@
+//! `UdonNative::Writer`: a builder API over the emitter.
+//!
+//! ```ruby
+//! w = UdonNative::Writer.new
+//! w.element("div", id: "main") do
+//!   w.directive("include", "header.udon", namespace: "app")
+//!   w.text(Udon.interp("user.name"))
+//! end
+//! w.to_s
+//! ```
+
+use std::cell::{Cell, RefCell};
+
+use magnus::{
+    encoding::RbEncoding, function, method, prelude::*, r_hash::ForEach, scan_args::get_kwargs,
+    scan_args::scan_args, typed_data::Obj, Error, Float, Integer, RArray, RHash, RModule, RString,
+    Ruby, Symbol, TryConvert, Value,
+};
+
+use crate::{emitter::Emitter, errors, options};
+
+/// Marker for an interpolation expression, rendered as `!{{expr}}` rather than
+/// escaped as text or quoted as a string.
+#[magnus::wrap(class = "UdonNative::Interpolation", free_immediately, size)]
+pub struct Interpolation {
+    expression: String,
+}
+
+impl Interpolation {
+    pub fn new(expression: String) -> Self {
+        Interpolation { expression }
+    }
+
+    pub fn expression(&self) -> String {
+        self.expression.clone()
+    }
+}
+
+#[magnus::wrap(class = "UdonNative::Writer", free_immediately, size)]
+pub struct Writer {
+    emitter: RefCell<Emitter>,
+    /// Events fed so far; used as the index in structure errors.
+    events: Cell<usize>,
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Writer {
+            emitter: RefCell::new(Emitter::new()),
+            events: Cell::new(0),
+        }
+    }
+
+    /// `Writer.new(quote: :preserve)`; see `options::check_quote`.
+    fn create(ruby: &Ruby, args: &[Value]) -> Result<Self, Error> {
+        let args = scan_args::<(), (), (), (), RHash, ()>(args)?;
+        let kwargs = get_kwargs::<_, (), (Option<Symbol>,), ()>(args.keywords, &[], &["quote"])?;
+        options::check_quote(ruby, kwargs.optional.0)?;
+        Ok(Self::new())
+    }
+
+    fn feed(&self, ruby: &Ruby, kind: &str, content: Option<&[u8]>) -> Result<(), Error> {
+        let index = self.events.get();
+        self.events.set(index + 1);
+        self.emitter
+            .borrow_mut()
+            .event(kind, content, index)
+            .map_err(|err| errors::emit_error(ruby, err))
+    }
+
+    /// Feed a Ruby value in a value position.
+    fn feed_value(&self, ruby: &Ruby, value: Value) -> Result<(), Error> {
+        if value.is_nil() {
+            return self.feed(ruby, "nil", Some(b"null"));
+        }
+        if value.is_kind_of(ruby.class_true_class()) {
+            return self.feed(ruby, "bool_true", Some(b"true"));
+        }
+        if value.is_kind_of(ruby.class_false_class()) {
+            return self.feed(ruby, "bool_false", Some(b"false"));
+        }
+        if let Ok(interp) = <&Interpolation>::try_convert(value) {
+            return self.feed(ruby, "interpolation", Some(interp.expression.as_bytes()));
+        }
+        if let Some(items) = RArray::from_value(value) {
+            self.feed(ruby, "array_start", None)?;
+            for item in items.to_vec::<Value>()? {
+                self.feed_value(ruby, item)?;
+            }
+            return self.feed(ruby, "array_end", None);
+        }
+        if let Some(symbol) = Symbol::from_value(value) {
+            return self.feed(ruby, "bare_value", Some(symbol.name()?.as_bytes()));
+        }
+        if Integer::from_value(value).is_some() {
+            let text: String = value.funcall("to_s", ())?;
+            return self.feed(ruby, "integer", Some(text.as_bytes()));
+        }
+        if Float::from_value(value).is_some() {
+            let text: String = value.funcall("to_s", ())?;
+            return self.feed(ruby, "float", Some(text.as_bytes()));
+        }
+        let text: RString = value.funcall("to_s", ())?;
+        self.feed(ruby, "string_value", Some(unsafe { text.as_slice() }))
+    }
+
+    /// Feed `{key => value}` pairs as attributes.
+    fn feed_attrs(&self, ruby: &Ruby, attrs: RHash) -> Result<(), Error> {
+        attrs.foreach(|key: Value, value: Value| {
+            let key: String = key.funcall("to_s", ())?;
+            self.feed(ruby, "attr", Some(key.as_bytes()))?;
+            self.feed_value(ruby, value)?;
+            Ok(ForEach::Continue)
+        })
+    }
+
+    /// Yield the writer to the block, if one was given.
+    fn nest(ruby: &Ruby, rb_self: Obj<Writer>) -> Result<(), Error> {
+        if ruby.block_given() {
+            let _: Value = ruby.yield_value(rb_self)?;
+        }
+        Ok(())
+    }
+
+    /// `element(name, attrs = {}) { |w| ... }`
+    pub fn element(
+        ruby: &Ruby,
+        rb_self: Obj<Writer>,
+        args: &[Value],
+    ) -> Result<Obj<Writer>, Error> {
+        let args = scan_args::<(String,), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (name,) = args.required;
+        let (attrs,) = args.optional;
+
+        rb_self.feed(ruby, "element_start", None)?;
+        rb_self.feed(ruby, "name", Some(name.as_bytes()))?;
+        if let Some(attrs) = attrs {
+            rb_self.feed_attrs(ruby, attrs)?;
+        }
+        Self::nest(ruby, rb_self)?;
+        rb_self.feed(ruby, "element_end", None)?;
+        Ok(rb_self)
+    }
+
+    /// `directive(name, *args, namespace: nil) { |w| ... }`
+    ///
+    /// Positional arguments become directive values; a trailing Hash argument
+    /// becomes attributes. The namespace is written as `!namespace:name`.
+    pub fn directive(
+        ruby: &Ruby,
+        rb_self: Obj<Writer>,
+        args: &[Value],
+    ) -> Result<Obj<Writer>, Error> {
+        let args = scan_args::<(String,), (), RArray, (), RHash, ()>(args)?;
+        let (name,) = args.required;
+        let kwargs =
+            get_kwargs::<_, (), (Option<String>,), ()>(args.keywords, &[], &["namespace"])?;
+        let (namespace,) = kwargs.optional;
+
+        let qualified = match namespace {
+            Some(namespace) => format!("{}:{}", namespace, name),
+            None => name,
+        };
+        rb_self.feed(ruby, "directive_start", None)?;
+        rb_self.feed(ruby, "name", Some(qualified.as_bytes()))?;
+        for arg in args.splat.to_vec::<Value>()? {
+            match RHash::from_value(arg) {
+                Some(attrs) => rb_self.feed_attrs(ruby, attrs)?,
+                None => rb_self.feed_value(ruby, arg)?,
+            }
+        }
+        Self::nest(ruby, rb_self)?;
+        rb_self.feed(ruby, "directive_end", None)?;
+        Ok(rb_self)
+    }
+
+    /// `inline_directive(name, content)` writes `!{name content}` in the text flow.
+    pub fn inline_directive(
+        ruby: &Ruby,
+        rb_self: Obj<Writer>,
+        name: String,
+        content: String,
+    ) -> Result<Obj<Writer>, Error> {
+        let index = rb_self.events.get();
+        rb_self.events.set(index + 1);
+        rb_self
+            .emitter
+            .borrow_mut()
+            .inline_directive(name.as_bytes(), content.as_bytes(), index)
+            .map_err(|err| errors::emit_error(ruby, err))?;
+        Ok(rb_self)
+    }
+
+    /// `text(content)`; an `Interpolation` renders as `!{{expr}}`.
+    pub fn text(ruby: &Ruby, rb_self: Obj<Writer>, content: Value) -> Result<Obj<Writer>, Error> {
+        if let Ok(interp) = <&Interpolation>::try_convert(content) {
+            rb_self.feed(ruby, "interpolation", Some(interp.expression.as_bytes()))?;
+        } else {
+            let content: RString = content.funcall("to_s", ())?;
+            rb_self.feed(ruby, "text", Some(unsafe { content.as_slice() }))?;
+        }
+        Ok(rb_self)
+    }
+
+    /// `comment(content)`
+    pub fn comment(
+        ruby: &Ruby,
+        rb_self: Obj<Writer>,
+        content: String,
+    ) -> Result<Obj<Writer>, Error> {
+        rb_self.feed(ruby, "comment_start", None)?;
+        rb_self.feed(ruby, "text", Some(format!(" {}", content).as_bytes()))?;
+        rb_self.feed(ruby, "comment_end", None)?;
+        Ok(rb_self)
+    }
+
+    /// The document written so far; raises if anything is still open.
+    pub fn to_s(ruby: &Ruby, rb_self: &Writer) -> Result<RString, Error> {
+        let mut emitter = rb_self.emitter.borrow_mut();
+        emitter
+            .finish()
+            .map_err(|err| errors::emit_error(ruby, err))?;
+        Ok(RString::enc_new(emitter.output(), RbEncoding::utf8()))
+    }
+}
+
+/// Define `UdonNative::Writer` and `UdonNative::Interpolation`.
+pub fn define(ruby: &Ruby, module: RModule) -> Result<(), Error> {
+    let class = module.define_class("Writer", ruby.class_object())?;
+    class.define_singleton_method("new", function!(Writer::create, -1))?;
+    class.define_method("element", method!(Writer::element, -1))?;
+    class.define_method("directive", method!(Writer::directive, -1))?;
+    class.define_method("inline_directive", method!(Writer::inline_directive, 2))?;
+    class.define_method("text", method!(Writer::text, 1))?;
+    class.define_method("comment", method!(Writer::comment, 1))?;
+    class.define_method("to_s", method!(Writer::to_s, 0))?;
+
+    let class = module.define_class("Interpolation", ruby.class_object())?;
+    class.define_singleton_method("new", function!(Interpolation::new, 1))?;
+    class.define_method("expression", method!(Interpolation::expression, 0))?;
+    Ok(())
+}
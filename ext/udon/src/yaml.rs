@@ -0,0 +1,450 @@
+//! Bridging UDON and YAML.
+//!
+//! The mapping is structural: an element becomes a mapping of its attributes
+//! and child elements (keys shared; a repeated key becomes a sequence), with
+//! its text under a configurable key (`"_text"` by default). Directives and
+//! comments have no YAML counterpart and are dropped. `from_yaml` reverses
+//! the mapping: scalars and scalar sequences become attributes, mappings
+//! become child elements, and sequences of mappings become repeated elements.
+
+use magnus::{
+    encoding::RbEncoding, scan_args::get_kwargs, scan_args::scan_args, Error, RHash, RString, Ruby,
+    Symbol, Value,
+};
+use udon_core::{Event, Parser};
+use yaml_rust2::{
+    parser::{Event as YamlEvent, Parser as YamlParser},
+    yaml::Hash,
+    Yaml, YamlEmitter, YamlLoader,
+};
+
+use crate::{coerce, emitter::Emitter, errors};
+
+const DEFAULT_TEXT_KEY: &str = "_text";
+
+// ========== UDON -> YAML ==========
+
+/// An element being collected: values per key in first-seen order, and text.
+#[derive(Default)]
+struct Node {
+    name: String,
+    entries: Vec<(String, Vec<Yaml>)>,
+    text: String,
+}
+
+impl Node {
+    fn insert(&mut self, key: String, value: Yaml) {
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, values)) => values.push(value),
+            None => self.entries.push((key, vec![value])),
+        }
+    }
+
+    fn into_yaml(self, text_key: &str) -> Yaml {
+        let mut hash = Hash::new();
+        for (key, mut values) in self.entries {
+            let value = if values.len() == 1 {
+                values.pop().expect("one value")
+            } else {
+                Yaml::Array(values)
+            };
+            hash.insert(Yaml::String(key), value);
+        }
+        if !self.text.is_empty() {
+            hash.insert(Yaml::String(text_key.to_string()), Yaml::String(self.text));
+        }
+        Yaml::Hash(hash)
+    }
+}
+
+fn lossy(content: &[u8]) -> String {
+    String::from_utf8_lossy(content).into_owned()
+}
+
+fn scalar(event: &Event) -> Option<Yaml> {
+    Some(match event {
+        Event::BoolTrue { .. } => Yaml::Boolean(true),
+        Event::BoolFalse { .. } => Yaml::Boolean(false),
+        Event::Nil { .. } => Yaml::Null,
+        Event::Integer { content, .. } => {
+            let text = lossy(content).replace('_', "");
+            match text.parse() {
+                Ok(n) => Yaml::Integer(n),
+                Err(_) => Yaml::String(text),
+            }
+        }
+        Event::Float { content, .. } => Yaml::Real(lossy(content).replace('_', "")),
+        Event::StringValue { content, .. }
+        | Event::BareValue { content, .. }
+        | Event::Rational { content, .. }
+        | Event::Complex { content, .. }
+        | Event::Reference { content, .. } => Yaml::String(lossy(content)),
+        Event::Interpolation { content, .. } => {
+            Yaml::String(format!("!{{{{{}}}}}", lossy(content)))
+        }
+        _ => return None,
+    })
+}
+
+struct TreeBuilder<'a> {
+    source: &'a [u8],
+    text_key: &'a str,
+    stack: Vec<Node>,
+    awaiting_name: bool,
+    pending_attr: Option<String>,
+    arrays: Vec<Vec<Yaml>>,
+    /// Depth of directive or comment nesting being skipped.
+    skipping: usize,
+    /// End of the last text piece, to rejoin lines with newlines.
+    text_end: Option<usize>,
+}
+
+impl TreeBuilder<'_> {
+    fn settle(&mut self) {
+        self.awaiting_name = false;
+        if let Some(key) = self.pending_attr.take() {
+            self.current().insert(key, Yaml::Boolean(true));
+        }
+    }
+
+    fn current(&mut self) -> &mut Node {
+        self.stack.last_mut().expect("root node")
+    }
+
+    fn value(&mut self, value: Yaml) {
+        if let Some(array) = self.arrays.last_mut() {
+            array.push(value);
+        } else if let Some(key) = self.pending_attr.take() {
+            self.current().insert(key, value);
+        }
+    }
+
+    fn text(&mut self, content: &str, span: &std::ops::Range<usize>) {
+        self.settle();
+        let newline = self.text_end.is_some_and(|end| {
+            self.source
+                .get(end..span.start)
+                .is_some_and(|gap| gap.contains(&b'\n'))
+        });
+        let node = self.stack.last_mut().expect("root node");
+        if newline && !node.text.is_empty() {
+            node.text.push('\n');
+        }
+        node.text.push_str(content);
+        self.text_end = Some(span.end);
+    }
+
+    fn event(&mut self, event: &Event) {
+        if self.skipping > 0 {
+            match event {
+                Event::DirectiveStart { .. } | Event::CommentStart { .. } => self.skipping += 1,
+                Event::DirectiveEnd { .. } | Event::CommentEnd { .. } => self.skipping -= 1,
+                _ => {}
+            }
+            return;
+        }
+        match event {
+            Event::ElementStart { .. } | Event::EmbeddedStart { .. } => {
+                self.settle();
+                self.stack.push(Node::default());
+                self.awaiting_name = true;
+                self.text_end = None;
+            }
+            Event::Name { content, .. } if self.awaiting_name => {
+                self.awaiting_name = false;
+                self.current().name = lossy(content);
+            }
+            Event::ElementEnd { .. } | Event::EmbeddedEnd { .. } if self.stack.len() > 1 => {
+                self.settle();
+                let node = self.stack.pop().expect("element");
+                let name = node.name.clone();
+                let value = node.into_yaml(self.text_key);
+                self.current().insert(name, value);
+                self.text_end = None;
+            }
+            Event::DirectiveStart { .. } | Event::CommentStart { .. } => {
+                self.settle();
+                self.skipping = 1;
+            }
+            Event::Attr { content, .. } => {
+                self.settle();
+                self.pending_attr = Some(lossy(content));
+            }
+            Event::ArrayStart { .. } => self.arrays.push(Vec::new()),
+            Event::ArrayEnd { .. } => {
+                if let Some(array) = self.arrays.pop() {
+                    self.value(Yaml::Array(array));
+                }
+            }
+            Event::Text { content, span }
+            | Event::RawContent { content, span }
+            | Event::Raw { content, span } => self.text(&lossy(content), span),
+            Event::Interpolation { content, span }
+                if self.arrays.is_empty() && self.pending_attr.is_none() =>
+            {
+                self.text(&format!("!{{{{{}}}}}", lossy(content)), span)
+            }
+            _ => {
+                if let Some(value) = scalar(event) {
+                    self.awaiting_name = false;
+                    self.value(value);
+                }
+            }
+        }
+    }
+}
+
+/// Build the YAML tree for a UDON document.
+fn udon_to_yaml(input: &[u8], text_key: &str) -> Yaml {
+    let mut builder = TreeBuilder {
+        source: input,
+        text_key,
+        stack: vec![Node::default()],
+        awaiting_name: false,
+        pending_attr: None,
+        arrays: Vec::new(),
+        skipping: 0,
+        text_end: None,
+    };
+    Parser::new(input).parse(|event| builder.event(&event));
+    builder.settle();
+    // Close anything left open by malformed input.
+    while builder.stack.len() > 1 {
+        let node = builder.stack.pop().expect("element");
+        let name = node.name.clone();
+        let value = node.into_yaml(text_key);
+        builder.current().insert(name, value);
+    }
+    builder.stack.pop().expect("root node").into_yaml(text_key)
+}
+
+/// `UdonNative.to_yaml(input, text_key: "_text")`
+pub fn to_yaml(ruby: &Ruby, args: &[Value]) -> Result<RString, Error> {
+    let args = scan_args::<(Value,), (), (), (), RHash, ()>(args)?;
+    let (input,) = args.required;
+    let input = coerce::string(ruby, input)?;
+    let kwargs = get_kwargs::<_, (), (Option<String>,), ()>(args.keywords, &[], &["text_key"])?;
+    let text_key = kwargs
+        .optional
+        .0
+        .unwrap_or_else(|| DEFAULT_TEXT_KEY.to_string());
+
+    let doc = udon_to_yaml(unsafe { input.as_slice() }, &text_key);
+    let mut out = String::new();
+    YamlEmitter::new(&mut out)
+        .dump(&doc)
+        .map_err(|err| errors::yaml_error(ruby, format!("{:?}", err)))?;
+    out.push('\n');
+    Ok(RString::enc_new(out, RbEncoding::utf8()))
+}
+
+// ========== YAML -> UDON ==========
+
+struct UdonWriter<'a> {
+    ruby: &'a Ruby,
+    emitter: Emitter,
+    index: usize,
+    text_key: &'a str,
+}
+
+impl UdonWriter<'_> {
+    fn feed(&mut self, kind: &str, content: Option<&[u8]>) -> Result<(), Error> {
+        let index = self.index;
+        self.index += 1;
+        self.emitter
+            .event(kind, content, index)
+            .map_err(|err| errors::emit_error(self.ruby, err))
+    }
+
+    fn key(&self, key: &Yaml) -> Result<String, Error> {
+        match key {
+            Yaml::String(s) | Yaml::Real(s) => Ok(s.clone()),
+            Yaml::Integer(n) => Ok(n.to_string()),
+            Yaml::Boolean(b) => Ok(b.to_string()),
+            other => Err(errors::yaml_error(
+                self.ruby,
+                format!("unsupported mapping key: {:?}", other),
+            )),
+        }
+    }
+
+    fn scalar(&mut self, value: &Yaml) -> Result<(), Error> {
+        match value {
+            Yaml::String(s) => self.feed("string_value", Some(s.as_bytes())),
+            Yaml::Integer(n) => self.feed("integer", Some(n.to_string().as_bytes())),
+            Yaml::Real(s) => self.feed("float", Some(s.as_bytes())),
+            Yaml::Boolean(true) => self.feed("bool_true", Some(b"true")),
+            Yaml::Boolean(false) => self.feed("bool_false", Some(b"false")),
+            Yaml::Null => self.feed("nil", Some(b"null")),
+            other => Err(errors::yaml_error(
+                self.ruby,
+                format!("unsupported YAML value: {:?}", other),
+            )),
+        }
+    }
+
+    fn text(&mut self, value: &Yaml) -> Result<(), Error> {
+        let text = match value {
+            Yaml::String(s) | Yaml::Real(s) => s.clone(),
+            Yaml::Integer(n) => n.to_string(),
+            Yaml::Boolean(b) => b.to_string(),
+            Yaml::Null => return Ok(()),
+            _ => {
+                return Err(errors::yaml_error(
+                    self.ruby,
+                    format!("{} must be a scalar", self.text_key),
+                ))
+            }
+        };
+        for line in text.lines() {
+            self.feed("text", Some(line.as_bytes()))?;
+        }
+        Ok(())
+    }
+
+    fn is_scalar(value: &Yaml) -> bool {
+        !matches!(value, Yaml::Hash(_) | Yaml::Array(_))
+    }
+
+    /// Write `name` as one element, or one per item if `value` is a sequence.
+    fn elements(&mut self, name: &str, value: &Yaml) -> Result<(), Error> {
+        match value {
+            Yaml::Array(items) => items.iter().try_for_each(|item| self.element(name, item)),
+            _ => self.element(name, value),
+        }
+    }
+
+    fn element(&mut self, name: &str, value: &Yaml) -> Result<(), Error> {
+        self.feed("element_start", None)?;
+        self.feed("name", Some(name.as_bytes()))?;
+        match value {
+            Yaml::Hash(hash) => {
+                // Attributes first, then children and text in mapping order.
+                for (key, value) in hash {
+                    let key = self.key(key)?;
+                    if key == self.text_key {
+                        continue;
+                    }
+                    match value {
+                        Yaml::Array(items) if items.iter().all(Self::is_scalar) => {
+                            self.feed("attr", Some(key.as_bytes()))?;
+                            self.feed("array_start", None)?;
+                            items.iter().try_for_each(|item| self.scalar(item))?;
+                            self.feed("array_end", None)?;
+                        }
+                        value if Self::is_scalar(value) => {
+                            self.feed("attr", Some(key.as_bytes()))?;
+                            self.scalar(value)?;
+                        }
+                        _ => {}
+                    }
+                }
+                for (key, value) in hash {
+                    let key = self.key(key)?;
+                    if key == self.text_key {
+                        self.text(value)?;
+                    } else if let Yaml::Array(items) = value {
+                        if !items.iter().all(Self::is_scalar) {
+                            self.elements(&key, value)?;
+                        }
+                    } else if !Self::is_scalar(value) {
+                        self.element(&key, value)?;
+                    }
+                }
+            }
+            Yaml::Array(_) => {
+                return Err(errors::yaml_error(
+                    self.ruby,
+                    format!("nested sequence under {} has no UDON equivalent", name),
+                ))
+            }
+            scalar => self.text(scalar)?,
+        }
+        self.feed("element_end", None)
+    }
+}
+
+/// Fail on the first alias, naming where it is.
+fn reject_aliases(ruby: &Ruby, yaml: &str) -> Result<(), Error> {
+    let mut parser = YamlParser::new(yaml.chars());
+    loop {
+        match parser.next_token() {
+            Ok((YamlEvent::Alias(_), marker)) => {
+                return Err(errors::yaml_error(
+                    ruby,
+                    format!(
+                        "alias at line {}, column {} (aliases: :reject); pass aliases: :expand to inline anchored values",
+                        marker.line(),
+                        marker.col() + 1
+                    ),
+                ))
+            }
+            Ok((YamlEvent::StreamEnd, _)) => return Ok(()),
+            Ok(_) => {}
+            // Syntax errors are reported by the loader.
+            Err(_) => return Ok(()),
+        }
+    }
+}
+
+/// `UdonNative.from_yaml(yaml_text, text_key: "_text", aliases: :reject)`
+///
+/// `aliases: :expand` inlines a copy of each anchored value at every alias;
+/// `:reject` (the default) raises `UdonNative::YamlError` on the first one.
+pub fn from_yaml(ruby: &Ruby, args: &[Value]) -> Result<RString, Error> {
+    let args = scan_args::<(String,), (), (), (), RHash, ()>(args)?;
+    let (yaml,) = args.required;
+    let kwargs = get_kwargs::<_, (), (Option<String>, Option<Symbol>), ()>(
+        args.keywords,
+        &[],
+        &["text_key", "aliases"],
+    )?;
+    let (text_key, aliases) = kwargs.optional;
+    let text_key = text_key.unwrap_or_else(|| DEFAULT_TEXT_KEY.to_string());
+    match aliases.map(|a| a.name()).transpose()?.as_deref() {
+        None | Some("reject") => reject_aliases(ruby, &yaml)?,
+        Some("expand") => {}
+        Some(other) => {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                format!("invalid value for aliases: :{}", other),
+            ))
+        }
+    }
+
+    let docs = YamlLoader::load_from_str(&yaml)
+        .map_err(|err| errors::yaml_error(ruby, err.to_string()))?;
+    let mut writer = UdonWriter {
+        ruby,
+        emitter: Emitter::new(),
+        index: 0,
+        text_key: &text_key,
+    };
+    match docs.first() {
+        None | Some(Yaml::Null) => {}
+        Some(Yaml::Hash(hash)) => {
+            for (key, value) in hash {
+                let key = writer.key(key)?;
+                if key == text_key {
+                    writer.text(value)?;
+                } else {
+                    writer.elements(&key, value)?;
+                }
+            }
+        }
+        Some(_) => {
+            return Err(errors::yaml_error(
+                ruby,
+                "top level of the YAML document must be a mapping".to_string(),
+            ))
+        }
+    }
+    writer
+        .emitter
+        .finish()
+        .map_err(|err| errors::emit_error(ruby, err))?;
+    Ok(RString::enc_new(
+        writer.emitter.output(),
+        RbEncoding::utf8(),
+    ))
+}
@@ -32,12 +32,62 @@ module Udon
   class Error < StandardError; end
   class ParseError < Error; end
 
+  # Builder for UDON documents; see UdonNative::Writer.
+  Writer = UdonNative::Writer
+
+  # A parsed document tree and its nodes; see {Udon.parse_document}.
+  Document = UdonNative::Document
+  Node = UdonNative::Node
+
+  # Byte offset <-> line/column lookups for error display; see
+  # UdonNative::LineIndex.
+  LineIndex = UdonNative::LineIndex
+
+  # Byte span value objects, as returned with spans: :object; see
+  # UdonNative::Span.
+  Span = UdonNative::Span
+
+  # Event indexes grouped by starting line; see {Udon.events_by_line}.
+  EventLines = UdonNative::EventLines
+
+  # Incremental parser: feed chunks, then finish; see UdonNative::Parser.
+  # Spans are offsets into the whole stream. After finish,
+  # Parser#source_digest(algorithm = :sha256) hashes the parsed bytes, and
+  # Parser#peak_buffered_events reports the most events held at once.
+  Parser = UdonNative::Parser
+
+  # Directive handler registry for parse(input, directives: :dispatch); see
+  # UdonNative::Directives.register.
+  Directives = UdonNative::Directives
+
+  # Embedded content handlers by embedded element name; see
+  # UdonNative::Embedded.register.
+  Embedded = UdonNative::Embedded
+
+  # What directive and embedded handlers and the interpolation_resolver see
+  # of the parse they run in; see {Udon.parse}.
+  HandlerContext = UdonNative::HandlerContext
+
+  # Custom scalar types for string values, for parse(input, value_plugins:
+  # true); see UdonNative::ValueTypes.register.
+  ValueTypes = UdonNative::ValueTypes
+
+  # Integer event type tags, for parse(input, type_tag: true); see
+  # UdonNative::EventType.
+  EventType = UdonNative::EventType
+
   class << self
     # Parse a UDON document and return an array of events.
     #
-    # @param input [String] The UDON document to parse
-    # @return [Array<Hash>] Array of event hashes
+    # @param input [String, #to_str] The UDON document to parse
+    # @param options [Hash] Parse options (see below)
+    # @return [Array<Hash>, Hash, nil] Array of event hashes; a Hash of
+    #   columns with format: :columnar; nil with handlers
     # @raise [ParseError] If parsing fails catastrophically
+    # @raise [TypeError] If +input+ is neither a String nor responds to
+    #   +#to_str+
+    # @raise [UdonNative::EncodingError] If the input starts with a UTF-16
+    #   byte order mark; transcode it to UTF-8 first
     #
     # Event types (all have :span with :start/:end):
     #
@@ -66,10 +116,910 @@ module Udon
     # Error events:
     # - :error - has :code instead of :content
     #
-    def parse(input)
-      input = input.to_s
+    # Options:
+    # - spans: :hash (default) - :span is { start:, end: } byte offsets
+    # - spans: :line_col_packed - :span is [start_line, start_col, end_line, end_col],
+    #   0-based with columns counted in characters (code points); LSP counts
+    #   UTF-16 units instead, see spans: :utf16
+    # - spans: :object - :span is a Udon::Span over the same byte offsets;
+    #   it is == to the equivalent { start:, end: } hash
+    # - spans: :utf16 - :span is { start:, end: } in UTF-16 code units, as
+    #   JavaScript and LSP clients count; costs a pass over the input plus a
+    #   binary search per offset for non-ASCII input
+    # - canonicalize: true - trim attribute string values and turn boolean
+    #   tokens (true/yes/on, false/no/off; case-insensitive) into :bool_true /
+    #   :bool_false events
+    # - boolean_tokens: { true: [...], false: [...] } - override those tokens
+    # - unify_directives: true - report a directive as one :directive event
+    #   with :name, :namespace and :inline (always false for now; libudon
+    #   only reports block directives) instead of :directive_start + :name
+    # - directive_args: :structured - fold each directive's events into one
+    #   { type: :directive, name:, namespace:, args: [...], options: {...},
+    #   body: [...], span: }: the values after the name, its :key value
+    #   arguments (String keys; true for a key alone) and the events after
+    #   them. Not with handlers, directives: :dispatch, unify_directives or
+    #   sort_by_span
+    # - inline_directive_content: :raw - leave inline directive content as
+    #   written. :parse (a nested parse of it) raises NotImplementedError,
+    #   as the parser does not report inline directives yet
+    # - shape_hash: true - add a :shape digest of the structural skeleton
+    #   (name, attribute keys, child shapes; no values or text) to each
+    #   :element_start
+    # - header_spans: true - add :header_span (the name and attributes) and
+    #   :body_span (first child or text to the end of the element; empty at
+    #   the header's end when there is none) to each :element_start
+    # - capture_indent: true - add :indent to each :element_start: the
+    #   spaces and tabs before it on its source line, or nil when something
+    #   else comes before it there
+    # - capture_column: true - add :col to each event, the 0-based display
+    #   column it starts at (tabs to stops every 8 columns or tab_width:)
+    # - parent_name: true - add :parent_name to each event, the name of the
+    #   nearest enclosing element (nil at the top level); an element's own
+    #   start, name and end events get the one around it
+    # - content_lengths: true - add :content_bytes to each :text, :raw,
+    #   :raw_content, :string_value and :bare_value event, its content's byte
+    #   length as parsed (before max_value_bytes cuts it)
+    # - type_tag: true - add :tag, the Integer Udon::EventType constant for
+    #   the event's :type (none for types libudon has no variant for)
+    # - precompute_extents: true - add :extent, the span from the start event
+    #   through its matching end event, to each :element_start,
+    #   :embedded_start, :directive_start (or :directive), :freeform_start and
+    #   :array_start
+    # - mark_container: true - add :has_children to each :element_start,
+    #   true when a child element or array value opens before the element
+    #   ends; embedded elements in text do not count
+    # - classify: true - add :kind to start events: :embedded,
+    #   :directive, or for an :element_start :container (has a child element
+    #   or array, as for mark_container) or :leaf
+    # - downcase_names: true - ASCII-lowercase element names; a changed name
+    #   keeps the original under :original_name
+    # - tab_width: n - with spans: :line_col_packed, a tab advances the
+    #   column to the next multiple of n, matching an editor's display; nil
+    #   (the default) counts a tab as one character. Also the tab stops of
+    #   capture_column (8 by default)
+    # - max_value_bytes: n - cut string, text and raw content to at most n
+    #   bytes (never splitting a UTF-8 character) and set truncated: true on
+    #   the event; its :span still covers the full value
+    # - sort_by_span: true - return events ordered by span start, keeping
+    #   emission order on ties. The parser emits in source order today, so
+    #   this is a guarantee for consumers rather than a change; handlers then
+    #   run only after the whole input is parsed
+    # - value_plugins: true - match bare string values against the types
+    #   registered through {ValueTypes}.register, in order; the first match
+    #   becomes { type: :custom_value, kind:, value:, raw:, span: }, value
+    #   being what its block returns. :all includes quoted strings
+    # - parse_times: true - give string values that are an ISO-8601 date
+    #   (2024-01-02) a Date :value and coerced: :date, and ones that are a
+    #   date and time (2024-01-02T03:04:05Z, optional seconds, fraction and
+    #   Z or +HH:MM offset; local time without one) a Time and
+    #   coerced: :time. Type and :content are kept; other strings pass
+    #   through, and are the only ones value types see
+    # - emit_document_bounds: true - start the events with a :document_start
+    #   and end them with a :document_end, both with empty spans at the
+    #   document's start and end (0 and the input's length). Not with
+    #   handlers or directives: :dispatch
+    # - split_interpolations: true - add :segments to :string_value and :text
+    #   events containing !{{expr}}: an array of { literal: "..." } and
+    #   { expr: "..." } hashes in source order. Values without one get no
+    #   :segments key
+    # - interpolation_resolver: ->(expr) { ... } - called with each
+    #   :interpolation event's expression as it is parsed; the event becomes
+    #   the value event for what it returns (:string_value, :integer,
+    #   :float, :bool_true, ...; other objects as a :string_value of their
+    #   to_s) with the same span, plus interpolated: true and :expression.
+    #   If it raises, the event becomes an :error with code
+    #   :interpolation_failed, :expression and :message instead. A
+    #   resolver taking a second argument also gets the {HandlerContext}
+    # - user_context: value - what HandlerContext#user_context returns to
+    #   directive and embedded handlers and the interpolation_resolver
+    # - strict_interpolation: true - raise UdonNative::InterpolationError,
+    #   naming the expression and span, for the first resolver failure once
+    #   the parse is done instead
+    # - interpolations: :env - resolve env.NAME interpolations from the
+    #   process environment: each becomes a :string_value with the
+    #   variable's value, the same span, interpolated: true and :expression.
+    #   env_prefix: sets the prefix ("env." by default). missing_env: :error
+    #   (default, an :error with code :env_missing and :name), :empty or
+    #   :keep (the :interpolation event) for unset variables, and for ones
+    #   outside env_allowlist: (an Array of names) when given, which are
+    #   never read. Values appear nowhere but the events. Not with
+    #   interpolation_resolver
+    # - interpolations: :structured - add :parts to each :interpolation,
+    #   { text:, span: } for each dot-separated segment of the expression,
+    #   with spans into the input. Not with interpolation_resolver
+    # - interpolation_delimiters: ["!{{", "}}"] - the open and close
+    #   delimiters of an interpolation. Only the default pair is supported by
+    #   the parser yet; any other raises NotImplementedError
+    # - arena_chunk_size: n - reserved; the parser allocates no arena, so a
+    #   size raises NotImplementedError (nil is accepted)
+    # - interpolation: false - keep !{{...}} as the text it was written as:
+    #   merged with the :text around it into one :text of the input bytes,
+    #   or a :bare_value in value position. Not with interpolation_resolver
+    #   or interpolations: :env
+    # - conditions: { "env" => "production" } - evaluate !if and !unless
+    #   directives against these variables while parsing: the indented block
+    #   of a true !if (false !unless) is kept, anything else is skipped
+    #   without converting its events, and the directives' own events are
+    #   dropped. The condition is the rest of the directive's line: name ==
+    #   value (or =), name != value, :name value, a bare name (set and not
+    #   false/nil), combined with not/!, and/&&, or/|| and parentheses.
+    #   Values are bare words or "quoted" and compare with the variables'
+    #   to_s. A bad condition raises UdonNative::ConditionError. Not with
+    #   handlers, directives: :dispatch, format: :columnar or
+    #   unify_directives
+    # - unknown_conditions: :error (default) / :false - whether a variable
+    #   missing from conditions: raises or makes its test false
+    # - unknown_directives: :allow (default) / :warn / :error - follow each
+    #   directive that has no Directives handler, is not in
+    #   known_directives: (names, "namespace:name" when qualified) and is
+    #   not one of if, unless, include, define and use with a :warning or
+    #   :error event: code: :unknown_directive, the qualified :name, the
+    #   name's span and a :suggestion when a known name is within two
+    #   edits. strict_directives: true raises the first error as
+    #   UdonNative::DirectiveError after the parse. Not with handlers,
+    #   directives: :dispatch or format: :columnar
+    # - directive_namespaces: ["app", "deploy"] - follow the name of each
+    #   directive whose namespace is not listed with an :error event, code
+    #   :undeclared_namespace, with :namespace, :name and :message.
+    #   Directives without a namespace pass unless allow_unnamespaced:
+    #   false. Not with handlers, directives: :dispatch or format: :columnar
+    # - immutable: true - deep-freeze the result: the array, every event
+    #   hash, its strings, nested arrays and spans. Mutating it raises
+    #   FrozenError, so it can be cached and shared safely. Handlers and
+    #   blocks receive frozen events too
+    # - strip_bom: false - parse a leading UTF-8 byte order mark as content
+    #   instead of skipping it (skipping is the default)
+    # - normalize_newlines: true - parse \r\n and lone \r as \n; content
+    #   then contains \n only, except raw content (freeform blocks, raw
+    #   directives) unless normalize_raw: true is also given
+    # - line_endings: :normalize / :preserve - the same as
+    #   normalize_newlines: true / false
+    # - nul_bytes: :error (default) / :strip / :allow - input with a NUL
+    #   byte parses to just an :error event with code :nul_byte at the
+    #   first NUL; :strip drops NULs first, :allow keeps them as content
+    # - severity: true - add :severity to each :error event: :fatal (the
+    #   rest of the document cannot be trusted: unexpected_eof,
+    #   unclosed_string_value, unclosed_freeform, nul_byte,
+    #   truncated_input), :recoverable (parsing continued with a best guess:
+    #   unexpected_char, unclosed, unclosed_array, unclosed_text,
+    #   unclosed_interpolation, interpolation_failed) or :stylistic (parses as meant but breaks a
+    #   rule: no_tabs)
+    # - span_base: n - add n to every span offset, so a tail parsed on its
+    #   own (the text appended at byte n) gets offsets into the whole
+    #   document (code units with spans: :utf16). Not with
+    #   spans: :line_col_packed
+    # - expect_encoding: "UTF-8" - validate the whole input before parsing
+    #   and raise UdonNative::EncodingError at the first invalid sequence,
+    #   naming its byte offset with a hex dump of the bytes from it.
+    #   parse_io and parse_file take any encoding they read
+    # - intern_keys: :global - :attr content comes from a process-wide pool
+    #   of frozen Strings, the same object for the same key in every parse;
+    #   :none (the default) allocates keys per parse. See {interned_key_count}
+    #
+    # Spans always index the original input string, whatever normalization
+    # is enabled, so +input.byteslice(start, end - start)+ is the source text
+    # of an event.
+    #
+    # Columns: +format: :columnar+ returns
+    # +{ type: [...], start: [...], end: [...], name: [...], value: [...] }+
+    # instead of an array of hashes, one entry per event in each array. +name+
+    # holds :name and :attr content, +value+ other content and error codes;
+    # fields an event lacks are nil. Not available with unify_directives,
+    # shape_hash, header_spans, mark_container, classify, precompute_extents,
+    # split_interpolations, severity, interpolation_resolver,
+    # interpolations: :env, interpolations: :structured, conditions,
+    # sort_by_span, emit_document_bounds, value_plugins, parse_times,
+    # unknown_directives, capture_indent, capture_column, parent_name,
+    # content_lengths, directive_args: :structured, directive_namespaces,
+    # spans: :line_col_packed, spans: :object, spans: :utf16 or handlers.
+    #
+    # Handlers: pass +on_<type>:+ callables (e.g. +on_text: ->(e) { ... }+)
+    # to have each event of that type passed to its handler instead of
+    # collected; parse then returns nil. Events without a handler are never
+    # converted to hashes. With +shape_hash+, +header_spans+,
+    # +mark_container+, +classify+ or +precompute_extents+ the handlers run after parsing,
+    # so those are already set. With +with_index: true+ each handler gets +(event, index, total)+,
+    # +index+ being the event's position in the array parse would return and
+    # +total+ the event count, or nil when handlers run during the parse.
+    #
+    # Directives: with +directives: :dispatch+ each directive with a handler
+    # registered through {Directives}.register is passed to it once its
+    # +:directive_end+ arrives, as +(args, span, ctx)+: its argument values,
+    # its span, and +{ name:, namespace:, options:, events:, errors: [] }+,
+    # +options+ holding its +:key value+ arguments. Returning
+    # nil keeps its events, false or :suppress drops them, and an Array of
+    # event hashes replaces them; messages pushed onto +ctx[:errors]+ follow
+    # as :directive_error events. A handler that raises keeps the events and
+    # adds a :directive_failed error with the directive's span. Unless a
+    # handler is registered for it, +!deprecated+ becomes a :warning with
+    # code :deprecated, its first argument as +:content+ and the span of the
+    # element it annotates, followed by its body;
+    # +deprecation_directive: "name"+ picks another name, nil or false none.
+    #
+    # Embedded elements: a handler registered through {Embedded}.register
+    # for an embedded element's name gets its unparsed content (dedented with
+    # +dedent_raw: true+) and span, and its return value follows as an
+    # :embedded_value event before the :embedded_end; if it raises, an
+    # :embedded_failed error does.
+    #
+    # Handler context: directive handlers, embedded handlers and the
+    # interpolation_resolver get a {HandlerContext} as their last argument
+    # (left out for lambdas that take no more). It answers +path+ (the
+    # enclosing element names), +depth+, +span+, +source_slice+ and
+    # +user_context+, and +emit_warning(code, message)+ adds a :warning
+    # event after the callback's output. Using it after the callback returns
+    # raises UdonNative::Error.
+    #
+    def parse(input, **options)
+      UdonNative.parse(utf8(input), **options)
+    end
+
+    # Parse UDON read from an IO, transcoding UTF-16 and UTF-32 to UTF-8.
+    #
+    # The IO is read in chunks with +#read(n)+ and transcoded as it arrives.
+    # The encoding comes from +encoding:+, else from a byte order mark, else
+    # from the NUL bytes of an ASCII first character, else it is UTF-8.
+    #
+    # Spans index the transcoded UTF-8; pass +spans: :original+ for byte
+    # offsets into the (decompressed) input instead.
+    #
+    # With +compression: :gzip+ the IO is gunzipped as it is read;
+    # +compression: :auto+ does so only if it starts with the gzip magic
+    # bytes.
+    #
+    # @param io [#read] The UDON source: an IO, StringIO, Tempfile or any
+    #   object with +#read(length)+
+    # @param encoding [String, Encoding, nil] "UTF-8", "UTF-16LE",
+    #   "UTF-16BE", "UTF-32LE" or "UTF-32BE"
+    # @param invalid_utf8 [Symbol] :raise (default) or :replace with U+FFFD
+    #   for bytes invalid in the input encoding
+    # @param compression [Symbol] :none (default), :gzip or :auto
+    # @param stats [Boolean] Return +{events:, stats:}+ as
+    #   {parse_with_stats} does, the stats adding +compressed_bytes+ (read
+    #   from the IO); +bytes+ counts the decompressed input
+    # @param options [Hash] Same options as {parse}
+    # @return [Array<Hash>, Hash] Event hashes, or events and stats
+    # @raise [UdonNative::EncodingError] For invalid input with
+    #   +invalid_utf8: :raise+
+    # @raise [UdonNative::IOError] For a corrupt or truncated gzip stream
+    # @raise [ArgumentError] If +expect_encoding:+ conflicts with
+    #   +encoding:+ or +invalid_utf8: :replace+
+    # @raise [TypeError] If +io+ does not respond to +#read+
+    def parse_io(io, **options)
+      UdonNative.parse_io(io, **options)
+    end
+
+    # Parse a UDON file; see {parse_io} for encodings and spans. Gzipped
+    # files are detected by their magic bytes (+compression: :auto+).
+    #
+    # With +mmap: true+ a UTF-8 file is memory-mapped and parsed straight
+    # from the mapping, never read into a Ruby String. Files it cannot serve
+    # (unmappable, gzipped, UTF-16/32, or invalid UTF-8 with
+    # +invalid_utf8: :replace+) are read as without it.
+    #
+    # @param path [String, Pathname] The file to read
+    # @param mmap [Boolean] Parse from a memory mapping where possible
+    # @param options [Hash] Same options as {parse_io}
+    # @return [Array<Hash>, Hash] Event hashes, or events and stats
+    # @raise [UdonNative::IOError] If a mapped file is seen to shrink while
+    #   it is being parsed
+    def parse_file(path, **options)
+      UdonNative.parse_file(path, **options)
+    end
+
+    # Parse several documents separated by delimiter lines.
+    #
+    # A delimiter line is the delimiter text at column 0, optionally followed
+    # by trailing whitespace. Blank segments before the first or after the
+    # last delimiter are dropped, so a stream may start or end with one.
+    #
+    # @param input [String] The documents
+    # @param delimiter [String] Delimiter line text
+    # @param offsets [Symbol] :global (default) for spans into +input+,
+    #   :segment for spans relative to each document
+    # @param options [Hash] Same options as {parse}
+    # @return [Array<Array<Hash>>] One event array per document
+    def parse_stream(input, delimiter: "---", offsets: :global, **options)
+      UdonNative.parse_stream(utf8(input), delimiter: delimiter, offsets: offsets, **options)
+    end
+
+    # Parse and also report document metrics.
+    #
+    # @param input [String] The UDON document
+    # @param options [Hash] Same options as {parse}
+    # @return [Hash] +{ events: [...], stats: { bytes:, input_bytes:, events:,
+    #   elements:, errors:, max_depth_reached: } }+. +input_bytes+ is the
+    #   length the parser read after BOM stripping and newline
+    #   normalization; +max_depth_reached+ is the deepest element/directive
+    #   nesting seen (1 for only top-level elements)
+    def parse_with_stats(input, **options)
+      UdonNative.parse_with_stats(utf8(input), **options)
+    end
+
+    # Parse without building any events, for timing the parser alone.
+    #
+    # @param input [String] The UDON document, parsed as given (no BOM
+    #   stripping or newline normalization)
+    # @return [Array(Integer, Integer)] +[count, checksum]+: the number of
+    #   events and a 64-bit hash of their types in order, which changes only
+    #   when the event structure does
+    def bench_parse(input)
+      UdonNative.bench_parse(utf8(input))
+    end
+
+    # Parse into one binary String of fixed-size records, for handing the
+    # events to other native code without a Ruby object per event.
+    #
+    # @param input [String] The UDON document
+    # @param options [Hash] The input options of {parse} (+strip_bom:+,
+    #   +normalize_newlines:+, +nul_bytes:+, +interpolation:+)
+    # @return [String] A binary String: a 16-byte header ("UDNP", u16
+    #   version 1, u16 record size 20, u32 record count, u32 string table
+    #   offset), then one 20-byte record per event (u8 EventType tag, 3 zero
+    #   bytes, u32 span start and end, u32 content offset into the string
+    #   table or 0xFFFFFFFF, u32 content length), then the string table. All
+    #   little-endian; see "Packed Output" in the README
+    def parse_packed(input, **options)
+      UdonNative.parse_packed(utf8(input), **options)
+    end
+
+    # Render the part of +input+ covered by a span, for error messages.
+    #
+    # @param input [String] The UDON document the span refers to
+    # @param span [Hash, Span, Range] An event +:span+ or a Range of byte
+    #   offsets
+    # @param context_lines [Integer] Lines shown before and after the span
+    # @param color [Boolean] Color the gutter and underline with ANSI escapes
+    # @param max_width [Integer, nil] Lines longer than this many characters
+    #   are cut around the span and marked with +…+; nil never cuts
+    # @return [String] Numbered lines with the span underlined by +^+. A span
+    #   past the end of the input is clamped and a +note:+ line says so
+    def snippet(input, span, context_lines: 2, color: false, max_width: 120)
+      UdonNative.snippet(utf8(input), span, context_lines: context_lines, color: color, max_width: max_width)
+    end
+
+    # Display width of each line, in terminal columns, for laying out text
+    # in a fixed-width grid: wide (CJK, fullwidth, emoji) characters count
+    # 2, combining marks and control characters 0, everything else 1.
+    #
+    # @param input [String] The UDON document
+    # @param options [Hash] Same options as {parse}; the normalization
+    #   options apply (a leading BOM is skipped by default) and +tab_width:+
+    #   expands tabs to tab stops (a tab counts 1 without it)
+    # @return [Array<Integer>] One width per line; a trailing newline ends
+    #   with a final 0 for the empty last line
+    def line_widths(input, **options)
+      UdonNative.line_widths(utf8(input), **options)
+    end
+
+    # The document's parse errors and warnings, shaped for an editor's
+    # diagnostics endpoint.
+    #
+    # @param input [String] The UDON document
+    # @param options [Hash] Same options as {parse}; the normalization
+    #   options and +tab_width:+ apply
+    # @return [Array<Hash>] +{ message:, code:, severity:, range: { start:
+    #   { line:, col: }, end: { line:, col: } } }+ in document order, lines and
+    #   columns 0-based, columns in characters. +severity+ is :error or
+    #   :warning; warnings have a nil +code+
+    def diagnostics(input, **options)
+      UdonNative.diagnostics(utf8(input), **options)
+    end
+
+    # Parse UDON into a tree.
+    #
+    # Nodes have a +type+ (:element, :embedded, :directive, :text, :comment,
+    # :interpolation, :reference or :freeform), +name+, +attributes+,
+    # +children+, +parent+ and +text_content+. Parse errors are available from
+    # Document#errors rather than raised. Node#attributes(metadata: true)
+    # gives each value as +{value:, type:, span:}+ with its UDON type and
+    # source span.
+    #
+    # Document#digest and Node#digest hash the structure rather than the
+    # source, so reformatting does not change them. Pass
+    # +ignore: [:comments, :whitespace, :attribute_order]+ to disregard those
+    # too, and +algorithm:+ (:sha256, :sha384, :sha512, or the fast
+    # non-cryptographic :xxh3) to choose the hash.
+    #
+    # Document#node_at(offset) returns the innermost node covering a byte
+    # offset (the enclosing element for whitespace between children), and
+    # Node#ancestors its enclosing nodes, innermost first. Lookups are a
+    # binary search, for editor hover and go-to-definition.
+    #
+    # Document#deprecations(name = "deprecated") lists each +!deprecated+
+    # directive as +{message:, node:, span:}+, +node+ being the element it
+    # annotates.
+    #
+    # @param input [String] The UDON document
+    # @return [Document]
+    def parse_document(input)
+      UdonNative.parse_document(utf8(input))
+    end
+
+    # The first top-level element, parsing as little of the input as it
+    # can: growing prefixes cut at line ends (16 KiB, then four times longer
+    # each time) until the element is known to have ended, so the rest of a
+    # large document is never parsed. Node#document holds only what was
+    # parsed.
+    #
+    # @param input [String] The UDON document
+    # @return [Node, nil] The element, or nil if the document has none
+    def first_element(input)
+      UdonNative.first_element(utf8(input))
+    end
+
+    # Parse UDON into a tree like {parse_document}, optionally splicing in
+    # the files named by +!include "path"+ directives.
+    #
+    # Include paths are relative to the directory of the file they appear
+    # in and must stay inside +include_base+. Included files may include
+    # others; a cycle or nesting deeper than +max_include_depth+ raises.
+    # Spliced nodes keep spans into their own file, which Node#file names;
+    # Document#errors entries carry a +:file+ for errors in included files.
+    #
+    # With +expand_definitions+, each +!use name+ is replaced by a copy of
+    # the block of the +!define name+ directive, with the +!use+'s
+    # attributes overriding those of the copied top-level elements.
+    # Node#expanded_from names the +!use+ behind a copied node; unknown
+    # names and too deep expansion are reported in Document#errors.
+    #
+    # @param source [String, Pathname] UDON text, or a file to read (anything
+    #   responding to +to_path+)
+    # @param resolve_includes [Boolean] Splice in included files; without it
+    #   +!include+ stays a plain directive node
+    # @param include_base [String, Pathname, nil] Directory includes are
+    #   confined to; defaults to the file's directory, or the current
+    #   directory for text
+    # @param loader [#call, nil] Called with each resolved include path
+    #   instead of reading the filesystem; returns the file's text, or nil if
+    #   there is no such file. Without +include_base+ paths stay relative
+    # @param max_include_depth [Integer] How deeply includes may nest
+    # @param allow_absolute [Boolean] Allow absolute paths and paths outside
+    #   +include_base+
+    # @param expand_definitions [Boolean] Expand +!define+/+!use+ directives
+    # @param max_expansion_depth [Integer] How deeply uses may nest
+    # @param as_objects [Boolean] Let nodes answer their attribute keys as
+    #   methods (+node.host+ for +node["host"]+, +node.max_age+ for
+    #   +max-age+); Node's own methods win over keys of the same name. This
+    #   goes through +method_missing+, so it is slower than {Node#[]}
+    # @return [Document]
+    # @raise [UdonNative::IncludeError] For a missing file, a path outside
+    #   the base, a cycle (named in the message) or too deep nesting
+    def parse_tree(source, **options)
+      UdonNative.parse_tree(utf8(source), **options)
+    end
+
+    # Find the innermost event covering a byte offset.
+    #
+    # Structures span from their +*_start+ event to their +*_end+ event, so an
+    # offset in whitespace between children finds the enclosing start event.
+    # Each call indexes +events+ afresh; for repeated lookups on one document
+    # use {parse_document} and Document#node_at.
+    #
+    # @param events [Array<Hash>] Events from {parse} with byte offset spans
+    #   (+spans: :hash+ or +:object+)
+    # @param offset [Integer] Byte offset into the parsed input
+    # @return [Hash, nil] +{ event:, ancestors: [...] }+, ancestors being the
+    #   enclosing start events, innermost first; nil if nothing covers offset
+    def event_at(events, offset)
+      UdonNative.event_at(events, offset)
+    end
+
+    # Index events by the line they start on, for repeated per-line queries
+    # such as highlighting the visible part of an editor buffer.
+    #
+    # The index is built once; EventLines#on_line(n) and
+    # EventLines#in_lines(range) then return event indexes (1-based lines,
+    # events in order within a line) without rescanning.
+    #
+    # @param events [Array<Hash>] Events from {parse}, with any +spans:+
+    #   format
+    # @param input [String] The input the events were parsed from
+    # @return [EventLines]
+    def events_by_line(events, input)
+      UdonNative::EventLines.new(events, utf8(input))
+    end
+
+    # Rebuild +input+ from the spans of its events, as a check that parsing
+    # loses nothing.
+    #
+    # Bytes covered by some event's span are copied in order; bytes in
+    # between are kept only when they are whitespace or UDON punctuation
+    # (+| : [ ] { } " ' ! ;+) the events imply. For a well-formed document the
+    # result equals +input+; anything missing is content no event carries.
+    #
+    # @param input [String] The UDON document
+    # @param options [Hash] Parse options, as for {parse}; only +strip_bom+,
+    #   +normalize_newlines+ and +nul_bytes+ affect the result
+    # @return [String]
+    # @raise [UdonNative::Error] for input with a NUL byte, unless
+    #   +nul_bytes: :strip+ or +:allow+
+    def reconstruct(input, **options)
+      UdonNative.reconstruct(utf8(input), **options)
+    end
+
+    # Serialize event hashes back into UDON text.
+    #
+    # @param events [Array<Hash>] Events as returned by {parse}
+    # @param io [#write, nil] Write the output here instead of returning it
+    # @param options [Hash] Ordering options:
+    # - sort_attributes: true - order attributes by key; pass an Array of keys
+    #   (e.g. +%w[id name]+) to put those first
+    # - sort_elements_by: "attr" - order sibling elements by that attribute's
+    #   value (numbers numerically, missing values last). Comments directly
+    #   above an element move with it.
+    # - indent: "\t" / 4 - indent each level with that String of spaces or
+    #   tabs, or that many spaces, instead of two spaces
+    # - quote: :preserve - write each value with the quoting it was parsed
+    #   with; this is what emit always does
+    # @return [String, IO] The UDON text, or +io+
+    # @raise [UdonNative::EmitError] If the events are not well nested; the
+    #   message names the offending event index
+    def emit(events, io = nil, **options)
+      UdonNative.emit(events, io, **options)
+    end
+
+    # Parse a document, pass every event through the block, and emit the result
+    # in one pass.
+    #
+    # The block receives each event hash and returns it (possibly modified), an
+    # Array of replacement events, or nil to drop it.
+    #
+    # @example Rename every |foo element to |bar
+    #   Udon.transform(input) do |event|
+    #     event[:content] = "bar" if event[:type] == :name && event[:content] == "foo"
+    #     event
+    #   end
+    #
+    # @param input [String] The UDON document to transform
+    # @param io [#write, nil] Write the output here instead of returning it
+    # @param indent [String, Integer, Symbol, nil] As for {emit}, or :detect
+    #   to indent with the input's own unit (see {detect_indentation})
+    # @return [String, IO] The UDON text, or +io+
+    # @raise [UdonNative::EmitError] If the transformed stream is not well
+    #   nested; the message names the originating event index
+    def transform(input, io = nil, indent: nil, &block)
+      UdonNative.transform(utf8(input), io, indent: indent, &block)
+    end
+
+    # Detect whether a document is indented with two spaces, four, tabs, ...
+    #
+    # Every line deeper than the one enclosing it contributes its extra
+    # leading whitespace as a step; the most common step is the unit. Blank
+    # lines and lines inside freeform blocks, raw directives and embedded
+    # elements, where indentation is content, are ignored.
+    #
+    # @example
+    #   Udon.detect_indentation("|a\n    |b\n        |c\n")
+    #   # => { unit: "    ", consistent: true, samples: { "    " => 2 }, offenders: [] }
+    #
+    # @param input [String] The UDON document
+    # @return [Hash] +unit+ (nil if nothing is indented), +consistent+ (no
+    #   line mixes tabs and spaces, uses the other kind than the unit, or
+    #   steps by anything but the unit), +samples+ (each step String and how
+    #   many lines took it) and +offenders+: up to five
+    #   +{ line:, span:, problem: }+ with a 0-based line, the span of its
+    #   indentation and :mixed, :style or :step
+    def detect_indentation(input)
+      UdonNative.detect_indentation(utf8(input))
+    end
+
+    # Parse a stream of length-prefixed documents (4-byte big-endian length,
+    # then that many bytes of UDON), yielding each document's events.
+    #
+    # Bodies are read in 64 KiB chunks, so a corrupt length prefix cannot
+    # make the reader allocate its length up front.
+    #
+    # @param io [#read] The framed stream
+    # @param options [Hash] Parse options, as for {parse}, and
+    #   +max_frame_bytes:+, the longest frame accepted
+    # @yieldparam events [Array<Hash>] Events of one frame
+    # @yieldparam index [Integer] The frame's index, with +with_index: true+
+    # @yieldparam total [nil] Unknown until EOF, with +with_index: true+
+    # @return [Integer] Number of frames read
+    # @raise [UdonNative::FrameError] If the stream ends inside a frame, or
+    #   a frame is longer than +max_frame_bytes+
+    def parse_framed(io, **options, &block)
+      UdonNative.parse_framed(io, **options, &block)
+    end
+
+    # Parse a document one top-level element at a time, yielding each
+    # element's events as soon as it closes.
+    #
+    # Events outside any element go with the element after them; any after
+    # the last element are yielded as a final array. A parse error is in the
+    # array of the element it occurred in.
+    #
+    # @example
+    #   Udon.parse_each_element(input) { |events| DB.transaction { store(events) } }
+    #
+    # @param input [String] The UDON document
+    # @param options [Hash] Options of {parse} that shape single events;
+    #   +sort_by_span:+, +emit_document_bounds:+, +structured_directives:+
+    #   and +conditions:+ do not apply. With +with_index: true+ the array's
+    #   index and a nil total are yielded too
+    # @yieldparam events [Array<Hash>] One top-level element's events
+    # @return [Integer] Number of arrays yielded
+    def parse_each_element(input, **options, &block)
+      UdonNative.parse_each_element(utf8(input), **options, &block)
+    end
+
+    # Parse documents piped to standard input, yielding each as it
+    # completes.
+    #
+    # File descriptor 0 is read in chunks by the extension, bypassing
+    # +$stdin+ (do not read +$stdin+ as well), and only as fast as the block
+    # returns, so a fast producer waits on the pipe rather than filling
+    # memory. With +delimiter:+ each document is yielded as soon as the
+    # delimiter line after it arrives; without one the whole input is a
+    # single document, yielded at EOF.
+    #
+    # @example
+    #   # cat docs.udon | ruby tool.rb
+    #   Udon.parse_stdin(delimiter: "---") { |events| handle(events) }
+    #
+    # @param delimiter [String, nil] Document separator line, as for
+    #   {parse_stream}; spans start at 0 in each document
+    # @param options [Hash] Same options as {parse}; with +with_index: true+
+    #   the document index and a nil total are yielded too
+    # @yieldparam events [Array<Hash>] One document's events. If reading
+    #   fails partway, the last document ends with an +:error+ event with
+    #   code +:truncated_input+ at the offset reading stopped
+    # @return [Integer] Number of documents
+    # @raise [UdonNative::IOError] If standard input is a terminal
+    def parse_stdin(delimiter: nil, **options, &block)
+      UdonNative.parse_stdin(delimiter: delimiter, **options, &block)
+    end
+
+    # Byte spans of every comment, from the opening +;+ through its content,
+    # without building event hashes for the rest of the document.
+    #
+    # @example Strip comments
+    #   Udon.comment_spans(src).reverse_each do |span|
+    #     src = src.byteslice(0, span[:start]) + src.byteslice(span[:end]..)
+    #   end
+    #
+    # @param input [String] The UDON document
+    # @return [Array<Hash>] Span hashes ({ start:, end: })
+    def comment_spans(input)
+      UdonNative.comment_spans(utf8(input))
+    end
+
+    # Every distinct attribute key in the document, in the order first seen,
+    # without building event hashes: a quick "what fields does this data
+    # have" for unfamiliar input.
+    #
+    # @param input [String] The UDON document
+    # @return [Array<String>] Frozen key Strings, shared with
+    #   +intern_keys: :global+ parses
+    def attribute_keys(input)
+      UdonNative.attribute_keys(utf8(input))
+    end
+
+    # How many elements (and embedded elements) of each name the document
+    # has, in one pass without building event hashes.
+    #
+    # @param input [String] The UDON document
+    # @param only [String, nil] Count just this name
+    # @return [Hash{String, nil => Integer}, Integer] Counts by name in
+    #   first-seen order, with nameless elements under nil and the names
+    #   frozen; with +only:+, its count
+    def count_elements(input, only: nil)
+      UdonNative.count_elements(utf8(input), only: only&.to_s)
+    end
+
+    # The namespaces the document's directives use, without building event
+    # hashes, for auditing which are in use.
+    #
+    # @param input [String] The UDON document
+    # @return [Hash{String => Hash}] Each namespace mapped to the byte span
+    #   of the first directive name using it, in first-use order
+    def directive_namespaces(input)
+      UdonNative.directive_namespaces(utf8(input))
+    end
+
+    # The structure of a document without its content, for comparing shapes
+    # cheaply: element and embedded element starts with their names, attribute
+    # keys, array starts, and the matching end events. Values, text, comments
+    # and directives are left out and never converted.
+    #
+    # @param input [String] The UDON document
+    # @param spans [Boolean] Add each event's byte +:span+; without spans,
+    #   documents of the same shape have equal skeletons
+    # @return [Array<Hash>] Events with +:type+, and +:content+ for names
+    #   and attribute keys
+    def skeleton(input, spans: false)
+      UdonNative.skeleton(utf8(input), spans: spans)
+    end
+
+    # The first element (or embedded element) named +name+, as its span and
+    # its events, without converting any other event to a hash.
+    #
+    # The parser cannot stop part-way, so like {first_element} it is run on
+    # growing prefixes of the input until the match is known to have ended:
+    # a match near the start costs a few kilobytes of parsing whatever the
+    # size of the document.
+    #
+    # @param input [String] The UDON document
+    # @param name [String] Element name to look for
+    # @param options [Hash] Same options as {parse}, applied to the events
+    # @return [Hash, nil] +{ span:, events: }+, or nil if there is no such
+    #   element; for input with a NUL byte (under the default +nul_bytes:
+    #   :error+) the NUL's span and its +:nul_byte+ error event
+    def find_element(input, name, **options)
+      UdonNative.find_element(utf8(input), name.to_s, **options)
+    end
+
+    # Every element (or embedded element) named +name+, without building
+    # anything for the rest of the document.
+    #
+    # @param input [String] The UDON document
+    # @param name [String] Element name to look for
+    # @param format [Symbol] :nodes for {Node}s of a tree holding only the
+    #   matches, :events for each match's events as {parse} gives them
+    # @param nested [Symbol] :include to also return matches inside other
+    #   matches, :skip to leave them to the outer match
+    # @param options [Hash] Same options as {parse}, for format: :events
+    # @return [Array<Node>, Array<Array<Hash>>] One entry per match, in
+    #   document order
+    def select_elements(input, name, format: :nodes, nested: :include, **options)
+      UdonNative.select_elements(utf8(input), name.to_s, format: format, nested: nested, **options)
+    end
+
+    # Every value of one attribute, in document order, without building
+    # events.
+    #
+    # @param input [String] The UDON document
+    # @param attribute [String] Attribute key
+    # @param element [String, nil] Only attributes of elements (or embedded
+    #   elements) with this name
+    # @param with_spans [Boolean] Return +[value, span]+ pairs; a flag
+    #   attribute's span is its key's
+    # @param flatten [Boolean] Return an array value's items one by one,
+    #   each with its own span, rather than as one Array
+    # @return [Array] Values typed as in {Node#attributes}
+    def pluck(input, attribute:, element: nil, with_spans: false, flatten: false)
+      UdonNative.pluck(utf8(input), attribute: attribute.to_s, element: element&.to_s,
+                                    with_spans: with_spans, flatten: flatten)
+    end
+
+    # Parse a document by calling methods on your own builder object, with no
+    # intermediate event hashes. Only methods the builder responds to are
+    # called: +start_element(name)+, +end_element+, +start_directive(name)+,
+    # +end_directive+, +attribute(key, value)+, +value(value)+, +text(string)+,
+    # +comment(string)+, +interpolation(expression)+, +reference(id)+ and
+    # +error(code, span)+.
+    #
+    # @example
+    #   class Names
+    #     def initialize = @names = []
+    #     def start_element(name) = @names << name
+    #     def result = @names
+    #   end
+    #   Udon.build("|a\n  |b\n", Names.new) # => ["a", "b"]
+    #
+    # @param input [String] The UDON document
+    # @param builder [Object] Receives the callbacks
+    # @return [Object] +builder.result+ if defined, otherwise the builder
+    def build(input, builder)
+      UdonNative.build(utf8(input), builder)
+    end
+
+    # Convert a UDON document to YAML text.
+    #
+    # Each element becomes a mapping of its attributes and child elements;
+    # repeated keys become sequences and text goes under +text_key+.
+    # Directives and comments are dropped.
+    #
+    # @param input [String] The UDON document
+    # @param text_key [String] Key for element text content
+    # @return [String] YAML text
+    def to_yaml(input, text_key: "_text")
+      UdonNative.to_yaml(utf8(input), text_key: text_key)
+    end
+
+    # Convert YAML text to a UDON document, reversing {to_yaml}: scalars and
+    # scalar sequences become attributes, mappings become child elements, and
+    # sequences of mappings become repeated elements.
+    #
+    # @param yaml [String] YAML text whose top level is a mapping
+    # @param text_key [String] Key holding element text content
+    # @param aliases [Symbol] +:reject+ (default) raises on anchors/aliases;
+    #   +:expand+ inlines a copy of the anchored value at each alias
+    # @return [String] UDON text
+    # @raise [UdonNative::YamlError] On invalid YAML, a rejected alias, or a
+    #   shape with no UDON equivalent
+    def from_yaml(yaml, text_key: "_text", aliases: :reject)
+      UdonNative.from_yaml(utf8(yaml), text_key: text_key, aliases: aliases)
+    end
+
+    # Find references that name no element: each +@[id]+ whose id is not
+    # the +by+ attribute (as +|name[id]+ sets it) of some element in the
+    # document, wherever that element is.
+    #
+    # @example
+    #   Udon.check_references("|a[x]\n|p See @[x] and @[y]\n")
+    #   # => [{ id: "y", span: { start: 22, end: 26 } }]
+    #
+    # @param input [String] The UDON document
+    # @param by [String] The attribute identifying elements
+    # @return [Array<Hash>] +{ id:, span: }+ per dangling reference, in
+    #   document order
+    def check_references(input, by: "id")
+      UdonNative.check_references(utf8(input), by: by)
+    end
+
+    # Flatten a document to one record per attribute value, for export to a
+    # table.
+    #
+    # @example
+    #   Udon.records("|config\n  |server :port 8080\n")
+    #   # => [{ path: "config/server/@port", type: :integer, value: 8080,
+    #   #       span: { start: 19, end: 23 } }]
+    #
+    # @param input [String] The UDON document
+    # @return [Array<Hash>] +{ path:, type:, value:, span: }+ in document
+    #   order. +path+ joins element names with "/" and ends in "@key";
+    #   same-named siblings, repeated keys and array items are suffixed with
+    #   their 0-based position ("server[1]", "@tags[0]"). Directive bodies
+    #   count as the directive's siblings
+    def records(input)
+      UdonNative.records(utf8(input))
+    end
+
+    # Layer +override+ over +base+, e.g. an environment's settings over a
+    # shared config, and return the merged UDON text.
+    #
+    # Elements are matched by their +by+ attribute anywhere in the base; the
+    # override's attributes replace the base's values for the same keys and
+    # new keys are appended. Elements without one line up with the first
+    # same-named base sibling that has none, so shared wrappers merge.
+    # Elements in only one document are kept, override-only content going
+    # after the base's own children. Where matching ids name elements of a
+    # different name or kind, the override element replaces the base one.
+    #
+    # @example
+    #   Udon.merge("|server[web] :port 80 :host a\n", "|server[web] :port 8080\n")
+    #   # => "|server :id web :port 8080 :host a\n" (attribute order kept)
+    #
+    # @param base [String] The UDON document to layer over
+    # @param override [String] The UDON document whose values win
+    # @param by [String] The attribute identifying elements
+    # @return [String] UDON text
+    # @raise [UdonNative::MergeError] If either document has a parse error or
+    #   two base elements share an id
+    def merge(base, override, by: "id")
+      UdonNative.merge(utf8(base), utf8(override), by: by)
+    end
+
+    # How many attribute keys the +intern_keys: :global+ pool holds. Pooled
+    # keys live for the life of the process, so the pool stops growing at
+    # 4096 keys and never takes keys over 64 bytes; others are allocated per
+    # parse as usual.
+    #
+    # @return [Integer]
+    def interned_key_count
+      UdonNative.interned_key_count
+    end
+
+    # Mark an expression for interpolation. {Writer} and {emit} render it as
+    # +!{{expr}}+ instead of escaping it as text or quoting it as a string.
+    #
+    # @param expression [String] e.g. "user.name"
+    # @return [UdonNative::Interpolation]
+    def interp(expression)
+      UdonNative::Interpolation.new(expression.to_s)
+    end
+
+    private
+
+    # Non-Strings are passed on for the extension to coerce (or reject), so
+    # every entry point accepts the same types.
+    def utf8(input)
+      return input unless input.is_a?(String)
+
       input = input.encode(Encoding::UTF_8) unless input.encoding == Encoding::UTF_8
-      UdonNative.parse(input)
+      input
     end
   end
 end
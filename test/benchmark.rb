@@ -320,6 +320,20 @@ configs.each do |config|
   udon_result[:elements] = udon_stats[:elements]
   results << udon_result
 
+  extents_result = run_benchmark("UDON + extents", config[:iters]) do
+    events = Udon.parse(udon_doc, precompute_extents: true)
+    traverse_udon_events(events)
+  end
+  extents_result[:elements] = udon_stats[:elements]
+  results << extents_result
+
+  expect_result = run_benchmark("UDON + expect UTF-8", config[:iters]) do
+    events = Udon.parse(udon_doc, expect_encoding: "UTF-8")
+    traverse_udon_events(events)
+  end
+  expect_result[:elements] = udon_stats[:elements]
+  results << expect_result
+
   yaml_result = run_benchmark("YAML (Psych)", config[:iters]) do
     data = YAML.safe_load(yaml_doc)
     traverse_yaml(data)
@@ -359,6 +373,45 @@ configs.each do |config|
   puts
 end
 
+puts "-" * 78
+puts "Selective extraction (1% of elements are |user)"
+puts "-" * 78
+puts
+
+select_doc = (0...20_000).map { |i| i % 100 == 0 ? "|user :id #{i}\n  |email x@y.z\n" : "|item :id #{i} value\n" }.join
+select_results = [
+  run_benchmark("parse", 10) { Udon.parse(select_doc) },
+  run_benchmark("parse_document", 10) { Udon.parse_document(select_doc).children.select { |n| n.name == "user" } },
+  run_benchmark("find_element", 10) { Udon.find_element(select_doc, "user") },
+  run_benchmark("select_elements", 10) { Udon.select_elements(select_doc, "user") },
+  run_benchmark("select_elements events", 10) { Udon.select_elements(select_doc, "user", format: :events) }
+]
+fastest = select_results.min_by { |r| r[:avg] }
+select_results.each do |r|
+  slower = r[:avg] / fastest[:avg]
+  slower_str = slower > 1.1 ? " (#{slower.round(1)}x slower)" : " (fastest)"
+  puts "  %-24s %12s%s" % [r[:name], format_time(r[:avg]), slower_str]
+end
+puts
+
+puts "-" * 78
+puts "First element of a large export"
+puts "-" * 78
+puts
+
+export_doc = "|manifest :version 2\n  |source crm\n" + "|record :id 1\n" * 200_000
+first_results = [
+  run_benchmark("bench_parse", 10) { Udon.bench_parse(export_doc) },
+  run_benchmark("first_element", 10) { Udon.first_element(export_doc) }
+]
+fastest = first_results.min_by { |r| r[:avg] }
+first_results.each do |r|
+  slower = r[:avg] / fastest[:avg]
+  slower_str = slower > 1.1 ? " (#{slower.round(1)}x slower)" : " (fastest)"
+  puts "  %-24s %12s%s" % [r[:name], format_time(r[:avg]), slower_str]
+end
+puts
+
 puts "=" * 78
 puts "Summary"
 puts "=" * 78
@@ -0,0 +1,104 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "udon"
+
+class BuildTest < Minitest::Test
+  class Recorder
+    attr_reader :calls
+
+    def initialize
+      @calls = []
+    end
+
+    %i[start_element end_element start_directive end_directive attribute value
+       text comment interpolation].each do |name|
+      define_method(name) { |*args| @calls << [name, *args] }
+    end
+  end
+
+  class TreeBuilder
+    Node = Struct.new(:name, :attrs, :children)
+
+    def initialize
+      @stack = [Node.new(nil, {}, [])]
+    end
+
+    def start_element(name)
+      node = Node.new(name, {}, [])
+      @stack.last.children << node
+      @stack << node
+    end
+
+    def end_element
+      @stack.pop
+    end
+
+    def attribute(key, value)
+      @stack.last.attrs[key] = value
+    end
+
+    def result
+      @stack.first.children
+    end
+  end
+
+  def test_calls_builder_methods_in_document_order
+    calls = Udon.build(<<~UDON, Recorder.new).calls
+      |div :id main :count 3 :ratio 1.5 :hidden
+        ; note
+        Hello !{{user}}
+        |span :tags [a 2]
+    UDON
+
+    assert_equal [
+      [:start_element, "div"],
+      [:attribute, "id", "main"],
+      [:attribute, "count", 3],
+      [:attribute, "ratio", 1.5],
+      [:attribute, "hidden", true],
+      [:comment, " note"],
+      [:text, "Hello "],
+      [:interpolation, "user"],
+      [:start_element, "span"],
+      [:attribute, "tags", ["a", 2]],
+      [:end_element],
+      [:end_element]
+    ], calls
+  end
+
+  def test_returns_result_from_partial_builder
+    tree = Udon.build("|a :x 1\n  |b\n", TreeBuilder.new)
+
+    assert_equal 1, tree.size
+    assert_equal "a", tree.first.name
+    assert_equal({ "x" => 1 }, tree.first.attrs)
+    assert_equal ["b"], tree.first.children.map(&:name)
+  end
+
+  def test_returns_builder_without_result
+    builder = Object.new
+
+    assert_same builder, Udon.build("|a\n", builder)
+  end
+
+  def test_builder_may_grow_the_input
+    source = +"|a :x 1\n  |b\n"
+    builder = TreeBuilder.new
+    builder.define_singleton_method(:end_element) do
+      source << ("|pad" * 4096) << "\n"
+      super()
+    end
+    tree = Udon.build(source, builder)
+
+    assert_equal ["a"], tree.map(&:name)
+    assert_equal ["b"], tree.first.children.map(&:name)
+  end
+
+  def test_builder_exception_propagates
+    builder = Object.new
+    def builder.start_element(_name) = raise(ArgumentError, "nope")
+
+    assert_raises(ArgumentError) { Udon.build("|a\n", builder) }
+  end
+end
@@ -0,0 +1,76 @@
+#!/usr/bin/env ruby
+# frozen_string_literal: true
+
+# CSV export: native UdonNative.to_csv vs the equivalent Ruby event walk.
+#
+# Run: bundle exec rake bench_csv
+
+require_relative '../lib/udon'
+require 'csv'
+require 'benchmark'
+
+COLUMNS = %w[id name price].freeze
+
+def generate_records(count)
+  lines = ["|catalog"]
+  count.times do |i|
+    lines << "  |record :id #{i} :name \"Item #{i}, large\" :price #{i}.99 :sku sku-#{i}"
+    lines << "    Description for item #{i}"
+  end
+  lines.join("\n") + "\n"
+end
+
+# Ruby-side equivalent: walk the event array and build rows with the csv gem.
+def ruby_to_csv(input)
+  rows = []
+  stack = []
+  key = nil
+  awaiting_name = false
+
+  Udon.parse(input).each do |event|
+    case event[:type]
+    when :element_start
+      stack << nil
+      awaiting_name = true
+    when :name
+      stack[-1] = {} if awaiting_name && event[:content] == "record"
+      awaiting_name = false
+    when :attr
+      key = stack.last && event[:content]
+    when :string_value, :bare_value, :integer, :float
+      stack.last[key] = event[:content] if key && stack.last
+      key = nil
+    when :element_end
+      record = stack.pop
+      rows << record.values_at(*COLUMNS) if record
+    end
+  end
+
+  CSV.generate do |csv|
+    csv << COLUMNS
+    rows.each { |row| csv << row }
+  end
+end
+
+def measure(iterations)
+  yield
+  GC.start
+  start = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+  iterations.times { yield }
+  (Process.clock_gettime(Process::CLOCK_MONOTONIC) - start) / iterations
+end
+
+puts "=" * 78
+puts "CSV Export Benchmark"
+puts "=" * 78
+
+[1_000, 10_000, 100_000].each do |count|
+  input = generate_records(count)
+  iterations = count >= 100_000 ? 3 : 10
+
+  native = measure(iterations) { UdonNative.to_csv(input, element: "record", columns: COLUMNS) }
+  ruby = measure(iterations) { ruby_to_csv(input) }
+
+  puts "%-8d records (%6.1f MB)  native %8.2f ms  ruby %8.2f ms  (%.1fx)" %
+       [count, input.bytesize / 1_048_576.0, native * 1000, ruby * 1000, ruby / native]
+end
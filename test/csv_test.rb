@@ -0,0 +1,55 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "stringio"
+require "udon"
+
+class CsvTest < Minitest::Test
+  SOURCE = <<~UDON
+    |catalog
+      |record :id 1 :name Widget :price 9.99
+      |record :id 2 :name "Gadget, deluxe" :price 19.5 :sale true
+      |record :id 3 :name "Say \\"hi\\""
+        |note :id ignored
+      |other :id 4
+  UDON
+
+  def test_fixed_columns
+    csv = UdonNative.to_csv(SOURCE, element: "record", columns: %w[id name price])
+
+    assert_equal <<~CSV, csv
+      id,name,price
+      1,Widget,9.99
+      2,"Gadget, deluxe",19.5
+      3,"Say ""hi""",
+    CSV
+  end
+
+  def test_auto_columns_take_union_of_sampled_keys
+    csv = UdonNative.to_csv(SOURCE, element: "record", columns: :auto)
+
+    header, *rows = csv.lines(chomp: true)
+    assert_equal "id,name,price,sale", header
+    assert_equal "2,\"Gadget, deluxe\",19.5,true", rows[1]
+    assert_equal 3, rows.size
+  end
+
+  def test_auto_columns_sample_size
+    csv = UdonNative.to_csv(SOURCE, element: "record", columns: :auto, sample: 1)
+
+    assert_equal "id,name,price", csv.lines(chomp: true).first
+  end
+
+  def test_writes_to_io
+    io = StringIO.new
+    result = UdonNative.to_csv(SOURCE, element: "record", columns: ["id"], io: io)
+
+    assert_same io, result
+    assert_equal "id\n1\n2\n3\n", io.string
+  end
+
+  def test_no_matches_writes_header_only
+    csv = UdonNative.to_csv(SOURCE, element: "missing", columns: %w[id])
+    assert_equal "id\n", csv
+  end
+end
@@ -0,0 +1,110 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "pathname"
+require "tmpdir"
+require "udon"
+
+class DefinitionsTest < Minitest::Test
+  def expand(source, **options)
+    Udon.parse_tree(source, expand_definitions: true, **options)
+  end
+
+  def test_use_splices_a_copy_with_overrides
+    source = <<~UDON
+      !define button
+        |button :class btn :type submit
+          Save
+      |form
+        !use button :class primary :id save
+        !use button
+    UDON
+    doc = expand(source)
+    form = doc.children.first
+
+    assert_equal %w[form], doc.children.map(&:name)
+    first, second = form.children
+    assert_equal({ "class" => "primary", "type" => "submit", "id" => "save" }, first.attributes)
+    assert_equal({ "class" => "btn", "type" => "submit" }, second.attributes)
+    assert_equal "Save", first.text_content
+    assert_equal source.index("|button"), first.span[:start]
+    assert_equal "button", first.expanded_from[:name]
+    assert_equal source.index("!use button :class"), first.expanded_from[:span][:start]
+    assert_equal first.expanded_from, first.children.first.expanded_from
+    assert_nil form.expanded_from
+    assert_equal "form", doc.node_at(source.index("primary")).name
+    assert_empty doc.errors
+    assert_equal :directive, Udon.parse_tree(source).children.first.type
+  end
+
+  def test_definitions_use_each_other_in_any_order
+    source = <<~UDON
+      |page
+        !use card
+      !define card
+        |card
+          !use title
+      !define title
+        |h1 Welcome
+    UDON
+    card = expand(source).children.first.children.first
+    title = card.children.first
+
+    assert_equal %w[card h1], [card.name, title.name]
+    assert_equal "title", title.expanded_from[:name]
+    assert_equal source.index("!use title"), title.expanded_from[:span][:start]
+  end
+
+  def test_undefined_and_duplicate_names
+    source = <<~UDON
+      !define a
+        |first
+      !define a
+        |second
+      |root
+        !use a
+        !use missing
+    UDON
+    doc = expand(source)
+
+    assert_equal %w[first], doc.children.first.children.map(&:name)
+    assert_equal %i[duplicate_definition undefined_definition], doc.errors.map { |e| e[:code] }
+    assert_equal source.index("!use missing"), doc.errors.last[:span][:start]
+  end
+
+  def test_recursion_is_limited
+    source = <<~UDON
+      !define loop
+        |level
+          !use loop
+      !use loop
+    UDON
+    doc = expand(source, max_expansion_depth: 3)
+    depth = 0
+    node = doc.children.first
+    while node
+      depth += 1
+      node = node.children.first
+    end
+
+    assert_equal 3, depth
+    assert_equal [:expansion_too_deep], doc.errors.map { |e| e[:code] }
+    assert_empty expand(source, max_expansion_depth: 0).children
+  end
+
+  def test_definitions_from_included_files
+    Dir.mktmpdir do |dir|
+      dir = Pathname(dir)
+      dir.join("parts.udon").write("!define footer\n  |footer :year 2024\n")
+      main = dir.join("app.udon")
+      main.write(%(!include "parts.udon"\n|page\n  !use footer :year 2025\n))
+
+      doc = Udon.parse_tree(main, resolve_includes: true, expand_definitions: true)
+      footer = doc.children.first.children.first
+
+      assert_equal 2025, footer["year"]
+      assert_equal "parts.udon", footer.file
+      assert_equal main.to_s, footer.expanded_from[:file]
+    end
+  end
+end
@@ -0,0 +1,182 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "udon"
+
+class DirectivesTest < Minitest::Test
+  SOURCE = <<~UDON
+    !env:require HOME 2 [a b]
+    |a
+    !deprecated
+      |old
+    !other x
+  UDON
+
+  def teardown
+    Udon::Directives.clear
+  end
+
+  def types(events)
+    events.map { |e| e[:type] }
+  end
+
+  def test_handlers_receive_arguments_span_and_context
+    calls = []
+    Udon::Directives.register("env", "require") do |args, span, ctx|
+      calls << [args, SOURCE.byteslice(span[:start]...span[:end]).strip, ctx[:name], ctx[:namespace]]
+      nil
+    end
+    events = Udon.parse(SOURCE, directives: :dispatch, deprecation_directive: false)
+
+    assert_equal [[["HOME", 2, %w[a b]], "!env:require HOME 2 [a b]", "require", "env"]], calls
+    assert_equal Udon.parse(SOURCE), events
+    assert_equal ["env:require"], Udon::Directives.registered
+  end
+
+  def test_handlers_suppress_or_replace
+    Udon::Directives.register("env", "require") { :suppress }
+    Udon::Directives.register("deprecated") { |_args, _span, ctx| ctx[:errors] << "gone"; Udon.parse("|new\n") }
+    events = Udon.parse(SOURCE, directives: :dispatch)
+    names = events.select { |e| e[:type] == :name }.map { |e| e[:content] }
+
+    assert_equal %w[a new other], names
+    error = events.find { |e| e[:type] == :error }
+    assert_equal [:directive_error, "gone", "deprecated"], error.values_at(:code, :message, :directive)
+  end
+
+  def test_handler_exceptions_become_error_events
+    Udon::Directives.register("other") { raise ArgumentError, "bad other" }
+    events = Udon.parse(SOURCE, directives: :dispatch, deprecation_directive: nil, severity: true)
+    error = events.last
+
+    assert_equal types(Udon.parse(SOURCE)), types(events[0...-1])
+    assert_equal [:error, :directive_failed, "bad other", :recoverable],
+                 error.values_at(:type, :code, :message, :severity)
+    directive_start = events.rindex { |e| e[:type] == :directive_start }
+    assert_equal events[directive_start][:span][:start], error[:span][:start]
+  end
+
+  def test_deprecated_directives_become_warnings
+    source = %(!deprecated "use |endpoint"\n  |url https://example.com\n|plain\n)
+    events = Udon.parse(source, directives: :dispatch)
+    warning = events.first
+
+    assert_equal [:warning, :deprecated, "use |endpoint", "deprecated"],
+                 warning.values_at(:type, :code, :content, :directive)
+    assert_equal source.index("|url"), warning[:span][:start]
+    assert_equal %w[url plain], events.select { |e| e[:type] == :name }.map { |e| e[:content] }
+    refute(events.any? { |e| e[:type] == :directive_start })
+
+    bare = Udon.parse("!deprecated\n", directives: :dispatch).first
+    assert_equal ["deprecated", 0], [bare[:content], bare[:span][:start]]
+    assert_equal Udon.parse(source), Udon.parse(source, directives: :dispatch, deprecation_directive: nil)
+
+    obsolete = Udon.parse("!obsolete gone\n  |x\n", directives: :dispatch, deprecation_directive: "obsolete")
+    assert_equal [:deprecated, "gone"], obsolete.first.values_at(:code, :content)
+
+    Udon::Directives.register("deprecated") { :suppress }
+    names = Udon.parse(source, directives: :dispatch).select { |e| e[:type] == :name }.map { |e| e[:content] }
+    assert_equal %w[plain], names
+  end
+
+  def test_handlers_receive_options
+    options = nil
+    Udon::Directives.register("cache") { |_args, _span, ctx| options = ctx[:options]; nil }
+    Udon.parse("!cache users :ttl 60 :warm :keys [a b]\n", directives: :dispatch)
+
+    assert_equal({ "ttl" => 60, "warm" => true, "keys" => %w[a b] }, options)
+  end
+
+  def test_structured_directive_args
+    source = "|a\n!cache users 2 :ttl 60 :warm\n  |b :x 1\n  !inner z\n    |c\n|d\n"
+    events = Udon.parse(source, directive_args: :structured)
+    directive = events.find { |e| e[:type] == :directive }
+
+    assert_equal %i[element_start name element_end directive element_start name element_end],
+                 types(events)
+    assert_equal ["cache", nil, ["users", 2], { "ttl" => 60, "warm" => true }],
+                 directive.values_at(:name, :namespace, :args, :options)
+    assert_equal source.index("!cache"), directive[:span][:start]
+    body = directive[:body]
+    assert_equal %i[element_start name attr integer element_end directive], types(body)
+    inner = body.last
+    assert_equal [["z"], {}], inner.values_at(:args, :options)
+    assert_equal %i[element_start name element_end], types(inner[:body])
+    assert_raises(ArgumentError) { Udon.parse(source, directive_args: :structured, unify_directives: true) }
+    assert_raises(ArgumentError) { Udon.parse(source, directive_args: :structured, directives: :dispatch) }
+    assert_raises(ArgumentError) { Udon.parse(source, directive_args: :nested) }
+  end
+
+  def test_directive_namespaces
+    errors = Udon.parse(SOURCE, directive_namespaces: ["app"]).select { |e| e[:type] == :error }
+
+    assert_equal [:undeclared_namespace], errors.map { |e| e[:code] }
+    assert_equal %w[env require], errors.first.values_at(:namespace, :name)
+    assert_equal SOURCE.index("env:require"), errors.first[:span][:start]
+    strict = Udon.parse(SOURCE, directive_namespaces: %w[env], allow_unnamespaced: false)
+                 .select { |e| e[:type] == :error }
+    assert_equal [[nil, "deprecated"], [nil, "other"]], strict.map { |e| e.values_at(:namespace, :name) }
+    assert_equal Udon.parse(SOURCE), Udon.parse(SOURCE, directive_namespaces: %w[env])
+  end
+
+  def test_directive_namespaces_scan
+    source = "!app:route x\n|a\n  !deploy:env prod\n!app:cache\n!plain\n"
+    used = Udon.directive_namespaces(source)
+
+    assert_equal %w[app deploy], used.keys
+    assert_equal source.index("app:route"), used["app"][:start]
+    assert_equal source.index("deploy:env"), used["deploy"][:start]
+    assert_empty Udon.directive_namespaces("|a\n")
+  end
+
+  def test_inline_directive_content
+    assert_equal Udon.parse(SOURCE), Udon.parse(SOURCE, inline_directive_content: :raw)
+    error = assert_raises(NotImplementedError) { Udon.parse(SOURCE, inline_directive_content: :parse) }
+    assert_match(/inline directives/, error.message)
+    assert_raises(ArgumentError) { Udon.parse(SOURCE, inline_directive_content: :eager) }
+  end
+
+  def test_registry_management_and_argument_errors
+    Udon::Directives.register(nil, "x") { nil }
+
+    assert Udon::Directives.unregister("x")
+    refute Udon::Directives.unregister("x")
+    assert_raises(ArgumentError) { Udon::Directives.register("x") }
+    assert_raises(ArgumentError) { Udon.parse(SOURCE, directives: :dispatch, unify_directives: true) }
+    assert_raises(ArgumentError) { Udon.parse(SOURCE, directives: :bogus) }
+  end
+
+  def test_unknown_directives
+    Udon::Directives.register("env", "require") { nil }
+    events = Udon.parse(SOURCE, unknown_directives: :warn, known_directives: ["other"])
+    warning = events.find { |e| e[:type] == :warning }
+    at = events.index(warning)
+
+    assert_equal 1, events.count { |e| e[:type] == :warning }
+    assert_equal :unknown_directive, warning[:code]
+    assert_equal "deprecated", warning[:name]
+    assert_match(/unknown directive !deprecated/, warning[:content])
+    assert_nil warning[:suggestion]
+    assert_equal "deprecated", events[at - 1][:content]
+    assert_equal events[at - 1][:span], warning[:span]
+    assert_equal Udon.parse(SOURCE), events - [warning]
+    assert_empty Udon.parse(SOURCE, unknown_directives: :allow).select { |e| e[:type] == :warning }
+  end
+
+  def test_unknown_directive_errors_suggest_and_raise
+    source = "!inclde \"base.udon\"\n!env:requier HOME\n!include \"x.udon\"\n"
+    Udon::Directives.register("env", "require") { nil }
+    errors = Udon.parse(source, unknown_directives: :error, severity: true).select { |e| e[:type] == :error }
+
+    assert_equal %w[inclde env:requier], errors.map { |e| e[:name] }
+    assert_equal %w[include env:require], errors.map { |e| e[:suggestion] }
+    assert_equal [:recoverable] * 2, errors.map { |e| e[:severity] }
+    assert_match(/did you mean !include\?/, errors.first[:message])
+    error = assert_raises(UdonNative::DirectiveError) do
+      Udon.parse(source, unknown_directives: :error, strict_directives: true)
+    end
+    assert_match(/!inclde .* at \d+\.\.\.\d+/, error.message)
+    assert_raises(ArgumentError) { Udon.parse(source, unknown_directives: :error, on_error: ->(e) {}) }
+    assert_raises(ArgumentError) { Udon.parse(source, unknown_directives: :strict) }
+  end
+end
@@ -0,0 +1,207 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "udon"
+
+class DocumentTest < Minitest::Test
+  SOURCE = <<~UDON
+    ; configuration
+    |server :host web :port 8080 :tls
+      |route :path "/a"
+        Hello there
+      |route :path "/b"
+  UDON
+
+  def digest(source, **options)
+    Udon.parse_document(source).digest(**options)
+  end
+
+  def test_tree_navigation
+    doc = Udon.parse_document(SOURCE)
+    server = doc.children.find { |n| n.type == :element }
+
+    assert_equal "server", server.name
+    assert_equal({ "host" => "web", "port" => 8080, "tls" => true }, server.attributes)
+    assert_equal %w[/a /b], server.children.map { |n| n["path"] }
+    assert_equal "server", server.children.first.parent.name
+    assert_nil server.parent
+  end
+
+  def test_attribute_metadata
+    input = "|a :port 8080 :name \"x y\" :tags [p q] :on :c 1 :c 2\n"
+    attributes = Udon.parse_document(input).children.first.attributes(metadata: true)
+    slice = ->(key) { input.byteslice(attributes[key][:span][:start]...attributes[key][:span][:end]) }
+
+    assert_equal({ value: 8080, type: :integer, span: { start: 9, end: 13 } }, attributes["port"])
+    assert_equal ["x y", :string_value], attributes["name"].values_at(:value, :type)
+    assert_equal [%w[p q], :array, "[p q]"], [*attributes["tags"].values_at(:value, :type), slice.("tags")]
+    assert_equal [true, :bool_true, "on"], [*attributes["on"].values_at(:value, :type), slice.("on")]
+    assert_equal [1, 2], attributes["c"].map { |a| a[:value] }
+  end
+
+  def test_bom_is_skipped_with_spans_counting_it
+    input = "\uFEFF|a :x 1\n  |b\n"
+    doc = Udon.parse_document(input)
+    a = doc.children.first
+
+    assert doc.bom?
+    refute Udon.parse_document("|a\n").bom?
+    assert_empty doc.errors
+    assert_equal "a", a.name
+    assert_equal 3, a.span[:start]
+    assert_equal "a", doc.node_at(3).name
+  end
+
+  def test_digest_ignores_formatting
+    assert_equal digest("|a :x 1000\n  |b :y \"z\"\n"), digest("|a   :x 1_000\n    |b :y z\n")
+  end
+
+  def test_digest_changes_with_content
+    refute_equal digest("|a :x 1\n"), digest("|a :x 2\n")
+    refute_equal digest("|a :x 1\n"), digest("|a :y 1\n")
+    refute_equal digest("|a\n  |b\n"), digest("|a\n|b\n")
+  end
+
+  def test_digest_ignore_comments
+    with_comment = "; note\n|a :x 1\n"
+    without = "|a :x 1\n"
+
+    refute_equal digest(with_comment), digest(without)
+    assert_equal digest(with_comment, ignore: [:comments]), digest(without, ignore: [:comments])
+  end
+
+  def test_digest_ignore_whitespace
+    wide = "|p\n  Hello    big\n  world\n"
+    tight = "|p Hello big world\n"
+
+    refute_equal digest(wide), digest(tight)
+    assert_equal digest(wide, ignore: [:whitespace]), digest(tight, ignore: [:whitespace])
+  end
+
+  def test_digest_ignore_attribute_order
+    a = "|a :x 1 :y 2\n"
+    b = "|a :y 2 :x 1\n"
+
+    refute_equal digest(a), digest(b)
+    assert_equal digest(a, ignore: [:attribute_order]), digest(b, ignore: [:attribute_order])
+  end
+
+  def test_digest_follows_canonical_values
+    assert_equal digest("|a :on yes\n"), digest("|a :on true\n")
+    assert_equal digest("|a :v \" x \"\n"), digest("|a :v x\n")
+  end
+
+  def test_node_digest_compares_subtrees_across_parses
+    before = Udon.parse_document(SOURCE).children.last
+    after = Udon.parse_document(SOURCE.sub("/b", "/c")).children.last
+
+    refute_equal before.digest, after.digest
+    assert_equal before.children.first.digest, after.children.first.digest
+  end
+
+  def test_digest_algorithm
+    assert_equal 64, digest(SOURCE).length
+    assert_equal 128, digest(SOURCE, algorithm: :sha512).length
+    assert_raises(ArgumentError) { digest(SOURCE, algorithm: :md5) }
+    assert_raises(ArgumentError) { digest(SOURCE, ignore: [:spans]) }
+  end
+
+  def test_to_udon_sorts_like_emit
+    doc = Udon.parse_document("|a :z 1 :b 2\n")
+
+    assert_equal Udon.emit(Udon.parse("|a :z 1 :b 2\n"), sort_attributes: true),
+                 doc.to_udon(sort_attributes: true)
+  end
+
+  def test_text_content
+    doc = Udon.parse_document(<<~UDON)
+      |article
+        ; not indexed
+        Hello !{{user}},
+        |p Welcome to |{em the} site.
+    UDON
+    article = doc.children.first
+
+    assert_equal "Hello ,\nWelcome to the site.", article.text_content
+    assert_equal "Hello !{{user}},\nWelcome to the site.", article.text_content(interpolations: true)
+    assert_equal "Welcome to the site.", article.children.last.text_content
+  end
+
+  def test_deprecations
+    doc = Udon.parse_document(<<~UDON)
+      |config
+        !deprecated "use |endpoint"
+          |url https://example.com
+        |endpoint https://example.com
+      !deprecated
+      !obsolete gone
+        |x
+    UDON
+
+    first, bare = doc.deprecations
+    assert_equal "use |endpoint", first[:message]
+    assert_equal "url", first[:node].name
+    assert_equal first[:node].span, first[:span]
+    assert_equal ["deprecated", nil], bare.values_at(:message, :node)
+    assert_equal [["gone", "x"]], doc.deprecations("obsolete").map { |d| [d[:message], d[:node].name] }
+  end
+
+  def test_node_at_finds_innermost_node_and_ancestors
+    input = "|article\n  |p Welcome to |{em the} site.\n  |p Bye\n"
+    doc = Udon.parse_document(input)
+
+    em = doc.node_at(input.index("the"))
+    assert_equal :embedded, em.type
+    assert_equal %w[p article], em.ancestors.map(&:name)
+
+    text = doc.node_at(input.index("Welcome"))
+    assert_equal :text, text.type
+    assert_equal "p", text.parent.name
+
+    between = doc.node_at(input.index("  |p Bye"))
+    assert_equal :element, between.type
+    assert_includes between.ancestors.map(&:name) + [between.name], "article"
+    assert_empty doc.node_at(0).ancestors
+    assert_nil doc.node_at(input.bytesize + 10)
+  end
+
+  def test_first_element_stops_before_the_rest
+    head = "; export\n|manifest :version 2\n  |source crm\n"
+    sentinel = "|broken :x \"never closed\n"
+    input = head + "|record :id 1\n" * 200_000 + sentinel
+
+    refute_empty Udon.parse_document(sentinel).errors
+    manifest = Udon.first_element(input)
+    assert_equal "manifest", manifest.name
+    assert_equal 2, manifest["version"]
+    assert_equal ["source"], manifest.children.map(&:name)
+    assert_nil manifest.parent
+    assert_equal input.index("|manifest"), manifest.span[:start]
+
+    parsed = manifest.document
+    assert_empty parsed.errors
+    assert_equal ["manifest"], parsed.children.select { |node| node.type == :element }.map(&:name)
+
+    assert_equal "last", Udon.first_element("|last :x 1").name
+    assert_nil Udon.first_element("; only a comment\n")
+    assert_nil Udon.first_element("")
+  end
+
+  def test_parse_tree_as_objects
+    input = "|server :host db.internal :port 5432 :max-age 60 :name main\n  |tls :enabled\n"
+    server = Udon.parse_tree(input, as_objects: true).children.first
+
+    assert_equal ["db.internal", 5432, 60], [server.host, server.port, server.max_age]
+    assert_equal true, server.children.first.enabled
+    assert_equal "server", server.name
+    assert_equal "main", server["name"]
+    assert server.respond_to?(:host)
+    refute server.respond_to?(:missing)
+    assert_raises(NoMethodError) { server.missing }
+    assert_raises(NoMethodError) { server.host(1) }
+
+    plain = Udon.parse_tree(input).children.first
+    refute plain.respond_to?(:host)
+    assert_raises(NoMethodError) { plain.host }
+  end
+end
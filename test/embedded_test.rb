@@ -0,0 +1,68 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "json"
+require "udon"
+
+class EmbeddedTest < Minitest::Test
+  SOURCE = %(|config |{json {"retries": 3}} and |{em plain}\n)
+
+  def teardown
+    Udon::Embedded.clear
+  end
+
+  def test_value_event_before_the_end
+    spans = []
+    Udon::Embedded.register("json") do |content, span|
+      spans << span
+      JSON.parse(content)
+    end
+    events = Udon.parse(SOURCE)
+    at = events.index { |e| e[:type] == :embedded_value }
+    value = events[at]
+
+    assert_equal({ "retries" => 3 }, value[:value])
+    assert_equal "json", value[:name]
+    assert_equal %(|{json {"retries": 3}}), SOURCE.byteslice(value[:span][:start]...value[:span][:end])
+    assert_equal [value[:span]], spans
+    assert_equal :embedded_end, events[at + 1][:type]
+    assert_equal 1, events.count { |e| e[:type] == :embedded_value }
+    assert_equal Udon.emit(Udon.parse(SOURCE)), Udon.emit(events)
+    assert_equal ["json"], Udon::Embedded.registered
+    assert Udon::Embedded.unregister(:json)
+    assert_nil Udon.parse(SOURCE).find { |e| e[:type] == :embedded_value }
+  end
+
+  def test_handler_errors_become_events
+    Udon::Embedded.register("json") { |content, _span| JSON.parse(content.sub("3", "")) }
+    error = Udon.parse(SOURCE, severity: true).find { |e| e[:type] == :error }
+
+    assert_equal :embedded_failed, error[:code]
+    assert_equal "json", error[:name]
+    assert_equal :recoverable, error[:severity]
+    assert_equal SOURCE.index("|{json"), error[:span][:start]
+    assert_kind_of String, error[:message]
+  end
+
+  def test_node_value
+    Udon::Embedded.register("json") { |content, _span| JSON.parse(content) }
+    json, em = Udon.parse_document(SOURCE).children.first.children.select { |n| n.type == :embedded }
+
+    assert_equal({ "retries" => 3 }, json.value)
+    assert_equal "plain", em.value
+    assert_nil Udon.parse_document(SOURCE).children.first.value
+    Udon::Embedded.register("em") { raise ArgumentError, "nope" }
+    assert_raises(ArgumentError) { em.value }
+  end
+
+  def test_dedent_raw
+    received = []
+    Udon::Embedded.register("sql") { |content, _span| received << content }
+    source = "|query |{sql\n    SELECT *\n      FROM t\n  }\n"
+    Udon.parse(source)
+    Udon.parse(source, dedent_raw: true)
+
+    assert_equal "    SELECT *\n      FROM t\n  ", received[0]
+    assert_equal "SELECT *\n  FROM t\n", received[1]
+  end
+end
@@ -0,0 +1,179 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "stringio"
+require "udon"
+
+class EmitTest < Minitest::Test
+  SOURCE = <<~UDON
+    |article[post].featured :title "Hello, World!" :draft
+      ; a note
+      |section :tags [a b c]
+        Some text with !{{name}} inside.
+      |foo :count 42
+  UDON
+
+  def without_spans(events)
+    events.map { |e| e.reject { |k, _| k == :span } }
+  end
+
+  def test_emit_round_trips_structure
+    events = Udon.parse(SOURCE)
+    output = Udon.emit(events)
+
+    assert_kind_of String, output
+    assert_equal without_spans(events), without_spans(Udon.parse(output))
+  end
+
+  def test_emit_writes_to_io
+    io = StringIO.new
+    result = Udon.emit(Udon.parse(SOURCE), io)
+
+    assert_same io, result
+    assert_equal Udon.emit(Udon.parse(SOURCE)), io.string
+  end
+
+  def test_emit_rejects_mismatched_end
+    events = Udon.parse("|div\n").reject { |e| e[:type] == :element_end }
+    events << { type: :array_end, span: { start: 0, end: 0 } }
+
+    error = assert_raises(UdonNative::EmitError) { Udon.emit(events) }
+    assert_match(/event #{events.size - 1}/, error.message)
+  end
+
+  def test_transform_renames_elements
+    output = Udon.transform(SOURCE) do |event|
+      event[:content] = "bar" if event[:type] == :name && event[:content] == "foo"
+      event
+    end
+
+    names = Udon.parse(output).select { |e| e[:type] == :name }.map { |e| e[:content] }
+    assert_includes names, "bar"
+    refute_includes names, "foo"
+  end
+
+  def test_transform_drops_and_replaces_events
+    output = Udon.transform(SOURCE) do |event|
+      case event[:type]
+      when :comment_start, :comment_end then nil
+      when :text
+        event[:content].include?("a note") ? nil : event
+      when :element_end
+        [event]
+      else
+        event
+      end
+    end
+
+    events = Udon.parse(output)
+    refute events.any? { |e| e[:type] == :comment_start }
+    assert_equal 3, events.count { |e| e[:type] == :element_end }
+  end
+
+  def test_transform_parses_a_copy_of_the_input
+    source = +"|a one\n|b two\n"
+    expected = Udon.transform(source.dup) { |event| event }
+    output = Udon.transform(source) do |event|
+      source << ("|pad" * 4096) << "\n"
+      event
+    end
+    assert_equal expected, output
+  end
+
+  def test_transform_reports_originating_event_index
+    events = Udon.parse(SOURCE)
+    dropped = events.index { |e| e[:type] == :element_end }
+
+    error = assert_raises(UdonNative::EmitError) do
+      index = -1
+      Udon.transform(SOURCE) do |event|
+        index += 1
+        event[:type] == :element_end && index == dropped ? { type: :array_end } : event
+      end
+    end
+    assert_match(/event #{dropped}/, error.message)
+  end
+
+  def test_detect_indentation
+    assert_equal({ unit: "  ", consistent: true, samples: { "  " => 2 }, offenders: [] },
+                 Udon.detect_indentation(SOURCE))
+    assert_equal "    ", Udon.detect_indentation("|a\n    |b\n        |c\n    |d\n")[:unit]
+    assert_nil Udon.detect_indentation("|a\n|b\n")[:unit]
+
+    detected = Udon.detect_indentation("|a\n\t|b\n\t\t|c\n\t  |d\n  |e\n")
+    assert_equal "\t", detected[:unit]
+    refute detected[:consistent]
+    assert_equal [[3, :mixed], [4, :style]], detected[:offenders].map { |o| [o[:line], o[:problem]] }
+    assert_equal({ start: 12, end: 15 }, detected[:offenders][0][:span])
+  end
+
+  def test_detect_indentation_skips_freeform_content
+    input = "|code\n    ```\n      odd\n       odder\n    ```\n    |p x\n"
+
+    assert_equal({ unit: "    ", consistent: true, samples: { "    " => 1 }, offenders: [] },
+                 Udon.detect_indentation(input))
+  end
+
+  def test_emit_and_transform_indent
+    tabbed = Udon.emit(Udon.parse(SOURCE), indent: "\t")
+
+    assert_match(/^\t\|section/, tabbed)
+    assert_equal without_spans(Udon.parse(SOURCE)), without_spans(Udon.parse(tabbed))
+    assert_equal Udon.emit(Udon.parse(SOURCE), indent: "    "), Udon.emit(Udon.parse(SOURCE), indent: 4)
+    assert_equal tabbed, Udon.transform(tabbed, indent: :detect) { |e| e }
+    assert_equal Udon.emit(Udon.parse(tabbed)), Udon.transform(tabbed) { |e| e }
+    assert_raises(ArgumentError) { Udon.emit([], indent: :detect) }
+    assert_raises(ArgumentError) { Udon.emit([], indent: "x") }
+  end
+
+  UNSORTED = <<~UDON
+    |list
+      |item :name beta :id 2 :class x
+      ; first
+      |item :id 10 :class y
+      |item :class z :id 1
+  UDON
+
+  def attr_keys(output)
+    Udon.parse(output).select { |e| e[:type] == :attr }.map { |e| e[:content] }
+  end
+
+  def test_sort_attributes
+    output = Udon.emit(Udon.parse(UNSORTED), sort_attributes: true)
+
+    assert_equal %w[class id name class id class id], attr_keys(output)
+  end
+
+  def test_sort_attributes_with_priority
+    output = Udon.emit(Udon.parse(UNSORTED), sort_attributes: %w[id name])
+
+    assert_equal %w[id name class id class id class], attr_keys(output)
+  end
+
+  def test_sort_elements_by_moves_leading_comments
+    output = Udon.emit(Udon.parse(UNSORTED), sort_elements_by: "id")
+    ids = Udon.parse(output).each_cons(2)
+      .select { |a, _| a[:type] == :attr && a[:content] == "id" }
+      .map { |_, value| value[:content] }
+
+    assert_equal %w[1 2 10], ids
+    assert_match(/; first\n\s*\|item :id 10/, output)
+  end
+
+  def test_sorting_is_idempotent
+    options = { sort_attributes: %w[id], sort_elements_by: :id }
+    once = Udon.emit(Udon.parse(UNSORTED), **options)
+
+    assert_equal once, Udon.emit(Udon.parse(once), **options)
+  end
+
+  def test_sorting_is_stable
+    source = "|a :k 1 :n first\n|a :k 1 :n second\n|a :n third\n"
+    output = Udon.emit(Udon.parse(source), sort_elements_by: "k", sort_attributes: true)
+    names = Udon.parse(output).each_cons(2)
+      .select { |a, _| a[:type] == :attr && a[:content] == "n" }
+      .map { |_, value| value[:content] }
+
+    assert_equal %w[first second third], names
+  end
+end
@@ -0,0 +1,84 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "udon"
+
+class HandlerContextTest < Minitest::Test
+  def teardown
+    Udon::Directives.clear
+    Udon::Embedded.clear
+  end
+
+  def test_directive_handlers_get_a_context
+    seen = nil
+    Udon::Directives.register("todo") do |(note), _span, _ctx, context|
+      seen = [context.path, context.depth, context.source_slice.strip, context.user_context]
+      context.emit_warning(:todo, note)
+      nil
+    end
+    source = %(|server\n  |tls\n    !todo "add certs"\n)
+    events = Udon.parse(source, directives: :dispatch, user_context: { env: "prod" })
+
+    assert_equal [%w[server tls], 2, %(!todo "add certs"), { env: "prod" }], seen
+    warning = events.find { |e| e[:type] == :warning }
+    assert_equal [:todo, "add certs"], warning.values_at(:code, :content)
+    assert_equal source.index("!todo"), warning[:span][:start]
+    assert seen[0].all?(&:frozen?)
+  end
+
+  def test_embedded_handlers_and_resolver_get_a_context
+    Udon::Embedded.register("json") do |content, _span, context|
+      context.emit_warning("legacy", "json in #{context.path.first}")
+      content
+    end
+    events = Udon.parse(%(|config |{json {}}\n))
+    value = events.index { |e| e[:type] == :embedded_value }
+    assert_equal [:warning, :legacy, "json in config"], events[value + 1].values_at(:type, :code, :content)
+    assert_equal :embedded_end, events[value + 2][:type]
+
+    resolver = ->(expr, context) { "#{expr}@#{context.user_context}:#{context.depth}" }
+    event = Udon.parse("|a\n  |b !{{x}}\n", interpolation_resolver: resolver, user_context: "ctx")
+                .find { |e| e[:interpolated] }
+    assert_equal "x@ctx:2", event[:content]
+    one_argument = Udon.parse("|a !{{x}}\n", interpolation_resolver: ->(expr) { expr.upcase })
+    assert_equal "X", one_argument.find { |e| e[:interpolated] }[:content]
+  end
+
+  def test_callbacks_may_grow_the_input
+    original = %(|server\n  !todo "add certs"\n  |b !{{x}} |{json {}}\n)
+    source = nil
+    grown = []
+    grow = ->(by) { grown << by && source << ("|pad" * 4096) << "\n" if source }
+    Udon::Directives.register("todo") { grow.(:directive); nil }
+    Udon::Embedded.register("json") { |content| grow.(:embedded); content }
+    resolver = ->(expr) { grow.(:resolver); expr.upcase }
+    expected = Udon.parse(original, interpolation_resolver: resolver)
+    dispatched = Udon.parse(original, directives: :dispatch)
+    found = Udon.find_element(original, "b", interpolation_resolver: resolver)
+
+    source = +original
+    assert_equal expected, Udon.parse(source, interpolation_resolver: resolver)
+    source = +original
+    assert_equal dispatched, Udon.parse(source, directives: :dispatch)
+    source = +original
+    assert_equal found, Udon.find_element(source, "b", interpolation_resolver: resolver)
+    assert_equal %i[resolver embedded directive resolver], grown
+  end
+
+  def test_context_is_invalid_after_the_callback
+    kept = nil
+    Udon::Embedded.register("json") { |content, _span, context| kept = context; content }
+    Udon.parse(%(|config |{json {}}\n))
+
+    assert_kind_of Udon::HandlerContext, kept
+    assert_raises(UdonNative::Error) { kept.path }
+    assert_raises(UdonNative::Error) { kept.emit_warning(:late, "too late") }
+  end
+
+  def test_node_value_context
+    Udon::Embedded.register("json") { |_content, _span, context| [context.path, context.user_context] }
+    json = Udon.parse_document(%(|config\n  |db |{json {}}\n)).children.first.children.first.children.first
+
+    assert_equal [%w[config db], 1], json.value(user_context: 1)
+  end
+end
@@ -0,0 +1,127 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "pathname"
+require "tmpdir"
+require "udon"
+
+class IncludeTest < Minitest::Test
+  def setup
+    @dir = Pathname(Dir.mktmpdir)
+  end
+
+  def teardown
+    @dir.rmtree
+  end
+
+  def write(path, content)
+    file = @dir.join(path)
+    file.dirname.mkpath
+    file.write(content)
+    file
+  end
+
+  def test_splices_included_nodes_with_their_file_and_span
+    main = write("app.udon", %(|app\n  !include "shared/db.udon"\n  |cache :size 10\n))
+    write("shared/db.udon", %(|database :host db1\n!include "pool.udon"\n))
+    write("shared/pool.udon", "|pool :max 5\n")
+
+    doc = Udon.parse_tree(main, resolve_includes: true)
+    app = doc.children.first
+
+    assert_equal %w[database pool cache], app.children.select { |n| n.type == :element }.map(&:name)
+    database, pool = app.children.first(2)
+    assert_equal "shared/db.udon", database.file
+    assert_equal 0, database.span[:start]
+    assert_equal "shared/pool.udon", pool.file
+    assert_equal main.to_s, app.file
+    assert_equal "app", doc.node_at(main.read.index("!include") + 2).name
+    assert_equal :directive, Udon.parse_tree(main).children.first.children.first.type
+  end
+
+  def test_errors_in_included_files_name_the_file
+    main = write("app.udon", %(!include "bad.udon"\n))
+    write("bad.udon", %(|a :x "open\n))
+
+    error = Udon.parse_tree(main, resolve_includes: true).errors.first
+    assert_equal "bad.udon", error[:file]
+  end
+
+  def test_cycle_is_named
+    main = write("a.udon", %(!include "b.udon"\n))
+    write("b.udon", %(!include "a.udon"\n))
+
+    error = assert_raises(UdonNative::IncludeError) { Udon.parse_tree(main, resolve_includes: true) }
+    assert_equal "include cycle: a.udon -> b.udon -> a.udon", error.message
+  end
+
+  def test_depth_limit
+    (1..4).each { |n| write("#{n}.udon", %(!include "#{n + 1}.udon"\n)) }
+    write("5.udon", "|leaf\n")
+    main = @dir.join("1.udon")
+
+    assert_equal "leaf", Udon.parse_tree(main, resolve_includes: true).children.first.name
+    error = assert_raises(UdonNative::IncludeError) do
+      Udon.parse_tree(main, resolve_includes: true, max_include_depth: 2)
+    end
+    assert_match(/nested more than 2 deep at 3\.udon byte 0/, error.message)
+  end
+
+  def test_paths_are_sandboxed_to_the_base
+    write("outside.udon", "|secret\n")
+    main = write("app/main.udon", %(!include "../outside.udon"\n))
+
+    assert_raises(UdonNative::IncludeError) { Udon.parse_tree(main, resolve_includes: true) }
+    absolute = %(!include "#{@dir.join("outside.udon")}"\n)
+    assert_raises(UdonNative::IncludeError) do
+      Udon.parse_tree(absolute, resolve_includes: true, include_base: @dir.join("app"))
+    end
+    doc = Udon.parse_tree(absolute, resolve_includes: true, include_base: @dir.join("app"), allow_absolute: true)
+    assert_equal "secret", doc.children.first.name
+    assert_equal "secret", Udon.parse_tree(main, resolve_includes: true, include_base: @dir).children.first.name
+  end
+
+  def test_symlink_out_of_the_base
+    write("outside.udon", "|secret\n")
+    main = write("app/main.udon", %(!include "link.udon"\n))
+    File.symlink(@dir.join("outside.udon"), @dir.join("app/link.udon"))
+
+    error = assert_raises(UdonNative::IncludeError) { Udon.parse_tree(main, resolve_includes: true) }
+    assert_match(/links outside the include base/, error.message)
+  end
+
+  def test_missing_file
+    error = assert_raises(UdonNative::IncludeError) do
+      Udon.parse_tree(%(!include "nope.udon"\n), resolve_includes: true, include_base: @dir)
+    end
+    assert_match(/cannot read include nope\.udon/, error.message)
+    assert_raises(UdonNative::IncludeError) do
+      Udon.parse_tree(%(!include\n), resolve_includes: true, include_base: @dir)
+    end
+  end
+
+  def test_loader
+    files = {
+      "parts/db.udon" => "|database\n",
+      "main.udon" => %(|app\n  !include "parts/loop.udon"\n),
+      "parts/loop.udon" => %(!include "../main.udon"\n)
+    }
+    requested = []
+    loader = lambda do |path|
+      requested << path
+      files[path]
+    end
+
+    doc = Udon.parse_tree(%(!include "parts/db.udon"\n), resolve_includes: true, loader: files.method(:[]))
+    assert_equal "parts/db.udon", doc.children.first.file
+    error = assert_raises(UdonNative::IncludeError) do
+      Udon.parse_tree(%(!include "main.udon"\n), resolve_includes: true, loader: loader)
+    end
+    assert_equal "include cycle: main.udon -> parts/loop.udon -> main.udon", error.message
+    assert_equal %w[main.udon parts/loop.udon], requested
+    assert_raises(UdonNative::IncludeError) do
+      Udon.parse_tree(%(!include "other.udon"\n), resolve_includes: true, loader: loader)
+    end
+    assert_raises(ArgumentError) { Udon.parse_tree("|a\n", resolve_includes: true, loader: 42) }
+  end
+end
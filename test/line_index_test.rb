@@ -0,0 +1,163 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "udon"
+
+class LineIndexTest < Minitest::Test
+  def test_locate_is_one_based_with_character_columns
+    index = Udon::LineIndex.new("|a\n  |b :name \"héllo\" :x 1\n")
+
+    assert_equal({ line: 1, column: 1 }, index.locate(0))
+    assert_equal({ line: 2, column: 3 }, index.locate(5))
+    x = "|a\n  |b :name \"héllo\" :".bytesize
+    assert_equal({ line: 2, column: 21 }, index.locate(x))
+  end
+
+  def test_tab_width_expands_tabs_before_and_after_multibyte_text
+    input = "|a\n\tä\tb\n"
+    b = input.index("b")
+
+    assert_equal({ line: 2, column: 4 }, Udon::LineIndex.new(input).locate(b))
+    assert_equal({ line: 2, column: 4 }, Udon::LineIndex.new(input, tab_width: nil).locate(b))
+    assert_equal({ line: 2, column: 9 }, Udon::LineIndex.new(input, tab_width: 4).locate(b))
+    assert_equal({ line: 2, column: 17 }, Udon::LineIndex.new(input, tab_width: 8).locate(b))
+    assert_equal({ line: 2, column: 6 }, Udon::LineIndex.new(input, tab_width: 4).locate(input.index("ä") + 2))
+  end
+
+  def test_line_endings
+    index = Udon::LineIndex.new("a\r\nb\rc\nd")
+
+    assert_equal 4, index.line_count
+    assert_equal({ line: 2, column: 1 }, index.locate(3))
+    assert_equal({ line: 3, column: 1 }, index.locate(5))
+    assert_equal({ line: 4, column: 1 }, index.locate(7))
+    assert_equal({ start: 0, end: 1 }, index.line_range(1))
+    assert_equal({ start: 3, end: 4 }, index.line_range(2))
+    assert_equal({ start: 7, end: 8 }, index.line_range(4))
+    assert_raises(IndexError) { index.line_range(5) }
+    assert_raises(IndexError) { index.line_range(0) }
+  end
+
+  def test_line_widths
+    assert_equal [13, 5, 0], Udon.line_widths("|title 日本語\n\tx\n", tab_width: 4)
+    assert_equal [2, 2], Udon.line_widths("\tx\r\ne\u0301\u200b!")
+    assert_equal [3], Udon.line_widths("\u{feff}|ab")
+    assert_equal [6], Udon.line_widths("|ｘ🎉a")
+  end
+
+  def test_snippet_underlines_span
+    input = "|a\n  |b :x 1\n  |c\n|d\n"
+    index = Udon::LineIndex.new(input)
+    start = input.index(":x")
+
+    assert_equal <<~SNIPPET, index.snippet({ start: start, end: start + 2 })
+      1 | |a
+      2 |   |b :x 1
+        |      ^^
+      3 |   |c
+    SNIPPET
+    assert_equal <<~SNIPPET, index.snippet(start...start + 2, context: 0)
+      2 |   |b :x 1
+        |      ^^
+    SNIPPET
+  end
+
+  def test_snippet_for_event_span
+    input = "|a\n  |b :name x\n"
+    attr = Udon.parse(input).find { |e| e[:type] == :attr }
+
+    assert_match(/2 \|   \|b :name x\n  \| +\^+\n/, Udon::LineIndex.new(input).snippet(attr[:span]))
+  end
+
+  def test_module_snippet_for_error_event
+    input = "|a\n|b\n|c [\n|d\n|e\n|f\n"
+    start = input.index("[")
+
+    assert_equal <<~SNIPPET, Udon.snippet(input, { start: start, end: start + 1 })
+      1 | |a
+      2 | |b
+      3 | |c [
+        |    ^
+      4 | |d
+      5 | |e
+    SNIPPET
+  end
+
+  def test_diagnostics
+    input = "|a\n|b :s \"open\n"
+    diagnostics = Udon.diagnostics(input)
+    errors = Udon.parse(input).select { |e| e[:type] == :error }
+
+    assert_equal errors.map { |e| e[:code] }, diagnostics.map { |d| d[:code] }
+    diagnostic = diagnostics.first
+    assert_kind_of String, diagnostic[:message]
+    assert_includes %i[error warning], diagnostic[:severity]
+    index = Udon::LineIndex.new(input)
+    start = index.locate(errors.first[:span][:start])
+    assert_equal({ line: start[:line] - 1, col: start[:column] - 1 }, diagnostic[:range][:start])
+    assert_empty Udon.diagnostics("|a\n  |b\n")
+  end
+
+  def test_diagnostics_severity_and_normalization
+    assert_equal [:error], Udon.diagnostics("|a\0").map { |d| d[:severity] }
+    assert_equal :nul_byte, Udon.diagnostics("|a\0").first[:code]
+    crlf = Udon.diagnostics("\uFEFF|a\r\n|b :s \"open\r\n")
+    lf = Udon.diagnostics("|a\n|b :s \"open\n")
+    assert_equal lf.map { |d| d[:range][:start] }, crlf.map { |d| d[:range][:start] }
+  end
+
+  def test_snippet_clamps_span_past_end
+    input = "|a :x 1"
+
+    assert_equal <<~SNIPPET, Udon.snippet(input, { start: 3, end: 100 })
+      1 | |a :x 1
+        |    ^^^^
+        = note: span 3..100 is past the end of the input (7 bytes); clamped
+    SNIPPET
+  end
+
+  def test_snippet_truncates_long_lines
+    input = "|a #{"x" * 200} :key v #{"y" * 200}"
+    start = input.index(":key")
+    snippet = Udon.snippet(input, { start: start, end: start + 4 }, max_width: 40)
+    line, marks = snippet.lines
+
+    assert line.start_with?("1 | …")
+    assert line.end_with?("…\n")
+    assert_equal line.index(":key"), marks.index("^")
+    assert_equal "^^^^\n", marks[marks.index("^")..]
+  end
+
+  def test_snippet_color
+    snippet = Udon.snippet("|a :x 1", 3..4, color: true)
+
+    assert_includes snippet, "\e[1;31m^^\e[0m"
+    assert_includes snippet, "\e[34m1 |\e[0m"
+  end
+
+  def test_events_by_line
+    input = "|a :x 1\n  |b Hello\n\n|c\n"
+    events = Udon.parse(input)
+    lines = Udon.events_by_line(events, input)
+    line_of = ->(i) { input.byteslice(0, events[i][:span][:start]).count("\n") + 1 }
+
+    assert_equal 5, lines.line_count
+    (1..lines.line_count).each do |n|
+      assert_equal (0...events.size).select { |i| line_of.(i) == n }, lines.on_line(n)
+    end
+    assert_empty lines.on_line(3)
+    assert_empty lines.on_line(0)
+    assert_empty lines.on_line(99)
+    assert_equal lines.on_line(1) + lines.on_line(2), lines.in_lines(1..2)
+    assert_equal lines.on_line(1), lines.in_lines(1...2)
+    assert_equal (0...events.size).sort_by { |i| [line_of.(i), i] }, lines.in_lines(1..)
+  end
+
+  def test_events_by_line_with_packed_spans
+    input = "|a\n  |b Hello\n"
+    packed = Udon.events_by_line(Udon.parse(input, spans: :line_col_packed), input)
+    hashes = Udon.events_by_line(Udon.parse(input), input)
+
+    assert_equal hashes.in_lines(1..), packed.in_lines(1..)
+  end
+end
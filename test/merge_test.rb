@@ -0,0 +1,76 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "udon"
+
+class MergeTest < Minitest::Test
+  BASE = <<~UDON
+    |config
+      |server[web] :host a :port 80
+        |route :path "/"
+      |server[db] :host b :port 5432
+      |cache :size 10
+  UDON
+
+  # Element names, attributes and children of a merged document.
+  def outline(udon)
+    shape = lambda do |node|
+      next node.content.strip if node.type == :text
+
+      [node.name, node.attributes, node.children.map(&shape)]
+    end
+    Udon.parse_document(udon).children.map(&shape)
+  end
+
+  def test_override_attributes_win_by_id
+    merged = Udon.merge(BASE, <<~UDON)
+      |config
+        |server[db] :port 5433 :replica true
+        |server[web] :tls
+    UDON
+
+    assert_equal [["config", {}, [
+      ["server", { "id" => "web", "host" => "a", "port" => 80, "tls" => true }, [["route", { "path" => "/" }, []]]],
+      ["server", { "id" => "db", "host" => "b", "port" => 5433, "replica" => true }, []],
+      ["cache", { "size" => 10 }, []]
+    ]]], outline(merged)
+  end
+
+  def test_ids_match_at_any_depth_and_unmatched_elements_are_appended
+    merged = Udon.merge(BASE, <<~UDON)
+      |server[web] :port 8080
+        |route :path "/health"
+      |server[queue] :host c
+      |cache :size 20
+    UDON
+    config, queue = outline(merged)
+
+    assert_equal ["server", { "id" => "queue", "host" => "c" }, []], queue
+    web, _db, cache = config[2]
+    assert_equal 8080, web[1]["port"]
+    assert_equal [{ "path" => "/" }, { "path" => "/health" }], web[2].map { |route| route[1] }
+    assert_equal ["cache", { "size" => 10 }, []], cache
+  end
+
+  def test_conflicting_types_replace_the_base_element
+    merged = Udon.merge(BASE, "|config\n  |client[web] :timeout 5\n")
+
+    assert_equal [["config", {}, [
+      ["client", { "id" => "web", "timeout" => 5 }, []],
+      ["server", { "id" => "db", "host" => "b", "port" => 5432 }, []],
+      ["cache", { "size" => 10 }, []]
+    ]]], outline(merged)
+  end
+
+  def test_by_names_the_id_attribute
+    merged = Udon.merge("|a :key x :n 1\n|a :key y :n 2\n", "|a :key y :n 3\n", by: "key")
+
+    assert_equal [["a", { "key" => "x", "n" => 1 }, []], ["a", { "key" => "y", "n" => 3 }, []]], outline(merged)
+  end
+
+  def test_errors
+    assert_raises(UdonNative::MergeError) { Udon.merge("|a[x]\n|b[x]\n", "|a[x] :n 1\n") }
+    assert_raises(UdonNative::MergeError) { Udon.merge("|a :x \"open\n", "|a\n") }
+    assert_raises(ArgumentError) { Udon.merge("|a\n", "|a\n", by: "") }
+  end
+end
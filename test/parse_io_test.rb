@@ -0,0 +1,215 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "pathname"
+require "stringio"
+require "tempfile"
+require "udon"
+require "zlib"
+
+class ParseIoTest < Minitest::Test
+  FIXTURES = File.join(__dir__, "fixtures", "encodings")
+  UTF8 = File.read(File.join(FIXTURES, "utf8.udon"), encoding: "UTF-8")
+
+  # Hands out at most +size+ bytes per read, so characters straddle chunks.
+  class TrickleIO
+    def initialize(bytes, size = 3)
+      @io = StringIO.new(bytes)
+      @size = size
+    end
+
+    def read(length)
+      @io.read([length, @size].min)
+    end
+  end
+
+  def fixture(name)
+    File.join(FIXTURES, "#{name}.udon")
+  end
+
+  def test_fixtures_parse_like_their_utf8_text
+    expected = Udon.parse(UTF8)
+
+    %w[utf8 utf16le_bom utf16le utf16be_bom utf16be utf32le_bom utf32be].each do |name|
+      assert_equal expected, Udon.parse_file(fixture(name)), name
+    end
+  end
+
+  def test_explicit_encoding
+    expected = Udon.parse(UTF8)
+
+    assert_equal expected, Udon.parse_file(fixture("utf16le"), encoding: "UTF-16LE")
+    assert_equal expected, Udon.parse_file(fixture("utf16be_bom"), encoding: Encoding::UTF_16BE)
+    assert_raises(ArgumentError) { Udon.parse_file(fixture("utf16le"), encoding: "Shift_JIS") }
+  end
+
+  def test_chunk_boundaries_inside_characters
+    %w[utf8 utf16le_bom utf16be utf32le_bom].each do |name|
+      bytes = File.binread(fixture(name))
+
+      assert_equal Udon.parse(UTF8), Udon.parse_io(TrickleIO.new(bytes)), name
+    end
+  end
+
+  def test_multibyte_fixture_at_every_chunk_size
+    bytes = File.binread(File.join(__dir__, "fixtures", "multibyte.udon"))
+    expected = Udon.parse(bytes.dup.force_encoding("UTF-8"))
+    utf16 = "\xFF\xFE".b + bytes.dup.force_encoding("UTF-8").encode("UTF-16LE").b
+
+    (1..8).each do |size|
+      assert_equal expected, Udon.parse_io(TrickleIO.new(bytes, size)), "UTF-8, chunk size #{size}"
+      assert_equal expected, Udon.parse_io(TrickleIO.new(utf16, size)), "UTF-16LE, chunk size #{size}"
+    end
+  end
+
+  def test_original_spans_index_the_file_bytes
+    %w[utf16le_bom utf16be utf32le_bom].each do |name|
+      bytes = File.binread(fixture(name))
+      encoding = name[0, 7].upcase.sub("UTF", "UTF-")
+      events = Udon.parse_file(fixture(name), spans: :original)
+
+      events.select { |e| e[:content] }.each do |event|
+        text = bytes.byteslice(event[:span][:start]...event[:span][:end]).force_encoding(encoding).encode("UTF-8")
+
+        assert_includes text, event[:content], "#{name} #{event[:type]}"
+      end
+    end
+  end
+
+  def test_accepted_sources
+    expected = Udon.parse(UTF8)
+    path = fixture("utf16le_bom")
+
+    assert_equal expected, Udon.parse_file(Pathname(path))
+    assert_equal expected, Udon.parse_io(StringIO.new(File.binread(path)))
+    Tempfile.create("udon") do |file|
+      file.binmode
+      file.write(File.binread(path))
+      file.rewind
+      assert_equal expected, Udon.parse_io(file)
+    end
+    assert_equal expected, Udon.parse(Struct.new(:to_str).new(UTF8))
+  end
+
+  def test_rejected_sources
+    error = assert_raises(TypeError) { Udon.parse_io(42) }
+    assert_equal "expected an IO-like object responding to #read, got Integer", error.message
+    error = assert_raises(TypeError) { Udon.parse_file(42) }
+    assert_equal "expected a path String or Pathname, got Integer", error.message
+    error = assert_raises(TypeError) { Udon.parse(42) }
+    assert_equal "expected a String or an object responding to #to_str, got Integer", error.message
+    assert_raises(Errno::ENOENT) { Udon.parse_file(fixture("missing")) }
+  end
+
+  def test_every_entry_point_coerces_its_document
+    document = Struct.new(:to_str).new("|a[x] b\n")
+    assert_equal UdonNative.pluck("|a[x] b\n", attribute: "x"), UdonNative.pluck(document, attribute: "x")
+    assert_equal UdonNative.count_elements("|a\n|b\n"), UdonNative.count_elements(Struct.new(:to_str).new("|a\n|b\n"))
+    message = "expected a String or an object responding to #to_str, got Integer"
+    assert_equal message, assert_raises(TypeError) { UdonNative.pluck(1, attribute: "x") }.message
+    assert_equal message, assert_raises(TypeError) { UdonNative.detect_indentation(1) }.message
+    assert_equal message, assert_raises(TypeError) { UdonNative.merge(1, "") }.message
+    assert_equal message, assert_raises(TypeError) { UdonNative::Parser.new.feed(1) }.message
+    error = assert_raises(TypeError) { UdonNative.parse_framed(1) {} }
+    assert_equal "expected an IO-like object responding to #read, got Integer", error.message
+  end
+
+  def test_nul_bytes_stop_reading
+    bytes = "|a\n|b x\0y\n".encode("UTF-16LE").b
+
+    assert_equal [{ type: :error, code: :nul_byte, span: { start: 7, end: 8 } }],
+                 Udon.parse_io(TrickleIO.new(bytes), encoding: "UTF-16LE")
+    assert_equal({ start: 14, end: 16 }, Udon.parse_io(StringIO.new(bytes), encoding: "UTF-16LE", spans: :original)[0][:span])
+    assert_equal "xy", Udon.parse_io(StringIO.new(bytes), encoding: "UTF-16LE", nul_bytes: :strip)
+                           .find { |e| e[:type] == :text }[:content]
+  end
+
+  def test_expect_encoding
+    assert_equal Udon.parse(UTF8), Udon.parse_file(fixture("utf16le"), expect_encoding: "UTF-16LE")
+    assert_equal Udon.parse(UTF8), Udon.parse_file(fixture("utf8"), expect_encoding: "UTF-8", mmap: true)
+
+    error = assert_raises(UdonNative::EncodingError) { Udon.parse_file(fixture("utf16le_bom"), expect_encoding: "UTF-8") }
+    assert_match(/\Ainvalid UTF-8 at byte 0: \[ff\] fe 7c 00/, error.message)
+    assert_raises(ArgumentError) { Udon.parse_io(StringIO.new(UTF8), expect_encoding: "UTF-8", invalid_utf8: :replace) }
+    assert_raises(ArgumentError) { Udon.parse_io(StringIO.new(UTF8), expect_encoding: "UTF-8", encoding: "UTF-16LE") }
+  end
+
+  def test_invalid_sequences
+    lone_surrogate = "|a ".encode("UTF-16LE").b + "\x00\xD8".b + "\n".encode("UTF-16LE").b
+
+    error = assert_raises(UdonNative::EncodingError) { Udon.parse_io(StringIO.new(lone_surrogate), encoding: "UTF-16LE") }
+    assert_match(/UTF-16LE at byte 6/, error.message)
+
+    events = Udon.parse_io(StringIO.new(lone_surrogate), encoding: "UTF-16LE", invalid_utf8: :replace)
+    assert_equal "�", events.find { |e| e[:type] == :text }[:content]
+
+    assert_raises(UdonNative::EncodingError) { Udon.parse_io(StringIO.new("|a \xFF\n".b)) }
+    assert_raises(ArgumentError) { Udon.parse_io(StringIO.new("|a\n"), invalid_utf8: :skip) }
+  end
+
+  def test_mmap
+    expected = Udon.parse(UTF8)
+
+    %w[utf8 utf16le_bom utf32be].each do |name|
+      assert_equal expected, Udon.parse_file(fixture(name), mmap: true), name
+    end
+    assert_equal Udon.parse_file(fixture("utf8"), stats: true),
+                 Udon.parse_file(fixture("utf8"), stats: true, mmap: true)
+    assert_equal Udon.parse_file(fixture("utf8"), spans: :line_col_packed),
+                 Udon.parse_file(fixture("utf8"), spans: :line_col_packed, mmap: true)
+    assert_raises(Errno::ENOENT) { Udon.parse_file(fixture("missing"), mmap: true) }
+  end
+
+  def test_mmap_invalid_and_empty_files
+    Tempfile.create("udon") do |file|
+      file.binmode
+      file.write("|a \xFF\n|b x\0\n")
+      file.flush
+
+      error = assert_raises(UdonNative::EncodingError) { Udon.parse_file(file.path, mmap: true) }
+      assert_equal "invalid UTF-8 at byte 3: [ff] 0a 7c 62 20 78 00 0a", error.message
+      assert_equal Udon.parse_file(file.path, invalid_utf8: :replace, nul_bytes: :allow),
+                   Udon.parse_file(file.path, invalid_utf8: :replace, nul_bytes: :allow, mmap: true)
+
+      file.truncate(0)
+      assert_equal [], Udon.parse_file(file.path, mmap: true)
+    end
+  end
+
+  def test_gzip_input
+    expected = Udon.parse(UTF8)
+    gzipped = Zlib.gzip(File.binread(fixture("utf16le_bom")))
+
+    Tempfile.create(["udon", ".udon.gz"]) do |file|
+      file.binmode
+      file.write(gzipped)
+      file.close
+      assert_equal expected, Udon.parse_file(file.path)
+      refute_equal expected, Udon.parse_file(file.path, compression: :none, invalid_utf8: :replace, nul_bytes: :strip)
+    end
+    assert_equal expected, Udon.parse_io(TrickleIO.new(gzipped), compression: :gzip)
+    assert_equal expected, Udon.parse_io(StringIO.new(gzipped), compression: :auto)
+    assert_equal expected, Udon.parse_io(StringIO.new(File.binread(fixture("utf8"))), compression: :auto)
+    assert_raises(ArgumentError) { Udon.parse_io(StringIO.new(gzipped), compression: :zstd) }
+  end
+
+  def test_gzip_stats
+    bytes = File.binread(fixture("utf8"))
+    gzipped = Zlib.gzip(bytes * 20)
+    result = Udon.parse_io(StringIO.new(gzipped), compression: :gzip, stats: true)
+
+    assert_equal bytes.bytesize * 20, result[:stats][:bytes]
+    assert_equal gzipped.bytesize, result[:stats][:compressed_bytes]
+    assert_equal Udon.parse(UTF8 * 20), result[:events]
+  end
+
+  def test_corrupt_gzip
+    gzipped = Zlib.gzip(File.binread(fixture("utf8")))
+
+    error = assert_raises(UdonNative::IOError) do
+      Udon.parse_io(StringIO.new(gzipped.byteslice(0, gzipped.bytesize - 12)), compression: :gzip)
+    end
+    assert_match(/corrupt gzip stream near compressed byte \d+/, error.message)
+    assert_raises(UdonNative::IOError) { Udon.parse_io(StringIO.new("\x1F\x8Bnot gzip".b), compression: :auto) }
+  end
+end
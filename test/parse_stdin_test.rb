@@ -0,0 +1,52 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "rbconfig"
+require "udon"
+
+class ParseStdinTest < Minitest::Test
+  # Prints the first name of each document yielded, one line each.
+  SCRIPT = <<~RUBY
+    $stdout.sync = true
+    count = Udon.parse_stdin(**eval(ARGV[0])) do |events, index|
+      name = events.find { |e| e[:type] == :name }
+      puts [index, name && name[:content]].inspect
+    end
+    puts count
+  RUBY
+
+  def child(options = "{}")
+    load_path = $LOAD_PATH.flat_map { |dir| ["-I", dir] }
+    IO.popen([RbConfig.ruby, *load_path, "-rudon", "-e", SCRIPT, options], "r+")
+  end
+
+  def test_yields_each_document_as_its_delimiter_arrives
+    io = child('{ delimiter: "---", with_index: true }')
+    io.write("---\n|a :x 1\n---\n")
+    io.flush
+
+    assert_equal '[0, "a"]', io.gets.chomp
+
+    io.write("|b\n  |c\n---\n")
+    io.close_write
+
+    assert_equal ['[1, "b"]', "2"], io.read.lines.map(&:chomp)
+  ensure
+    io&.close
+  end
+
+  def test_whole_input_is_one_document_without_delimiter
+    io = child
+    io.write("|a\n---\n|b\n")
+    io.close_write
+
+    assert_equal ['[nil, "a"]', "1"], io.read.lines.map(&:chomp)
+  ensure
+    io&.close
+  end
+
+  def test_requires_a_block
+    assert_raises(ArgumentError) { Udon.parse_stdin }
+    assert_raises(ArgumentError) { Udon.parse_stdin(delimiter: "") { |_| } }
+  end
+end
@@ -0,0 +1,109 @@
+# frozen_string_literal: true
+
+require "digest"
+require "minitest/autorun"
+require "udon"
+
+class ParserTest < Minitest::Test
+  FIXTURE = <<~UDON
+    ; Incremental fixture
+    |article[intro].featured :title "Héllo, wörld" :tags [a b c]
+      Some text with !{{name}} inside.
+      |section :count 42
+        |p Nested |{em inline} text
+    |footer :year 2024
+  UDON
+
+  def feed_in_chunks(input, size, **options)
+    parser = Udon::Parser.new(**options)
+    input.b.each_char.each_slice(size) { |chunk| parser.feed(chunk.join) }
+    parser.finish
+  end
+
+  def test_spans_match_single_feed_parse_with_7_byte_chunks
+    assert_equal Udon.parse(FIXTURE), feed_in_chunks(FIXTURE, 7)
+  end
+
+  def test_spans_are_absolute_for_every_chunk_size
+    expected = Udon.parse(FIXTURE)
+    [1, 2, 3, 13, 64].each do |size|
+      assert_equal expected, feed_in_chunks(FIXTURE, size), "chunk size #{size}"
+    end
+  end
+
+  def test_multibyte_characters_split_at_every_chunk_size
+    input = File.read(File.join(__dir__, "fixtures", "multibyte.udon"), encoding: "UTF-8")
+    expected = Udon.parse(input)
+
+    (1..8).each do |size|
+      assert_equal expected, feed_in_chunks(input, size), "chunk size #{size}"
+    end
+  end
+
+  def test_options_apply
+    assert_equal Udon.parse(FIXTURE, spans: :line_col_packed),
+                 feed_in_chunks(FIXTURE, 7, spans: :line_col_packed)
+  end
+
+  def test_bytes_fed_and_block_form
+    parser = Udon::Parser.new
+    parser << "|a :x 1\n" << "|b\n"
+    assert_equal 11, parser.bytes_fed
+
+    types = []
+    parser.finish { |event| types << event[:type] }
+    assert_equal 2, types.count(:element_start)
+  end
+
+  def test_finish_with_index
+    parser = Udon::Parser.new(with_index: true)
+    parser << FIXTURE
+    yielded = []
+    parser.finish { |event, index, total| yielded << [event, index, total] }
+
+    assert_equal (0...yielded.size).to_a, yielded.map { |_, index, _| index }
+    assert_equal [yielded.size], yielded.map(&:last).uniq
+    assert_equal Udon.parse(FIXTURE), yielded.map(&:first)
+  end
+
+  def test_feed_after_finish_raises
+    parser = Udon::Parser.new
+    parser.finish
+
+    assert_raises(UdonNative::Error) { parser.feed("|a\n") }
+    assert_raises(UdonNative::Error) { parser.finish }
+  end
+
+  def test_source_digest
+    parser = Udon::Parser.new
+    parser << FIXTURE.byteslice(0, 10) << FIXTURE.byteslice(10..)
+
+    assert_raises(UdonNative::Error) { parser.source_digest }
+    parser.finish
+    assert_equal Digest::SHA256.hexdigest(FIXTURE), parser.source_digest
+    assert_equal Digest::SHA512.hexdigest(FIXTURE), parser.source_digest(:sha512)
+    assert_match(/\A\h{16}\z/, parser.source_digest(:xxh3))
+
+    other = Udon::Parser.new << "|a\n"
+    other.finish
+    refute_equal parser.source_digest(:xxh3), other.source_digest(:xxh3)
+    assert_raises(ArgumentError) { parser.source_digest(:md5) }
+  end
+
+  def test_peak_buffered_events
+    parser = Udon::Parser.new
+    parser << FIXTURE.byteslice(0, 10) << FIXTURE.byteslice(10..)
+
+    assert_nil parser.peak_buffered_events
+    events = parser.finish
+    assert_equal events.size, parser.peak_buffered_events
+  end
+
+  def test_source_digest_covers_normalized_bytes
+    parser = Udon::Parser.new(strip_bom: true, normalize_newlines: true)
+    parser << "\uFEFF|a\r\n  |b\r\n"
+    parser.finish
+
+    assert_equal Digest::SHA256.hexdigest("|a\n  |b\n"), parser.source_digest
+  end
+end
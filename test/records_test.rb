@@ -0,0 +1,28 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "udon"
+
+class RecordsTest < Minitest::Test
+  def test_paths_types_and_values
+    source = "|config\n  |server :port 8080 :tags [a b] :tls\n  |server :port 8081\n  |db :url \"pg://x\"\n"
+    records = Udon.records(source)
+
+    assert_equal ["config/server[0]/@port", "config/server[0]/@tags[0]", "config/server[0]/@tags[1]",
+                  "config/server[0]/@tls", "config/server[1]/@port", "config/db/@url"],
+                 records.map { |r| r[:path] }
+    assert_equal [8080, "a", "b", true, 8081, "pg://x"], records.map { |r| r[:value] }
+    assert_equal %i[integer bare_value bare_value bool_true integer string_value], records.map { |r| r[:type] }
+    port = records.first
+    assert_equal "8080", source.byteslice(port[:span][:start]...port[:span][:end])
+    assert_equal records[1][:span], records[2][:span]
+  end
+
+  def test_repeated_keys_and_directives
+    records = Udon.records("|a :k 1 :k 2\n!if on\n  |b :k 3\n")
+
+    assert_equal ["a/@k[0]", "a/@k[1]", "b/@k"], records.map { |r| r[:path] }
+    assert_equal [1, 2, 3], records.map { |r| r[:value] }
+    assert_empty Udon.records("|p just text\n")
+  end
+end
@@ -0,0 +1,27 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "udon"
+
+class ReferencesTest < Minitest::Test
+  def test_reports_dangling_references_with_their_id_and_span
+    input = "|p See @[intro] and @[missing]\n|section[intro]\n  |p Back to @[top]\n"
+    dangling = Udon.check_references(input)
+
+    assert_equal %w[missing top], dangling.map { |ref| ref[:id] }
+    assert_equal ["@[missing]", "@[top]"], dangling.map { |ref| input.byteslice(ref[:span][:start]...ref[:span][:end]) }
+  end
+
+  def test_everything_resolves
+    assert_empty Udon.check_references("|a[x]\n|p @[x]\n")
+    assert_empty Udon.check_references("|p no references\n")
+  end
+
+  def test_by
+    input = "|a :key x\n|p @[x]\n"
+
+    assert_empty Udon.check_references(input, by: "key")
+    assert_equal ["x"], Udon.check_references(input).map { |ref| ref[:id] }
+    assert_raises(ArgumentError) { Udon.check_references(input, by: "") }
+  end
+end
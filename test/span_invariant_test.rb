@@ -0,0 +1,95 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "udon"
+
+# Every span must index the caller's input, whatever the options.
+class SpanInvariantTest < Minitest::Test
+  FIXTURES = Dir[File.join(__dir__, "fixtures", "*.udon")].sort.to_h do |path|
+    [File.basename(path), File.binread(path).force_encoding("UTF-8")]
+  end
+
+  FLAGS = %i[canonicalize unify_directives shape_hash downcase_names strip_bom
+             normalize_newlines header_spans sort_by_span].freeze
+
+  def option_combinations
+    (0...(1 << FLAGS.size)).map do |bits|
+      options = FLAGS.each_with_index.to_h { |flag, i| [flag, bits[i] == 1] }
+      options[:max_value_bytes] = 4 if bits.odd?
+      options
+    end
+  end
+
+  def assert_span_within(span, input, context)
+    assert_operator 0, :<=, span[:start], context
+    assert_operator span[:start], :<=, span[:end], context
+    assert_operator span[:end], :<=, input.bytesize, context
+  end
+
+  def test_spans_stay_within_input_for_every_option_combination
+    refute_empty FIXTURES
+    FIXTURES.each do |name, input|
+      option_combinations.each do |options|
+        Udon.parse(input, **options).each_with_index do |event, i|
+          context = "#{name} event #{i} (#{event[:type]}) with #{options}"
+          assert_span_within(event[:span], input, context)
+          assert_span_within(event[:header_span], input, context) if event[:header_span]
+          assert_span_within(event[:body_span], input, context) if event[:body_span]
+        end
+      end
+    end
+  end
+
+  def test_attribute_spans_are_exactly_the_key
+    FIXTURES.each do |name, input|
+      [{}, { normalize_newlines: true, strip_bom: true }, { canonicalize: true }].each do |options|
+        Udon.parse(input, **options).select { |e| e[:type] == :attr }.each do |attr|
+          span = attr[:span]
+          assert_equal attr[:content], input.byteslice(span[:start], span[:end] - span[:start]),
+                       "#{name} with #{options}"
+        end
+      end
+    end
+  end
+
+  def test_attribute_keys_after_multibyte_values
+    input = FIXTURES.fetch("attributes.udon")
+    keys = Udon.parse(input).select { |e| e[:type] == :attr }
+
+    assert_equal %w[name city zip note flag title count tag last alpha beta gamma],
+                 keys.map { |e| e[:content] }
+    keys.each do |key|
+      assert_equal ":#{key[:content]}", input.byteslice(key[:span][:start] - 1, key[:content].bytesize + 1)
+    end
+  end
+
+  def test_reconstruct_round_trips_well_formed_fixtures
+    FIXTURES.except("unterminated.udon").each do |name, input|
+      assert_equal input.b, Udon.reconstruct(input).b, name
+      assert_equal input.b, Udon.reconstruct(input, strip_bom: true, normalize_newlines: true).b, name
+    end
+  end
+
+  def test_reconstruct_simple_documents
+    assert_equal "|a :x 1\n", Udon.reconstruct("|a :x 1\n")
+    assert_equal "", Udon.reconstruct("")
+    assert_raises(UdonNative::Error) { Udon.reconstruct("|a x\0\n") }
+  end
+
+  def test_synthetic_events_are_zero_length
+    events = Udon.parse(FIXTURES.fetch("unterminated.udon"))
+
+    events.select { |e| e[:synthetic] }.each do |event|
+      assert_equal event[:span][:start], event[:span][:end]
+    end
+    refute events.any? { |e| e[:synthetic] && !%i[embedded_end array_end freeform_end].include?(e[:type]) }
+  end
+
+  def test_merged_directive_covers_its_sources
+    input = FIXTURES.fetch("directives.udon")
+    directive = Udon.parse(input, unify_directives: true).find { |e| e[:type] == :directive }
+    span = directive[:span]
+
+    assert input.byteslice(span[:start], span[:end] - span[:start]).start_with?("!app:include")
+  end
+end
@@ -0,0 +1,74 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "udon"
+
+class SpanTest < Minitest::Test
+  def test_object_spans_match_hash_spans
+    input = "|a :x 1\n  |b Hello\n"
+    objects = Udon.parse(input, spans: :object)
+
+    assert(objects.all? { |e| e[:span].is_a?(Udon::Span) })
+    assert_equal objects, Udon.parse(input)
+  end
+
+  def test_accessors_and_range
+    span = Udon::Span.new(2, 5)
+
+    assert_equal [2, 5, 3], [span.start, span.end, span.length]
+    assert_equal 2...5, span.to_range
+    assert_equal({ start: 2, end: 5 }, span.to_h)
+    assert_equal "#<UdonNative::Span 2...5>", span.inspect
+    assert_raises(ArgumentError) { Udon::Span.new(5, 2) }
+  end
+
+  def test_cover
+    span = Udon::Span.new(2, 5)
+
+    assert span.cover?(2)
+    assert span.cover?(4)
+    refute span.cover?(5)
+    assert span.cover?(Udon::Span.new(3, 5))
+    refute span.cover?({ start: 1, end: 3 })
+    refute Udon::Span.new(3, 3).cover?(3)
+  end
+
+  def test_union_and_intersect
+    a = Udon::Span.new(2, 6)
+    b = Udon::Span.new(4, 9)
+
+    assert_equal Udon::Span.new(2, 9), a.union(b)
+    assert_equal Udon::Span.new(4, 6), a.intersect(b)
+    assert_equal Udon::Span.new(6, 6), a.intersect({ start: 6, end: 8 })
+    assert_nil a.intersect(Udon::Span.new(7, 8))
+  end
+
+  def test_slice
+    input = "|café :x 1\n"
+    name = Udon.parse(input, spans: :object).find { |e| e[:type] == :name }
+
+    assert_equal "café", name[:span].slice(input)
+    assert_equal Encoding::UTF_8, name[:span].slice(input).encoding
+  end
+
+  def test_equality_with_hashes
+    span = Udon::Span.new(1, 3)
+
+    assert_equal span, { start: 1, end: 3 }
+    refute_equal span, { start: 1, end: 4 }
+    refute_equal span, { start: 1, end: 3, line: 0 }
+    assert span.eql?(Udon::Span.new(1, 3))
+    refute span.eql?({ start: 1, end: 3 })
+    assert_equal 1, [span, Udon::Span.new(1, 3)].uniq.size
+  end
+
+  def test_helpers_accept_object_spans
+    input = "|a\n  |b Hello\n"
+    events = Udon.parse(input, spans: :object)
+    text = events.find { |e| e[:type] == :text }
+
+    assert_equal text, Udon.event_at(events, text[:span].start)[:event]
+    assert_includes Udon.snippet(input, text[:span]), "^"
+    assert_raises(ArgumentError) { Udon.parse(input, spans: :object, format: :columnar) }
+  end
+end
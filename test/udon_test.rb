@@ -1,6 +1,7 @@
 # frozen_string_literal: true
 
 require "minitest/autorun"
+require "stringio"
 require "udon"
 
 class UdonTest < Minitest::Test
@@ -216,6 +217,208 @@ class UdonTest < Minitest::Test
     assert_equal "name", interp[:content]
   end
 
+  def test_interpolation_resolver
+    input = "|p Hello !{{name}}, !{{count}} !{{ratio}} !{{on}} !{{none}} !{{missing}}\n"
+    values = { "name" => "Ada", "count" => 3, "ratio" => 0.5, "on" => true, "none" => nil }
+    plain = Udon.parse(input).select { |e| e[:type] == :interpolation }
+    resolved = Udon.parse(input, interpolation_resolver: values.method(:fetch))
+                   .select { |e| e[:interpolated] || e[:type] == :error }
+
+    assert_equal %i[string_value integer float bool_true nil error], resolved.map { |e| e[:type] }
+    assert_equal ["Ada", "3", "0.5", "true", "null"], resolved.first(5).map { |e| e[:content] }
+    assert_equal plain.map { |e| e[:span] }, resolved.map { |e| e[:span] }
+    assert_equal plain.map { |e| e[:content] }, resolved.map { |e| e[:expression] }
+    assert_equal :interpolation_failed, resolved.last[:code]
+    assert_match(/missing/, resolved.last[:message])
+  end
+
+  def test_interpolation_resolver_handlers_and_strict_mode
+    input = "|p !{{a}} !{{b}}\n"
+    integers = []
+    Udon.parse(input, interpolation_resolver: ->(expr) { expr.ord }, on_integer: ->(e) { integers << e[:content] })
+
+    assert_equal %w[97 98], integers
+    error = assert_raises(UdonNative::InterpolationError) do
+      Udon.parse(input, interpolation_resolver: ->(expr) { raise "no #{expr}" }, strict_interpolation: true)
+    end
+    assert_match(/"a" at \d+\.\.\.\d+: no a/, error.message)
+    assert_raises(ArgumentError) { Udon.parse(input, interpolation_resolver: 42) }
+  end
+
+  def test_interpolation_delimiters
+    input = "|p Hello !{{name}}\n"
+
+    assert_equal Udon.parse(input), Udon.parse(input, interpolation_delimiters: ["!{{", "}}"])
+    assert_equal Udon.parse(input), Udon.parse(input, interpolation_delimiters: nil)
+    error = assert_raises(NotImplementedError) { Udon.parse(input, interpolation_delimiters: ["${", "}"]) }
+    assert_match(/"\$\{", "\}"/, error.message)
+    assert_raises(ArgumentError) { Udon.parse(input, interpolation_delimiters: ["${"]) }
+    assert_raises(ArgumentError) { Udon.parse(input, interpolation_delimiters: ["", "}"]) }
+    assert_raises(ArgumentError) { Udon.parse(input, interpolation_delimiters: "${}") }
+  end
+
+  def test_env_interpolations
+    ENV["UDON_TEST_HOST"] = "db.internal"
+    ENV["UDON_TEST_SECRET"] = "hunter2"
+    input = "|db !{{env.UDON_TEST_HOST}} !{{env.UDON_TEST_SECRET}} !{{env.UDON_TEST_UNSET}} !{{other}}\n"
+    events = Udon.parse(input, interpolations: :env, env_allowlist: %w[UDON_TEST_HOST UDON_TEST_UNSET])
+    host = events.find { |e| e[:expression] == "env.UDON_TEST_HOST" }
+
+    assert_equal :string_value, host[:type]
+    assert_equal "db.internal", host[:content]
+    assert host[:interpolated]
+    assert_equal input.index("!{{env.UDON_TEST_HOST"), host[:span][:start]
+    errors = events.select { |e| e[:type] == :error }
+    assert_equal %w[UDON_TEST_SECRET UDON_TEST_UNSET], errors.map { |e| e[:name] }
+    assert_equal [:env_missing] * 2, errors.map { |e| e[:code] }
+    refute_includes events.inspect, "hunter2"
+    assert_equal ["other"], events.select { |e| e[:type] == :interpolation }.map { |e| e[:content] }
+
+    empty = Udon.parse(input, interpolations: :env, missing_env: :empty)
+    assert_equal ["db.internal", "hunter2", ""], empty.select { |e| e[:interpolated] }.map { |e| e[:content] }
+    kept = Udon.parse(input, interpolations: :env, missing_env: :keep)
+    assert_equal %w[env.UDON_TEST_UNSET other], kept.select { |e| e[:type] == :interpolation }.map { |e| e[:content] }
+    prefixed = Udon.parse("|p !{{ENV:UDON_TEST_HOST}}\n", interpolations: :env, env_prefix: "ENV:")
+    assert_equal ["db.internal"], prefixed.select { |e| e[:interpolated] }.map { |e| e[:content] }
+    assert_raises(ArgumentError) { Udon.parse(input, interpolations: :env, missing_env: :ignore) }
+    assert_raises(ArgumentError) { Udon.parse(input, interpolations: :env, interpolation_resolver: ->(x) { x }) }
+  ensure
+    ENV.delete("UDON_TEST_HOST")
+    ENV.delete("UDON_TEST_SECRET")
+  end
+
+  def test_parse_times
+    input = %(|build :at 2024-01-02T03:04:05Z :on 2024-02-29 :local "2024-01-02 03:04" ) +
+            %(:offset 2024-01-02T03:04:05.25+05:30 :bad 2023-02-29 :name v2024\n)
+    values = Udon.parse(input, parse_times: true).select { |e| %i[bare_value string_value].include?(e[:type]) }
+    at, on, local, offset, bad, name = values
+
+    assert_equal Time.utc(2024, 1, 2, 3, 4, 5), at[:value]
+    assert_equal :time, at[:coerced]
+    assert_equal "2024-01-02T03:04:05Z", at[:content]
+    assert_equal Date.new(2024, 2, 29), on[:value]
+    assert_equal :date, on[:coerced]
+    assert_equal Time.new(2024, 1, 2, 3, 4), local[:value]
+    assert_equal :string_value, local[:type]
+    assert_equal Time.new(2024, 1, 2, 3, 4, Rational(21, 4), "+05:30"), offset[:value]
+    assert_equal 19_800, offset[:value].utc_offset
+    [bad, name].each do |event|
+      refute event.key?(:value)
+      refute event.key?(:coerced)
+    end
+    refute Udon.parse(input).any? { |e| e.key?(:coerced) }
+  end
+
+  def test_emit_document_bounds
+    input = "|a :x 1\n  |b\n"
+    plain = Udon.parse(input)
+    events = Udon.parse(input, emit_document_bounds: true)
+
+    assert_equal({ type: :document_start, span: { start: 0, end: 0 } }, events.first)
+    assert_equal({ type: :document_end, span: { start: input.bytesize, end: input.bytesize } }, events.last)
+    assert_equal plain, events[1...-1]
+    assert_equal Udon.emit(plain), Udon.emit(events)
+
+    bom = Udon.parse("\xEF\xBB\xBF|a\n".b.force_encoding("UTF-8"), emit_document_bounds: true)
+    assert_equal [0, 7], [bom.first[:span][:start], bom.last[:span][:end]]
+    assert_equal [0, 0, 2, 0], Udon.parse(input, emit_document_bounds: true, spans: :line_col_packed)
+                                    .values_at(0, -1).flat_map { |e| e[:span][0, 2] }
+    assert_raises(ArgumentError) { Udon.parse(input, emit_document_bounds: true, format: :columnar) }
+    assert_raises(ArgumentError) { Udon.parse(input, emit_document_bounds: true, on_text: ->(_) {}) }
+  end
+
+  def test_arena_chunk_size
+    input = "|p :big \"#{"x" * 1000}\"\n"
+
+    assert_equal Udon.parse(input), Udon.parse(input, arena_chunk_size: nil)
+    assert_raises(NotImplementedError) { Udon.parse(input, arena_chunk_size: 64 * 1024) }
+    assert_raises(ArgumentError) { Udon.parse(input, arena_chunk_size: 0) }
+    assert_raises(TypeError) { Udon.parse(input, arena_chunk_size: "big") }
+  end
+
+  def test_structured_interpolation_parts
+    input = "|p Hi !{{ user.address.city }}\n|q :x !{{名前.ß}}\n"
+    parts = Udon.parse(input, interpolations: :structured).select { |e| e[:type] == :interpolation }.map { |e| e[:parts] }
+
+    assert_equal [%w[user address city], %w[名前 ß]], parts.map { |p| p.map { |part| part[:text] } }
+    parts.flatten.each do |part|
+      assert_equal part[:text], input.byteslice(part[:span][:start]...part[:span][:end])
+    end
+    assert_equal [{ start: 6, end: 8 }], Udon.parse("|p !{{名前}}\n", interpolations: :structured, spans: :utf16)
+                                              .find { |e| e[:type] == :interpolation }[:parts].map { |p| p[:span] }
+    refute Udon.parse(input).any? { |e| e.key?(:parts) }
+    assert_raises(ArgumentError) { Udon.parse(input, interpolations: :structured, interpolation_resolver: ->(_) { 1 }) }
+    assert_raises(ArgumentError) { Udon.parse(input, interpolations: :structured, format: :columnar) }
+  end
+
+  def test_interpolation_false_keeps_literal_text
+    input = "|p Run !{{cmd}} now !{{again}}\n|q :x !{{v}} :list [a !{{b}}]\n"
+    events = Udon.parse(input, interpolation: false)
+
+    refute events.any? { |e| e[:type] == :interpolation }
+    text = events.find { |e| e[:type] == :text }
+    assert_equal "Run !{{cmd}} now !{{again}}", text[:content].chomp
+    assert_equal input.byteslice(text[:span][:start]...text[:span][:end]), text[:content]
+    assert_equal %w[!{{v}} !{{b}}], events.select { |e| e[:type] == :bare_value && e[:content].start_with?("!") }
+                                          .map { |e| e[:content] }
+    assert_equal input, Udon.emit(events)
+    assert_equal Udon.parse("|p plain\n"), Udon.parse("|p plain\n", interpolation: false)
+    assert_raises(ArgumentError) { Udon.parse(input, interpolation: false, interpolation_resolver: ->(_) { 1 }) }
+    assert_raises(ArgumentError) { Udon.parse(input, interpolation: false, interpolations: :env) }
+  end
+
+  def test_conditions
+    input = <<~UDON
+      |server
+        !if env == production and not region == "us"
+          |cache :size 512
+          !unless debug
+            |log :level warn
+        !if :env staging
+          |debug-bar
+          !if undefined
+            |never
+    UDON
+    names = lambda do |events|
+      events.select { |e| e[:type] == :name }.map { |e| e[:content] }
+    end
+
+    context = { "env" => "production", "region" => "eu", "debug" => false }
+    events = Udon.parse(input, conditions: context)
+    assert_equal %w[server cache log], names.(events)
+    refute events.any? { |e| %i[directive_start directive_end].include?(e[:type]) }
+    assert_equal %w[server cache], names.(Udon.parse(input, conditions: context.merge("debug" => "yes")))
+    assert_equal %w[server debug-bar], names.(Udon.parse(input, conditions: { "env" => "staging", "region" => "us" },
+                                                                unknown_conditions: :false))
+    assert_equal names.(events), names.(Udon.parse_with_stats(input, conditions: context)[:events])
+    operators = Udon.parse("!if (a || b) && !c\n  |keep\n!other\n", conditions: { a: 1, b: nil, c: nil })
+    assert_equal %w[keep other], names.(operators)
+
+    error = assert_raises(UdonNative::ConditionError) do
+      Udon.parse(input, conditions: { "env" => "staging", "region" => "us" })
+    end
+    assert_match(/unknown variable "undefined" in `!if undefined` at byte \d+/, error.message)
+    assert_raises(UdonNative::ConditionError) { Udon.parse("!if env ==\n", conditions: { "env" => "x" }) }
+    assert_raises(ArgumentError) { Udon.parse(input, conditions: context, format: :columnar) }
+    assert_raises(ArgumentError) { Udon.parse(input, conditions: context, unknown_conditions: :maybe) }
+  end
+
+  def test_immutable
+    input = "|server[web] :ports [80 443]\n  Hello\n"
+    events = Udon.parse(input, immutable: true)
+
+    assert events.frozen?
+    assert events.all? { |e| e.frozen? && e[:span].frozen? }
+    assert events.select { |e| e[:content] }.all? { |e| e[:content].frozen? }
+    assert_raises(FrozenError) { events << {} }
+    assert_raises(FrozenError) { events.first[:type] = :text }
+    assert_raises(FrozenError) { events.find { |e| e[:type] == :text }[:content] << "!" }
+    assert Udon.parse(input, immutable: true, spans: :object).all? { |e| e[:span].frozen? }
+    assert Udon.parse(input, immutable: true, format: :columnar).values.all?(&:frozen?)
+    assert Udon.parse_with_stats(input, immutable: true)[:stats].frozen?
+    refute Udon.parse(input).first.frozen?
+  end
+
   def test_parse_nested_elements
     events = Udon.parse("|parent\n  |child\n")
 
@@ -232,4 +435,942 @@ class UdonTest < Minitest::Test
     assert_includes names, "parent"
     assert_includes names, "child"
   end
+
+  def source_of(input, event)
+    input.byteslice(event[:span][:start], event[:span][:end] - event[:span][:start])
+  end
+
+  def test_spans_index_original_input_after_stripping_bom
+    input = "\uFEFF|a :x 1\n|b :y 2\n"
+    events = Udon.parse(input, strip_bom: true)
+    names = events.select { |e| e[:type] == :name }
+
+    assert_equal %w[a b], names.map { |e| e[:content] }
+    assert_equal %w[a b], names.map { |e| source_of(input, e) }
+    assert_equal "x", source_of(input, events.find { |e| e[:type] == :attr })
+    assert_equal "|a", source_of(input, events.find { |e| e[:type] == :element_start })[0, 2]
+  end
+
+  def test_bom_is_skipped_by_default
+    input = "\uFEFF|a :x 1\n"
+    events = Udon.parse(input)
+
+    assert_equal Udon.parse(input, strip_bom: true), events
+    refute events.any? { |e| e[:type] == :error }
+    assert_equal "a", source_of(input, events.find { |e| e[:type] == :name })
+    refute_equal events, Udon.parse(input, strip_bom: false)
+  end
+
+  def test_utf16_input_raises_encoding_error
+    ["\xFF\xFE|\x00a\x00", "\xFE\xFF\x00|\x00a"].each do |bytes|
+      input = bytes.b.force_encoding(Encoding::UTF_8)
+      error = assert_raises(UdonNative::EncodingError) { Udon.parse(input) }
+      assert_match(/UTF-16[LB]E byte order mark.*transcode/, error.message)
+      assert_raises(UdonNative::EncodingError) { Udon.parse_document(input) }
+      assert_raises(UdonNative::EncodingError) { Udon.parse(input, on_name: ->(e) {}) }
+    end
+    assert_kind_of UdonNative::Error, UdonNative::EncodingError.new
+  end
+
+  def test_utf16_tagged_strings_are_transcoded
+    input = "\uFEFF|a\n".encode(Encoding::UTF_16LE)
+    assert_equal "a", Udon.parse(input).find { |e| e[:type] == :name }[:content]
+  end
+
+  def test_spans_index_original_input_after_normalizing_crlf
+    input = "|a :x 1\r\n|b :y 2\r\n|c :z 3\r\n"
+    events = Udon.parse(input, normalize_newlines: true)
+
+    %i[name attr integer].each do |type|
+      events.select { |e| e[:type] == type }.each do |event|
+        assert_equal event[:content], source_of(input, event)
+      end
+    end
+    starts = events.select { |e| e[:type] == :element_start }
+    assert_equal %w[|b |c], starts.drop(1).map { |e| source_of(input, e)[0, 2] }
+    refute events.any? { |e| e[:content].to_s.include?("\r") }
+  end
+
+  def test_line_endings_normalize_keeps_raw_content
+    input = File.binread(File.join(__dir__, "fixtures", "mixed_line_endings.udon")).force_encoding("UTF-8")
+    events = Udon.parse(input, line_endings: :normalize)
+    raw, cooked = events.select { |e| e[:content] }.partition { |e| e[:type] == :raw_content }
+
+    refute cooked.any? { |e| e[:content].include?("\r") }
+    assert raw.any? { |e| e[:content].include?("\r\n") }
+    assert_equal %w[config path note after code end], events.select { |e| e[:type] == :name }.map { |e| source_of(input, e) }
+    refute Udon.parse(input, line_endings: :normalize, normalize_raw: true).any? { |e| e[:content].to_s.include?("\r") }
+    assert_equal Udon.parse(input), Udon.parse(input, line_endings: :preserve)
+    assert_equal events, Udon.parse(input, normalize_newlines: true)
+    assert_raises(ArgumentError) { Udon.parse(input, line_endings: :crlf) }
+  end
+
+  def test_spans_index_original_input_with_bom_and_crlf
+    input = "\uFEFF|a :x 1\r\n|b :y \"two\"\r\n"
+    events = Udon.parse(input, strip_bom: true, normalize_newlines: true)
+    value = events.find { |e| e[:type] == :string_value }
+
+    assert_includes source_of(input, value), "two"
+    assert_equal "b", source_of(input, events.select { |e| e[:type] == :name }.last)
+    packed = Udon.parse(input, strip_bom: true, normalize_newlines: true, spans: :line_col_packed)
+    assert_equal [1, 1], packed.select { |e| e[:type] == :name }.last[:span].first(2)
+  end
+
+  def test_line_col_packed_spans_with_tab_width
+    input = "|p é\tc |{em x}\n"
+    start = Udon.parse(input).find { |e| e[:type] == :embedded_start }[:span][:start]
+    embedded = lambda do |**options|
+      Udon.parse(input, spans: :line_col_packed, **options).find { |e| e[:type] == :embedded_start }[:span]
+    end
+
+    assert_equal Udon::LineIndex.new(input).locate(start)[:column] - 1, embedded.call[1]
+    assert_equal Udon::LineIndex.new(input, tab_width: 4).locate(start)[:column] - 1,
+                 embedded.call(tab_width: 4)[1]
+    assert_equal embedded.call[1] + 3, embedded.call(tab_width: 4)[1]
+  end
+
+  def test_line_col_packed_spans
+    input = "|parent\n  |child caf\u00e9 text\n"
+    byte_events = Udon.parse(input)
+    packed_events = Udon.parse(input, spans: :line_col_packed)
+
+    assert_equal byte_events.size, packed_events.size
+
+    line_col = lambda do |offset|
+      before = input.byteslice(0, offset)
+      line = before.count("\n")
+      [line, before.split("\n", -1).last.to_s.length]
+    end
+
+    byte_events.zip(packed_events).each do |bytes, packed|
+      assert_equal bytes[:type], packed[:type]
+      span = bytes[:span]
+      assert_equal line_col.(span[:start]) + line_col.(span[:end]), packed[:span]
+    end
+
+    child = packed_events.find { |e| e[:type] == :name && e[:content] == "child" }
+    assert_equal 1, child[:span][0]
+  end
+
+  def test_unknown_option_raises
+    assert_raises(ArgumentError) { Udon.parse("|div\n", bogus: true) }
+  end
+
+  def frame(doc)
+    [doc.bytesize].pack("N") + doc
+  end
+
+  def test_parse_framed_yields_each_document
+    io = StringIO.new(frame("|a\n") + frame("|b :x 1\n") + frame(""))
+    documents = []
+
+    count = Udon.parse_framed(io) { |events| documents << events }
+
+    assert_equal 3, count
+    assert_equal "a", documents[0].find { |e| e[:type] == :name }[:content]
+    assert_equal "b", documents[1].find { |e| e[:type] == :name }[:content]
+    assert_equal [], documents[2]
+  end
+
+  def test_parse_framed_with_index
+    io = StringIO.new(frame("|a\n") + frame("|b\n"))
+    yielded = []
+
+    Udon.parse_framed(io, with_index: true) { |events, index, total| yielded << [events.size, index, total] }
+
+    assert_equal [0, 1], yielded.map { |_, index, _| index }
+    assert(yielded.all? { |_, _, total| total.nil? })
+  end
+
+  def test_parse_framed_raises_on_partial_frame
+    truncated = StringIO.new(frame("|a\n") + frame("|b\n")[0, 5])
+    error = assert_raises(UdonNative::FrameError) { Udon.parse_framed(truncated) { |_| } }
+    assert_match(/frame 1: incomplete body/, error.message)
+
+    short_prefix = StringIO.new(frame("|a\n") + "\x00\x00")
+    assert_raises(UdonNative::FrameError) { Udon.parse_framed(short_prefix) { |_| } }
+
+    hostile = StringIO.new("\xFF\xFF\xFF\xFF|a\n".b)
+    error = assert_raises(UdonNative::FrameError) { Udon.parse_framed(hostile) { |_| } }
+    assert_match(/frame 0: incomplete body \(3 of 4294967295 bytes\)/, error.message)
+  end
+
+  def test_parse_framed_max_frame_bytes
+    io = StringIO.new(frame("|a\n") + frame("|b :x 1\n"))
+    documents = []
+    error = assert_raises(UdonNative::FrameError) do
+      Udon.parse_framed(io, max_frame_bytes: 4) { |events| documents << events }
+    end
+
+    assert_match(/frame 1: length 8 exceeds max_frame_bytes \(4\)/, error.message)
+    assert_equal 1, documents.size
+  end
+
+  def test_parse_each_element_yields_each_top_level_element
+    input = "; users\n|user :id 1\n  |email a@b.c\n|user :id !{{env.UDON_TEST_UNSET}}\n; end\n"
+    batches = []
+
+    count = Udon.parse_each_element(input, interpolations: :env, env_allowlist: %w[UDON_TEST_UNSET]) { |events| batches << events }
+
+    assert_equal 3, count
+    assert_equal :comment_start, batches[0].first[:type]
+    assert_equal 1, batches[0].count { |e| e[:type] == :element_start && e[:span][:start] == input.index("|user") }
+    assert_equal %w[user email], batches[0].select { |e| e[:type] == :name }.map { |e| e[:content] }
+    assert_equal :element_end, batches[1].last[:type]
+    assert_equal [:env_missing], batches[1].select { |e| e[:type] == :error }.map { |e| e[:code] }
+    refute batches[0].any? { |e| e[:type] == :error }
+    assert_equal :comment_start, batches[2].first[:type]
+    refute batches[2].any? { |e| e[:type] == :element_start }
+    assert_equal Udon.parse(input, interpolations: :env, env_allowlist: %w[UDON_TEST_UNSET]), batches.flatten(1)
+
+    indices = []
+    Udon.parse_each_element("|a\n|b\n", with_index: true) { |_, index, total| indices << [index, total] }
+    assert_equal [[0, nil], [1, nil]], indices
+    assert_raises(ArgumentError) { Udon.parse_each_element("|a\n") }
+  end
+
+  def test_parse_each_element_block_may_grow_the_input
+    source = +"|a :x 1\n|b :x 2\n"
+    expected = Udon.parse(source.dup)
+    batches = []
+    Udon.parse_each_element(source) do |events|
+      batches << events
+      source << ("|pad" * 4096) << "\n"
+    end
+
+    assert_equal expected, batches.flatten(1)
+  end
+
+  def test_canonicalize_trims_and_maps_boolean_tokens
+    events = Udon.parse("|config :debug yes :cache \"  off \" :name \"  app  \" :count 3\n",
+                        canonicalize: true)
+
+    value_of = lambda do |key|
+      idx = events.index { |e| e[:type] == :attr && e[:content] == key }
+      events[idx + 1]
+    end
+
+    assert_equal :bool_true, value_of.("debug")[:type]
+    assert_equal :bool_false, value_of.("cache")[:type]
+    assert_equal [:string_value, "app"], value_of.("name").values_at(:type, :content)
+    assert_equal :integer, value_of.("count")[:type]
+  end
+
+  def test_canonicalize_custom_tokens
+    input = "|config :debug enabled :verbose yes\n"
+    events = Udon.parse(input, canonicalize: true, boolean_tokens: { true: ["enabled"] })
+
+    types = events.each_cons(2).select { |a, _| a[:type] == :attr }.map { |_, v| v[:type] }
+    assert_equal [:bool_true, :bare_value], types
+  end
+
+  def test_canonicalize_is_off_by_default
+    events = Udon.parse("|config :debug yes\n")
+    assert events.any? { |e| e[:type] == :bare_value && e[:content] == "yes" }
+  end
+
+  def test_unify_directives
+    events = Udon.parse("!app:include \"header.udon\"\n!raw\n", unify_directives: true)
+    directives = events.select { |e| e[:type] == :directive }
+
+    assert_equal [["include", "app", false], ["raw", nil, false]],
+                 directives.map { |d| d.values_at(:name, :namespace, :inline) }
+    refute events.any? { |e| %i[directive_start name].include?(e[:type]) }
+    assert_equal 2, events.count { |e| e[:type] == :directive_end }
+  end
+
+  def test_directives_keep_distinct_events_by_default
+    events = Udon.parse("!include x\n")
+
+    assert_equal %i[directive_start name], events.first(2).map { |e| e[:type] }
+  end
+
+  def test_shape_hash_groups_elements_by_structure
+    events = Udon.parse(<<~UDON, shape_hash: true)
+      |item :id 1 :name a
+        |price 10
+      |item :name b :id 2
+        |price 20
+      |item :id 3
+        |price 30
+    UDON
+    shapes = events.select { |e| e[:type] == :element_start }.map { |e| e[:shape] }
+    item_shapes = shapes.values_at(0, 2, 4)
+
+    assert_equal item_shapes[0], item_shapes[1]
+    refute_equal item_shapes[0], item_shapes[2]
+    assert_equal 1, shapes.values_at(1, 3, 5).uniq.size
+    assert(shapes.all? { |s| s.match?(/\A\h{64}\z/) })
+  end
+
+  def test_shape_hash_is_off_by_default
+    refute Udon.parse("|a\n").first.key?(:shape)
+  end
+
+  def test_downcase_names
+    events = Udon.parse("|DIV :Class x\n  Hi |{Em Straße}\n  |span\n!Include y\n", downcase_names: true)
+    names = events.select { |e| e[:type] == :name }
+
+    assert_equal %w[div em span Include], names.map { |e| e[:content] }
+    assert_equal ["DIV", "Em", nil, nil], names.map { |e| e[:original_name] }
+    assert events.any? { |e| e[:type] == :attr && e[:content] == "Class" }
+  end
+
+  def test_parse_with_stats
+    input = "|a\n  |b\n    |c :x 1\n  |d\n|e\n"
+    result = Udon.parse_with_stats(input)
+
+    assert_equal Udon.parse(input), result[:events]
+    assert_equal 3, result[:stats][:max_depth_reached]
+    assert_equal 5, result[:stats][:elements]
+    assert_equal 0, result[:stats][:errors]
+    assert_equal input.bytesize, result[:stats][:bytes]
+    assert_equal input.bytesize, result[:stats][:input_bytes]
+    assert_equal result[:events].size, result[:stats][:events]
+  end
+
+  def test_capture_indent
+    input = "|a\n  |b |c\n|d\n    |e :x |f\n"
+    starts = Udon.parse(input, capture_indent: true).select { |e| e[:type] == :element_start }
+
+    assert_equal [0, 2, nil, 0, 4], starts.first(5).map { |e| e[:indent] }
+    assert starts.all? { |e| e.key?(:indent) }
+    assert_equal [0, 2], Udon.parse("\uFEFF|a\r\n  |b\r\n", capture_indent: true)
+                             .select { |e| e[:type] == :element_start }.map { |e| e[:indent] }
+    refute Udon.parse(input).any? { |e| e.key?(:indent) }
+  end
+
+  def test_capture_column
+    input = "|table\n  |row :a 1   :b 22\n  |row :a 333 :b 4\n"
+    events = Udon.parse(input, capture_column: true)
+
+    assert events.all? { |e| e.key?(:col) }
+    values = events.select { |e| e[:type] == :integer }
+    assert_equal [[10, 17], [10, 17]], values.each_slice(2).map { |pair| pair.map { |e| e[:col] } }
+    assert_equal [0, 2], events.select { |e| e[:type] == :element_start }.first(2).map { |e| e[:col] }
+    tabbed = "|a\t:x 1\n"
+    assert_equal 11, Udon.parse(tabbed, capture_column: true).find { |e| e[:type] == :integer }[:col]
+    assert_equal 7, Udon.parse(tabbed, capture_column: true, tab_width: 4).find { |e| e[:type] == :integer }[:col]
+    assert_equal 0, Udon.parse("\uFEFF|a\n", capture_column: true).first[:col]
+    refute Udon.parse(input).any? { |e| e.key?(:col) }
+  end
+
+  def test_parent_name
+    input = "|doc\n  |description Some |{em text}\n    |p more\n|top\n"
+    events = Udon.parse(input, parent_name: true)
+    parent_of = ->(type, content) { events.find { |e| e[:type] == type && e[:content] == content }[:parent_name] }
+
+    assert events.all? { |e| e.key?(:parent_name) }
+    assert_nil events.first[:parent_name]
+    assert_equal "doc", parent_of.(:name, "description")
+    assert_equal "description", parent_of.(:name, "em")
+    assert_equal "description", parent_of.(:name, "p")
+    assert_equal "p", parent_of.(:text, "more")
+    assert_nil parent_of.(:name, "top")
+    element_starts = events.select { |e| e[:type] == :element_start }
+    assert_equal [nil, "doc", "description", nil], element_starts.map { |e| e[:parent_name] }
+    assert events.select { |e| e[:parent_name] }.all? { |e| e[:parent_name].frozen? }
+    assert_same parent_of.(:name, "em"), parent_of.(:name, "p")
+    pooled = Udon.interned_key_count
+    Udon.parse("|parent-name-unpooled\n  |b\n", parent_name: true)
+    assert_equal pooled, Udon.interned_key_count
+    refute Udon.parse(input).any? { |e| e.key?(:parent_name) }
+    assert_raises(ArgumentError) { Udon.parse(input, parent_name: true, format: :columnar) }
+  end
+
+  def test_content_lengths
+    input = "|doc :title \"caf\u00e9 \\\"x\\\"\" :mode verbose :n 12\n  ; note\n  some text here\n"
+    events = Udon.parse(input, content_lengths: true)
+    sized = events.select { |e| e.key?(:content_bytes) }
+
+    assert_equal %i[string_value bare_value text text], sized.map { |e| e[:type] }
+    sized.each { |e| assert_equal e[:content].bytesize, e[:content_bytes] }
+    refute events.find { |e| e[:type] == :integer }.key?(:content_bytes)
+
+    cut = Udon.parse(input, content_lengths: true, max_value_bytes: 4)
+    assert_equal sized.map { |e| e[:content_bytes] },
+                 cut.select { |e| e.key?(:content_bytes) }.map { |e| e[:content_bytes] }
+    refute Udon.parse(input).any? { |e| e.key?(:content_bytes) }
+    assert_raises(ArgumentError) { Udon.parse(input, content_lengths: true, format: :columnar) }
+  end
+
+  def test_type_tag
+    input = "|a :n 1\n  |b text\n"
+    events = Udon.parse(input, type_tag: true)
+
+    assert_equal Udon::EventType::ELEMENT_START, events.first[:tag]
+    assert_equal events.map { |e| Udon::EventType.const_get(e[:type].to_s.upcase) }, events.map { |e| e[:tag] }
+    assert_equal 0, Udon::EventType::ELEMENT_START
+    assert_equal 29, Udon::EventType::ERROR
+    assert_equal events.map { |e| e[:tag] }, Udon.parse(input, type_tag: true, format: :columnar)[:tag]
+    refute Udon.parse(input).any? { |e| e.key?(:tag) }
+    refute Udon.parse(input, format: :columnar).key?(:tag)
+    resolved = Udon.parse("|a !{{n}}\n", type_tag: true, interpolation_resolver: ->(_) { 1 })
+    assert_includes resolved.map { |e| e[:tag] }, Udon::EventType::INTEGER
+  end
+
+  def test_bench_parse
+    input = "|a\n  |b :x 1\n    text\n|c\n"
+    count, checksum = Udon.bench_parse(input)
+
+    assert_equal Udon.parse(input).size, count
+    assert_kind_of Integer, checksum
+    assert_equal [count, checksum], Udon.bench_parse(input.tr("abc", "xyz"))
+    refute_equal checksum, Udon.bench_parse("|a\n  |b :x 1\n    |text\n|c\n").last
+    assert_equal 0, Udon.bench_parse("").first
+  end
+
+  def test_parse_packed
+    input = "\uFEFF|a :x 1\n  text\n|b\n"
+    packed = Udon.parse_packed(input)
+    magic, version, size, count, strings = packed.unpack("a4 S< S< L< L<")
+    records = (0...count).map { |i| packed.byteslice(16 + i * size, size).unpack("C x3 L< L< L< L<") }
+    events = Udon.parse(input, type_tag: true)
+
+    assert_equal Encoding::BINARY, packed.encoding
+    assert_equal ["UDNP", 1, 20, events.size, 16 + count * 20], [magic, version, size, count, strings]
+    assert_equal(events.map { |e| [e[:tag], e[:span][:start], e[:span][:end]] },
+                 records.map { |tag, start, stop| [tag, start, stop] })
+    contents = records.map { |*, offset, length| offset == 0xFFFF_FFFF ? nil : packed.byteslice(strings + offset, length) }
+    assert_equal(events.map { |e| e[:content]&.b }, contents)
+
+    nul = Udon.parse_packed("|a\0\n").unpack("x16 C x3 L< L< L< L<")
+    assert_equal [Udon::EventType::ERROR, 2, 3, 0, 8], nul
+  end
+
+  def test_nul_bytes
+    input = "|a\n|b x\0y\n"
+    error = { type: :error, code: :nul_byte, span: { start: 7, end: 8 } }
+
+    assert_equal [error], Udon.parse(input)
+    assert_equal [error], Udon.parse_with_stats(input)[:events]
+    assert_equal [:nul_byte], Udon.parse(input, format: :columnar)[:value]
+    errors = []
+    Udon.parse(input, on_error: ->(e) { errors << e })
+    assert_equal [error], errors
+
+    stripped = Udon.parse(input, nul_bytes: :strip)
+    text = stripped.find { |e| e[:type] == :text }
+    assert_equal "xy", text[:content]
+    assert_equal "x\0y", input.byteslice(text[:span][:start]...text[:span][:end])
+    assert_equal "x\0y", Udon.parse(input, nul_bytes: :allow).find { |e| e[:type] == :text }[:content]
+    assert_raises(ArgumentError) { Udon.parse(input, nul_bytes: :skip) }
+  end
+
+  def test_stats_input_bytes_after_normalization
+    stats = Udon.parse_with_stats("\uFEFF|a\r\n|b\r\n", line_endings: :normalize)[:stats]
+
+    assert_equal 13, stats[:bytes]
+    assert_equal 6, stats[:input_bytes]
+  end
+
+  def test_max_depth_reached_for_flat_and_empty_documents
+    assert_equal 1, Udon.parse_with_stats("|a\n|b\n")[:stats][:max_depth_reached]
+    assert_equal 0, Udon.parse_with_stats("")[:stats][:max_depth_reached]
+  end
+
+  def test_find_element
+    input = "|list\n  |item :id 1\n  |record :id 2\n    |field x\n  |record :id 3\n"
+    found = Udon.find_element(input, "record")
+
+    assert_equal Udon.parse("|record :id 2\n  |field x\n").map { |e| e[:type] }, found[:events].map { |e| e[:type] }
+    assert_equal "2", found[:events].find { |e| e[:type] == :integer }[:content]
+    assert input.byteslice(found[:span][:start]...found[:span][:end]).start_with?("|record :id 2")
+    assert_nil Udon.find_element(input, "missing")
+
+    long = "|list\n  |record :id 1\n#{"    |field x\n" * 3000}  |record :id 2\n"
+    first = Udon.find_element(long, "record")
+    assert_equal 3000, first[:events].count { |e| e[:type] == :name && e[:content] == "field" }
+    assert_equal "1", first[:events].find { |e| e[:type] == :integer }[:content]
+
+    db = Udon.find_element("|config\n  |db :host x\n", "db", parent_name: true)[:events]
+    assert_equal %w[config config db], db.first(3).map { |e| e[:parent_name] }
+
+    nul = Udon.find_element("|a\n  |b x\0\n", "b")
+    assert_equal [:nul_byte], nul[:events].map { |e| e[:code] }
+    assert_equal 9, nul[:span][:start]
+  end
+
+  def test_select_elements
+    input = "|list\n  |user :id 1\n    |user :id 2\n  |item :id 3\n  |user :id 4\n"
+    users = Udon.select_elements(input, "user")
+
+    assert_equal [1, 2, 4], users.map { |user| user["id"] }
+    assert_equal %w[user user user], users.map(&:name)
+    assert_equal ["user"], users.first.children.map(&:name)
+    assert_nil users.first.parent
+    assert input.byteslice(users.last.span[:start]...users.last.span[:end]).start_with?("|user :id 4")
+    assert_equal [1, 4], Udon.select_elements(input, "user", nested: :skip).map { |user| user["id"] }
+    assert_empty Udon.select_elements(input, "missing")
+
+    events = Udon.select_elements(input, "user", format: :events)
+    assert_equal 3, events.size
+    assert_equal Udon.parse("|user :id 1\n  |user :id 2\n").map { |e| e[:type] }, events[0].map { |e| e[:type] }
+    assert_equal events[1], events[0][events[0].index(events[1].first), events[1].size]
+    assert_equal "4", events[2].find { |e| e[:type] == :integer }[:content]
+    skipped = Udon.select_elements(input, "user", format: :events, nested: :skip, spans: :object)
+    assert_equal 2, skipped.size
+    assert_kind_of Udon::Span, skipped[0][0][:span]
+    assert_raises(ArgumentError) { Udon.select_elements(input, "user", spans: :object) }
+    assert_raises(ArgumentError) { Udon.select_elements(input, "user", nested: :maybe) }
+    nul = Udon.select_elements("|user x\0\n", "user", format: :events)
+    assert_equal [[:nul_byte]], nul.map { |events| events.map { |e| e[:code] } }
+  end
+
+  def test_pluck
+    input = "|users\n  |user :email a@b.c :age 30 :admin\n  |team :email t@b.c\n  |user :email !{{x}} :tags [a [b 2]]\n"
+
+    assert_equal ["a@b.c", "t@b.c"], Udon.pluck(input, attribute: "email").first(2)
+    assert_kind_of UdonNative::Interpolation, Udon.pluck(input, attribute: "email", element: "user").last
+    assert_equal [30], Udon.pluck(input, attribute: "age")
+    assert_equal [true], Udon.pluck(input, attribute: "admin")
+    assert_equal [["a", ["b", 2]]], Udon.pluck(input, attribute: "tags")
+    assert_equal ["a", "b", 2], Udon.pluck(input, attribute: "tags", flatten: true)
+    assert_empty Udon.pluck(input, attribute: "email", element: "users")
+
+    pairs = Udon.pluck(input, attribute: "email", element: "user", with_spans: true)
+    assert_equal "a@b.c", pairs[0][0]
+    assert_equal "a@b.c", input.byteslice(pairs[0][1][:start]...pairs[0][1][:end])
+    flag = Udon.pluck(input, attribute: "admin", with_spans: true)[0][1]
+    assert_equal "admin", input.byteslice(flag[:start]...flag[:end])
+  end
+
+  def test_count_elements
+    input = "|records\n  |record :id 1\n  |record :id 2 |{em x}\n  |.note hi\n  |record :id 3\n"
+    counts = Udon.count_elements(input)
+
+    assert_equal({ "records" => 1, "record" => 3, "em" => 1, nil => 1 }, counts)
+    assert_equal ["records", "record", "em", nil], counts.keys
+    assert counts.keys.compact.all?(&:frozen?)
+    pooled = Udon.interned_key_count
+    Udon.count_elements("|count-elements-unpooled\n")
+    assert_equal pooled, Udon.interned_key_count
+    assert_equal 3, Udon.count_elements(input, only: "record")
+    assert_equal 0, Udon.count_elements(input, only: "missing")
+    assert_equal({}, Udon.count_elements(""))
+  end
+
+  def test_attribute_keys
+    keys = Udon.attribute_keys("|a :id 1 :class x\n  |b :href y :id 2\n  |c :class z\n")
+
+    assert_equal %w[id class href], keys
+    assert keys.all?(&:frozen?)
+    assert_same keys[0], Udon.attribute_keys("|d :id 3\n")[0]
+    assert_equal [], Udon.attribute_keys("|a\n")
+    assert_equal %w[id], Udon.attribute_keys("\uFEFF|a :id 1\n")
+  end
+
+  def test_skeleton
+    skeleton = Udon.skeleton("|a :tags [x y] :n 1\n  Hello |{em there}\n  ; note\n  !if x\n")
+
+    assert_equal [%i[element_start], [:name, "a"], [:attr, "tags"], %i[array_start], %i[array_end],
+                  [:attr, "n"], %i[embedded_start], [:name, "em"], %i[embedded_end], %i[element_end]],
+                 skeleton.map { |e| e.values_at(:type, :content).compact }
+    assert_equal Udon.skeleton("|a :n 1\n"), Udon.skeleton("|a :n \"other\"\n  text\n")
+    refute_equal Udon.skeleton("|a :n 1\n"), Udon.skeleton("|a :m 1\n")
+
+    input = "\u{feff}|a :n 1\n"
+    spanned = Udon.skeleton(input, spans: true)
+    assert_equal "|a :n 1", input.byteslice(spanned.first[:span][:start]...spanned.last[:span][:end]).strip
+  end
+
+  def test_comment_spans
+    input = "; header\n|div Hello\n  ; inner\n  |span x\n"
+    spans = Udon.comment_spans(input)
+
+    assert_equal 2, spans.size
+    spans.each do |span|
+      assert_equal ";", input.byteslice(span[:start], 1)
+    end
+    assert_includes input.byteslice(spans[1][:start], spans[1][:end] - spans[1][:start]), "inner"
+
+    stripped = input.dup
+    spans.reverse_each do |span|
+      stripped = stripped.byteslice(0, span[:start]) + stripped.byteslice(span[:end]..)
+    end
+    events = Udon.parse(stripped)
+    refute events.any? { |e| e[:type] == :comment_start }
+    names = events.select { |e| e[:type] == :name }.map { |e| e[:content] }
+    assert_equal %w[div span], names
+
+    bom = "\uFEFF; header\n|div\n"
+    span = Udon.comment_spans(bom).first
+    assert_equal ";", bom.byteslice(span[:start], 1)
+    assert_includes bom.byteslice(span[:start], span[:end] - span[:start]), "header"
+  end
+
+  def test_parse_dispatches_to_type_handlers
+    texts = []
+    names = []
+    result = Udon.parse("|div Hello\n  |span :x 1 World\n",
+                        on_text: ->(e) { texts << e[:content] },
+                        on_name: ->(e) { names << e[:content] })
+
+    assert_nil result
+    assert_equal %w[div span], names
+    assert_equal ["Hello", "World"], texts.map(&:strip)
+  end
+
+  def test_parse_handlers_may_grow_the_input
+    source = +"|div Hello\n  |span :x 1 World\n"
+    names = []
+    Udon.parse(source, on_name: lambda { |e|
+      names << e[:content]
+      source << ("|pad" * 4096) << "\n"
+    })
+
+    assert_equal %w[div span], names
+  end
+
+  def test_parse_handlers_respect_options
+    values = []
+    directives = []
+    Udon.parse("!app:include x\n|a :flag yes\n",
+               canonicalize: true, unify_directives: true,
+               on_bool_true: ->(e) { values << e[:type] },
+               on_directive: ->(e) { directives << e[:name] })
+
+    assert_equal [:bool_true], values
+    assert_equal ["include"], directives
+  end
+
+  def test_parse_handlers_see_shapes
+    shapes = []
+    Udon.parse("|a :x 1\n|a :x 2\n", shape_hash: true, on_element_start: ->(e) { shapes << e[:shape] })
+
+    assert_equal 2, shapes.size
+    assert shapes.all?
+    assert_equal shapes.first, shapes.last
+  end
+
+  def test_parse_handler_errors
+    assert_raises(ArgumentError) { Udon.parse("|a", on_bogus: ->(e) {}) }
+    assert_raises(ArgumentError) { Udon.parse("|a", on_text: 42) }
+    error = assert_raises(RuntimeError) { Udon.parse("|a\n|b\n", on_name: ->(e) { raise "stop" }) }
+    assert_equal "stop", error.message
+  end
+
+  def test_parse_handlers_with_index
+    input = "|a :x 1\n  |b Hello\n"
+    events = Udon.parse(input)
+    seen = []
+
+    Udon.parse(input, with_index: true, on_name: ->(e, i, total) { seen << [e, i, total] })
+    assert_equal(events.each_index.select { |i| events[i][:type] == :name }, seen.map { |_, i, _| i })
+    assert(seen.all? { |e, i, total| events[i] == e && total.nil? })
+
+    seen.clear
+    Udon.parse(input, with_index: true, shape_hash: true, on_name: ->(e, i, total) { seen << [i, total] })
+    assert_equal [events.size], seen.map(&:last).uniq
+
+    seen.clear
+    Udon.parse(input, with_index: true, sort_by_span: true, on_text: ->(e, i, total) { seen << [i, total] })
+    assert_equal [[events.index { |e| e[:type] == :text }, events.size]], seen
+  end
+
+  def test_columnar_format_matches_events
+    input = "|a :x 1\n  |b Hello\n"
+    events = Udon.parse(input)
+    columns = Udon.parse(input, format: :columnar)
+
+    assert_equal %i[type start end name value], columns.keys
+    assert(columns.values.all? { |column| column.size == events.size })
+    assert_equal events.map { |e| e[:type] }, columns[:type]
+    assert_equal events.map { |e| e[:span][:start] }, columns[:start]
+    assert_equal events.map { |e| e[:span][:end] }, columns[:end]
+
+    i = columns[:type].index(:attr)
+    assert_equal ["x", nil], [columns[:name][i], columns[:value][i]]
+    i = columns[:type].index(:integer)
+    assert_equal [nil, "1"], [columns[:name][i], columns[:value][i]]
+    i = columns[:type].index(:element_start)
+    assert_equal [nil, nil], [columns[:name][i], columns[:value][i]]
+    assert_equal %w[a b], columns[:name].values_at(*columns[:type].each_index.select { |j| columns[:type][j] == :name })
+  end
+
+  def test_columnar_format_applies_rewrites
+    columns = Udon.parse("|A :flag yes\n", format: :columnar, canonicalize: true, downcase_names: true)
+
+    assert_includes columns[:name], "a"
+    assert_includes columns[:type], :bool_true
+  end
+
+  def test_columnar_format_rejects_unsupported_options
+    assert_raises(ArgumentError) { Udon.parse("|a", format: :columnar, shape_hash: true) }
+    assert_raises(ArgumentError) { Udon.parse("|a", format: :columnar, spans: :line_col_packed) }
+    assert_raises(ArgumentError) { Udon.parse("|a", format: :columnar, on_name: ->(e) {}) }
+    assert_raises(ArgumentError) { Udon.parse("|a", format: :table) }
+  end
+
+  def test_max_value_bytes_truncates_values_and_text
+    input = "|a :title \"#{"x" * 50}\" :short ok\n  Some long body text here\n"
+    events = Udon.parse(input, max_value_bytes: 8)
+    title = events.find { |e| e[:type] == :string_value }
+    short = events.find { |e| e[:content] == "ok" }
+    text = events.find { |e| e[:type] == :text }
+
+    assert_equal "x" * 8, title[:content]
+    assert title[:truncated]
+    assert_operator title[:span][:end] - title[:span][:start], :>=, 50
+    refute short.key?(:truncated)
+    assert_equal 8, text[:content].bytesize
+    assert text[:truncated]
+    refute events.find { |e| e[:type] == :attr }.key?(:truncated)
+  end
+
+  def test_max_value_bytes_keeps_utf8_characters_whole
+    events = Udon.parse("|a :k \"ééé\"\n", max_value_bytes: 3)
+    value = events.find { |e| e[:type] == :string_value }
+
+    assert_equal "é", value[:content].force_encoding("UTF-8")
+    assert value[:content].valid_encoding?
+    assert value[:truncated]
+  end
+
+  def test_event_at_finds_innermost_event_and_ancestors
+    input = "|a :x 1\n  |b Hello\n"
+    events = Udon.parse(input)
+
+    found = Udon.event_at(events, input.index("Hello") + 1)
+    assert_equal :text, found[:event][:type]
+    assert_equal [:element_start, :element_start], found[:ancestors].map { |e| e[:type] }
+
+    attr = Udon.event_at(events, input.index(":x") + 1)
+    assert_equal :attr, attr[:event][:type]
+    assert_equal 1, attr[:ancestors].size
+
+    between = Udon.event_at(events, input.index("\n  |b"))
+    assert_equal :element_start, between[:event][:type]
+    assert_nil Udon.event_at(events, input.bytesize + 5)
+  end
+
+  def test_event_at_requires_byte_spans
+    events = Udon.parse("|a\n", spans: :line_col_packed)
+    assert_raises(ArgumentError) { Udon.event_at(events, 0) }
+  end
+
+  def test_header_spans_split_header_from_body
+    input = "|section :id main :tags [a b]\n  |p Hello\n|empty :x 1\n"
+    starts = Udon.parse(input, header_spans: true).select { |e| e[:type] == :element_start }
+    section, p, empty = starts
+    slice = ->(span) { input.byteslice(span[:start], span[:end] - span[:start]) }
+
+    assert_equal "|section :id main :tags [a b]", slice.(section[:header_span])
+    assert slice.(section[:body_span]).start_with?("|p Hello")
+    assert_equal "|p", slice.(p[:header_span])
+    assert slice.(p[:body_span]).start_with?("Hello")
+    assert_equal "|empty :x 1", slice.(empty[:header_span])
+    assert_equal empty[:header_span][:end], empty[:body_span][:start]
+    assert_equal empty[:body_span][:start], empty[:body_span][:end]
+  end
+
+  def test_header_spans_absent_by_default
+    refute Udon.parse("|a\n").first.key?(:header_span)
+  end
+
+  def test_mark_container_flags_elements_with_children
+    input = "|list\n  |item one\n|leaf :x 1 Hello |{em there}\n|tags :t [a b]\n"
+    starts = Udon.parse(input, mark_container: true).select { |e| e[:type] == :element_start }
+
+    assert_equal [true, false, false, true], starts.map { |e| e[:has_children] }
+  end
+
+  def test_classify_kinds
+    input = "|list\n  |item one |{em there}\n!if x\n  |p Hi\n|tags :t [a b]\n"
+    kinds = Udon.parse(input, classify: true).filter_map { |e| [e[:type], e[:kind]] if e.key?(:kind) }
+
+    assert_equal [%i[element_start container], %i[element_start leaf], %i[embedded_start embedded],
+                  %i[directive_start directive], %i[element_start leaf], %i[element_start container]], kinds
+    directive = Udon.parse(input, classify: true, unify_directives: true).find { |e| e[:type] == :directive }
+    assert_equal :directive, directive[:kind]
+    refute Udon.parse(input).any? { |e| e.key?(:kind) }
+  end
+
+  def test_precompute_extents_span_to_matching_end
+    input = "|a :t [x y]\n  |b Hello |{em there}\n|c\n"
+    events = Udon.parse(input, precompute_extents: true)
+    starts = events.each_index.select { |i| events[i][:type].to_s.end_with?("_start") }
+    depth_end = lambda do |i|
+      depth = 0
+      (i...events.size).find do |j|
+        kind = events[j][:type].to_s
+        depth += 1 if kind.end_with?("_start")
+        depth -= 1 if kind.end_with?("_end")
+        depth.zero?
+      end
+    end
+
+    starts.reject { |i| events[i][:type] == :comment_start }.each do |i|
+      close = events[depth_end.(i)]
+      assert_equal({ start: events[i][:span][:start], end: close[:span][:end] }, events[i][:extent])
+    end
+    a = events.find { |e| e[:type] == :element_start }
+    assert input.byteslice(a[:extent][:start], a[:extent][:end] - a[:extent][:start]).start_with?("|a :t [x y]\n  |b Hello")
+  end
+
+  def test_precompute_extents_for_unified_directives
+    input = "!app:include :path x\n  |a\n"
+    directive = Udon.parse(input, unify_directives: true, precompute_extents: true)
+                    .find { |e| e[:type] == :directive }
+
+    assert_equal 0, directive[:extent][:start]
+    assert_operator directive[:extent][:end], :>, directive[:span][:end]
+  end
+
+  def test_mark_container_absent_by_default
+    refute Udon.parse("|a\n").first.key?(:has_children)
+  end
+
+  def test_split_interpolations_segments_string_values
+    input = "|a :t \"a !{{x}} b !{{ y.z }}\" :u \"plain\" :v \"!{{open\"\n"
+    values = Udon.parse(input, split_interpolations: true).select { |e| e[:type] == :string_value }
+
+    assert_equal [{ literal: "a " }, { expr: "x" }, { literal: " b " }, { expr: " y.z " }],
+                 values[0][:segments]
+    refute values[1].key?(:segments)
+    refute values[2].key?(:segments)
+  end
+
+  def test_split_interpolations_off_by_default
+    value = Udon.parse("|a :t \"!{{x}}\"\n").find { |e| e[:type] == :string_value }
+    refute value.key?(:segments)
+  end
+
+  def test_expect_encoding
+    input = "|a :x \"caf\xC3\"\n|b\n".b.force_encoding("UTF-8")
+
+    assert_equal Udon.parse("|a\n"), Udon.parse("|a\n", expect_encoding: "UTF-8")
+    assert_equal Udon.parse("|a\n"), Udon.parse("|a\n", expect_encoding: Encoding::UTF_8)
+    error = assert_raises(UdonNative::EncodingError) { Udon.parse(input, expect_encoding: "UTF-8") }
+    assert_equal "invalid UTF-8 at byte 10: [c3] 22 0a 7c 62 0a", error.message
+    assert_raises(UdonNative::EncodingError) { Udon.parse_with_stats(input, expect_encoding: "utf-8") }
+    assert_raises(UdonNative::EncodingError) { Udon.parse(input, expect_encoding: "UTF-8", format: :columnar) }
+    assert_raises(ArgumentError) { Udon.parse("|a\n", expect_encoding: "UTF-16LE") }
+  end
+
+  SEVERITIES = {
+    unexpected_eof: :fatal, unclosed_string_value: :fatal, unclosed_freeform: :fatal,
+    unexpected_char: :recoverable, unclosed: :recoverable, unclosed_array: :recoverable,
+    unclosed_text: :recoverable, unclosed_interpolation: :recoverable, interpolation_failed: :recoverable,
+    no_tabs: :stylistic
+  }.freeze
+
+  def test_severity
+    input = File.read(File.join(__dir__, "fixtures", "unterminated.udon")) + "|s :x \"open\n"
+    errors = Udon.parse(input, severity: true).select { |e| e[:type] == :error }
+
+    refute_empty errors
+    errors.each { |e| assert_equal SEVERITIES.fetch(e[:code]), e[:severity], e[:code] }
+    refute Udon.parse(input).any? { |e| e.key?(:severity) }
+    assert_equal :fatal, Udon.parse("|a\0", severity: true)[0][:severity]
+    assert_raises(ArgumentError) { Udon.parse(input, severity: true, format: :columnar) }
+  end
+
+  def test_span_base_offsets_a_tail_into_the_whole_document
+    head = "|a :x 1\n"
+    tail = "|b :y \"é\"\n  |c z\n"
+    events = Udon.parse(tail, span_base: head.bytesize)
+
+    assert_equal Udon.parse(head + tail).last(events.size), events
+    assert_equal events.map { |e| e[:span][:start] },
+                 Udon.parse(tail, span_base: head.bytesize, format: :columnar)[:start]
+    assert_equal Udon.parse(tail, span_base: 8, spans: :object)[1][:span], events[1][:span]
+    assert_raises(ArgumentError) { Udon.parse(tail, span_base: 8, spans: :line_col_packed) }
+  end
+
+  def test_spans_past_u32_max_do_not_wrap
+    base = 2**32 - 3
+    input = "|a :x 1\r\n|b\r\n"
+    events = Udon.parse(input, span_base: base, line_endings: :normalize)
+    plain = Udon.parse(input, line_endings: :normalize)
+
+    assert_equal plain.map { |e| e[:span].transform_values { |offset| offset + base } },
+                 events.map { |e| e[:span] }
+    assert_operator events.last[:span][:end], :>, 2**32
+    assert_equal events.map { |e| e[:span][:start] },
+                 Udon.parse(input, span_base: base, line_endings: :normalize, format: :columnar)[:start]
+  end
+
+  def test_line_col_packed_columns_count_code_points
+    input = "|a :x \"😀\" :y 1\n"
+    packed = Udon.parse(input, spans: :line_col_packed).find { |e| e[:content] == "1" }[:span]
+    utf16 = Udon.parse(input, spans: :utf16).find { |e| e[:content] == "1" }[:span]
+
+    assert_equal [0, input.index("1")], packed.first(2)
+    assert_equal input.index("1") + 1, utf16[:start]
+  end
+
+  def test_utf16_spans
+    input = "|a :x \"😀 é\" :y 1\n  |b ünïcode 𝄞 text\n"
+    units = ->(offset) { input.byteslice(0, offset).encode("UTF-16LE").bytesize / 2 }
+    expected = Udon.parse(input).map do |event|
+      event.merge(span: { start: units.(event[:span][:start]), end: units.(event[:span][:end]) })
+    end
+
+    assert_equal expected, Udon.parse(input, spans: :utf16)
+    assert_equal Udon.parse("|a :x 1\n"), Udon.parse("|a :x 1\n", spans: :utf16)
+    assert_equal 10, Udon.parse(input, spans: :utf16, span_base: 10).first[:span][:start]
+    assert_raises(ArgumentError) { Udon.parse(input, spans: :utf16, format: :columnar) }
+  end
+
+  def test_sort_by_span_keeps_in_order_events
+    input = "|a :x 1\n  |b Hello |{em there}\n"
+    sorted = Udon.parse(input, sort_by_span: true)
+
+    assert_equal Udon.parse(input), sorted
+    starts = sorted.map { |e| e[:span][:start] }
+    assert_equal starts.sort, starts
+  end
+
+  def test_sort_by_span_with_handlers_and_stats
+    input = "|a :x 1\n|b\n"
+    names = []
+    Udon.parse(input, sort_by_span: true, on_name: ->(e) { names << e[:content] })
+
+    assert_equal %w[a b], names
+    assert_equal Udon.parse(input), Udon.parse_with_stats(input, sort_by_span: true)[:events]
+  end
+
+  def test_intern_keys_global
+    keys = ->(events) { events.select { |e| e[:type] == :attr }.map { |e| e[:content] } }
+    first = keys.(Udon.parse("|a :href x :id y\n", intern_keys: :global))
+    second = keys.(Udon.parse("|b :id z :href w\n", intern_keys: :global))
+
+    assert first.all?(&:frozen?)
+    assert_same first[0], second[1]
+    assert_same first[1], second[0]
+    assert_same first[1], Udon.parse("|c :id 1\n", intern_keys: :global, format: :columnar)[:name][2]
+    assert_operator Udon.interned_key_count, :>=, 2
+
+    long = "k" * 65
+    refute_same(*Array.new(2) { keys.(Udon.parse("|a :#{long} 1\n", intern_keys: :global))[0] })
+    refute_same(*Array.new(2) { keys.(Udon.parse("|a :id 1\n"))[0] })
+    assert_raises(ArgumentError) { Udon.parse("|a\n", intern_keys: :local) }
+  end
+
+  def test_parse_stream_splits_on_delimiter_lines
+    input = "|a :x 1\n---\n|b :y 2\n--- \n|c\n---\n"
+    docs = Udon.parse_stream(input)
+
+    assert_equal 3, docs.size
+    names = docs.map { |events| events.select { |e| e[:type] == :name }.map { |e| e[:content] } }
+    assert_equal [["a"], ["b"], ["c"]], names
+    docs.flatten.each do |event|
+      next unless event[:content]
+
+      span = event[:span]
+      assert_equal event[:content], input.byteslice(span[:start], span[:end] - span[:start])
+    end
+  end
+
+  def test_parse_stream_segment_offsets_and_delimiter
+    input = "|a\n===\n|b\n"
+    docs = Udon.parse_stream(input, delimiter: "===", offsets: :segment)
+
+    assert_equal 2, docs.size
+    assert_equal Udon.parse("|b\n"), docs.last
+    assert_equal 1, Udon.parse_stream("---\n|a\n---\n\n").size
+    assert_equal 1, Udon.parse_stream("|a\n  ---\n").size
+    assert_raises(ArgumentError) { Udon.parse_stream("|a", offsets: :local) }
+  end
 end
@@ -0,0 +1,71 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "date"
+require "udon"
+
+class ValueTypesTest < Minitest::Test
+  INPUT = %(|release :on 2024-01-01 :size 10MB :note "2024-02-02" :tag v1\n)
+
+  def setup
+    Udon::ValueTypes.register(:date, /\A\d{4}-\d{2}-\d{2}\z/) { |s| Date.iso8601(s) }
+    Udon::ValueTypes.register(:bytes, /\A(\d+)mb\z/i) { |s| s.to_i * 1024 * 1024 }
+  end
+
+  def teardown
+    Udon::ValueTypes.clear
+  end
+
+  def custom(events)
+    events.select { |e| e[:type] == :custom_value }
+  end
+
+  def test_matching_bare_values_become_custom_values
+    events = Udon.parse(INPUT, value_plugins: true)
+    date, size = custom(events)
+
+    assert_equal({ type: :custom_value, kind: :date, value: Date.new(2024, 1, 1), raw: "2024-01-01",
+                   span: { start: 13, end: 23 } }, date)
+    assert_equal [:bytes, 10 * 1024 * 1024, "10MB"], size.values_at(:kind, :value, :raw)
+    assert_equal 2, custom(events).size
+    assert_equal Udon.parse(INPUT).size, events.size
+    assert_empty custom(Udon.parse(INPUT))
+  end
+
+  def test_quoted_strings_only_with_all
+    kinds = custom(Udon.parse(INPUT, value_plugins: :all)).map { |e| [e[:kind], e[:raw]] }
+
+    assert_equal [[:date, "2024-01-01"], [:bytes, "10MB"], [:date, "2024-02-02"]], kinds
+    assert_raises(ArgumentError) { Udon.parse(INPUT, value_plugins: :quoted) }
+  end
+
+  def test_registration_order_prefixes_and_replacement
+    Udon::ValueTypes.register(:version, "v") { |s| Gem::Version.new(s.delete_prefix("v")) }
+    Udon::ValueTypes.register(:anything, /./) { |s| s }
+    Udon::ValueTypes.register(:date, "2024") { |s| s.to_sym }
+
+    kinds = custom(Udon.parse(INPUT, value_plugins: true)).map { |e| e[:kind] }
+    assert_equal %i[date bytes version], kinds
+    assert_equal %i[date bytes version anything], Udon::ValueTypes.registered
+    assert Udon::ValueTypes.unregister(:anything)
+    refute Udon::ValueTypes.unregister(:anything)
+  end
+
+  def test_failures_handlers_and_emit
+    Udon::ValueTypes.register(:date, /\A\d{4}-\d{2}-\d{2}\z/) { |s| raise ArgumentError, "bad #{s}" }
+    error = Udon.parse(INPUT, value_plugins: true).find { |e| e[:type] == :error }
+
+    assert_equal [:value_plugin_failed, :date, "bad 2024-01-01"], error.values_at(:code, :kind, :message)
+    seen = []
+    Udon.parse(INPUT, value_plugins: true, on_custom_value: ->(e) { seen << e[:raw] })
+    assert_equal ["10MB"], seen
+    assert_match(/:size "10MB"/, Udon.emit(Udon.parse(INPUT, value_plugins: true)))
+    sorted = Udon.emit(Udon.parse(INPUT, value_plugins: true), sort_attributes: true)
+    assert_match(/:note .* :on "2024-01-01" :size "10MB" :tag /, sorted)
+    assert_raises(ArgumentError) { Udon::ValueTypes.register(:hex, /\h+/) { |s| s } }
+    assert_raises(ArgumentError) { Udon::ValueTypes.register(:tail, /\d+\Z/) { |s| s } }
+    assert_raises(ArgumentError) { Udon::ValueTypes.register(:ahead, /v(?=\d)/) { |s| s } }
+    assert_raises(TypeError) { Udon::ValueTypes.register(:n, 42) { |s| s } }
+    assert_raises(ArgumentError) { Udon.parse(INPUT, value_plugins: true, format: :columnar) }
+  end
+end
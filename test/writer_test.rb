@@ -0,0 +1,74 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "udon"
+
+class WriterTest < Minitest::Test
+  def test_elements_attributes_and_text
+    w = Udon::Writer.new
+    w.element("article", id: "post", count: 3, draft: false, tags: %w[a b]) do
+      w.comment("note")
+      w.element("p") { w.text("Hello") }
+    end
+
+    assert_equal <<~UDON, w.to_s
+      |article :id "post" :count 3 :draft false :tags ["a" "b"]
+        ; note
+        |p Hello
+    UDON
+  end
+
+  def test_directives_and_interpolation
+    w = Udon::Writer.new
+    w.element("page", title: "Home") do
+      w.directive("include", "header.udon", namespace: "app")
+      w.directive("if", env: "production") do
+        w.element("banner", user: Udon.interp("user.id"))
+      end
+      w.element("p") do
+        w.text("Hello ")
+        w.text(Udon.interp("user.name"))
+        w.inline_directive("date", "today")
+      end
+    end
+
+    assert_equal <<~UDON, w.to_s
+      |page :title "Home"
+        !app:include "header.udon"
+        !if :env "production"
+          |banner :user !{{user.id}}
+        |p Hello !{{user.name}}!{date today}
+    UDON
+  end
+
+  def test_to_s_with_open_element_raises
+    w = Udon::Writer.new
+    assert_raises(UdonNative::EmitError) { w.element("a") { w.to_s } }
+  end
+
+  def test_directive_fixture_round_trips_byte_for_byte
+    fixture = <<~UDON
+      !app:include "header.udon"
+      |page :title "Home" :layout wide
+        !if :env production
+          |banner Hello !{{user.name}}!
+          |p Posted !{date today} by !{{author}}
+        |footer :year 2024
+    UDON
+
+    assert_equal fixture, Udon.emit(Udon.parse(fixture), quote: :preserve)
+  end
+
+  def test_writer_output_round_trips_byte_for_byte
+    w = Udon::Writer.new(quote: :preserve)
+    w.element("p") do
+      w.text("Hello ")
+      w.text(Udon.interp("user.name"))
+      w.inline_directive("date", "today")
+    end
+
+    assert_equal w.to_s, Udon.emit(Udon.parse(w.to_s), quote: :preserve)
+    assert_raises(ArgumentError) { Udon.emit([], quote: :double) }
+    assert_raises(ArgumentError) { Udon::Writer.new(quote: :single) }
+  end
+end
@@ -0,0 +1,70 @@
+# frozen_string_literal: true
+
+require "minitest/autorun"
+require "yaml"
+require "udon"
+
+class YamlTest < Minitest::Test
+  def test_to_yaml_types_scalars
+    data = YAML.safe_load(Udon.to_yaml("|config :port 8080 :ratio 0.5 :debug true :name web :extra null\n"))
+
+    assert_equal(
+      { "config" => { "port" => 8080, "ratio" => 0.5, "debug" => true, "name" => "web", "extra" => nil } },
+      data
+    )
+  end
+
+  def test_to_yaml_repeated_children_become_sequences
+    data = YAML.safe_load(Udon.to_yaml(<<~UDON))
+      |servers
+        |server :host a
+        |server :host b
+        |owner :name ops
+    UDON
+
+    assert_equal [{ "host" => "a" }, { "host" => "b" }], data["servers"]["server"]
+    assert_equal({ "name" => "ops" }, data["servers"]["owner"])
+  end
+
+  def test_to_yaml_text_key
+    data = YAML.safe_load(Udon.to_yaml("|p Hello\n", text_key: "content"))
+
+    assert_equal({ "p" => { "content" => "Hello" } }, data)
+  end
+
+  def test_round_trip_scalars
+    yaml = "config:\n  port: 8080\n  ratio: 0.5\n  debug: false\n  name: web\n  extra: ~\n"
+
+    assert_equal YAML.safe_load(yaml), YAML.safe_load(Udon.to_yaml(Udon.from_yaml(yaml)))
+  end
+
+  def test_round_trip_nested_maps
+    yaml = "app:\n  db:\n    primary:\n      host: a\n      port: 5432\n  cache:\n    ttl: 60\n"
+
+    assert_equal YAML.safe_load(yaml), YAML.safe_load(Udon.to_yaml(Udon.from_yaml(yaml)))
+  end
+
+  def test_round_trip_sequences
+    yaml = "list:\n  tags: [a, b, 3]\n  item:\n    - id: 1\n    - id: 2\n"
+
+    assert_equal YAML.safe_load(yaml), YAML.safe_load(Udon.to_yaml(Udon.from_yaml(yaml)))
+  end
+
+  def test_aliases_rejected_by_default
+    yaml = "base: &b\n  port: 1\nprod: *b\n"
+
+    error = assert_raises(UdonNative::YamlError) { Udon.from_yaml(yaml) }
+    assert_match(/alias at line 3/, error.message)
+  end
+
+  def test_aliases_expanded
+    yaml = "base: &b\n  port: 1\nprod: *b\n"
+    data = YAML.safe_load(Udon.to_yaml(Udon.from_yaml(yaml, aliases: :expand)))
+
+    assert_equal({ "port" => 1 }, data["prod"])
+  end
+
+  def test_top_level_must_be_mapping
+    assert_raises(UdonNative::YamlError) { Udon.from_yaml("- a\n- b\n") }
+  end
+end